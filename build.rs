@@ -0,0 +1,404 @@
+//! Builds the user programs in `user/`, which the kernel embeds.
+//!
+//! Every `user/<name>.s` is assembled and linked with `user/link.ld` into `$OUT_DIR/<name>`. The
+//! GNU assembler and linker are used, other ones that produce x86_64 ELF files can be set with the
+//! `AS` and `LD` environment variables, like `x86_64-elf-as` and `x86_64-elf-ld` on macOS.
+//!
+//! The programs can include `abi.inc`, which defines the constants of `src/syscall/abi.rs` with
+//! `SYS_` in front of the system call numbers.
+//!
+//! `initramfs.cpio` is a newc cpio archive of the files in `initramfs/` and the programs in
+//! `/bin`, which the kernel unpacks into its root file system.
+//!
+//! `test-disk.img`, `fat-disk.img` and `ext2-disk.img` in `test-disks/` next to the kernel
+//! executables, like `target/x86_64-ferocios-kernel/debug/test-disks/`, are the disks the tests
+//! attach to QEMU, see `scripts/runner.sh`, `block::ata`, `vfs::fat` and `vfs::ext2`. The FAT disk is made with
+//! `mkfs.fat` from dosfstools and `mcopy` from mtools, and the ext2 disk with `mke2fs` from
+//! e2fsprogs. Other ones can be set with the `MKFS_FAT`, `MCOPY` and `MKE2FS` environment
+//! variables. Without them, the disks are blank and their tests are skipped, unless
+//! `FEROCIOS_REQUIRE_TEST_DISKS` is set, which makes a missing tool an error.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Only the system call numbers and error codes are used here.
+#[allow(dead_code)]
+#[path = "src/syscall/abi.rs"]
+mod abi;
+
+fn main() {
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is not set"));
+    println!("cargo:rerun-if-changed=user");
+    println!("cargo:rerun-if-changed=initramfs");
+    println!("cargo:rerun-if-changed=src/syscall/abi.rs");
+    println!("cargo:rerun-if-env-changed=AS");
+    println!("cargo:rerun-if-env-changed=LD");
+    println!("cargo:rerun-if-env-changed=MKFS_FAT");
+    println!("cargo:rerun-if-env-changed=MCOPY");
+    println!("cargo:rerun-if-env-changed=MKE2FS");
+    println!("cargo:rerun-if-env-changed=FEROCIOS_REQUIRE_TEST_DISKS");
+
+    fs::write(out_dir.join("abi.inc"), abi_include()).expect("Writing abi.inc failed");
+
+    let mut sources: Vec<PathBuf> = fs::read_dir("user")
+        .expect("Reading user/ failed")
+        .map(|entry| entry.expect("Reading user/ failed").path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "s"))
+        .collect();
+    sources.sort();
+
+    let mut files = Files::new();
+    files.insert(String::from("."), (MODE_DIRECTORY, Vec::new()));
+    add_directory(&mut files, Path::new("initramfs"), "");
+    files.insert(String::from("bin"), (MODE_DIRECTORY, Vec::new()));
+    for source in sources {
+        let program = build_program(&source, &out_dir);
+        let name = program.file_name().expect("Program without name");
+        let contents = fs::read(&program).expect("Reading program failed");
+        let path = format!("bin/{}", name.to_string_lossy());
+        files.insert(path, (MODE_FILE | 0o755, contents));
+    }
+    fs::write(out_dir.join("initramfs.cpio"), cpio(&files)).expect("Writing initramfs failed");
+
+    write_test_disk();
+    write_fat_disk(&out_dir);
+    write_ext2_disk(&out_dir);
+}
+
+/// Sectors of the test disk, 2 MiB.
+const TEST_DISK_SECTORS: u64 = 4096;
+
+/// Writes the test disk: the first 8 bytes of each sector are its number, the other bytes its
+/// number modulo 256.
+fn write_test_disk() {
+    let mut disk = Vec::new();
+    for sector in 0..TEST_DISK_SECTORS {
+        disk.extend_from_slice(&sector.to_le_bytes());
+        disk.resize(disk.len() + 504, sector as u8);
+    }
+    write_disk("test-disk.img", &disk);
+}
+
+fn write_disk(name: &str, contents: &[u8]) {
+    fs::write(disk_directory().join(name), contents).expect("Writing a test disk failed");
+}
+
+/// Returns the directory of the test disks and creates it. The runner gives every test run fresh
+/// copies of them.
+fn disk_directory() -> PathBuf {
+    // `OUT_DIR` is `<target>/<triple>/<profile>/build/ferocios-<hash>/out`, wherever
+    // `CARGO_TARGET_DIR` puts `<target>`. The disks go next to the executables in `<profile>`.
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR is not set"));
+    let directory = out_dir
+        .ancestors()
+        .nth(3)
+        .expect("OUT_DIR is not in a profile directory")
+        .join("test-disks");
+    fs::create_dir_all(&directory).expect("Creating test-disks/ failed");
+    directory
+}
+
+/// Sectors of the FAT test disk, 37 MiB, and the first sector of its partition, at 1 MiB. With
+/// clusters of one sector, the partition is just large enough for FAT32.
+const FAT_DISK_SECTORS: u32 = 75776;
+const FAT_PARTITION_START: u32 = 2048;
+/// Lines of `docs/notes.txt`, which spans several clusters.
+const FAT_NOTES_LINES: usize = 200;
+
+/// Writes the FAT test disk: an MBR with a FAT32 partition made by `mkfs.fat`, with a few files
+/// copied by `mcopy`. Without `mkfs.fat` or `mcopy`, the disk is blank.
+fn write_fat_disk(out_dir: &Path) {
+    let root = out_dir.join("fat-root");
+    if root.exists() {
+        fs::remove_dir_all(&root).expect("Removing the old FAT files failed");
+    }
+    fs::create_dir_all(root.join("docs")).expect("Creating the FAT files failed");
+    let write = |path: &str, contents: &[u8]| {
+        fs::write(root.join(path), contents).expect("Writing the FAT files failed")
+    };
+    write("HELLO.TXT", b"Hello from the host!\n");
+    write(
+        "A long file name.txt",
+        b"Long names are stored in extra entries.\n",
+    );
+    let notes: String = (0..FAT_NOTES_LINES)
+        .map(|line| format!("Line {}\n", line))
+        .collect();
+    write("docs/notes.txt", notes.as_bytes());
+
+    let partition = out_dir.join("fat-partition.img");
+    let sectors = FAT_DISK_SECTORS - FAT_PARTITION_START;
+    write_blank_disk(&partition, sectors as u64 * 512);
+    let installed = run_if_installed(
+        Command::new(tool("MKFS_FAT", "mkfs.fat"))
+            .args([
+                "-F", "32", "-S", "512", "-s", "1", "-i", "F32C0501", "-n", "FEROCIOS",
+            ])
+            .arg("-h")
+            .arg(FAT_PARTITION_START.to_string())
+            .arg(&partition),
+    ) && run_if_installed(
+        // The files are added to the root in this order. `MTOOLS_SKIP_CHECK` keeps mtools from
+        // checking the geometry of the image.
+        Command::new(tool("MCOPY", "mcopy"))
+            .env("MTOOLS_SKIP_CHECK", "1")
+            .args(["-s", "-i"])
+            .arg(&partition)
+            .args(["HELLO.TXT", "A long file name.txt", "docs", "::"])
+            .current_dir(&root),
+    );
+    let image = disk_directory().join("fat-disk.img");
+    if !installed {
+        assert!(
+            !test_disks_required(),
+            "mkfs.fat or mcopy was not found, but FEROCIOS_REQUIRE_TEST_DISKS is set"
+        );
+        println!("cargo:warning=mkfs.fat or mcopy was not found, the FAT tests are skipped");
+        write_blank_disk(&image, FAT_DISK_SECTORS as u64 * 512);
+        return;
+    }
+
+    let mut disk = vec![0; FAT_PARTITION_START as usize * 512];
+    // Type 0x0C is FAT32 with LBA.
+    let entry = &mut disk[446..462];
+    entry[4] = 0x0C;
+    entry[8..12].copy_from_slice(&FAT_PARTITION_START.to_le_bytes());
+    entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    disk[510..512].copy_from_slice(&[0x55, 0xAA]);
+    disk.extend(fs::read(&partition).expect("Reading the FAT partition failed"));
+    write_disk("fat-disk.img", &disk);
+}
+
+/// Blocks of the ext2 test disk, 4 MiB of 1 KiB blocks.
+const EXT2_DISK_BLOCKS: u32 = 4096;
+/// Size of `docs/big.bin`, which needs double indirect blocks with 1 KiB blocks.
+const EXT2_BIG_FILE_SIZE: usize = 300 * 1024 + 100;
+
+/// Writes the ext2 test disk with `mke2fs -d` from a directory with a few files, a file only its
+/// owner can read and symbolic links to files. Without `mke2fs`, the disk is blank.
+fn write_ext2_disk(out_dir: &Path) {
+    let root = out_dir.join("ext2-root");
+    if root.exists() {
+        fs::remove_dir_all(&root).expect("Removing the old ext2 files failed");
+    }
+    fs::create_dir_all(root.join("docs")).expect("Creating the ext2 files failed");
+    let write = |path: &str, contents: &[u8]| {
+        fs::write(root.join(path), contents).expect("Writing the ext2 files failed")
+    };
+    write("hello.txt", b"Hello from the host!\n");
+    let big: Vec<u8> = (0..EXT2_BIG_FILE_SIZE)
+        .map(|index| (index % 251) as u8)
+        .collect();
+    write("docs/big.bin", &big);
+    write("secret.txt", b"Only the owner can read this.\n");
+    fs::set_permissions(root.join("secret.txt"), fs::Permissions::from_mode(0o600))
+        .expect("Changing the mode of secret.txt failed");
+    // Targets of 60 bytes and more do not fit into the inode.
+    let links = [
+        ("hello", String::from("hello.txt")),
+        ("big", format!("{}docs/big.bin", "./".repeat(30))),
+    ];
+    for (name, target) in links.iter() {
+        symlink(target, root.join(name)).expect("Creating a symbolic link failed")
+    }
+
+    // mke2fs creates missing images with a message.
+    let image = disk_directory().join("ext2-disk.img");
+    fs::write(&image, []).expect("Writing a test disk failed");
+    let installed = run_if_installed(
+        Command::new(tool("MKE2FS", "mke2fs"))
+            .args(["-q", "-F", "-t", "ext2", "-b", "1024", "-d"])
+            .arg(&root)
+            .arg(&image)
+            .arg(EXT2_DISK_BLOCKS.to_string()),
+    );
+    if !installed {
+        assert!(
+            !test_disks_required(),
+            "mke2fs was not found, but FEROCIOS_REQUIRE_TEST_DISKS is set"
+        );
+        println!("cargo:warning=mke2fs was not found, the ext2 tests are skipped");
+        write_blank_disk(&image, EXT2_DISK_BLOCKS as u64 * 1024);
+    }
+}
+
+// File types in cpio modes, directories with their permissions.
+const MODE_DIRECTORY: u32 = 0o040755;
+const MODE_FILE: u32 = 0o100000;
+
+/// The files of the initramfs by path, with their mode and contents. Sorted by path, directories
+/// come before their contents.
+type Files = BTreeMap<String, (u32, Vec<u8>)>;
+
+/// Adds the contents of the directory, with paths starting with the prefix.
+fn add_directory(files: &mut Files, directory: &Path, prefix: &str) {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        // The directory is optional.
+        Err(_) => return,
+    };
+    for entry in entries {
+        let entry = entry.expect("Reading initramfs/ failed");
+        let name = entry.file_name().into_string().expect("Name is not UTF-8");
+        let path = format!("{}{}", prefix, name);
+        if entry.path().is_dir() {
+            files.insert(path.clone(), (MODE_DIRECTORY, Vec::new()));
+            add_directory(files, &entry.path(), &format!("{}/", path));
+        } else {
+            let contents = fs::read(entry.path()).expect("Reading initramfs/ failed");
+            files.insert(path, (MODE_FILE | 0o644, contents));
+        }
+    }
+}
+
+/// Builds a newc cpio archive. The inode numbers are made up and the times are 0, so the same files
+/// give the same archive.
+fn cpio(files: &Files) -> Vec<u8> {
+    let mut archive = Vec::new();
+    let trailer = (&String::from("TRAILER!!!"), &(0, Vec::new()));
+    for (inode, (path, (mode, contents))) in files.iter().chain([trailer]).enumerate() {
+        let name_size = path.len() + 1;
+        let fields = [
+            inode,
+            *mode as usize,
+            0,
+            0,
+            1,
+            0,
+            contents.len(),
+            0,
+            0,
+            0,
+            0,
+            name_size,
+            0,
+        ];
+        archive.extend_from_slice(b"070701");
+        for field in fields.iter() {
+            archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        archive.extend_from_slice(path.as_bytes());
+        archive.push(0);
+        archive.resize((archive.len() + 3) & !3, 0);
+        archive.extend_from_slice(contents);
+        archive.resize((archive.len() + 3) & !3, 0);
+    }
+    archive
+}
+
+fn abi_include() -> String {
+    let constants = [
+        ("SYS_EXIT", abi::EXIT),
+        ("SYS_WRITE", abi::WRITE),
+        ("SYS_READ", abi::READ),
+        ("SYS_GETPID", abi::GETPID),
+        ("SYS_SLEEP", abi::SLEEP),
+        ("SYS_YIELD", abi::YIELD),
+        ("SYS_MMAP", abi::MMAP),
+        ("SYS_FORK", abi::FORK),
+        ("SYS_EXEC", abi::EXEC),
+        ("SYS_WAITPID", abi::WAITPID),
+        ("SYS_OPEN", abi::OPEN),
+        ("SYS_CLOSE", abi::CLOSE),
+        ("SYS_SEEK", abi::SEEK),
+        ("SYS_STAT", abi::STAT),
+        ("SYS_READDIR", abi::READDIR),
+        ("STDIN", abi::STDIN),
+        ("STDOUT", abi::STDOUT),
+        ("STDERR", abi::STDERR),
+        ("PROT_READ", abi::PROT_READ),
+        ("PROT_WRITE", abi::PROT_WRITE),
+        ("PROT_EXEC", abi::PROT_EXEC),
+        ("O_READ", abi::O_READ),
+        ("O_WRITE", abi::O_WRITE),
+        ("O_CREATE", abi::O_CREATE),
+        ("O_TRUNCATE", abi::O_TRUNCATE),
+        ("O_APPEND", abi::O_APPEND),
+        ("SEEK_SET", abi::SEEK_SET),
+        ("SEEK_CUR", abi::SEEK_CUR),
+        ("SEEK_END", abi::SEEK_END),
+        ("TYPE_FILE", abi::TYPE_FILE),
+        ("TYPE_DIRECTORY", abi::TYPE_DIRECTORY),
+        ("TYPE_CHAR_DEVICE", abi::TYPE_CHAR_DEVICE),
+        ("TYPE_SYMLINK", abi::TYPE_SYMLINK),
+        ("AT_NULL", abi::AT_NULL),
+        ("AT_PHDR", abi::AT_PHDR),
+        ("AT_PHENT", abi::AT_PHENT),
+        ("AT_PHNUM", abi::AT_PHNUM),
+        ("AT_PAGESZ", abi::AT_PAGESZ),
+        ("AT_ENTRY", abi::AT_ENTRY),
+    ];
+    let mut include = String::from("# Generated by build.rs from src/syscall/abi.rs.\n");
+    for (name, value) in constants.iter() {
+        include += &format!(".set {}, {}\n", name, value);
+    }
+    include
+}
+
+/// Assembles and links the program and returns the path of the executable.
+fn build_program(source: &Path, out_dir: &Path) -> PathBuf {
+    let name = source.file_stem().expect("Source without name");
+    let object = out_dir.join(name).with_extension("o");
+    let program = out_dir.join(name);
+
+    run(Command::new(tool("AS", "as"))
+        .args(["--64", "--noexecstack", "-I"])
+        .arg(out_dir)
+        .arg("-o")
+        .arg(&object)
+        .arg(source));
+    run(Command::new(tool("LD", "ld"))
+        .args([
+            "-static",
+            "-nostdlib",
+            "-z",
+            "max-page-size=4096",
+            "-T",
+            "user/link.ld",
+        ])
+        .arg("-o")
+        .arg(&program)
+        .arg(&object));
+    program
+}
+
+fn tool(variable: &str, default: &str) -> String {
+    env::var(variable).unwrap_or_else(|_| default.to_string())
+}
+
+/// Returns whether the tools for the test disks have to be installed, like on CI, instead of the
+/// tests that need them being skipped.
+fn test_disks_required() -> bool {
+    env::var_os("FEROCIOS_REQUIRE_TEST_DISKS").is_some_and(|value| value != "0")
+}
+
+/// Runs the command like `run`, but returns false if its program is not installed.
+fn run_if_installed(command: &mut Command) -> bool {
+    match command.status() {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => false,
+        status => {
+            let status =
+                status.unwrap_or_else(|error| panic!("Running {:?} failed: {}", command, error));
+            assert!(status.success(), "{:?} failed with {}", command, status);
+            true
+        }
+    }
+}
+
+/// Writes a disk of zeros in place of one that cannot be made. QEMU still attaches it.
+fn write_blank_disk(image: &Path, size: u64) {
+    let disk = fs::File::create(image).expect("Writing a test disk failed");
+    disk.set_len(size).expect("Writing a test disk failed");
+}
+
+fn run(command: &mut Command) {
+    let status = command
+        .status()
+        .unwrap_or_else(|error| panic!("Running {:?} failed: {}", command, error));
+    assert!(status.success(), "{:?} failed with {}", command, status);
+}
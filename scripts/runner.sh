@@ -0,0 +1,25 @@
+#!/bin/sh
+# Cargo runner: boots the kernel executable in QEMU with `bootimage runner`.
+#
+# Tests get fresh copies of the disks `build.rs` writes to `test-disks/` next to the kernel
+# executables, since they write to them. The copies of the last run stay in `test-disks/run/`.
+set -e
+
+executable="$1"
+shift
+directory="$(dirname "$executable")"
+# Test executables are in `deps/`, the kernel itself is one level up.
+if [ "$(basename "$directory")" != deps ]; then
+  exec bootimage runner "$executable" "$@"
+fi
+
+disks="$(dirname "$directory")/test-disks"
+mkdir -p "$disks/run"
+for disk in ext2-disk.img test-disk.img fat-disk.img; do
+  cp "$disks/$disk" "$disks/run/$disk"
+done
+# See `block::ata`, `vfs::fat` and `vfs::ext2`.
+exec bootimage runner "$executable" "$@" \
+  -drive "file=$disks/run/ext2-disk.img,format=raw,if=ide,index=1" \
+  -drive "file=$disks/run/test-disk.img,format=raw,if=ide,index=2" \
+  -drive "file=$disks/run/fat-disk.img,format=raw,if=ide,index=3"
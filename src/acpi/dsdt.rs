@@ -0,0 +1,56 @@
+//! Just enough of the AML in the DSDT to find the sleep types for entering S5.
+
+use x86_64::PhysAddr;
+
+use super::{read_phys, SdtHeader};
+
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0A;
+
+/// Values to write to the SLP_TYP fields of the PM1a and PM1b control registers to enter S5.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub pm1a: u8,
+    pub pm1b: u8,
+}
+
+/// Finds the `_S5_` package, which is encoded as
+/// `NameOp "_S5_" PackageOp PkgLength NumElements SLP_TYPa SLP_TYPb ...`.
+///
+/// # Safety
+///
+/// `address` must point to a valid DSDT.
+pub(super) unsafe fn find_s5(address: PhysAddr) -> Option<SleepType> {
+    let length = read_phys::<SdtHeader>(address).length as usize;
+    let aml = core::slice::from_raw_parts(
+        crate::memory::paging::phys_to_virt(address).as_ptr::<u8>(),
+        length,
+    );
+
+    let start = aml.windows(4).position(|name| name == b"_S5_")?;
+    // The name may be written as the absolute path `\_S5_`.
+    let name_op = match aml.get(start.checked_sub(1)?)? {
+        b'\\' => aml.get(start.checked_sub(2)?)?,
+        byte => byte,
+    };
+    if *name_op != NAME_OP || *aml.get(start + 4)? != PACKAGE_OP {
+        return None;
+    }
+
+    // The top two bits of the first PkgLength byte count the bytes that follow it.
+    let package_length = usize::from(aml.get(start + 5)? >> 6) + 1;
+    let mut elements = aml.get(start + 5 + package_length + 1..)?.iter();
+    let mut next_integer = || match *elements.next()? {
+        ZERO_OP => Some(0),
+        ONE_OP => Some(1),
+        BYTE_PREFIX => elements.next().copied(),
+        _ => None,
+    };
+    Some(SleepType {
+        pm1a: next_integer()?,
+        pm1b: next_integer()?,
+    })
+}
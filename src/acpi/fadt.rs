@@ -0,0 +1,150 @@
+//! Fixed ACPI Description Table, describes the power management hardware.
+
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+use super::{read_phys, SdtHeader};
+use crate::memory::paging;
+
+/// The reset register in the FADT is valid.
+const FLAG_RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+
+// Offsets of the fields from the start of the table. Later revisions only append fields.
+const DSDT: u64 = 40;
+const SCI_INTERRUPT: u64 = 46;
+const SMI_COMMAND: u64 = 48;
+const ACPI_ENABLE: u64 = 52;
+const ACPI_DISABLE: u64 = 53;
+const PM1A_EVENT_BLOCK: u64 = 56;
+const PM1B_EVENT_BLOCK: u64 = 60;
+const PM1A_CONTROL_BLOCK: u64 = 64;
+const PM1B_CONTROL_BLOCK: u64 = 68;
+const PM_TIMER_BLOCK: u64 = 76;
+const CENTURY: u64 = 108;
+const FLAGS: u64 = 112;
+const RESET_REGISTER: u64 = 116;
+const RESET_VALUE: u64 = 128;
+const X_DSDT: u64 = 140;
+
+/// Location of a register in memory or I/O space.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct GenericAddress {
+    /// 0 for memory, 1 for I/O ports.
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const MEMORY: u8 = 0;
+    pub const IO: u8 = 1;
+
+    /// Writes a byte to the register. Registers in other address spaces are not supported.
+    ///
+    /// # Safety
+    ///
+    /// The write must not violate memory safety, which depends on the register.
+    pub unsafe fn write_u8(&self, value: u8) {
+        let address = self.address;
+        match self.address_space {
+            GenericAddress::IO => Port::<u8>::new(address as u16).write(value),
+            GenericAddress::MEMORY => {
+                if let Ok(register) = paging::map_mmio(PhysAddr::new(address), 1) {
+                    register.as_mut_ptr::<u8>().write_volatile(value)
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl core::fmt::Debug for GenericAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let address = self.address;
+        let space = match self.address_space {
+            GenericAddress::MEMORY => "memory",
+            GenericAddress::IO => "I/O",
+            _ => "other",
+        };
+        write!(f, "{} {:#x} ({} bits)", space, address, self.bit_width)
+    }
+}
+
+// Fields the kernel does not use yet are still shown by the `acpi` command.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct Fadt {
+    /// Address of the DSDT, which holds the AML code describing the machine.
+    pub dsdt: PhysAddr,
+    /// ISA interrupt of the system control interrupt.
+    pub sci_interrupt: u16,
+    /// Writing `acpi_enable` to this port hands over power management from the firmware. Zero if
+    /// ACPI is always enabled.
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    // I/O ports of the power management register blocks. Zero if they do not exist.
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    /// Index of the century in the CMOS RTC, or zero.
+    pub century: u8,
+    pub flags: u32,
+    /// Register to write `reset_value` to to reset the machine, if supported.
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    /// # Safety
+    ///
+    /// `address` must point to a FADT.
+    pub(super) unsafe fn parse(address: PhysAddr) -> Fadt {
+        let length = u64::from(read_phys::<SdtHeader>(address).length);
+        // Reads a field, or zero if the table revision does not have it.
+        let field = |offset: u64, size: u64| -> u64 {
+            if offset + size > length {
+                return 0;
+            }
+            match size {
+                1 => u64::from(read_phys::<u8>(address + offset)),
+                2 => u64::from(read_phys::<u16>(address + offset)),
+                4 => u64::from(read_phys::<u32>(address + offset)),
+                _ => read_phys::<u64>(address + offset),
+            }
+        };
+
+        let flags = field(FLAGS, 4) as u32;
+        let reset_supported = flags & FLAG_RESET_REGISTER_SUPPORTED != 0 && RESET_VALUE < length;
+        let dsdt = match field(X_DSDT, 8) {
+            0 => field(DSDT, 4),
+            x_dsdt => x_dsdt,
+        };
+
+        Fadt {
+            dsdt: PhysAddr::new(dsdt),
+            sci_interrupt: field(SCI_INTERRUPT, 2) as u16,
+            smi_command: field(SMI_COMMAND, 4) as u32,
+            acpi_enable: field(ACPI_ENABLE, 1) as u8,
+            acpi_disable: field(ACPI_DISABLE, 1) as u8,
+            pm1a_event_block: field(PM1A_EVENT_BLOCK, 4) as u32,
+            pm1b_event_block: field(PM1B_EVENT_BLOCK, 4) as u32,
+            pm1a_control_block: field(PM1A_CONTROL_BLOCK, 4) as u32,
+            pm1b_control_block: field(PM1B_CONTROL_BLOCK, 4) as u32,
+            pm_timer_block: field(PM_TIMER_BLOCK, 4) as u32,
+            century: field(CENTURY, 1) as u8,
+            flags,
+            reset_register: if reset_supported {
+                Some(read_phys::<GenericAddress>(address + RESET_REGISTER))
+            } else {
+                None
+            },
+            reset_value: field(RESET_VALUE, 1) as u8,
+        }
+    }
+}
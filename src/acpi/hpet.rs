@@ -0,0 +1,47 @@
+//! High Precision Event Timer description table.
+
+use x86_64::PhysAddr;
+
+use super::fadt::GenericAddress;
+use super::read_phys;
+
+const EVENT_TIMER_BLOCK_ID: u64 = 36;
+const BASE_ADDRESS: u64 = 40;
+const HPET_NUMBER: u64 = 52;
+const MINIMUM_TICK: u64 = 53;
+
+// Only the address and the comparators are used, the rest is shown by the `acpi` command.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct Hpet {
+    /// Physical address of the memory-mapped registers.
+    pub address: PhysAddr,
+    pub number: u8,
+    pub pci_vendor_id: u16,
+    /// Amount of timers that can raise interrupts.
+    pub comparators: u8,
+    pub counter_is_64_bit: bool,
+    /// The HPET can take over the interrupts of the PIT and the RTC.
+    pub legacy_replacement: bool,
+    /// Minimum amount of counter ticks between interrupts in periodic mode.
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    /// # Safety
+    ///
+    /// `address` must point to a HPET table.
+    pub(super) unsafe fn parse(address: PhysAddr) -> Hpet {
+        let id = read_phys::<u32>(address + EVENT_TIMER_BLOCK_ID);
+        let base = read_phys::<GenericAddress>(address + BASE_ADDRESS);
+        Hpet {
+            address: PhysAddr::new(base.address),
+            number: read_phys(address + HPET_NUMBER),
+            pci_vendor_id: (id >> 16) as u16,
+            comparators: ((id >> 8) & 0b1_1111) as u8 + 1,
+            counter_is_64_bit: id & (1 << 13) != 0,
+            legacy_replacement: id & (1 << 15) != 0,
+            minimum_tick: read_phys(address + MINIMUM_TICK),
+        }
+    }
+}
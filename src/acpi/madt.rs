@@ -0,0 +1,110 @@
+//! Multiple APIC Description Table.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::PhysAddr;
+
+use super::{read_phys, SdtHeader};
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// Processor and the ID of its local APIC.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    #[allow(dead_code)]
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// Disabled processors cannot be started.
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    #[allow(dead_code)]
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt handled by this I/O APIC.
+    pub gsi_base: u32,
+}
+
+/// ISA interrupt that is not wired to the global system interrupt of the same number, or that has
+/// a non-standard polarity or trigger mode.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+impl InterruptSourceOverride {
+    pub fn active_low(&self) -> bool {
+        self.flags & 0b11 == 0b11
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0b11 == 0b11
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// The machine also has the two legacy 8259 PICs, which must be masked to use the APICs.
+    pub legacy_pics: bool,
+    pub local_apics: Vec<LocalApic>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptSourceOverride>,
+}
+
+impl Madt {
+    /// # Safety
+    ///
+    /// `address` must point to a MADT.
+    pub(super) unsafe fn parse(address: PhysAddr) -> Madt {
+        let header = read_phys::<SdtHeader>(address);
+        let body = address + size_of::<SdtHeader>();
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(u64::from(read_phys::<u32>(body))),
+            legacy_pics: read_phys::<u32>(body + 4u64) & 1 == 1,
+            local_apics: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        let end = address + u64::from(header.length);
+        let mut entry = body + 8u64;
+        while entry < end {
+            let kind = read_phys::<u8>(entry);
+            let length = read_phys::<u8>(entry + 1u64);
+            if length < 2 {
+                break;
+            }
+            match kind {
+                ENTRY_LOCAL_APIC => madt.local_apics.push(LocalApic {
+                    processor_id: read_phys(entry + 2u64),
+                    apic_id: read_phys(entry + 3u64),
+                    enabled: read_phys::<u32>(entry + 4u64) & 1 == 1,
+                }),
+                ENTRY_IO_APIC => madt.io_apics.push(IoApic {
+                    id: read_phys(entry + 2u64),
+                    address: PhysAddr::new(u64::from(read_phys::<u32>(entry + 4u64))),
+                    gsi_base: read_phys(entry + 8u64),
+                }),
+                ENTRY_INTERRUPT_SOURCE_OVERRIDE => madt.overrides.push(InterruptSourceOverride {
+                    irq: read_phys(entry + 3u64),
+                    gsi: read_phys(entry + 4u64),
+                    flags: read_phys(entry + 8u64),
+                }),
+                ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                    madt.local_apic_address = PhysAddr::new(read_phys(entry + 4u64))
+                }
+                _ => (),
+            }
+            entry += u64::from(length);
+        }
+        madt
+    }
+}
@@ -0,0 +1,310 @@
+//! Discovery of the ACPI tables the firmware leaves in memory.
+//!
+//! The tables are only read, through the physical memory mapping. Tables with a wrong checksum are
+//! ignored.
+
+mod dsdt;
+mod fadt;
+mod hpet;
+mod madt;
+
+#[allow(unused_imports)]
+pub use dsdt::SleepType;
+#[allow(unused_imports)]
+pub use fadt::{Fadt, GenericAddress};
+#[allow(unused_imports)]
+pub use hpet::Hpet;
+#[allow(unused_imports)]
+pub use madt::{InterruptSourceOverride, IoApic, LocalApic, Madt};
+
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt::{self, Write};
+use core::mem::size_of;
+use core::str;
+use x86_64::PhysAddr;
+
+use crate::memory::paging;
+
+/// The BIOS data area holds the segment of the extended BIOS data area at this address.
+const EBDA_SEGMENT_POINTER: u64 = 0x40E;
+const EBDA_SEARCH_LENGTH: u64 = 1024;
+const BIOS_AREA_START: u64 = 0xE_0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+const RSDP_SIGNATURE: [u8; 8] = *b"RSD PTR ";
+/// Length of the part of the RSDP the first checksum covers.
+const RSDP_V1_LENGTH: usize = 20;
+
+/// Root System Description Pointer, points to the RSDT and, from revision 2 on, the XSDT.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // The remaining fields only exist from revision 2 on.
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Header shared by all system description tables.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// The tables the kernel understands, parsed once at boot.
+pub struct Tables {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    /// Address and header of every valid table listed in the RSDT or XSDT.
+    pub headers: Vec<(PhysAddr, SdtHeader)>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    /// How to enter the S5 (soft off) sleep state, found in the DSDT.
+    pub s5: Option<SleepType>,
+}
+
+static TABLES: OnceCell<Tables> = OnceCell::uninit();
+
+/// Finds and parses the ACPI tables. Does nothing if the firmware does not provide any.
+pub fn init() {
+    if let Some(rsdp) = find_rsdp() {
+        TABLES.init_once(|| unsafe { Tables::parse(&rsdp) })
+    }
+}
+
+/// Returns the ACPI tables, or `None` if there are none or `init` was not called.
+pub fn tables() -> Option<&'static Tables> {
+    TABLES.try_get().ok()
+}
+
+impl Tables {
+    /// # Safety
+    ///
+    /// `rsdp` must be valid.
+    unsafe fn parse(rsdp: &Rsdp) -> Tables {
+        let headers: Vec<(PhysAddr, SdtHeader)> = table_addresses(rsdp)
+            .into_iter()
+            .filter(|&address| is_valid_table(address))
+            .map(|address| (address, read_phys::<SdtHeader>(address)))
+            .collect();
+        let find = |signature: &[u8; 4]| {
+            headers
+                .iter()
+                .find(|(_, header)| header.signature == *signature)
+                .map(|&(address, _)| address)
+        };
+
+        let fadt = find(b"FACP").map(|address| Fadt::parse(address));
+        let s5 = fadt
+            .as_ref()
+            .map(|fadt| fadt.dsdt)
+            .filter(|&dsdt| dsdt.as_u64() != 0 && is_valid_table(dsdt))
+            .and_then(|dsdt| dsdt::find_s5(dsdt));
+
+        Tables {
+            revision: rsdp.revision,
+            oem_id: rsdp.oem_id,
+            madt: find(b"APIC").map(|address| Madt::parse(address)),
+            fadt,
+            hpet: find(b"HPET").map(|address| Hpet::parse(address)),
+            s5,
+            headers,
+        }
+    }
+}
+
+/// Reads a value from physical memory.
+///
+/// # Safety
+///
+/// The memory at `address` must hold a valid `T`.
+unsafe fn read_phys<T: Copy>(address: PhysAddr) -> T {
+    paging::phys_to_virt(address).as_ptr::<T>().read_unaligned()
+}
+
+/// Returns whether the bytes add up to zero, which is how all ACPI checksums work.
+///
+/// # Safety
+///
+/// The memory must be readable.
+unsafe fn checksum_is_valid(address: PhysAddr, length: usize) -> bool {
+    let bytes = core::slice::from_raw_parts(paging::phys_to_virt(address).as_ptr::<u8>(), length);
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+unsafe fn is_valid_table(address: PhysAddr) -> bool {
+    let length = read_phys::<SdtHeader>(address).length as usize;
+    length >= size_of::<SdtHeader>() && checksum_is_valid(address, length)
+}
+
+/// Searches the first KiB of the extended BIOS data area and the main BIOS area for the RSDP.
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda_segment = unsafe { read_phys::<u16>(PhysAddr::new(EBDA_SEGMENT_POINTER)) };
+    let ebda = u64::from(ebda_segment) << 4;
+    let candidates = (ebda..ebda + EBDA_SEARCH_LENGTH).chain(BIOS_AREA_START..BIOS_AREA_END);
+
+    candidates
+        .step_by(16)
+        .map(PhysAddr::new)
+        .find(|&address| unsafe { is_valid_rsdp(address) })
+        .map(|address| unsafe { read_phys::<Rsdp>(address) })
+}
+
+unsafe fn is_valid_rsdp(address: PhysAddr) -> bool {
+    let rsdp = read_phys::<Rsdp>(address);
+    if rsdp.signature != RSDP_SIGNATURE || !checksum_is_valid(address, RSDP_V1_LENGTH) {
+        return false;
+    }
+    rsdp.revision < 2 || checksum_is_valid(address, rsdp.length as usize)
+}
+
+/// Returns the addresses of the tables listed in the XSDT, or in the RSDT on ACPI 1.0 machines.
+///
+/// # Safety
+///
+/// `rsdp` must be valid.
+unsafe fn table_addresses(rsdp: &Rsdp) -> Vec<PhysAddr> {
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (PhysAddr::new(rsdp.xsdt_address), size_of::<u64>())
+    } else {
+        (
+            PhysAddr::new(u64::from(rsdp.rsdt_address)),
+            size_of::<u32>(),
+        )
+    };
+    if !is_valid_table(root) {
+        return Vec::new();
+    }
+
+    let header = read_phys::<SdtHeader>(root);
+    let entries = (header.length as usize - size_of::<SdtHeader>()) / entry_size;
+    (0..entries)
+        .map(|i| {
+            let entry = root + size_of::<SdtHeader>() + i * entry_size;
+            let address = if entry_size == size_of::<u64>() {
+                read_phys::<u64>(entry)
+            } else {
+                u64::from(read_phys::<u32>(entry))
+            };
+            PhysAddr::new(address)
+        })
+        .collect()
+}
+
+fn as_str(bytes: &[u8]) -> &str {
+    str::from_utf8(bytes).unwrap_or("?").trim_end()
+}
+
+/// Writes the parsed tables to `out`, for the `acpi` shell command.
+pub fn dump(out: &mut dyn Write) -> fmt::Result {
+    let tables = match tables() {
+        Some(tables) => tables,
+        None => return writeln!(out, "ACPI: no tables found"),
+    };
+
+    writeln!(
+        out,
+        "ACPI: revision {}, OEM {}",
+        tables.revision,
+        as_str(&tables.oem_id)
+    )?;
+    for (address, header) in &tables.headers {
+        let length = header.length;
+        writeln!(
+            out,
+            "  {} at {:#x}, {} bytes, OEM {} {}",
+            as_str(&header.signature),
+            address.as_u64(),
+            length,
+            as_str(&header.oem_id),
+            as_str(&header.oem_table_id)
+        )?;
+    }
+
+    if let Some(madt) = &tables.madt {
+        writeln!(
+            out,
+            "MADT: local APIC at {:#x}, legacy PICs: {}",
+            madt.local_apic_address.as_u64(),
+            madt.legacy_pics
+        )?;
+        for local_apic in &madt.local_apics {
+            writeln!(out, "  {:?}", local_apic)?;
+        }
+        for io_apic in &madt.io_apics {
+            writeln!(out, "  {:?}", io_apic)?;
+        }
+        for interrupt_override in &madt.overrides {
+            writeln!(out, "  {:?}", interrupt_override)?;
+        }
+    }
+    if let Some(fadt) = &tables.fadt {
+        writeln!(out, "FADT: {:#x?}", fadt)?;
+    }
+    if let Some(hpet) = &tables.hpet {
+        writeln!(out, "HPET: {:#x?}", hpet)?;
+    }
+    if let Some(s5) = &tables.s5 {
+        writeln!(out, "S5: {:?}", s5)?;
+    }
+    Ok(())
+}
+
+#[test_case]
+fn rsdp_is_found() {
+    let rsdp = find_rsdp().expect("No RSDP");
+    assert_eq!(rsdp.signature, RSDP_SIGNATURE);
+}
+
+#[test_case]
+fn listed_tables_are_valid() {
+    let tables = tables().unwrap();
+    assert!(!tables.headers.is_empty());
+    for &(address, _) in &tables.headers {
+        assert!(unsafe { is_valid_table(address) });
+    }
+}
+
+#[test_case]
+fn madt_lists_processor_and_io_apic() {
+    let madt = tables().unwrap().madt.as_ref().expect("No MADT");
+    assert!(!madt.local_apics.is_empty());
+    assert!(!madt.io_apics.is_empty());
+}
+
+#[test_case]
+fn fadt_has_power_management_ports() {
+    let fadt = tables().unwrap().fadt.as_ref().expect("No FADT");
+    assert_ne!(fadt.pm1a_control_block, 0);
+    assert_ne!(fadt.pm_timer_block, 0);
+    assert_ne!(fadt.dsdt.as_u64(), 0);
+}
+
+#[test_case]
+fn hpet_is_found() {
+    // QEMU puts the HPET at its standard address.
+    let hpet = tables().unwrap().hpet.as_ref().expect("No HPET");
+    assert_eq!(hpet.address, PhysAddr::new(0xFED0_0000));
+    assert!(hpet.comparators >= 3);
+}
+
+#[test_case]
+fn s5_sleep_type_is_found() {
+    assert!(tables().unwrap().s5.is_some());
+}
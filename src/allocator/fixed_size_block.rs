@@ -0,0 +1,94 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::ptr;
+
+use super::linked_list::LinkedListAllocator;
+use super::Locked;
+
+/// The block sizes to use.
+///
+/// The sizes must each be power of 2 because they are also used as the block alignment.
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct ListNode {
+    next: *mut ListNode,
+}
+
+/// Allocator that serves small allocations from lists of fixed-size blocks.
+///
+/// Allocations bigger than the largest block size, and new blocks when a list is empty, come from
+/// a linked list allocator. Freed blocks are kept in their list and never returned to it.
+pub struct FixedSizeBlockAllocator {
+    list_heads: [*mut ListNode; BLOCK_SIZES.len()],
+    fallback_allocator: LinkedListAllocator,
+}
+
+// The blocks are only reachable through the allocator, which is protected by `Locked`.
+unsafe impl Send for FixedSizeBlockAllocator {}
+
+impl FixedSizeBlockAllocator {
+    pub const fn new() -> Self {
+        FixedSizeBlockAllocator {
+            list_heads: [ptr::null_mut(); BLOCK_SIZES.len()],
+            fallback_allocator: LinkedListAllocator::new(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The heap region must be mapped and unused, and this function must only be called once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size)
+    }
+
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        self.fallback_allocator
+            .allocate(layout)
+            .unwrap_or(ptr::null_mut())
+    }
+}
+
+/// Chooses the smallest block size that fits the layout.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        match list_index(&layout) {
+            Some(index) => {
+                let head = allocator.list_heads[index];
+                if head.is_null() {
+                    // No block left in the list, allocate a new one.
+                    let block_size = BLOCK_SIZES[index];
+                    let layout = Layout::from_size_align(block_size, block_size).unwrap();
+                    allocator.fallback_alloc(layout)
+                } else {
+                    allocator.list_heads[index] = (*head).next;
+                    head as *mut u8
+                }
+            }
+            None => allocator.fallback_alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        match list_index(&layout) {
+            Some(index) => {
+                // Every block size can hold a node and is aligned for it.
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+
+                let node = ptr as *mut ListNode;
+                node.write(ListNode {
+                    next: allocator.list_heads[index],
+                });
+                allocator.list_heads[index] = node;
+            }
+            None => allocator.fallback_allocator.deallocate(ptr, layout),
+        }
+    }
+}
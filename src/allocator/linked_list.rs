@@ -0,0 +1,159 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::ptr;
+
+use super::{align_up, Locked};
+
+struct ListNode {
+    size: usize,
+    next: *mut ListNode,
+}
+
+impl ListNode {
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+/// First-fit allocator that keeps the free regions in a list sorted by address.
+///
+/// Adjacent free regions are merged when memory is freed, so the heap does not fragment into ever
+/// smaller regions under allocation churn.
+pub struct LinkedListAllocator {
+    // Dummy node whose `next` is the first free region.
+    head: ListNode,
+}
+
+// The free regions are only reachable through the allocator, which is protected by `Locked`.
+unsafe impl Send for LinkedListAllocator {}
+
+impl LinkedListAllocator {
+    pub const fn new() -> Self {
+        LinkedListAllocator {
+            head: ListNode {
+                size: 0,
+                next: ptr::null_mut(),
+            },
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The heap region must be mapped and unused, and this function must only be called once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.add_free_region(heap_start, heap_size)
+    }
+
+    /// Returns a region of memory matching the layout, or `None` if no free region is big enough.
+    pub fn allocate(&mut self, layout: Layout) -> Option<*mut u8> {
+        let (size, align) = Self::size_align(layout);
+
+        let mut previous: *mut ListNode = &mut self.head;
+        unsafe {
+            while !(*previous).next.is_null() {
+                let region = (*previous).next;
+                if let Some((alloc_start, alloc_end)) = Self::fit(&*region, size, align) {
+                    let region_start = (*region).start_addr();
+                    let region_end = (*region).end_addr();
+                    (*previous).next = (*region).next;
+
+                    if alloc_start > region_start {
+                        self.add_free_region(region_start, alloc_start - region_start)
+                    }
+                    if region_end > alloc_end {
+                        self.add_free_region(alloc_end, region_end - alloc_end)
+                    }
+                    return Some(alloc_start as *mut u8);
+                }
+                previous = region;
+            }
+        }
+        None
+    }
+
+    /// Returns memory previously handed out by `allocate` with the same layout.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must come from `allocate` on this allocator with the same layout.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr as usize, size)
+    }
+
+    /// Adjusts the layout so that the freed memory can always hold a `ListNode`.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("Adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+
+    /// Returns the start and end of an allocation inside the region, if it fits.
+    fn fit(region: &ListNode, size: usize, align: usize) -> Option<(usize, usize)> {
+        let node_size = mem::size_of::<ListNode>();
+
+        let mut alloc_start = align_up(region.start_addr(), align);
+        let excess_front = alloc_start - region.start_addr();
+        if excess_front > 0 && excess_front < node_size {
+            // The space in front would be too small to be tracked as a free region.
+            alloc_start = align_up(region.start_addr() + node_size, align);
+        }
+
+        let alloc_end = alloc_start.checked_add(size)?;
+        if alloc_end > region.end_addr() {
+            return None;
+        }
+
+        let excess_end = region.end_addr() - alloc_end;
+        if excess_end > 0 && excess_end < node_size {
+            return None;
+        }
+        Some((alloc_start, alloc_end))
+    }
+
+    unsafe fn add_free_region(&mut self, address: usize, size: usize) {
+        assert_eq!(align_up(address, mem::align_of::<ListNode>()), address);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        // Find the last node in front of the new region.
+        let mut previous: *mut ListNode = &mut self.head;
+        while !(*previous).next.is_null() && (*(*previous).next).start_addr() < address {
+            previous = (*previous).next;
+        }
+
+        let node = address as *mut ListNode;
+        node.write(ListNode {
+            size,
+            next: (*previous).next,
+        });
+        (*previous).next = node;
+
+        // Merge with the following region, then with the preceding one.
+        let next = (*node).next;
+        if !next.is_null() && (*node).end_addr() == (*next).start_addr() {
+            (*node).size += (*next).size;
+            (*node).next = (*next).next;
+        }
+        if !ptr::eq(previous, &self.head) && (*previous).end_addr() == address {
+            (*previous).size += (*node).size;
+            (*previous).next = (*node).next;
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(ptr, layout)
+    }
+}
@@ -0,0 +1,204 @@
+#[cfg(feature = "heap-bump")]
+mod bump;
+#[cfg(all(not(feature = "heap-bump"), not(feature = "heap-linked-list")))]
+mod fixed_size_block;
+#[cfg(not(feature = "heap-bump"))]
+mod linked_list;
+
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::memory::demand::{self, Region, RegionError};
+
+pub const HEAP_START: usize = 0xFFFF_C000_0000_0000;
+pub const HEAP_SIZE: usize = 16 * 1024 * 1024;
+
+// The backend is picked at compile time. When several backend features are enabled, like with
+// `--all-features`, the first one in this list wins.
+#[cfg(feature = "heap-bump")]
+type Backend = bump::BumpAllocator;
+#[cfg(all(not(feature = "heap-bump"), feature = "heap-linked-list"))]
+type Backend = linked_list::LinkedListAllocator;
+#[cfg(all(not(feature = "heap-bump"), not(feature = "heap-linked-list")))]
+type Backend = fixed_size_block::FixedSizeBlockAllocator;
+
+#[global_allocator]
+static ALLOCATOR: Locked<Backend> = Locked::new(Backend::new());
+
+/// Registers the heap region and hands it to the global allocator.
+///
+/// Heap pages are backed by frames on first access, so the heap only uses as much physical memory
+/// as has been touched.
+pub fn init_heap() -> Result<(), RegionError> {
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let region = Region::lazy(
+        "heap",
+        VirtAddr::new(HEAP_START as u64),
+        HEAP_SIZE as u64,
+        flags,
+    );
+    demand::register(region)?;
+
+    unsafe { ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE) }
+    Ok(())
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    eprintln!("Allocation error: {:?}", layout);
+    panic!("Out of heap memory")
+}
+
+/// Spin lock around an allocator backend.
+///
+/// The global allocator has to be constructed in a `const` context, which is why this does not use
+/// `spinning::Mutex` and `lazy_static!`. Interrupts are disabled while the lock is held, so an
+/// interrupt handler can never spin on a lock held by the code it interrupted.
+pub struct Locked<A> {
+    locked: AtomicBool,
+    inner: UnsafeCell<A>,
+}
+
+unsafe impl<A: Send> Sync for Locked<A> {}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            locked: AtomicBool::new(false),
+            inner: UnsafeCell::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> LockedGuard<A> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop()
+        }
+        LockedGuard {
+            lock: self,
+            interrupts_enabled,
+        }
+    }
+}
+
+pub struct LockedGuard<'a, A> {
+    lock: &'a Locked<A>,
+    interrupts_enabled: bool,
+}
+
+impl<'a, A> Deref for LockedGuard<'a, A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<'a, A> DerefMut for LockedGuard<'a, A> {
+    fn deref_mut(&mut self) -> &mut A {
+        unsafe { &mut *self.lock.inner.get() }
+    }
+}
+
+impl<'a, A> Drop for LockedGuard<'a, A> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        if self.interrupts_enabled {
+            interrupts::enable()
+        }
+    }
+}
+
+/// Aligns the address upwards to the given alignment, which must be a power of two.
+fn align_up(address: usize, align: usize) -> usize {
+    (address + align - 1) & !(align - 1)
+}
+
+#[test_case]
+fn simple_allocation() {
+    use alloc::boxed::Box;
+
+    let a = Box::new(41);
+    let b = Box::new(13);
+    assert_eq!(*a, 41);
+    assert_eq!(*b, 13);
+}
+
+#[test_case]
+fn large_vec() {
+    use alloc::vec::Vec;
+
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+#[test_case]
+fn large_allocation() {
+    use alloc::vec;
+
+    // A quarter of the heap in a single allocation.
+    let size = HEAP_SIZE / 4;
+    let mut buffer = vec![0u8; size];
+    buffer[0] = 1;
+    buffer[size - 1] = 2;
+    assert_eq!(buffer.iter().map(|&byte| byte as usize).sum::<usize>(), 3);
+}
+
+#[test_case]
+fn many_boxes() {
+    use alloc::vec;
+
+    // Allocates twice the heap size in total, which only works if freed memory is reused.
+    let size = 4096;
+    for i in 0..2 * HEAP_SIZE / size {
+        let buffer = vec![i as u8; size];
+        assert_eq!(buffer[size - 1], i as u8);
+    }
+}
+
+// The bump allocator can only reuse memory once every allocation is freed.
+#[cfg(not(feature = "heap-bump"))]
+#[test_case]
+fn many_boxes_long_lived() {
+    use alloc::boxed::Box;
+
+    let long_lived = Box::new(1);
+    for i in 0..100_000 {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+#[test_case]
+fn allocation_churn() {
+    use alloc::vec::Vec;
+
+    // Interleaves allocations of different sizes and lifetimes to fragment the heap.
+    let mut kept = Vec::new();
+    for round in 0..64 {
+        let mut scratch = Vec::new();
+        for size in [8, 24, 100, 512, 4096].iter() {
+            scratch.push(alloc::vec![round as u8; *size]);
+        }
+        kept.push(scratch.swap_remove(round % scratch.len()));
+    }
+    for (round, buffer) in kept.iter().enumerate() {
+        assert!(buffer.iter().all(|&byte| byte == round as u8));
+    }
+}
@@ -0,0 +1,462 @@
+//! ATA disks on the legacy IDE controller, read and written with PIO.
+//!
+//! Each of the two buses has a master and a slave drive, named `hda`, `hdb` on the primary bus
+//! and `hdc`, `hdd` on the secondary bus. A bus runs one command at a time. The drive raises IRQ 14
+//! or 15 when a sector can be transferred or a command is done, the thread waits for it blocked.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use spinning::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use super::{check_request, BlockDevice, BlockError, DeviceLock};
+use crate::task::{self, ThreadId};
+use crate::time::Instant;
+
+const SECTOR_SIZE: usize = 512;
+/// Most sectors one command transfers.
+const MAX_SECTORS: u64 = 256;
+/// Last sector that 28-bit commands can address, plus one.
+const LBA28_LIMIT: u64 = 1 << 28;
+/// How long a drive may take for a sector or a command.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+// Registers from the I/O base.
+const DATA: u16 = 0;
+const ERROR: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE: u16 = 6;
+/// The status when read, the command when written.
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+// Bits of the status register.
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_DRIVE_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+
+// Bits of the drive register.
+const DRIVE_SLAVE: u8 = 1 << 4;
+const DRIVE_LBA: u8 = 1 << 6;
+/// Bits that are always set in the drive register of old drives.
+const DRIVE_OBSOLETE: u8 = 0xA0;
+
+const IDENTIFY: u8 = 0xEC;
+const READ_SECTORS: u8 = 0x20;
+const READ_SECTORS_EXT: u8 = 0x24;
+const WRITE_SECTORS: u8 = 0x30;
+const WRITE_SECTORS_EXT: u8 = 0x34;
+const FLUSH_CACHE: u8 = 0xE7;
+const FLUSH_CACHE_EXT: u8 = 0xEA;
+
+// Words of the IDENTIFY data.
+const IDENTIFY_LBA28_SECTORS: usize = 60;
+const IDENTIFY_FEATURES: usize = 83;
+const FEATURE_LBA48: u16 = 1 << 10;
+const IDENTIFY_LBA48_SECTORS: usize = 100;
+
+lazy_static! {
+    static ref BUSES: [Bus; 2] = [Bus::new(0x1F0, 0x3F6), Bus::new(0x170, 0x376)];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// The bus at IRQ 14.
+    Primary,
+    /// The bus at IRQ 15.
+    Secondary,
+}
+
+impl Channel {
+    fn bus(self) -> &'static Bus {
+        &BUSES[self as usize]
+    }
+}
+
+struct Bus {
+    io_base: u16,
+    /// The alternate status register, which does not acknowledge interrupts, and the device
+    /// control register.
+    control: u16,
+    /// Held by the thread that runs a command.
+    lock: DeviceLock,
+    /// Set by the interrupt handler, cleared before a command.
+    interrupted: AtomicBool,
+    /// The thread blocked until the interrupt.
+    waiting: Mutex<Option<ThreadId>>,
+}
+
+impl Bus {
+    fn new(io_base: u16, control: u16) -> Self {
+        Bus {
+            io_base,
+            control,
+            lock: DeviceLock::new(),
+            interrupted: AtomicBool::new(false),
+            waiting: Mutex::new(None),
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.io_base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.io_base + register).write(value) }
+    }
+
+    fn alternate_status(&self) -> u8 {
+        unsafe { Port::new(self.control).read() }
+    }
+
+    /// Gives the drive the 400 ns it needs to update its status after a command or selection.
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    /// Waits until the drive is no longer busy and returns the status, without blocking.
+    fn wait_not_busy(&self) -> Result<u8, BlockError> {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let status = self.alternate_status();
+            if status & STATUS_BUSY == 0 {
+                return Ok(status);
+            }
+            if deadline.has_passed() {
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop()
+        }
+    }
+
+    /// Waits until the drive can transfer a sector.
+    fn wait_data_request(&self) -> Result<(), BlockError> {
+        let status = self.wait_not_busy()?;
+        self.check(status)?;
+        if status & STATUS_DATA_REQUEST == 0 {
+            return Err(BlockError::Device(0));
+        }
+        Ok(())
+    }
+
+    /// Blocks until the drive raises its interrupt, and returns the status.
+    fn wait_interrupt(&self) -> Result<u8, BlockError> {
+        let deadline = Instant::now() + TIMEOUT;
+        interrupts::without_interrupts(|| {
+            while !self.interrupted.swap(false, Ordering::SeqCst) {
+                if deadline.has_passed() {
+                    return Err(BlockError::Timeout);
+                }
+                *self.waiting.lock() = Some(task::current_id());
+                task::block_for(TIMEOUT);
+                *self.waiting.lock() = None;
+            }
+            Ok(())
+        })?;
+        self.wait_not_busy()
+    }
+
+    fn check(&self, status: u8) -> Result<(), BlockError> {
+        if status & (STATUS_ERROR | STATUS_DRIVE_FAULT) != 0 {
+            return Err(BlockError::Device(self.read(ERROR)));
+        }
+        Ok(())
+    }
+
+    /// Called from the interrupt handler.
+    fn interrupt(&self) {
+        // Reading the status acknowledges the interrupt.
+        self.read(STATUS);
+        self.interrupted.store(true, Ordering::SeqCst);
+        if let Some(thread) = *self.waiting.lock() {
+            task::wake(thread)
+        }
+    }
+
+    fn select(&self, drive: u8) {
+        self.write(DRIVE, drive);
+        self.delay()
+    }
+
+    /// Selects the drive and starts the command for `count` sectors from `sector`.
+    fn start(&self, slave: bool, command: u8, sector: u64, count: u64, lba48: bool) {
+        let slave = if slave { DRIVE_SLAVE } else { 0 };
+        self.interrupted.store(false, Ordering::SeqCst);
+        let bytes = sector.to_le_bytes();
+        // A count of 0 means 256 sectors, or 65536 with 48-bit addresses.
+        let count = count as u16;
+        if lba48 {
+            self.select(DRIVE_LBA | slave);
+            self.write(SECTOR_COUNT, (count >> 8) as u8);
+            self.write(LBA_LOW, bytes[3]);
+            self.write(LBA_MID, bytes[4]);
+            self.write(LBA_HIGH, bytes[5]);
+        } else {
+            self.select(DRIVE_OBSOLETE | DRIVE_LBA | slave | (bytes[3] & 0x0F));
+        }
+        self.write(SECTOR_COUNT, count as u8);
+        self.write(LBA_LOW, bytes[0]);
+        self.write(LBA_MID, bytes[1]);
+        self.write(LBA_HIGH, bytes[2]);
+        self.write(COMMAND, command);
+        self.delay()
+    }
+
+    fn read_sector(&self, buffer: &mut [u8]) {
+        let mut data: Port<u16> = Port::new(self.io_base + DATA);
+        for word in buffer.chunks_exact_mut(2) {
+            word.copy_from_slice(&unsafe { data.read() }.to_le_bytes())
+        }
+    }
+
+    fn write_sector(&self, sector: &[u8]) {
+        let mut data: Port<u16> = Port::new(self.io_base + DATA);
+        for word in sector.chunks_exact(2) {
+            unsafe { data.write(u16::from_le_bytes([word[0], word[1]])) }
+        }
+    }
+
+    /// Identifies the drive, `None` if there is none or it is no ATA disk.
+    fn identify(&'static self, slave: bool) -> Option<Drive> {
+        // A bus without drives floats high.
+        if self.alternate_status() == 0xFF {
+            return None;
+        }
+        self.lock.with(|| {
+            self.select(DRIVE_OBSOLETE | if slave { DRIVE_SLAVE } else { 0 });
+            for &register in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH].iter() {
+                self.write(register, 0)
+            }
+            self.write(COMMAND, IDENTIFY);
+            self.delay();
+            if self.read(STATUS) == 0 {
+                return None;
+            }
+            self.wait_not_busy().ok()?;
+            // ATAPI and SATA devices put their signature here.
+            if self.read(LBA_MID) != 0 || self.read(LBA_HIGH) != 0 {
+                return None;
+            }
+            self.wait_data_request().ok()?;
+            let mut bytes = [0; SECTOR_SIZE];
+            self.read_sector(&mut bytes);
+            let mut words = [0u16; SECTOR_SIZE / 2];
+            for (word, bytes) in words.iter_mut().zip(bytes.chunks_exact(2)) {
+                *word = u16::from_le_bytes([bytes[0], bytes[1]])
+            }
+            Some(Drive::new(self, slave, &words))
+        })
+    }
+}
+
+/// An ATA disk.
+pub struct Drive {
+    bus: &'static Bus,
+    slave: bool,
+    lba48: bool,
+    sectors: u64,
+}
+
+impl Drive {
+    fn new(bus: &'static Bus, slave: bool, identify: &[u16; SECTOR_SIZE / 2]) -> Self {
+        let lba48 = identify[IDENTIFY_FEATURES] & FEATURE_LBA48 != 0;
+        let words = |start: usize, count: usize| {
+            identify[start..start + count]
+                .iter()
+                .rev()
+                .fold(0, |value, &word| value << 16 | u64::from(word))
+        };
+        let sectors = if lba48 {
+            words(IDENTIFY_LBA48_SECTORS, 4)
+        } else {
+            words(IDENTIFY_LBA28_SECTORS, 2)
+        };
+        Drive {
+            bus,
+            slave,
+            lba48,
+            sectors,
+        }
+    }
+
+    /// Splits the request into commands of at most `MAX_SECTORS` and returns the commands with
+    /// their first sector, count and whether they need 48-bit addresses.
+    fn commands(&self, sector: u64, count: u64) -> impl Iterator<Item = (u64, u64, bool)> {
+        let lba48 = self.lba48;
+        (0..count).step_by(MAX_SECTORS as usize).map(move |done| {
+            let start = sector + done;
+            let count = (count - done).min(MAX_SECTORS);
+            (start, count, lba48 && start + count > LBA28_LIMIT)
+        })
+    }
+}
+
+impl BlockDevice for Drive {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let count = check_request(self, sector, buffer.len())?;
+        let mut sectors = buffer.chunks_exact_mut(SECTOR_SIZE);
+        self.bus.lock.with(|| {
+            for (start, count, lba48) in self.commands(sector, count) {
+                let command = if lba48 {
+                    READ_SECTORS_EXT
+                } else {
+                    READ_SECTORS
+                };
+                self.bus.start(self.slave, command, start, count, lba48);
+                for sector in sectors.by_ref().take(count as usize) {
+                    let status = self.bus.wait_interrupt()?;
+                    self.bus.check(status)?;
+                    self.bus.read_sector(sector)
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn write_sectors(&self, sector: u64, data: &[u8]) -> Result<(), BlockError> {
+        let count = check_request(self, sector, data.len())?;
+        let mut sectors = data.chunks_exact(SECTOR_SIZE);
+        self.bus.lock.with(|| {
+            for (start, count, lba48) in self.commands(sector, count) {
+                let command = if lba48 {
+                    WRITE_SECTORS_EXT
+                } else {
+                    WRITE_SECTORS
+                };
+                self.bus.start(self.slave, command, start, count, lba48);
+                // The drive asks for the first sector without an interrupt.
+                self.bus.wait_data_request()?;
+                for sector in sectors.by_ref().take(count as usize) {
+                    self.bus.write_sector(sector);
+                    let status = self.bus.wait_interrupt()?;
+                    self.bus.check(status)?
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.bus.lock.with(|| {
+            let command = if self.lba48 {
+                FLUSH_CACHE_EXT
+            } else {
+                FLUSH_CACHE
+            };
+            self.bus.start(self.slave, command, 0, 0, false);
+            let status = self.bus.wait_interrupt()?;
+            self.bus.check(status)
+        })
+    }
+}
+
+/// Identifies the drives on both buses and returns them with their names.
+pub fn find_drives() -> Vec<(&'static str, Drive)> {
+    let drives = [
+        ("hda", Channel::Primary, false),
+        ("hdb", Channel::Primary, true),
+        ("hdc", Channel::Secondary, false),
+        ("hdd", Channel::Secondary, true),
+    ];
+    drives
+        .iter()
+        .filter_map(|&(name, channel, slave)| Some((name, channel.bus().identify(slave)?)))
+        .collect()
+}
+
+/// Called from the interrupt handler of the bus, with interrupts disabled.
+pub fn interrupt(channel: Channel) {
+    channel.bus().interrupt()
+}
+
+/// The disk image `build.rs` generates for the tests, attached as `hdc`. The first 8 bytes of each
+/// sector are its number, the other bytes its number modulo 256.
+#[cfg(test)]
+const TEST_DISK_SECTORS: u64 = 4096;
+
+#[cfg(test)]
+fn test_disk() -> alloc::sync::Arc<dyn BlockDevice> {
+    super::device("hdc").expect("The test disk is missing")
+}
+
+#[cfg(test)]
+fn check_test_sector(number: u64, sector: &[u8]) {
+    assert_eq!(sector[..8], number.to_le_bytes());
+    assert!(sector[8..].iter().all(|&byte| byte == number as u8));
+}
+
+#[test_case]
+fn identify_drives() {
+    let disk = test_disk();
+    assert_eq!(disk.sector_count(), TEST_DISK_SECTORS);
+    assert_eq!(disk.capacity(), TEST_DISK_SECTORS * 512);
+    // QEMU boots from the primary master.
+    let boot = super::device("hda").unwrap();
+    assert!(boot.sector_count() > 0);
+    // The primary slave is the ext2 test disk.
+    assert!(super::device("hdb").is_some());
+}
+
+#[test_case]
+fn read_sectors() {
+    let disk = test_disk();
+    let mut buffer = alloc::vec![0; 3 * SECTOR_SIZE];
+    disk.read_sectors(0, &mut buffer).unwrap();
+    for (number, sector) in buffer.chunks(SECTOR_SIZE).enumerate() {
+        check_test_sector(number as u64, sector)
+    }
+
+    // More sectors than one command transfers.
+    let mut buffer = alloc::vec![0; 300 * SECTOR_SIZE];
+    disk.read_sectors(1000, &mut buffer).unwrap();
+    check_test_sector(1000, &buffer[..SECTOR_SIZE]);
+    check_test_sector(1299, &buffer[299 * SECTOR_SIZE..]);
+}
+
+#[test_case]
+fn write_sectors() {
+    let disk = test_disk();
+    // The last sectors are left for writing.
+    let sector = TEST_DISK_SECTORS - 2;
+    let data: alloc::vec::Vec<u8> = (0..2 * SECTOR_SIZE).map(|i| (i % 251) as u8).collect();
+    disk.write_sectors(sector, &data).unwrap();
+    disk.flush().unwrap();
+    let mut buffer = alloc::vec![0; 2 * SECTOR_SIZE];
+    disk.read_sectors(sector, &mut buffer).unwrap();
+    assert_eq!(buffer, data);
+}
+
+#[test_case]
+fn invalid_requests() {
+    let disk = test_disk();
+    let mut buffer = [0; SECTOR_SIZE];
+    assert_eq!(
+        disk.read_sectors(TEST_DISK_SECTORS, &mut buffer),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(
+        disk.write_sectors(u64::MAX, &buffer),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(
+        disk.read_sectors(0, &mut buffer[..100]),
+        Err(BlockError::InvalidBuffer)
+    );
+}
@@ -0,0 +1,308 @@
+//! A write-back cache of the sectors of a block device.
+//!
+//! Reads of sectors that are not cached go to the device in runs of consecutive sectors. Writes
+//! only change the cache, the sectors are written to the device when they are evicted or on
+//! `flush`. Once the cache is full, the least recently used sectors are evicted.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spinning::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{check_request, BlockDevice, BlockError, DeviceLock};
+
+/// Sectors a cache holds by default, 1 MiB with 512 byte sectors.
+pub const DEFAULT_CAPACITY: usize = 2048;
+
+struct CachedSector {
+    data: Box<[u8]>,
+    /// Changed since it was read or written back.
+    dirty: bool,
+    /// Value of `State::clock` when the sector was last used.
+    used: u64,
+}
+
+struct State {
+    sectors: BTreeMap<u64, CachedSector>,
+    /// Counts the uses of sectors, for finding the least recently used one.
+    clock: u64,
+}
+
+pub struct Cache {
+    device: Arc<dyn BlockDevice>,
+    capacity: usize,
+    /// Held during every operation, which may block on the device.
+    lock: DeviceLock,
+    state: Mutex<State>,
+}
+
+impl Cache {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Self {
+        Cache::with_capacity(device, DEFAULT_CAPACITY)
+    }
+
+    /// Creates a cache that holds at most `capacity` sectors, at least one.
+    pub fn with_capacity(device: Arc<dyn BlockDevice>, capacity: usize) -> Self {
+        Cache {
+            device,
+            capacity: capacity.max(1),
+            lock: DeviceLock::new(),
+            state: Mutex::new(State {
+                sectors: BTreeMap::new(),
+                clock: 0,
+            }),
+        }
+    }
+
+    /// Returns the amount of cached sectors and how many of them are dirty.
+    #[allow(dead_code)]
+    pub fn usage(&self) -> (usize, usize) {
+        self.with_state(|state| {
+            let dirty = state.sectors.values().filter(|sector| sector.dirty).count();
+            (state.sectors.len(), dirty)
+        })
+    }
+
+    fn with_state<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut State) -> R,
+    {
+        interrupts::without_interrupts(|| f(&mut self.state.lock()))
+    }
+
+    /// Copies the sector into the buffer if it is cached.
+    fn read_cached(&self, sector: u64, buffer: &mut [u8]) -> bool {
+        self.with_state(|state| {
+            state.clock += 1;
+            let clock = state.clock;
+            match state.sectors.get_mut(&sector) {
+                Some(cached) => {
+                    cached.used = clock;
+                    buffer.copy_from_slice(&cached.data);
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    fn insert(&self, sector: u64, data: &[u8], dirty: bool) {
+        self.with_state(|state| {
+            state.clock += 1;
+            let cached = CachedSector {
+                data: Box::from(data),
+                dirty,
+                used: state.clock,
+            };
+            state.sectors.insert(sector, cached);
+        })
+    }
+
+    /// Evicts the least recently used sectors until the cache is within its capacity, and writes
+    /// the dirty ones back.
+    fn evict(&self) -> Result<(), BlockError> {
+        let evicted = self.with_state(|state| {
+            let mut evicted = Vec::new();
+            while state.sectors.len() > self.capacity {
+                let (&sector, _) = state
+                    .sectors
+                    .iter()
+                    .min_by_key(|(_, cached)| cached.used)
+                    .expect("Cache over capacity is empty");
+                let cached = state.sectors.remove(&sector).expect("Sector is missing");
+                if cached.dirty {
+                    evicted.push((sector, cached.data))
+                }
+            }
+            evicted
+        });
+        self.write_back(evicted)
+    }
+
+    /// Writes the sectors to the device, consecutive ones with one request.
+    fn write_back(&self, mut sectors: Vec<(u64, Box<[u8]>)>) -> Result<(), BlockError> {
+        sectors.sort_by_key(|&(sector, _)| sector);
+        let mut index = 0;
+        while index < sectors.len() {
+            let first = sectors[index].0;
+            let mut run = Vec::new();
+            while index < sectors.len()
+                && sectors[index].0 == first + (run.len() / self.sector_size()) as u64
+            {
+                run.extend_from_slice(&sectors[index].1);
+                index += 1
+            }
+            self.device.write_sectors(first, &run)?
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for Cache {
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let count = check_request(self, sector, buffer.len())? as usize;
+        let size = self.sector_size();
+        self.lock.with(|| {
+            let mut index = 0;
+            while index < count {
+                if self.read_cached(sector + index as u64, &mut buffer[index * size..][..size]) {
+                    index += 1;
+                    continue;
+                }
+                // Read the missing sectors up to the next cached one at once.
+                let cached = |index: usize| {
+                    self.with_state(|state| state.sectors.contains_key(&(sector + index as u64)))
+                };
+                let end = (index + 1..count).find(|&end| cached(end)).unwrap_or(count);
+                let run = &mut buffer[index * size..end * size];
+                self.device.read_sectors(sector + index as u64, run)?;
+                for (offset, data) in run.chunks_exact(size).enumerate() {
+                    self.insert(sector + (index + offset) as u64, data, false)
+                }
+                index = end
+            }
+            self.evict()
+        })
+    }
+
+    fn write_sectors(&self, sector: u64, data: &[u8]) -> Result<(), BlockError> {
+        check_request(self, sector, data.len())?;
+        self.lock.with(|| {
+            for (index, data) in data.chunks_exact(self.sector_size()).enumerate() {
+                self.insert(sector + index as u64, data, true)
+            }
+            self.evict()
+        })
+    }
+
+    /// Writes the dirty sectors back and flushes the device.
+    fn flush(&self) -> Result<(), BlockError> {
+        self.lock.with(|| {
+            let dirty = self.with_state(|state| {
+                let dirty = state.sectors.iter_mut().filter(|(_, cached)| cached.dirty);
+                dirty
+                    .map(|(&sector, cached)| {
+                        cached.dirty = false;
+                        (sector, cached.data.clone())
+                    })
+                    .collect()
+            });
+            self.write_back(dirty)?;
+            self.device.flush()
+        })
+    }
+}
+
+/// Counts the requests to the device behind it.
+#[cfg(test)]
+struct Counter {
+    device: super::ram::RamDisk,
+    reads: core::sync::atomic::AtomicUsize,
+    writes: core::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl Counter {
+    fn new(sectors: u64) -> Arc<Self> {
+        use core::sync::atomic::AtomicUsize;
+        Arc::new(Counter {
+            device: super::ram::RamDisk::new(sectors),
+            reads: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+        })
+    }
+
+    fn counts(&self) -> (usize, usize) {
+        use core::sync::atomic::Ordering;
+        (
+            self.reads.load(Ordering::SeqCst),
+            self.writes.load(Ordering::SeqCst),
+        )
+    }
+}
+
+#[cfg(test)]
+impl BlockDevice for Counter {
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.reads
+            .fetch_add(1, core::sync::atomic::Ordering::SeqCst);
+        self.device.read_sectors(sector, buffer)
+    }
+
+    fn write_sectors(&self, sector: u64, data: &[u8]) -> Result<(), BlockError> {
+        self.writes
+            .fetch_add(1, core::sync::atomic::Ordering::SeqCst);
+        self.device.write_sectors(sector, data)
+    }
+}
+
+#[test_case]
+fn reads_are_cached() {
+    let counter = Counter::new(64);
+    let cache = Cache::with_capacity(counter.clone(), 16);
+    let mut buffer = [0; 4 * 512];
+    cache.read_sectors(2, &mut buffer[..512]).unwrap();
+    // Sectors 0, 1 and 3 are read with two requests, 2 is cached.
+    cache.read_sectors(0, &mut buffer).unwrap();
+    assert_eq!(counter.counts(), (3, 0));
+    cache.read_sectors(0, &mut buffer).unwrap();
+    assert_eq!(counter.counts(), (3, 0));
+    assert_eq!(cache.usage(), (4, 0));
+}
+
+#[test_case]
+fn writes_are_written_back() {
+    let counter = Counter::new(64);
+    let cache = Cache::with_capacity(counter.clone(), 4);
+    let data = [7; 3 * 512];
+    cache.write_sectors(10, &data).unwrap();
+    assert_eq!(counter.counts(), (0, 0));
+    let mut buffer = [0; 3 * 512];
+    cache.read_sectors(10, &mut buffer).unwrap();
+    assert_eq!(buffer, data);
+    assert_eq!(cache.usage(), (3, 3));
+
+    // Consecutive sectors are written with one request.
+    cache.flush().unwrap();
+    assert_eq!(counter.counts(), (0, 1));
+    assert_eq!(cache.usage(), (3, 0));
+    counter.device.read_sectors(10, &mut buffer).unwrap();
+    assert_eq!(buffer, data);
+}
+
+#[test_case]
+fn least_recently_used_sectors_are_evicted() {
+    let counter = Counter::new(64);
+    let cache = Cache::with_capacity(counter.clone(), 2);
+    let mut sector = [0; 512];
+    cache.write_sectors(0, &[1; 512]).unwrap();
+    cache.read_sectors(1, &mut sector).unwrap();
+    cache.read_sectors(0, &mut sector).unwrap();
+    // Sector 1 is the least recently used one, it is clean.
+    cache.write_sectors(2, &[2; 512]).unwrap();
+    assert_eq!(counter.counts(), (1, 0));
+    // Now sector 0 is the least recently used one, it is written back.
+    cache.read_sectors(1, &mut sector).unwrap();
+    assert_eq!(counter.counts(), (2, 1));
+    assert_eq!(cache.usage(), (2, 1));
+    counter.device.read_sectors(0, &mut sector).unwrap();
+    assert_eq!(sector, [1; 512]);
+}
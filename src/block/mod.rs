@@ -0,0 +1,182 @@
+//! Block devices: storage that is read and written in whole sectors, like disks.
+//!
+//! Drivers implement `BlockDevice` and register their devices by name, file systems look them up
+//! with `device`. Disks are registered behind a `Cache`, and each partition of a disk as a device
+//! of its own, named after the disk with the number of the partition, like `hda1`.
+
+pub mod ata;
+pub mod cache;
+pub mod partition;
+pub mod ram;
+
+pub use cache::Cache;
+pub use partition::Partition;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spinning::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::task;
+
+lazy_static! {
+    static ref DEVICES: Mutex<Vec<(String, Arc<dyn BlockDevice>)>> = Mutex::new(Vec::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The sectors are beyond the end of the device.
+    OutOfRange,
+    /// The buffer is not a whole number of sectors.
+    InvalidBuffer,
+    /// The device reported an error, with its error code.
+    Device(u8),
+    /// The device did not answer in time.
+    Timeout,
+}
+
+pub trait BlockDevice: Send + Sync {
+    /// Size of a sector in bytes.
+    fn sector_size(&self) -> usize;
+
+    fn sector_count(&self) -> u64;
+
+    /// Size of the device in bytes.
+    fn capacity(&self) -> u64 {
+        self.sector_count() * self.sector_size() as u64
+    }
+
+    /// Reads the sectors starting at `sector` into the buffer, which is a whole number of sectors.
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes the data, which is a whole number of sectors, to the sectors starting at `sector`.
+    fn write_sectors(&self, sector: u64, data: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes sure that written sectors are stored on the medium.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// Reads the bytes at the position, which do not have to be whole sectors.
+    fn read_bytes(&self, position: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        let sector_size = self.sector_size() as u64;
+        let first = position / sector_size;
+        let end = (position + buffer.len() as u64).div_ceil(sector_size);
+        let mut sectors = vec![0; ((end - first) * sector_size) as usize];
+        self.read_sectors(first, &mut sectors)?;
+        let start = (position % sector_size) as usize;
+        buffer.copy_from_slice(&sectors[start..start + buffer.len()]);
+        Ok(())
+    }
+
+    /// Writes the bytes at the position. Sectors that are written partly are read first.
+    fn write_bytes(&self, position: u64, data: &[u8]) -> Result<(), BlockError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let sector_size = self.sector_size() as u64;
+        let first = position / sector_size;
+        let end = (position + data.len() as u64).div_ceil(sector_size);
+        let start = (position % sector_size) as usize;
+        let mut sectors = vec![0; ((end - first) * sector_size) as usize];
+        if start != 0 || data.len() != sectors.len() {
+            self.read_sectors(first, &mut sectors)?;
+        }
+        sectors[start..start + data.len()].copy_from_slice(data);
+        self.write_sectors(first, &sectors)
+    }
+}
+
+/// Checks that the buffer is a whole number of sectors inside the device, and returns the amount
+/// of sectors.
+pub fn check_request(
+    device: &dyn BlockDevice,
+    sector: u64,
+    length: usize,
+) -> Result<u64, BlockError> {
+    if !length.is_multiple_of(device.sector_size()) {
+        return Err(BlockError::InvalidBuffer);
+    }
+    let count = (length / device.sector_size()) as u64;
+    match sector.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Lock for devices that block while it is held. Threads wait for it by yielding, so it must not
+/// be taken with interrupts disabled.
+pub struct DeviceLock {
+    locked: AtomicBool,
+}
+
+impl DeviceLock {
+    pub const fn new() -> Self {
+        DeviceLock {
+            locked: AtomicBool::new(false),
+        }
+    }
+
+    /// Runs `f` while no other thread holds the lock.
+    pub fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        while self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            task::yield_now()
+        }
+        let result = f();
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+/// Finds the drives and registers them with their partitions. Interrupts must be enabled.
+pub fn init() {
+    for (name, drive) in ata::find_drives() {
+        add_disk(name, Arc::new(drive))
+    }
+}
+
+/// Registers the disk behind a cache, and its partitions. A disk without a valid partition table
+/// has no partitions.
+pub fn add_disk(name: &str, disk: Arc<dyn BlockDevice>) {
+    let disk: Arc<dyn BlockDevice> = Arc::new(Cache::new(disk));
+    register(name, disk.clone());
+    for entry in partition::read_table(&*disk).unwrap_or_default() {
+        let partition = Partition::new(disk.clone(), entry.first, entry.count);
+        register(&format!("{}{}", name, entry.number), Arc::new(partition))
+    }
+}
+
+/// Adds the device under the name, replacing any device with the same name.
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) {
+    interrupts::without_interrupts(|| {
+        let mut devices = DEVICES.lock();
+        devices.retain(|(other, _)| other != name);
+        devices.push((String::from(name), device))
+    })
+}
+
+#[allow(dead_code)]
+pub fn device(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    interrupts::without_interrupts(|| {
+        DEVICES
+            .lock()
+            .iter()
+            .find(|(other, _)| other == name)
+            .map(|(_, device)| device.clone())
+    })
+}
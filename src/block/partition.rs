@@ -0,0 +1,429 @@
+//! Partition tables and partitions.
+//!
+//! An MBR is the first sector of a disk, with four primary partitions at offset 446 and the
+//! signature 0x55 0xAA at the end. An extended partition holds a chain of EBRs, each one
+//! describing a logical partition relative to itself and the next EBR relative to the extended
+//! partition. A GPT disk has a protective MBR with a single partition of type 0xEE, the GPT
+//! header in the second sector and an array of entries, both protected by CRC32 checksums.
+
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use super::{check_request, BlockDevice, BlockError};
+
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const EXTENDED_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+const GPT_PROTECTIVE_TYPE: u8 = 0xEE;
+/// Number of the first logical partition in an extended partition.
+const FIRST_LOGICAL: u32 = 5;
+/// Limit of EBRs in an extended partition, each one describing at most one logical partition.
+const MAX_EBRS: usize = 64;
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+/// Limit of GPT entries, 128 is the usual amount.
+const MAX_GPT_ENTRIES: u32 = 1024;
+const GPT_ENTRY_SIZE: usize = 128;
+/// Limit of the size of the GPT entry array, as large as the limit of entries of the usual size.
+const MAX_GPT_TABLE_SIZE: usize = MAX_GPT_ENTRIES as usize * GPT_ENTRY_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError {
+    Block(BlockError),
+    /// The table is malformed or has partitions outside the disk.
+    InvalidTable,
+    /// A GPT checksum does not match.
+    BadChecksum,
+}
+
+impl From<BlockError> for PartitionError {
+    fn from(error: BlockError) -> Self {
+        PartitionError::Block(error)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// The partition type of an MBR entry.
+    Mbr(u8),
+    Gpt {
+        type_guid: [u8; 16],
+        name: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionEntry {
+    /// Number of the partition, starting at 1. Logical partitions start at 5.
+    pub number: u32,
+    pub first: u64,
+    pub count: u64,
+    pub kind: PartitionKind,
+}
+
+/// A range of sectors of a device, which is a device of its own.
+pub struct Partition {
+    device: Arc<dyn BlockDevice>,
+    first: u64,
+    count: u64,
+}
+
+impl Partition {
+    pub fn new(device: Arc<dyn BlockDevice>, first: u64, count: u64) -> Self {
+        Partition {
+            device,
+            first,
+            count,
+        }
+    }
+}
+
+impl BlockDevice for Partition {
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buffer.len())?;
+        self.device.read_sectors(self.first + sector, buffer)
+    }
+
+    fn write_sectors(&self, sector: u64, data: &[u8]) -> Result<(), BlockError> {
+        check_request(self, sector, data.len())?;
+        self.device.write_sectors(self.first + sector, data)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+}
+
+/// Reads the partition table of the device. A device without an MBR signature has no partitions.
+pub fn read_table(device: &dyn BlockDevice) -> Result<Vec<PartitionEntry>, PartitionError> {
+    let mbr = read_sector(device, 0)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+    let entries = mbr_entries(&mbr);
+    let partitions = if entries
+        .iter()
+        .any(|&(kind, _, _)| kind == GPT_PROTECTIVE_TYPE)
+    {
+        read_gpt(device)?
+    } else {
+        read_mbr(device, &entries)?
+    };
+    if partitions.iter().any(|partition| {
+        partition.count == 0
+            || partition
+                .first
+                .checked_add(partition.count)
+                .is_none_or(|end| end > device.sector_count())
+    }) {
+        return Err(PartitionError::InvalidTable);
+    }
+    Ok(partitions)
+}
+
+fn read_sector(device: &dyn BlockDevice, sector: u64) -> Result<Vec<u8>, BlockError> {
+    let mut buffer = vec![0; device.sector_size()];
+    device.read_sectors(sector, &mut buffer)?;
+    Ok(buffer)
+}
+
+/// Returns the type, first sector and sector count of the four entries of an MBR or EBR.
+fn mbr_entries(sector: &[u8]) -> [(u8, u64, u64); 4] {
+    let mut entries = [(0, 0, 0); 4];
+    for (index, entry) in entries.iter_mut().enumerate() {
+        let bytes = &sector[MBR_ENTRIES + index * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        *entry = (bytes[4], u32_at(bytes, 8) as u64, u32_at(bytes, 12) as u64)
+    }
+    entries
+}
+
+fn read_mbr(
+    device: &dyn BlockDevice,
+    entries: &[(u8, u64, u64)],
+) -> Result<Vec<PartitionEntry>, PartitionError> {
+    let mut partitions = Vec::new();
+    let mut logical = FIRST_LOGICAL;
+    for (index, &(kind, first, count)) in entries.iter().enumerate() {
+        if kind == 0 {
+            continue;
+        }
+        if EXTENDED_TYPES.contains(&kind) {
+            read_logical(device, first, &mut logical, &mut partitions)?;
+            continue;
+        }
+        partitions.push(PartitionEntry {
+            number: index as u32 + 1,
+            first,
+            count,
+            kind: PartitionKind::Mbr(kind),
+        })
+    }
+    Ok(partitions)
+}
+
+/// Follows the EBR chain of the extended partition starting at `extended`. Chains that link back
+/// to an earlier EBR or that are longer than `MAX_EBRS` are invalid.
+fn read_logical(
+    device: &dyn BlockDevice,
+    extended: u64,
+    number: &mut u32,
+    partitions: &mut Vec<PartitionEntry>,
+) -> Result<(), PartitionError> {
+    let mut visited = BTreeSet::new();
+    let mut ebr = extended;
+    loop {
+        if visited.len() == MAX_EBRS || !visited.insert(ebr) || ebr >= device.sector_count() {
+            return Err(PartitionError::InvalidTable);
+        }
+        let sector = read_sector(device, ebr)?;
+        if sector[510..512] != MBR_SIGNATURE {
+            return Err(PartitionError::InvalidTable);
+        }
+        let [(kind, first, count), (next_kind, next, _), ..] = mbr_entries(&sector);
+        if kind != 0 {
+            partitions.push(PartitionEntry {
+                number: *number,
+                first: ebr + first,
+                count,
+                kind: PartitionKind::Mbr(kind),
+            });
+            *number += 1
+        }
+        if !EXTENDED_TYPES.contains(&next_kind) {
+            return Ok(());
+        }
+        ebr = extended + next
+    }
+}
+
+fn read_gpt(device: &dyn BlockDevice) -> Result<Vec<PartitionEntry>, PartitionError> {
+    let mut header = read_sector(device, 1)?;
+    if &header[..8] != GPT_SIGNATURE {
+        return Err(PartitionError::InvalidTable);
+    }
+    let header_size = u32_at(&header, 12) as usize;
+    if header_size < GPT_HEADER_SIZE || header_size > header.len() {
+        return Err(PartitionError::InvalidTable);
+    }
+    // The checksum is computed with its own field set to zero.
+    let checksum = u32_at(&header, 16);
+    header[16..20].fill(0);
+    if crc32(&header[..header_size]) != checksum {
+        return Err(PartitionError::BadChecksum);
+    }
+
+    let entries_sector = u64_at(&header, 72);
+    let entry_count = u32_at(&header, 80);
+    let entry_size = u32_at(&header, 84) as usize;
+    // Entries are at least 128 bytes. Larger ones are allowed, up to a sector.
+    if entry_count > MAX_GPT_ENTRIES
+        || entry_size < GPT_ENTRY_SIZE
+        || entry_size > device.sector_size()
+        || !entry_size.is_multiple_of(8)
+    {
+        return Err(PartitionError::InvalidTable);
+    }
+    let length = entry_count as usize * entry_size;
+    if length > MAX_GPT_TABLE_SIZE {
+        return Err(PartitionError::InvalidTable);
+    }
+    let sectors = length.div_ceil(device.sector_size()) as u64;
+    if entries_sector
+        .checked_add(sectors)
+        .is_none_or(|end| end > device.sector_count())
+    {
+        return Err(PartitionError::InvalidTable);
+    }
+    let mut entries = vec![0; sectors as usize * device.sector_size()];
+    device.read_sectors(entries_sector, &mut entries)?;
+    let entries = &entries[..length];
+    if crc32(entries) != u32_at(&header, 88) {
+        return Err(PartitionError::BadChecksum);
+    }
+
+    let mut partitions = Vec::new();
+    for (index, entry) in entries.chunks_exact(entry_size).enumerate() {
+        let type_guid: [u8; 16] = entry[..16].try_into().unwrap();
+        if type_guid == [0; 16] {
+            continue;
+        }
+        let (first, last) = (u64_at(entry, 32), u64_at(entry, 40));
+        if last < first {
+            return Err(PartitionError::InvalidTable);
+        }
+        let name = entry[56..128]
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|&unit| unit != 0);
+        let name = core::char::decode_utf16(name)
+            .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+            .collect();
+        partitions.push(PartitionEntry {
+            number: index as u32 + 1,
+            first,
+            count: last - first + 1,
+            kind: PartitionKind::Gpt { type_guid, name },
+        })
+    }
+    Ok(partitions)
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// The CRC32 of GPT, the one of zlib and Ethernet.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+fn write_mbr_entry(sector: &mut [u8], index: usize, kind: u8, first: u32, count: u32) {
+    let entry = &mut sector[MBR_ENTRIES + index * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+    entry[4] = kind;
+    entry[8..12].copy_from_slice(&first.to_le_bytes());
+    entry[12..16].copy_from_slice(&count.to_le_bytes());
+    sector[510..512].copy_from_slice(&MBR_SIGNATURE);
+}
+
+#[test_case]
+fn mbr_partitions() {
+    use super::ram::RamDisk;
+
+    let disk = Arc::new(RamDisk::new(1024));
+    let mut mbr = [0; 512];
+    write_mbr_entry(&mut mbr, 0, 0x83, 8, 100);
+    write_mbr_entry(&mut mbr, 2, 0x05, 200, 300);
+    disk.write_sectors(0, &mbr).unwrap();
+    // Two logical partitions, each one relative to its EBR.
+    let mut ebr = [0; 512];
+    write_mbr_entry(&mut ebr, 0, 0x0C, 4, 50);
+    write_mbr_entry(&mut ebr, 1, 0x05, 100, 80);
+    disk.write_sectors(200, &ebr).unwrap();
+    let mut ebr = [0; 512];
+    write_mbr_entry(&mut ebr, 0, 0x83, 2, 70);
+    disk.write_sectors(300, &ebr).unwrap();
+
+    let table = read_table(&*disk).unwrap();
+    let ranges: Vec<(u32, u64, u64)> = table
+        .iter()
+        .map(|entry| (entry.number, entry.first, entry.count))
+        .collect();
+    assert_eq!(ranges, [(1, 8, 100), (5, 204, 50), (6, 302, 70)]);
+    assert_eq!(table[1].kind, PartitionKind::Mbr(0x0C));
+
+    let partition = Partition::new(disk.clone(), 8, 100);
+    partition.write_sectors(99, &[3; 512]).unwrap();
+    let mut sector = [0; 512];
+    disk.read_sectors(107, &mut sector).unwrap();
+    assert_eq!(sector, [3; 512]);
+    assert_eq!(
+        partition.read_sectors(100, &mut sector),
+        Err(BlockError::OutOfRange)
+    );
+
+    // An EBR that links back to itself, without a logical partition.
+    let mut ebr = [0; 512];
+    write_mbr_entry(&mut ebr, 1, 0x05, 0, 80);
+    disk.write_sectors(200, &ebr).unwrap();
+    assert_eq!(read_table(&*disk), Err(PartitionError::InvalidTable));
+
+    write_mbr_entry(&mut mbr, 1, 0x83, 1000, 100);
+    disk.write_sectors(0, &mbr).unwrap();
+    assert_eq!(read_table(&*disk), Err(PartitionError::InvalidTable));
+    assert_eq!(read_table(&RamDisk::new(16)), Ok(Vec::new()));
+}
+
+#[test_case]
+fn gpt_partitions() {
+    use super::ram::RamDisk;
+
+    let disk = RamDisk::new(256);
+    let mut mbr = [0; 512];
+    write_mbr_entry(&mut mbr, 0, GPT_PROTECTIVE_TYPE, 1, 255);
+    disk.write_sectors(0, &mbr).unwrap();
+
+    let mut entries = [0; 4 * GPT_ENTRY_SIZE];
+    let entry = &mut entries[GPT_ENTRY_SIZE..2 * GPT_ENTRY_SIZE];
+    entry[..16].copy_from_slice(&[0xAF; 16]);
+    entry[32..40].copy_from_slice(&34u64.to_le_bytes());
+    entry[40..48].copy_from_slice(&133u64.to_le_bytes());
+    for (index, unit) in "root".encode_utf16().enumerate() {
+        entry[56 + index * 2..][..2].copy_from_slice(&unit.to_le_bytes())
+    }
+    disk.write_sectors(2, &entries).unwrap();
+
+    let mut header = [0; 512];
+    header[..8].copy_from_slice(GPT_SIGNATURE);
+    header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&4u32.to_le_bytes());
+    header[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+    header[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+    let checksum = crc32(&header[..GPT_HEADER_SIZE]);
+    header[16..20].copy_from_slice(&checksum.to_le_bytes());
+    disk.write_sectors(1, &header).unwrap();
+
+    let table = read_table(&disk).unwrap();
+    assert_eq!(
+        table,
+        [PartitionEntry {
+            number: 2,
+            first: 34,
+            count: 100,
+            kind: PartitionKind::Gpt {
+                type_guid: [0xAF; 16],
+                name: String::from("root"),
+            },
+        }]
+    );
+
+    let write_header = |header: &mut [u8; 512]| {
+        header[16..20].fill(0);
+        let checksum = crc32(&header[..GPT_HEADER_SIZE]);
+        header[16..20].copy_from_slice(&checksum.to_le_bytes());
+        disk.write_sectors(1, header).unwrap();
+    };
+    // Entries larger than a sector, and too many large entries.
+    header[84..88].copy_from_slice(&0x10_0000u32.to_le_bytes());
+    write_header(&mut header);
+    assert_eq!(read_table(&disk), Err(PartitionError::InvalidTable));
+    header[80..84].copy_from_slice(&MAX_GPT_ENTRIES.to_le_bytes());
+    header[84..88].copy_from_slice(&512u32.to_le_bytes());
+    write_header(&mut header);
+    assert_eq!(read_table(&disk), Err(PartitionError::InvalidTable));
+
+    header[80] = 3;
+    disk.write_sectors(1, &header).unwrap();
+    assert_eq!(read_table(&disk), Err(PartitionError::BadChecksum));
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+}
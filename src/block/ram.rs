@@ -0,0 +1,75 @@
+//! A block device in memory.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use spinning::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{check_request, BlockDevice, BlockError};
+
+const SECTOR_SIZE: usize = 512;
+
+/// A disk on the heap, which starts out zeroed.
+pub struct RamDisk {
+    sectors: u64,
+    data: Mutex<Vec<u8>>,
+}
+
+#[allow(dead_code)]
+impl RamDisk {
+    pub fn new(sectors: u64) -> Self {
+        RamDisk {
+            sectors,
+            data: Mutex::new(vec![0; sectors as usize * SECTOR_SIZE]),
+        }
+    }
+
+    /// Creates a disk with the contents, padded with zeros to whole sectors.
+    pub fn with_contents(contents: &[u8]) -> Self {
+        let sectors = contents.len().div_ceil(SECTOR_SIZE);
+        let mut data = Vec::from(contents);
+        data.resize(sectors * SECTOR_SIZE, 0);
+        RamDisk {
+            sectors: sectors as u64,
+            data: Mutex::new(data),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buffer.len())?;
+        let start = sector as usize * SECTOR_SIZE;
+        interrupts::without_interrupts(|| {
+            buffer.copy_from_slice(&self.data.lock()[start..start + buffer.len()])
+        });
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, data: &[u8]) -> Result<(), BlockError> {
+        check_request(self, sector, data.len())?;
+        let start = sector as usize * SECTOR_SIZE;
+        interrupts::without_interrupts(|| {
+            self.data.lock()[start..start + data.len()].copy_from_slice(data)
+        });
+        Ok(())
+    }
+}
+
+#[test_case]
+fn ram_disk() {
+    let disk = RamDisk::with_contents(b"boot");
+    assert_eq!(disk.sector_count(), 1);
+    let mut sector = [0xFF; SECTOR_SIZE];
+    disk.read_sectors(0, &mut sector).unwrap();
+    assert_eq!(&sector[..5], b"boot\0");
+    assert_eq!(disk.write_sectors(1, &sector), Err(BlockError::OutOfRange));
+}
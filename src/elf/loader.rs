@@ -0,0 +1,229 @@
+//! Loading executables into new address spaces and running them.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use super::{Elf, ElfError, ProgramHeader, PF_W, PF_X, PT_LOAD};
+use crate::memory::address_space::AddressSpace;
+use crate::memory::paging::PagingError;
+use crate::syscall::abi::{AT_ENTRY, AT_NULL, AT_PAGESZ, AT_PHDR, AT_PHENT, AT_PHNUM};
+use crate::userspace::{self, USER_START};
+
+/// The user stack ends one page below the end of user memory.
+const STACK_TOP: u64 = userspace::USER_END - 4096;
+const STACK_SIZE: u64 = 64 * 1024;
+/// At most this much of the stack is used for the arguments, environment and auxiliary vector.
+const MAX_ARGUMENTS_SIZE: u64 = 16 * 1024;
+
+impl From<PagingError> for ElfError {
+    fn from(error: PagingError) -> Self {
+        match error {
+            PagingError::OutOfMemory => ElfError::OutOfMemory,
+            PagingError::AlreadyMapped | PagingError::NotMapped | PagingError::HugePage => {
+                ElfError::InvalidSegment
+            }
+        }
+    }
+}
+
+/// A loaded program, ready to run.
+#[derive(Debug)]
+pub struct Program {
+    pub address_space: AddressSpace,
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+
+impl Program {
+    /// Runs the program on the current thread until it exits, and returns its exit code. Programs
+    /// usually run in processes, see `process::spawn`.
+    #[allow(dead_code)]
+    pub fn run(self) -> u64 {
+        let (previous, flags) = Cr3::read();
+        let code = unsafe {
+            self.address_space.activate();
+            let code = userspace::run(self.entry, self.stack_pointer);
+            Cr3::write(previous, flags);
+            code
+        };
+        drop(self);
+        code
+    }
+}
+
+/// Loads the executable into a new address space, with a stack holding the arguments and the
+/// environment.
+pub fn load(image: &[u8], args: &[&str], env: &[&str]) -> Result<Program, ElfError> {
+    let elf = Elf::parse(image)?;
+    let mut address_space = AddressSpace::new()?;
+
+    let mut entry_is_executable = false;
+    for header in elf.program_headers() {
+        if header.kind != PT_LOAD || header.memory_size == 0 {
+            continue;
+        }
+        load_segment(&elf, &header, &mut address_space)?;
+        let end = header.virtual_address + header.memory_size;
+        if header.flags & PF_X != 0 && (header.virtual_address..end).contains(&elf.entry) {
+            entry_is_executable = true
+        }
+    }
+    if !entry_is_executable {
+        return Err(ElfError::InvalidSegment);
+    }
+
+    let stack_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    let stack_start = Page::containing_address(VirtAddr::new(STACK_TOP - STACK_SIZE));
+    for page in Page::range(stack_start, stack_start + STACK_SIZE / 4096) {
+        address_space.map_page(page, stack_flags)?;
+    }
+    let stack_pointer = build_stack(&elf, args, env, &mut address_space)?;
+
+    Ok(Program {
+        address_space,
+        entry: VirtAddr::new(elf.entry),
+        stack_pointer,
+    })
+}
+
+/// Loads the executable and runs it on the current thread, see `load` and `Program::run`.
+#[allow(dead_code)]
+pub fn run(image: &[u8], args: &[&str], env: &[&str]) -> Result<u64, ElfError> {
+    Ok(load(image, args, env)?.run())
+}
+
+fn load_segment(
+    elf: &Elf,
+    header: &ProgramHeader,
+    address_space: &mut AddressSpace,
+) -> Result<(), ElfError> {
+    let start = header.virtual_address;
+    let end = start
+        .checked_add(header.memory_size)
+        .ok_or(ElfError::InvalidSegment)?;
+    if header.file_size > header.memory_size || start < USER_START || end > STACK_TOP - STACK_SIZE {
+        return Err(ElfError::InvalidSegment);
+    }
+    let data = elf.segment_data(header)?;
+
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if header.flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE
+    }
+    if header.flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE
+    }
+    let first: Page = Page::containing_address(VirtAddr::new(start));
+    let last = Page::containing_address(VirtAddr::new(end - 1));
+    for page in Page::range_inclusive(first, last) {
+        address_space.map_page(page, flags)?;
+    }
+    // The frames are zeroed, which covers the memory after the file data.
+    address_space.write(VirtAddr::new(start), data)?;
+    Ok(())
+}
+
+/// Writes the start stack described in `abi` and returns the stack pointer. The strings and a copy
+/// of the program headers, which `AT_PHDR` points to, are at the top of the stack.
+fn build_stack(
+    elf: &Elf,
+    args: &[&str],
+    env: &[&str],
+    address_space: &mut AddressSpace,
+) -> Result<VirtAddr, ElfError> {
+    let strings_size: usize = args.iter().chain(env).map(|string| string.len() + 1).sum();
+    let program_headers = elf.program_header_table();
+    let words = 1 + args.len() + 1 + env.len() + 1 + 2 * 6;
+    let size = (strings_size + program_headers.len() + 16 + words * size_of::<u64>()) as u64;
+    if size > MAX_ARGUMENTS_SIZE {
+        return Err(ElfError::ArgumentsTooLong);
+    }
+
+    let strings_start = STACK_TOP - strings_size as u64;
+    let program_headers_start = (strings_start - program_headers.len() as u64) & !7;
+    let stack_pointer = (program_headers_start - (words * size_of::<u64>()) as u64) & !15;
+
+    let mut stack = Vec::new();
+    let push = |stack: &mut Vec<u8>, word: u64| stack.extend_from_slice(&word.to_le_bytes());
+    push(&mut stack, args.len() as u64);
+    let mut string_address = strings_start;
+    for strings in [args, env].iter() {
+        for string in strings.iter() {
+            push(&mut stack, string_address);
+            string_address += string.len() as u64 + 1
+        }
+        push(&mut stack, 0)
+    }
+    let auxiliary_vector = [
+        (AT_PHDR, program_headers_start),
+        (AT_PHENT, elf.program_header_size() as u64),
+        (AT_PHNUM, elf.program_header_count as u64),
+        (AT_PAGESZ, 4096),
+        (AT_ENTRY, elf.entry),
+        (AT_NULL, 0),
+    ];
+    for &(kind, value) in auxiliary_vector.iter() {
+        push(&mut stack, kind);
+        push(&mut stack, value)
+    }
+    stack.resize((program_headers_start - stack_pointer) as usize, 0);
+    stack.extend_from_slice(program_headers);
+    stack.resize((strings_start - stack_pointer) as usize, 0);
+    for string in args.iter().chain(env) {
+        stack.extend_from_slice(string.as_bytes());
+        stack.push(0)
+    }
+
+    address_space.write(VirtAddr::new(stack_pointer), &stack)?;
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+#[cfg(test)]
+use super::ARGS;
+
+#[test_case]
+fn run_program() {
+    let hello = include_bytes!(concat!(env!("OUT_DIR"), "/hello"));
+    assert_eq!(run(hello, &["hello"], &[]), Ok(0));
+}
+
+#[test_case]
+fn start_stack() {
+    let code = run(ARGS, &["args", "one", "two"], &["HOME=/", "TERM=vt100"]).unwrap();
+    assert_eq!(code & 0xFF, 3, "argc");
+    assert_eq!((code >> 8) & 0xFF, 2, "environment");
+    assert_eq!((code >> 16) & 0xFF, 4, "AT_PAGESZ in KiB");
+    assert_eq!((code >> 24) & 0xFF, 8, "data and bss");
+    assert_eq!(code >> 32, 4, "argv[0] length");
+
+    assert_eq!(run(ARGS, &[], &[]), Ok(8 << 24 | 4 << 16));
+}
+
+#[test_case]
+fn invalid_programs_are_rejected() {
+    let arguments = [[b'a'; 100]; 200];
+    let long: Vec<&str> = arguments
+        .iter()
+        .map(|argument| core::str::from_utf8(argument).unwrap())
+        .collect();
+    assert_eq!(
+        load(ARGS, &long, &[]).unwrap_err(),
+        ElfError::ArgumentsTooLong
+    );
+
+    // Move the first segment into the kernel.
+    let mut image = Vec::from(ARGS);
+    let elf = Elf::parse(ARGS).unwrap();
+    let virtual_address = elf.program_header_offset + 16;
+    image[virtual_address..virtual_address + 8].copy_from_slice(&0x1000u64.to_le_bytes());
+    assert_eq!(
+        load(&image, &[], &[]).unwrap_err(),
+        ElfError::InvalidSegment
+    );
+}
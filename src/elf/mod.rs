@@ -0,0 +1,203 @@
+//! ELF64 executables, the format of user programs.
+//!
+//! Only statically linked x86_64 executables are supported. Parsing reads the fields one by one
+//! with bounds checks, so a malformed image is an error and never a panic.
+
+mod loader;
+
+#[allow(unused_imports)]
+pub use loader::{load, run, Program};
+
+use core::convert::TryFrom;
+
+const MAGIC: [u8; 4] = *b"\x7FELF";
+const CLASS_64: u8 = 2;
+const LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 62;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// Segment that is loaded into memory.
+pub const PT_LOAD: u32 = 1;
+// Permissions of segments.
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+#[allow(dead_code)]
+pub const PF_R: u32 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The image ends in the middle of a header or segment.
+    Truncated,
+    NotElf,
+    /// The image is not a 64-bit little-endian executable.
+    NotExecutable,
+    WrongArchitecture,
+    /// A segment is outside user memory or overlaps another one.
+    InvalidSegment,
+    OutOfMemory,
+    /// The arguments and environment do not fit on the stack.
+    ArgumentsTooLong,
+}
+
+/// A parsed executable, borrowing the image.
+#[derive(Debug, Clone, Copy)]
+pub struct Elf<'a> {
+    image: &'a [u8],
+    pub entry: u64,
+    program_header_offset: usize,
+    program_header_size: usize,
+    pub program_header_count: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub virtual_address: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(image: &'a [u8]) -> Result<Self, ElfError> {
+        if image.len() < HEADER_SIZE {
+            return Err(if image.starts_with(&MAGIC) || MAGIC.starts_with(image) {
+                ElfError::Truncated
+            } else {
+                ElfError::NotElf
+            });
+        }
+        if image[0..4] != MAGIC {
+            return Err(ElfError::NotElf);
+        }
+        if image[4] != CLASS_64
+            || image[5] != LITTLE_ENDIAN
+            || read_u16(image, 16)? != TYPE_EXECUTABLE
+        {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(image, 18)? != MACHINE_X86_64 {
+            return Err(ElfError::WrongArchitecture);
+        }
+
+        let elf = Elf {
+            image,
+            entry: read_u64(image, 24)?,
+            program_header_offset: to_usize(read_u64(image, 32)?)?,
+            program_header_size: usize::from(read_u16(image, 54)?),
+            program_header_count: usize::from(read_u16(image, 56)?),
+        };
+        if elf.program_header_size < PROGRAM_HEADER_SIZE {
+            return Err(ElfError::NotExecutable);
+        }
+        let table_size = elf
+            .program_header_size
+            .checked_mul(elf.program_header_count)
+            .ok_or(ElfError::Truncated)?;
+        elf.bytes(elf.program_header_offset, table_size)?;
+        Ok(elf)
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let elf = *self;
+        (0..self.program_header_count).map(move |i| {
+            let offset = elf.program_header_offset + i * elf.program_header_size;
+            // The table was bounds checked by `parse`.
+            let header = &elf.image[offset..offset + PROGRAM_HEADER_SIZE];
+            let u64_at = |offset| read_u64(header, offset).unwrap();
+            ProgramHeader {
+                kind: read_u32(header, 0).unwrap(),
+                flags: read_u32(header, 4).unwrap(),
+                offset: u64_at(8),
+                virtual_address: u64_at(16),
+                file_size: u64_at(32),
+                memory_size: u64_at(40),
+            }
+        })
+    }
+
+    /// Returns the program header table as it is in the image.
+    pub fn program_header_table(&self) -> &'a [u8] {
+        let size = self.program_header_size * self.program_header_count;
+        &self.image[self.program_header_offset..self.program_header_offset + size]
+    }
+
+    pub fn program_header_size(&self) -> usize {
+        self.program_header_size
+    }
+
+    /// Returns the file contents of the segment.
+    pub fn segment_data(&self, header: &ProgramHeader) -> Result<&'a [u8], ElfError> {
+        self.bytes(to_usize(header.offset)?, to_usize(header.file_size)?)
+    }
+
+    fn bytes(&self, offset: usize, length: usize) -> Result<&'a [u8], ElfError> {
+        let end = offset.checked_add(length).ok_or(ElfError::Truncated)?;
+        self.image.get(offset..end).ok_or(ElfError::Truncated)
+    }
+}
+
+fn to_usize(value: u64) -> Result<usize, ElfError> {
+    usize::try_from(value).map_err(|_| ElfError::Truncated)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = bytes.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = bytes.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    let mut value = [0; 4];
+    value.copy_from_slice(bytes);
+    Ok(u32::from_le_bytes(value))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let bytes = bytes.get(offset..offset + 8).ok_or(ElfError::Truncated)?;
+    let mut value = [0; 8];
+    value.copy_from_slice(bytes);
+    Ok(u64::from_le_bytes(value))
+}
+
+#[cfg(test)]
+const ARGS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/args"));
+
+#[test_case]
+fn parse_program() {
+    let elf = Elf::parse(ARGS).unwrap();
+    assert_eq!(elf.entry, crate::userspace::USER_START);
+    let loaded: alloc::vec::Vec<_> = elf
+        .program_headers()
+        .filter(|header| header.kind == PT_LOAD && header.memory_size > 0)
+        .collect();
+    assert_eq!(loaded.len(), 2);
+    assert_eq!(loaded[0].flags, PF_R | PF_X);
+    assert_eq!(loaded[1].flags, PF_R | PF_W);
+    // The data segment ends with zero-initialized memory.
+    assert!(loaded[1].memory_size > loaded[1].file_size);
+}
+
+#[test_case]
+fn malformed_images_are_rejected() {
+    assert_eq!(Elf::parse(b"\x7FEL").unwrap_err(), ElfError::Truncated);
+    assert_eq!(Elf::parse(&[0; 64]).unwrap_err(), ElfError::NotElf);
+
+    let mut image = alloc::vec::Vec::from(ARGS);
+    image[18] = 3; // i386
+    assert_eq!(Elf::parse(&image).unwrap_err(), ElfError::WrongArchitecture);
+    image[18] = 62;
+    image[16] = 3; // Shared object
+    assert_eq!(Elf::parse(&image).unwrap_err(), ElfError::NotExecutable);
+    image[16] = 2;
+    image[56] = 0xFF; // More program headers than the image holds
+    assert_eq!(Elf::parse(&image).unwrap_err(), ElfError::Truncated);
+    image[56] = 3;
+    assert_eq!(
+        Elf::parse(&image[..HEADER_SIZE + 100]).unwrap_err(),
+        ElfError::Truncated
+    );
+}
@@ -1,49 +1,150 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use core::cell::UnsafeCell;
+use core::ptr::addr_of_mut;
 use lazy_static::lazy_static;
-use x86_64::instructions::segmentation::{Segment, CS};
+use x86_64::instructions::segmentation::{Segment, CS, SS};
 use x86_64::instructions::tables::load_tss;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::{structures::tss::TaskStateSegment, VirtAddr};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+// Page faults get their own stack so that running into a stack guard page can be reported.
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
 
+/// Index of the privilege stack the CPU switches to on interrupts in ring 3.
+const RING_0_STACK_INDEX: usize = 0;
+
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+/// Loads the GDT and TSS of the bootstrap processor.
 pub fn init() {
-    GDT.gdt.load();
-    unsafe {
-        CS::set_reg(GDT.code_selector);
-        load_tss(GDT.tss_selector)
+    GDT.load()
+}
+
+/// Returns the TSS of the bootstrap processor.
+pub fn bsp_tss() -> &'static Tss {
+    &TSS
+}
+
+/// Creates and loads a GDT and TSS for an application processor, with its own interrupt stacks.
+/// Requires the heap.
+pub fn init_ap() -> &'static Tss {
+    // Zeroing the stacks backs them with frames, so using them never causes a page fault.
+    let allocate_stack = || {
+        let stack = Box::leak(vec![0u8; IST_STACK_SIZE].into_boxed_slice());
+        VirtAddr::from_ptr(stack.as_ptr()) + IST_STACK_SIZE
+    };
+    let tss: &'static Tss = Box::leak(Box::new(Tss::new(allocate_stack(), allocate_stack())));
+    let gdt: &'static GdtLayout = Box::leak(Box::new(GdtLayout::new(tss)));
+    gdt.load();
+    tss
+}
+
+/// Returns the segment selectors, which are the same in the GDT of every CPU.
+pub fn selectors() -> Selectors {
+    GDT.selectors
+}
+
+/// Task state segment of a CPU. Only the ring 0 stack changes once the TSS is loaded.
+pub struct Tss(UnsafeCell<TaskStateSegment>);
+
+// The CPU only reads the TSS, and the ring 0 stack is only written by the CPU the TSS belongs to.
+unsafe impl Sync for Tss {}
+
+impl Tss {
+    fn new(double_fault_stack: VirtAddr, page_fault_stack: VirtAddr) -> Self {
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack;
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = page_fault_stack;
+        Tss(UnsafeCell::new(tss))
+    }
+
+    /// Sets the stack the CPU switches to when an interrupt arrives while it runs in user mode.
+    pub fn set_ring_0_stack(&self, stack_top: VirtAddr) {
+        unsafe {
+            addr_of_mut!((*self.0.get()).privilege_stack_table[RING_0_STACK_INDEX])
+                .write_unaligned(stack_top)
+        }
     }
 }
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+    static ref TSS: Tss = {
+        let double_fault_stack = {
+            static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
 
             let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_start + STACK_SIZE
+            stack_start + IST_STACK_SIZE
         };
-        tss
+        let page_fault_stack = {
+            static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            stack_start + IST_STACK_SIZE
+        };
+        Tss::new(double_fault_stack, page_fault_stack)
     };
 }
 
 lazy_static! {
-    static ref GDT: GdtLayout = {
+    static ref GDT: GdtLayout = GdtLayout::new(&TSS);
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub user_data: SegmentSelector,
+}
+
+struct GdtLayout {
+    gdt: GlobalDescriptorTable,
+    selectors: Selectors,
+    tss_selector: SegmentSelector,
+}
+
+impl GdtLayout {
+    fn new(tss: &'static Tss) -> Self {
         let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        // `syscall` and `sysret` expect the segments in this order.
+        let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss.0.get() }));
 
         GdtLayout {
             gdt,
-            code_selector,
+            selectors: Selectors {
+                kernel_code,
+                kernel_data,
+                user_code,
+                user_data,
+            },
             tss_selector,
         }
-    };
+    }
+
+    fn load(&'static self) {
+        self.gdt.load();
+        unsafe {
+            CS::set_reg(self.selectors.kernel_code);
+            SS::set_reg(self.selectors.kernel_data);
+            load_tss(self.tss_selector)
+        }
+    }
 }
 
-struct GdtLayout {
-    gdt: GlobalDescriptorTable,
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+#[test_case]
+fn user_segments_are_ring_3() {
+    use x86_64::PrivilegeLevel;
+
+    let selectors = selectors();
+    assert_eq!(selectors.kernel_code.rpl(), PrivilegeLevel::Ring0);
+    assert_eq!(selectors.user_code.rpl(), PrivilegeLevel::Ring3);
+    assert_eq!(selectors.user_data.rpl(), PrivilegeLevel::Ring3);
+    // `sysret` loads the user code segment from right after the user data segment.
+    assert_eq!(selectors.user_code.index(), selectors.user_data.index() + 1);
 }
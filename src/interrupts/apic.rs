@@ -0,0 +1,293 @@
+//! Local APIC of the CPU and the I/O APICs that route device interrupts to it.
+//!
+//! The registers of both are memory-mapped. Every CPU sees its own local APIC at the same address.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::controller::InterruptController;
+use super::pic::Pic;
+use super::IRQ_OFFSET;
+use crate::acpi::{self, InterruptSourceOverride};
+use crate::memory::paging;
+
+/// Vector of the interrupt the local APIC raises when an interrupt disappears before it is
+/// delivered. It must not be acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+// Local APIC registers.
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SPURIOUS: usize = 0xF0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+
+// Interrupt command register bits, used to send inter-processor interrupts (IPIs).
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DESTINATION_SHIFT: u32 = 24;
+
+// I/O APIC registers are accessed indirectly through a register select and a data window.
+const IOAPIC_SELECT: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_DESTINATION_SHIFT: u64 = 56;
+
+static LOCAL_APIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+unsafe fn read_local(register: usize) -> u32 {
+    let base = VirtAddr::new(LOCAL_APIC_BASE.load(Ordering::Relaxed));
+    (base + register).as_ptr::<u32>().read_volatile()
+}
+
+unsafe fn write_local(register: usize, value: u32) {
+    let base = VirtAddr::new(LOCAL_APIC_BASE.load(Ordering::Relaxed));
+    (base + register).as_mut_ptr::<u32>().write_volatile(value)
+}
+
+/// Returns whether the APICs are used instead of the legacy PICs.
+pub fn is_enabled() -> bool {
+    LOCAL_APIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Enables the local APIC of the current CPU and makes it accept all interrupts.
+///
+/// # Safety
+///
+/// The local APIC registers must be mapped, see `is_enabled`.
+pub unsafe fn enable_local_apic() {
+    let mut base = Msr::new(IA32_APIC_BASE_MSR);
+    base.write(base.read() | APIC_GLOBAL_ENABLE);
+    write_local(LAPIC_TASK_PRIORITY, 0);
+    write_local(
+        LAPIC_SPURIOUS,
+        LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR),
+    )
+}
+
+/// Returns the ID of the local APIC of the current CPU.
+pub fn local_apic_id() -> u8 {
+    (unsafe { read_local(LAPIC_ID) } >> 24) as u8
+}
+
+/// Acknowledges an interrupt that did not come through an I/O APIC, such as an IPI.
+pub fn end_of_interrupt() {
+    unsafe { write_local(LAPIC_EOI, 0) }
+}
+
+/// Sends an IPI to the local APIC with the ID and waits until it is delivered.
+///
+/// # Safety
+///
+/// The local APICs must be enabled and `command` must be a valid interrupt command.
+unsafe fn send_ipi(apic_id: u8, command: u32) {
+    // The command register is split in two, which must not be interleaved with another IPI.
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        write_local(LAPIC_ICR_HIGH, u32::from(apic_id) << ICR_DESTINATION_SHIFT);
+        write_local(LAPIC_ICR_LOW, command);
+        while read_local(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop()
+        }
+    })
+}
+
+/// Raises the interrupt at the vector on the CPU with the local APIC ID.
+pub fn send_interrupt(apic_id: u8, vector: u8) {
+    assert!(is_enabled(), "Sending IPI without local APIC");
+    unsafe { send_ipi(apic_id, ICR_LEVEL_ASSERT | u32::from(vector)) }
+}
+
+/// Sends an INIT IPI, which resets the CPU and makes it wait for a startup IPI.
+///
+/// # Safety
+///
+/// The CPU must not be running anything.
+pub unsafe fn send_init(apic_id: u8) {
+    send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT)
+}
+
+/// Sends a startup IPI, which makes a CPU waiting after INIT start executing in real mode at the
+/// beginning of the physical page with the number.
+///
+/// # Safety
+///
+/// The page must contain code that brings up the CPU.
+pub unsafe fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(apic_id, ICR_DELIVERY_STARTUP | u32::from(page))
+}
+
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    redirections: u32,
+}
+
+impl IoApic {
+    /// Maps the I/O APIC and masks all its interrupts.
+    fn new(address: PhysAddr, gsi_base: u32) -> Self {
+        let base = paging::map_mmio(address, 0x20).expect("Mapping I/O APIC failed");
+        let mut io_apic = IoApic {
+            base,
+            gsi_base,
+            redirections: 0,
+        };
+        io_apic.redirections = ((unsafe { io_apic.read(IOAPIC_VERSION) } >> 16) & 0xFF) + 1;
+        for gsi in gsi_base..gsi_base + io_apic.redirections {
+            io_apic.set_redirection(gsi, REDIRECTION_MASKED)
+        }
+        io_apic
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.redirections).contains(&gsi)
+    }
+
+    unsafe fn read(&mut self, register: u32) -> u32 {
+        (self.base + IOAPIC_SELECT)
+            .as_mut_ptr::<u32>()
+            .write_volatile(register);
+        (self.base + IOAPIC_WINDOW).as_ptr::<u32>().read_volatile()
+    }
+
+    unsafe fn write(&mut self, register: u32, value: u32) {
+        (self.base + IOAPIC_SELECT)
+            .as_mut_ptr::<u32>()
+            .write_volatile(register);
+        (self.base + IOAPIC_WINDOW)
+            .as_mut_ptr::<u32>()
+            .write_volatile(value)
+    }
+
+    fn set_redirection(&mut self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        // The low half holds the mask bit, so it is written last.
+        unsafe {
+            self.write(register + 1, (entry >> 32) as u32);
+            self.write(register, entry as u32)
+        }
+    }
+}
+
+/// Local APIC and I/O APICs, with the legacy PICs disabled.
+pub struct Apic {
+    io_apics: Vec<IoApic>,
+    overrides: Vec<InterruptSourceOverride>,
+}
+
+impl Apic {
+    /// Sets up the APICs listed in the MADT. Returns `None` if there is no MADT or I/O APIC.
+    pub fn new() -> Option<Self> {
+        let madt = acpi::tables()?.madt.as_ref()?;
+        if madt.io_apics.is_empty() {
+            return None;
+        }
+        if madt.legacy_pics {
+            Pic::disable()
+        }
+
+        let base =
+            paging::map_mmio(madt.local_apic_address, 4096).expect("Mapping local APIC failed");
+        LOCAL_APIC_BASE.store(base.as_u64(), Ordering::Relaxed);
+        unsafe { enable_local_apic() };
+
+        let io_apics = madt
+            .io_apics
+            .iter()
+            .map(|io_apic| IoApic::new(io_apic.address, io_apic.gsi_base))
+            .collect();
+        Some(Apic {
+            io_apics,
+            overrides: madt.overrides.clone(),
+        })
+    }
+
+    /// Returns the global system interrupt the ISA interrupt is wired to, with its polarity and
+    /// trigger mode.
+    fn redirection(&self, irq: u8) -> (u32, u64) {
+        match self.overrides.iter().find(|o| o.irq == irq) {
+            Some(o) => {
+                let mut flags = 0;
+                if o.active_low() {
+                    flags |= REDIRECTION_ACTIVE_LOW
+                }
+                if o.level_triggered() {
+                    flags |= REDIRECTION_LEVEL_TRIGGERED
+                }
+                (o.gsi, flags)
+            }
+            // ISA interrupts are active high and edge triggered.
+            None => (u32::from(irq), 0),
+        }
+    }
+}
+
+impl InterruptController for Apic {
+    fn name(&self) -> &'static str {
+        "APIC"
+    }
+
+    fn set_irq_enabled(&mut self, irq: u8, enabled: bool) {
+        let (gsi, flags) = self.redirection(irq);
+        let mut entry = flags
+            | u64::from(IRQ_OFFSET + irq)
+            | u64::from(local_apic_id()) << REDIRECTION_DESTINATION_SHIFT;
+        if !enabled {
+            entry |= REDIRECTION_MASKED
+        }
+        if let Some(io_apic) = self.io_apics.iter_mut().find(|io| io.handles(gsi)) {
+            io_apic.set_redirection(gsi, entry)
+        }
+    }
+
+    fn end_of_interrupt(&mut self, _vector: u8) {
+        end_of_interrupt()
+    }
+}
+
+#[test_case]
+fn apic_is_used() {
+    // QEMU emulates an APIC by default.
+    let name = super::with_controller(|controller| controller.name());
+    assert_eq!(name, "APIC");
+}
+
+#[test_case]
+fn legacy_pics_are_masked() {
+    use x86_64::instructions::port::Port;
+
+    let primary = unsafe { Port::<u8>::new(0x21).read() };
+    let secondary = unsafe { Port::<u8>::new(0xA1).read() };
+    assert_eq!((primary, secondary), (u8::MAX, u8::MAX));
+}
+
+#[test_case]
+fn local_apic_is_listed_in_madt() {
+    let madt = acpi::tables().unwrap().madt.as_ref().unwrap();
+    let id = local_apic_id();
+    assert!(madt
+        .local_apics
+        .iter()
+        .any(|local_apic| local_apic.apic_id == id && local_apic.enabled));
+}
+
+#[test_case]
+fn timer_interrupts_are_routed() {
+    let start = crate::time::ticks();
+    while crate::time::ticks() < start + 2 {
+        x86_64::instructions::hlt()
+    }
+}
@@ -0,0 +1,48 @@
+use alloc::boxed::Box;
+use lazy_static::lazy_static;
+use spinning::Mutex;
+
+use super::apic::Apic;
+use super::pic::Pic;
+
+/// Hardware that delivers device interrupts to the CPU.
+///
+/// Legacy ISA interrupt `irq` is always delivered at vector `IRQ_OFFSET + irq`, whichever
+/// controller is used.
+pub trait InterruptController: Send {
+    #[allow(dead_code)]
+    fn name(&self) -> &'static str;
+
+    /// Enables or disables delivery of the ISA interrupt. All interrupts start out disabled.
+    fn set_irq_enabled(&mut self, irq: u8, enabled: bool);
+
+    /// Notifies the controller that the interrupt at the vector was handled, which allows for new
+    /// interrupts to be received.
+    fn end_of_interrupt(&mut self, vector: u8);
+}
+
+lazy_static! {
+    static ref CONTROLLER: Mutex<Option<Box<dyn InterruptController>>> = Mutex::new(None);
+}
+
+/// Uses the APICs if ACPI describes them, and falls back to the legacy PICs otherwise.
+pub fn init() {
+    let controller: Box<dyn InterruptController> = match Apic::new() {
+        Some(apic) => Box::new(apic),
+        None => Box::new(Pic::new()),
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| *CONTROLLER.lock() = Some(controller))
+}
+
+/// Runs `f` with exclusive access to the interrupt controller.
+pub fn with_controller<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut dyn InterruptController) -> R,
+{
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut controller = CONTROLLER.lock();
+        f(controller
+            .as_deref_mut()
+            .expect("Interrupt controller is not initialized"))
+    })
+}
@@ -0,0 +1,459 @@
+pub mod apic;
+mod controller;
+mod pic;
+
+use core::ops::AddAssign;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::paging::Translate;
+
+use crate::block::ata::{self, Channel};
+use crate::memory::address_space;
+use crate::memory::demand::{self, Fault};
+use crate::memory::paging;
+use crate::smp::percpu::KernelGs;
+use crate::syscall::abi;
+use crate::{gdt, keyboard, serial, smp, task, time, userspace};
+
+pub use controller::{with_controller, InterruptController};
+
+/// Vector of ISA interrupt 0, the following interrupts come right after it.
+pub const IRQ_OFFSET: u8 = 32;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = IRQ_OFFSET,
+    Keyboard,
+    Serial = IRQ_OFFSET + 4,
+    PrimaryAta = IRQ_OFFSET + 14,
+    SecondaryAta,
+}
+
+/// The device interrupts that have a handler, they are enabled at boot.
+const DEVICE_INTERRUPTS: [InterruptIndex; 5] = [
+    InterruptIndex::Timer,
+    InterruptIndex::Keyboard,
+    InterruptIndex::Serial,
+    InterruptIndex::PrimaryAta,
+    InterruptIndex::SecondaryAta,
+];
+
+// Amount of interrupts handled for every ISA interrupt, on all CPUs together.
+static IRQ_COUNTS: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+
+impl InterruptIndex {
+    fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    fn as_usize(self) -> usize {
+        usize::from(self.as_u8())
+    }
+
+    fn irq(self) -> u8 {
+        self.as_u8() - IRQ_OFFSET
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            InterruptIndex::Timer => "timer",
+            InterruptIndex::Keyboard => "keyboard",
+            InterruptIndex::Serial => "serial (COM1)",
+            InterruptIndex::PrimaryAta => "primary ATA",
+            InterruptIndex::SecondaryAta => "secondary ATA",
+        }
+    }
+}
+
+/// Number, device and amount of handled interrupts of every enabled ISA interrupt.
+pub fn irq_counts() -> [(u8, &'static str, u64); DEVICE_INTERRUPTS.len()] {
+    DEVICE_INTERRUPTS.map(|index| {
+        let count = IRQ_COUNTS[usize::from(index.irq())].load(Ordering::Relaxed);
+        (index.irq(), index.name(), count)
+    })
+}
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+
+        idt.divide_error.set_handler_fn(divide_by_zero_handler);
+        idt.debug.set_handler_fn(debug_handler);
+        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.non_maskable_interrupt
+            .set_handler_fn(non_maskable_interrupt_handler);
+        idt.overflow.set_handler_fn(overflow_handler);
+        idt.bound_range_exceeded
+            .set_handler_fn(bound_range_exceeded_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.device_not_available
+            .set_handler_fn(device_not_available_handler);
+
+        unsafe {
+            idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX)
+        };
+
+        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+        idt.segment_not_present
+            .set_handler_fn(segment_not_present_handler);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault_handler);
+        unsafe {
+            idt.page_fault.set_handler_fn(page_fault_handler).set_stack_index(gdt::PAGE_FAULT_IST_INDEX)
+        };
+        idt.x87_floating_point
+            .set_handler_fn(x87_floating_point_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        idt.machine_check.set_handler_fn(machine_check_handler);
+        idt.simd_floating_point
+            .set_handler_fn(simd_floating_point_handler);
+        idt.security_exception
+            .set_handler_fn(security_exception_handler);
+
+        // Hardware interrupt codes
+        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_handler);
+        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_handler);
+        idt[InterruptIndex::Serial.as_usize()].set_handler_fn(serial_handler);
+        idt[InterruptIndex::PrimaryAta.as_usize()].set_handler_fn(primary_ata_handler);
+        idt[InterruptIndex::SecondaryAta.as_usize()].set_handler_fn(secondary_ata_handler);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
+        idt[usize::from(smp::CALL_VECTOR)].set_handler_fn(call_handler);
+
+        // Register handlers for tests
+        #[cfg(test)]
+        idt.divide_error.set_handler_fn(divide_by_zero_handler_test);
+
+        idt
+    };
+}
+
+/// Loads the IDT, after which CPU exceptions are handled.
+pub fn init() {
+    IDT.load()
+}
+
+/// Sets up the interrupt controller and enables the timer, keyboard, serial port and disk
+/// interrupts. The heap must be initialized.
+pub fn enable_hardware_interrupts() {
+    controller::init();
+    with_controller(|controller| {
+        for index in DEVICE_INTERRUPTS {
+            controller.set_irq_enabled(index.irq(), true)
+        }
+    });
+    x86_64::instructions::interrupts::enable()
+}
+
+extern "x86-interrupt" fn divide_by_zero_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame("divide_by_zero_handler", stack_frame, None)
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame("debug_handler", stack_frame, None)
+}
+
+extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame("non_maskable_interrupt_handler", stack_frame, None)
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame("breakpoint_handler", stack_frame, None)
+}
+
+extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame("overflow_handler", stack_frame, None)
+}
+
+extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame("bound_range_exceeded_handler", stack_frame, None)
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame("invalid_opcode_handler", stack_frame, None)
+}
+
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame("device_not_available_handler", stack_frame, None)
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame("double_fault_handler", stack_frame, Some(error_code));
+
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
+
+extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame("invalid_tss_handler", stack_frame, Some(error_code))
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame("segment_not_present_handler", stack_frame, Some(error_code))
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame(
+        "general_protection_fault_handler",
+        stack_frame,
+        Some(error_code),
+    )
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let _gs = KernelGs::enter(&stack_frame);
+    let address = Cr2::read();
+    let write_protected =
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if error_code.contains(write_protected) && address_space::copy_on_write(address) {
+        return;
+    }
+    match demand::handle_page_fault(address, error_code) {
+        Fault::Resolved => return,
+        Fault::Guard(name) => println!("Guard page hit: {}", name),
+        Fault::Unhandled => (),
+    }
+
+    println!(
+        "Page fault: {} {:?} in {} mode ({})",
+        if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "executing"
+        } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "writing"
+        } else {
+            "reading"
+        },
+        address,
+        if error_code.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        },
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "protection violation"
+        } else {
+            "page not present"
+        },
+    );
+    print_faulting_instruction(&stack_frame);
+    print_exception_stack_frame("page_fault_handler", stack_frame, Some(error_code.bits()))
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame("x87_floating_point_handler", stack_frame, None)
+}
+
+extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame("alignment_check_handler", stack_frame, Some(error_code))
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame("machine_check_handler", stack_frame, None);
+
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame("simd_floating_point_handler", stack_frame, None)
+}
+
+extern "x86-interrupt" fn security_exception_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = KernelGs::enter(&stack_frame);
+    print_exception_stack_frame("security_exception_handler", stack_frame, Some(error_code))
+}
+
+extern "x86-interrupt" fn timer_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    time::tick();
+    // Acknowledge first, the scheduler might switch to another thread before this returns.
+    ack_interrupt(InterruptIndex::Timer);
+    task::on_tick()
+}
+
+extern "x86-interrupt" fn keyboard_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    keyboard::process_input();
+    ack_interrupt(InterruptIndex::Keyboard)
+}
+
+extern "x86-interrupt" fn serial_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    serial::process_input();
+    ack_interrupt(InterruptIndex::Serial)
+}
+
+extern "x86-interrupt" fn primary_ata_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    ata::interrupt(Channel::Primary);
+    ack_interrupt(InterruptIndex::PrimaryAta)
+}
+
+extern "x86-interrupt" fn secondary_ata_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    ata::interrupt(Channel::Secondary);
+    ack_interrupt(InterruptIndex::SecondaryAta)
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+extern "x86-interrupt" fn call_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    smp::run_calls();
+    apic::end_of_interrupt()
+}
+
+// Notify the interrupt controller that the interrupt was handled, which allows for new interrupts
+// to be received. Every device interrupt handler ends here, so this also counts the interrupts.
+fn ack_interrupt(index: InterruptIndex) {
+    IRQ_COUNTS[usize::from(index.irq())].fetch_add(1, Ordering::Relaxed);
+    with_controller(|controller| controller.end_of_interrupt(index.as_u8()))
+}
+
+fn print_exception_stack_frame(
+    fn_name: &str,
+    stack_frame: InterruptStackFrame,
+    error_code: Option<u64>,
+) {
+    println!("Exception {}:\n{:#?}", fn_name, stack_frame);
+    if let Some(code) = error_code {
+        println!("Error code: {:?}", code)
+    }
+    // Only the user program cannot go on, the kernel ends it and runs the other threads.
+    if stack_frame.code_segment & 3 == 3 {
+        userspace::exit(abi::FAULT_EXIT_CODE)
+    }
+    // Spin so we have a chance to read the output stack frame. Otherwise, for
+    // exceptions that set the instruction_pointer to the instruction that
+    // caused the exception iretq will return to the faulty instruction and
+    // endless loop
+    #[cfg(not(test))]
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
+
+// Prints the bytes at the instruction pointer, which is enough to decode the faulting instruction.
+fn print_faulting_instruction(stack_frame: &InterruptStackFrame) {
+    const INSTRUCTION_BYTES: u64 = 15; // Maximum length of an x86 instruction.
+
+    let start = stack_frame.instruction_pointer;
+    let end = start + (INSTRUCTION_BYTES - 1);
+    // The fault may have happened while the page tables were locked, waiting would deadlock.
+    let mapped = paging::try_with_mapper(|mapper| {
+        mapper.translate_addr(start).is_some() && mapper.translate_addr(end).is_some()
+    });
+    match mapped {
+        Some(true) => (),
+        Some(false) => {
+            println!("Instruction at {:?} is not mapped", start);
+            return;
+        }
+        None => {
+            println!(
+                "Instruction at {:?} not shown, the page tables are locked",
+                start
+            );
+            return;
+        }
+    }
+
+    print!("Instruction at {:?}:", start);
+    for offset in 0..INSTRUCTION_BYTES {
+        let byte = unsafe { (start + offset).as_ptr::<u8>().read_volatile() };
+        print!(" {:02x}", byte);
+    }
+    println!()
+}
+
+// Progresses the instruction pointer by N bytes. This is useful in situations
+// where an exception occurs and the instruction_pointer is set to faulty a
+// fault instruction. By progressing the instruction pointer we can resume
+// execution _after_ fixing the error.
+#[allow(dead_code)]
+fn incr_instruction_pointer(stack_frame: &mut InterruptStackFrame, num_bytes: u64) {
+    unsafe {
+        let frame = stack_frame.as_mut();
+        frame
+            .extract_inner()
+            .instruction_pointer
+            .add_assign(num_bytes)
+    }
+}
+
+#[test_case]
+fn count_interrupts() {
+    let (irq, name, before) = irq_counts()[0];
+    assert_eq!((irq, name), (0, "timer"));
+    time::busy_wait(core::time::Duration::from_millis(20));
+    assert!(irq_counts()[0].2 > before);
+}
+
+#[test_case]
+fn test_debug() {
+    unsafe { core::arch::asm!("int 1") }
+}
+
+#[test_case]
+fn test_breakpoint_handler() {
+    x86_64::instructions::interrupts::int3()
+}
+
+#[cfg(test)]
+extern "x86-interrupt" fn divide_by_zero_handler_test(mut stack_frame: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&stack_frame);
+    // 0x3 is the number of bytes for the instruction that triggered the
+    // exception: `divw %dx` (66 f7 f2) is a 3-byte instruction.
+    incr_instruction_pointer(&mut stack_frame, 0x3)
+}
+
+#[test_case]
+fn test_divide_by_zero() {
+    // The Rust runtime guards against divide by zero errors by triggering a
+    // panic. So writing the assembly to divide by zero to bypass the runtime.
+    unsafe {
+        core::arch::asm!("mov dx, 0");
+        core::arch::asm!("div dx")
+    }
+    // The exception handler will progress the instruction_pointer to this instruction so the test
+    // will pass. The value is put into a variable because `assert!(true)` could be optimized out by
+    // the compiler.
+    let res = true;
+    assert!(res)
+}
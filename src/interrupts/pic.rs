@@ -0,0 +1,53 @@
+use pic8259::ChainedPics;
+
+use super::controller::InterruptController;
+use super::IRQ_OFFSET;
+
+/// Line of the primary PIC the secondary PIC is connected to.
+const CASCADE_IRQ: u8 = 2;
+
+/// The two chained legacy 8259 PICs.
+pub struct Pic {
+    pics: ChainedPics,
+}
+
+impl Pic {
+    /// Remaps the PICs to the vectors after the CPU exceptions, with all interrupts disabled.
+    pub fn new() -> Self {
+        let mut pics = unsafe { ChainedPics::new(IRQ_OFFSET, IRQ_OFFSET + 8) };
+        unsafe {
+            pics.initialize();
+            pics.write_masks(!(1 << CASCADE_IRQ), u8::MAX)
+        }
+        Pic { pics }
+    }
+
+    /// Remaps and masks the PICs, so they never raise an interrupt.
+    ///
+    /// Remapping them first means a spurious interrupt cannot be mistaken for a CPU exception.
+    pub fn disable() {
+        let mut pics = Pic::new().pics;
+        unsafe { pics.write_masks(u8::MAX, u8::MAX) }
+    }
+}
+
+impl InterruptController for Pic {
+    fn name(&self) -> &'static str {
+        "8259 PIC"
+    }
+
+    fn set_irq_enabled(&mut self, irq: u8, enabled: bool) {
+        let mut masks = unsafe { self.pics.read_masks() };
+        let mask = &mut masks[usize::from(irq / 8)];
+        if enabled {
+            *mask &= !(1 << (irq % 8))
+        } else {
+            *mask |= 1 << (irq % 8)
+        }
+        unsafe { self.pics.write_masks(masks[0], masks[1]) }
+    }
+
+    fn end_of_interrupt(&mut self, vector: u8) {
+        unsafe { self.pics.notify_end_of_interrupt(vector) }
+    }
+}
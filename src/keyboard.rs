@@ -1,27 +1,325 @@
+use conquer_once::spin::OnceCell;
+use core::mem;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
 use lazy_static::lazy_static;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 use spinning::Mutex;
+use x86_64::instructions::interrupts;
 use x86_64::instructions::port::Port;
 
+use crate::vga;
+
 const DATA_PORT: u16 = 0x60;
+const SCANCODE_QUEUE_CAPACITY: usize = 100;
+
+// Scancodes of scancode set 1 for paging the screen. Releasing a key sets the highest bit.
+const EXTENDED: u8 = 0xE0;
+const RELEASED: u8 = 0x80;
+const LEFT_SHIFT: u8 = 0x2A;
+const RIGHT_SHIFT: u8 = 0x36;
+const PAGE_UP: u8 = 0x49;
+const PAGE_DOWN: u8 = 0x51;
+
+// Filled by the interrupt handler, which must never block or allocate, so the queue is lock-free
+// and only created outside of interrupt context.
+static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+// Scancodes dropped because the queue was full. Printing from the interrupt handler would lock
+// the screen, so they are counted there and reported by the readers.
+static DROPPED_SCANCODES: AtomicUsize = AtomicUsize::new(0);
+
+type Decoder = Keyboard<layouts::Us104Key, ScancodeSet1>;
 
 lazy_static! {
-    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
+    // The one decoder of the scancodes, shared by all readers, so that a key pressed while one of
+    // them reads, like shift, still applies when another one reads the next key.
+    static ref DECODER: Mutex<Decoder> = Mutex::new(
         Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
     );
+    // Only used by the interrupt handler.
+    static ref PAGING: Mutex<Paging> = Mutex::new(Paging::new());
 }
 
+/// Reads the scancode from the controller. Called from the keyboard interrupt handler.
 pub fn process_input() {
-    let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(DATA_PORT);
-
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
+    match PAGING.lock().filter(scancode, add_scancode) {
+        Some(Page::Up) => vga::page_up(),
+        Some(Page::Down) => vga::page_down(),
+        None => (),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Page {
+    Up,
+    Down,
+}
+
+/// Picks Shift+PageUp and Shift+PageDown out of the scancodes, to page the screen back and forth.
+///
+/// This happens in the interrupt handler, so paging works whether anything reads the keyboard or
+/// not, like after a panic.
+struct Paging {
+    left_shift: bool,
+    right_shift: bool,
+    // The previous scancode was the prefix of an extended key, it is held back until the next one.
+    extended: bool,
+}
+
+impl Paging {
+    fn new() -> Self {
+        Paging {
+            left_shift: false,
+            right_shift: false,
+            extended: false,
+        }
+    }
+
+    /// Passes the scancodes that are not for paging on to `pass`, and returns the page key.
+    fn filter(&mut self, scancode: u8, mut pass: impl FnMut(u8)) -> Option<Page> {
+        if scancode == EXTENDED {
+            self.extended = true;
+            return None;
+        }
+        if !mem::replace(&mut self.extended, false) {
+            match scancode {
+                LEFT_SHIFT => self.left_shift = true,
+                RIGHT_SHIFT => self.right_shift = true,
+                _ if scancode == LEFT_SHIFT | RELEASED => self.left_shift = false,
+                _ if scancode == RIGHT_SHIFT | RELEASED => self.right_shift = false,
+                _ => (),
+            }
+            pass(scancode);
+            return None;
+        }
+
+        // Only the presses are taken, a release without press is ignored by the decoder.
+        match scancode {
+            PAGE_UP if self.left_shift || self.right_shift => Some(Page::Up),
+            PAGE_DOWN if self.left_shift || self.right_shift => Some(Page::Down),
+            _ => {
+                pass(EXTENDED);
+                pass(scancode);
+                None
             }
         }
     }
 }
+
+fn add_scancode(scancode: u8) {
+    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
+        if queue.push(scancode).is_err() {
+            DROPPED_SCANCODES.fetch_add(1, Ordering::Relaxed);
+        } else {
+            WAKER.wake()
+        }
+    }
+    // Without a stream nobody is interested in the input, so it is dropped.
+}
+
+/// Reports the scancodes dropped since the last report. Must not be called in interrupt context.
+fn report_dropped_scancodes() {
+    let dropped = DROPPED_SCANCODES.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        eprintln!("Scancode queue full, dropped {} scancodes", dropped)
+    }
+}
+
+/// Stream of the raw scancodes received from the keyboard.
+pub struct ScancodeStream {
+    queue: &'static ArrayQueue<u8>,
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        let queue = SCANCODE_QUEUE.get_or_init(|| ArrayQueue::new(SCANCODE_QUEUE_CAPACITY));
+        ScancodeStream { queue }
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u8>> {
+        report_dropped_scancodes();
+        if let Some(scancode) = self.queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        // Register before checking again, so a scancode pushed in between still wakes us.
+        WAKER.register(context.waker());
+        match self.queue.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Returns the key the scancode completes. Most scancodes, like key releases, do not produce one.
+fn decode(scancode: u8) -> Option<DecodedKey> {
+    interrupts::without_interrupts(|| {
+        let mut keyboard = DECODER.lock();
+        match keyboard.add_byte(scancode) {
+            Ok(Some(key_event)) => keyboard.process_keyevent(key_event),
+            _ => None,
+        }
+    })
+}
+
+/// Stream of the keys decoded from the scancodes.
+pub struct KeyStream {
+    scancodes: ScancodeStream,
+}
+
+impl KeyStream {
+    pub fn new() -> Self {
+        KeyStream {
+            scancodes: ScancodeStream::new(),
+        }
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = DecodedKey;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<DecodedKey>> {
+        let this = self.get_mut();
+        loop {
+            let scancode = match this.scancodes.poll_next_unpin(context) {
+                Poll::Ready(Some(scancode)) => scancode,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Some(key) = decode(scancode) {
+                return Poll::Ready(Some(key));
+            }
+        }
+    }
+}
+
+/// Returns the next character typed, without waiting. Keys without a character are skipped.
+pub fn try_read_char() -> Option<char> {
+    let queue = SCANCODE_QUEUE.get_or_init(|| ArrayQueue::new(SCANCODE_QUEUE_CAPACITY));
+    report_dropped_scancodes();
+    while let Some(scancode) = queue.pop() {
+        if let Some(DecodedKey::Unicode(character)) = decode(scancode) {
+            return Some(character);
+        }
+    }
+    None
+}
+
+#[test_case]
+fn scancode_stream() {
+    use crate::task::executor::{Executor, Task};
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let mut scancodes = ScancodeStream::new();
+        for &scancode in [0x1e, 0x9e].iter() {
+            add_scancode(scancode);
+        }
+        assert_eq!(scancodes.next().await, Some(0x1e));
+        assert_eq!(scancodes.next().await, Some(0x9e));
+    }));
+    executor.run_until_done();
+}
+
+#[test_case]
+fn drop_scancodes_when_full() {
+    let queue = ScancodeStream::new().queue;
+    while queue.pop().is_some() {}
+    for _ in 0..SCANCODE_QUEUE_CAPACITY + 2 {
+        add_scancode(0x1e)
+    }
+    assert_eq!(DROPPED_SCANCODES.load(Ordering::Relaxed), 2);
+    report_dropped_scancodes();
+    assert_eq!(DROPPED_SCANCODES.load(Ordering::Relaxed), 0);
+    while queue.pop().is_some() {}
+}
+
+#[test_case]
+fn key_stream() {
+    use crate::task::executor::{Executor, Task};
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let mut keys = KeyStream::new();
+        // Press and release `a`, then press `b`.
+        for &scancode in [0x1e, 0x9e, 0x30].iter() {
+            add_scancode(scancode);
+        }
+        assert_eq!(keys.next().await, Some(DecodedKey::Unicode('a')));
+        assert_eq!(keys.next().await, Some(DecodedKey::Unicode('b')));
+    }));
+    executor.run_until_done();
+}
+
+#[test_case]
+fn page_keys() {
+    use alloc::vec::Vec;
+
+    let mut paging = Paging::new();
+    let mut passed = Vec::new();
+    // PageUp, Shift+PageUp with its release, and Shift+PageDown with the right shift.
+    let scancodes = [
+        0xE0, 0x49, 0xE0, 0xC9, 0x2A, 0xE0, 0x49, 0xE0, 0xC9, 0xAA, 0x36, 0xE0, 0x51, 0xB6, 0x1E,
+    ];
+    let pages: Vec<Page> = scancodes
+        .iter()
+        .filter_map(|&scancode| paging.filter(scancode, |scancode| passed.push(scancode)))
+        .collect();
+    assert_eq!(pages, [Page::Up, Page::Down]);
+    assert_eq!(
+        passed,
+        [0xE0, 0x49, 0xE0, 0xC9, 0x2A, 0xE0, 0xC9, 0xAA, 0x36, 0xB6, 0x1E]
+    );
+}
+
+#[test_case]
+fn read_char() {
+    // Creates the queue, without it the input is dropped.
+    while try_read_char().is_some() {}
+
+    // Press and release `a`, press shift and `b`.
+    for &scancode in [0x1e, 0x9e, 0x2a, 0x30].iter() {
+        add_scancode(scancode);
+    }
+    assert_eq!(try_read_char(), Some('a'));
+    assert_eq!(try_read_char(), Some('B'));
+    assert_eq!(try_read_char(), None);
+    // Release shift again.
+    add_scancode(0xaa);
+    assert_eq!(try_read_char(), None);
+}
+
+#[test_case]
+fn share_decoder() {
+    use crate::task::executor::{Executor, Task};
+
+    while try_read_char().is_some() {}
+    // Press shift, which is read without a character.
+    add_scancode(0x2a);
+    assert_eq!(try_read_char(), None);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let mut keys = KeyStream::new();
+        // Press `b`, then release shift again.
+        add_scancode(0x30);
+        assert_eq!(keys.next().await, Some(DecodedKey::Unicode('B')));
+        add_scancode(0xaa);
+    }));
+    executor.run_until_done();
+    while try_read_char().is_some() {}
+}
@@ -1,6 +1,7 @@
 #![no_std]
 #![no_main]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 #![feature(custom_test_frameworks)]
 #![test_runner(crate::test::test_runner)]
 #![reexport_test_harness_main = "test_main"]
@@ -10,8 +11,11 @@ mod qemu;
 #[cfg(test)]
 use crate::qemu::{exit_qemu, QemuExitCode};
 
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
+use task::executor::{Executor, Task};
 
+extern crate alloc;
 extern crate rlibc;
 
 #[macro_use]
@@ -26,9 +30,25 @@ mod test;
 #[macro_use]
 mod vga;
 
+mod acpi;
+mod allocator;
+mod block;
+mod elf;
 mod gdt;
 mod interrupts;
 mod keyboard;
+mod memory;
+mod pci;
+mod power;
+mod process;
+mod rtc;
+mod shell;
+mod smp;
+mod syscall;
+mod task;
+mod time;
+mod userspace;
+mod vfs;
 
 #[cfg(not(test))]
 #[panic_handler]
@@ -54,18 +74,32 @@ fn hlt_loop() -> ! {
     }
 }
 
-fn init() {
+fn init(boot_info: &'static BootInfo) {
     gdt::init();
     interrupts::init();
+    syscall::init();
+    memory::init(boot_info);
+    allocator::init_heap().expect("Heap initialization failed");
+    acpi::init();
+    time::init();
+    interrupts::enable_hardware_interrupts();
+    smp::init();
+    task::init();
+    vfs::init();
+    block::init();
 
     #[cfg(test)]
     test_main();
 }
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    init();
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    init(boot_info);
 
     println!("FerociOS booting..");
-    panic!("Not implemented");
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(shell::run()));
+    executor.run()
 }
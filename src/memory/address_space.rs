@@ -0,0 +1,379 @@
+//! Address spaces for user programs.
+//!
+//! Every address space has its own level 4 page table. Its entries for the kernel image at the
+//! bottom of the lower half and for the higher half point to the same level 3 tables in every
+//! address space, so kernel mappings are shared. The rest of the lower half belongs to the user
+//! program and is freed with the address space.
+//!
+//! `fork` shares the user memory of an address space with its copy. Writable pages become read-only
+//! and `COPY_ON_WRITE` in both, and the first write to such a page gets its own copy of the frame
+//! from the page fault handler.
+
+use conquer_once::spin::OnceCell;
+use core::ops::Range;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+use x86_64::structures::paging::page_table::{FrameError, PageTableEntry};
+use x86_64::structures::paging::{
+    Mapper, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+use super::paging::{self, PagingError};
+use super::GlobalFrameAllocator;
+
+/// Entries of the level 4 table that belong to user programs, from `userspace::USER_START` to
+/// `userspace::USER_END`.
+const USER_ENTRIES: Range<usize> = 1..256;
+
+static KERNEL_LEVEL_4_FRAME: OnceCell<PhysFrame> = OnceCell::uninit();
+
+/// Marks pages that are shared read-only after `fork` but writable by the program.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Gives every kernel entry of the active level 4 table a level 3 table. The kernel entries then
+/// never change and can be copied to new address spaces.
+pub fn init() {
+    KERNEL_LEVEL_4_FRAME.init_once(|| Cr3::read().0);
+    paging::with_mapper(|mapper| {
+        for (i, entry) in mapper.level_4_table().iter_mut().enumerate() {
+            if USER_ENTRIES.contains(&i) || !entry.is_unused() {
+                continue;
+            }
+            let frame =
+                super::allocate_zeroed_frame().expect("Out of memory for kernel page tables");
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+        }
+    })
+}
+
+/// Returns the level 4 table set up by the bootloader, which kernel threads use.
+pub fn kernel_level_4_frame() -> PhysFrame {
+    *KERNEL_LEVEL_4_FRAME
+        .try_get()
+        .expect("Address spaces are not initialized")
+}
+
+/// Page table of a user program, with the kernel mapped like in every other address space.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space without any user memory.
+    pub fn new() -> Result<Self, PagingError> {
+        let level_4_frame = super::allocate_zeroed_frame().ok_or(PagingError::OutOfMemory)?;
+        let level_4_table = unsafe { table(level_4_frame) };
+        paging::with_mapper(|mapper| {
+            for (i, entry) in mapper.level_4_table().iter().enumerate() {
+                if !USER_ENTRIES.contains(&i) {
+                    level_4_table[i] = entry.clone()
+                }
+            }
+        });
+        Ok(AddressSpace { level_4_frame })
+    }
+
+    #[allow(dead_code)]
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Switches to this address space.
+    ///
+    /// # Safety
+    ///
+    /// The address space must stay alive while it is active.
+    pub unsafe fn activate(&self) {
+        Cr3::write(self.level_4_frame, Cr3Flags::empty())
+    }
+
+    /// Maps the page to a zeroed frame and returns the frame.
+    pub fn map_page(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, PagingError> {
+        let frame = super::allocate_zeroed_frame().ok_or(PagingError::OutOfMemory)?;
+        let result: Result<(), PagingError> =
+            paging::with_mapper_for(self.level_4_frame, |mapper| {
+                let flush =
+                    unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator)? };
+                // Only the active address space has its mappings cached.
+                if self.is_active() {
+                    flush.flush()
+                } else {
+                    flush.ignore()
+                }
+                Ok(())
+            });
+        result.inspect_err(|_| super::deallocate_frame(frame))?;
+        Ok(frame)
+    }
+
+    /// Creates a copy of the address space that shares the user memory, see the module
+    /// documentation.
+    pub fn fork(&mut self) -> Result<AddressSpace, PagingError> {
+        let child = AddressSpace::new()?;
+        let result = paging::with_mapper_for(self.level_4_frame, |mapper| {
+            let child_table = unsafe { table(child.level_4_frame) };
+            let entries = mapper
+                .level_4_table()
+                .iter_mut()
+                .zip(child_table.iter_mut());
+            for (parent, child) in entries.take(USER_ENTRIES.end).skip(USER_ENTRIES.start) {
+                copy_entry(parent, child, 4)?
+            }
+            Ok(())
+        });
+        // Pages that were writable are read-only now.
+        if self.is_active() {
+            tlb::flush_all()
+        }
+        // On errors, dropping the partial copy gives up its share of the frames.
+        result.map(|()| child)
+    }
+
+    /// Returns the flags of the page containing the address.
+    #[allow(dead_code)]
+    pub fn page_flags(&self, address: VirtAddr) -> Option<PageTableFlags> {
+        paging::with_mapper_for(self.level_4_frame, |mapper| {
+            match mapper.translate(address) {
+                TranslateResult::Mapped { flags, .. } => Some(flags),
+                TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => None,
+            }
+        })
+    }
+
+    /// Copies the data to the mapped memory at the address, whether the address space is active or
+    /// not. Page flags are ignored.
+    pub fn write(&mut self, address: VirtAddr, data: &[u8]) -> Result<(), PagingError> {
+        let mut written = 0;
+        while written < data.len() {
+            let address = address + written;
+            let physical = paging::with_mapper_for(self.level_4_frame, |mapper| {
+                mapper.translate_addr(address)
+            })
+            .ok_or(PagingError::NotMapped)?;
+            let length = (4096 - usize::from(address.page_offset())).min(data.len() - written);
+            unsafe {
+                let destination = paging::phys_to_virt(physical).as_mut_ptr::<u8>();
+                core::ptr::copy_nonoverlapping(data[written..].as_ptr(), destination, length)
+            }
+            written += length;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "Dropping the active address space");
+
+        // Walk the user part of the page table, freeing the mapped frames and the tables.
+        let level_4_table = unsafe { table(self.level_4_frame) };
+        for entry in level_4_table
+            .iter_mut()
+            .take(USER_ENTRIES.end)
+            .skip(USER_ENTRIES.start)
+        {
+            if let Ok(frame) = entry.frame() {
+                free_table(frame, 3)
+            }
+            entry.set_unused()
+        }
+        super::deallocate_frame(self.level_4_frame)
+    }
+}
+
+/// Resolves a write to a `COPY_ON_WRITE` page of the active address space by making it writable,
+/// after copying the frame if other address spaces still share it. Returns `false` if the page is
+/// not copy-on-write or there is no memory for the copy.
+///
+/// Called from the page fault handler, so it must not use the heap.
+pub fn copy_on_write(address: VirtAddr) -> bool {
+    let page: Page = Page::containing_address(address);
+    paging::with_mapper(|mapper| {
+        let (frame, flags) = match mapper.translate(address) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } if flags.contains(COPY_ON_WRITE) => (frame, flags),
+            _ => return false,
+        };
+        let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+        if !super::is_frame_shared(frame) {
+            // The other address spaces dropped their share already.
+            let flush = unsafe { mapper.update_flags(page, flags) };
+            flush.expect("Page was just translated").flush();
+            return true;
+        }
+        let copy = match super::allocate_frame() {
+            Some(copy) => copy,
+            None => return false,
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                paging::phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                paging::phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                4096,
+            )
+        }
+        mapper
+            .unmap(page)
+            .expect("Page was just translated")
+            .1
+            .ignore();
+        unsafe { mapper.map_to(page, copy, flags, &mut GlobalFrameAllocator) }
+            .expect("Page was just unmapped")
+            .flush();
+        super::deallocate_frame(frame);
+        true
+    })
+}
+
+/// Copies the entry of a table at the level into the table of a forked address space. Tables are
+/// copied, mapped frames are shared.
+fn copy_entry(
+    parent: &mut PageTableEntry,
+    child: &mut PageTableEntry,
+    level: u8,
+) -> Result<(), PagingError> {
+    let frame = match parent.frame() {
+        Ok(frame) => frame,
+        Err(FrameError::FrameNotPresent) => return Ok(()),
+        Err(FrameError::HugeFrame) => return Err(PagingError::HugePage),
+    };
+    if level == 1 {
+        let mut flags = parent.flags();
+        if flags.contains(PageTableFlags::WRITABLE) {
+            flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+            parent.set_flags(flags)
+        }
+        super::share_frame(frame);
+        child.set_frame(frame, flags);
+        return Ok(());
+    }
+
+    let child_frame = super::allocate_zeroed_frame().ok_or(PagingError::OutOfMemory)?;
+    child.set_frame(child_frame, parent.flags());
+    let (parent_table, child_table) = unsafe { (table(frame), table(child_frame)) };
+    for (parent, child) in parent_table.iter_mut().zip(child_table.iter_mut()) {
+        copy_entry(parent, child, level - 1)?
+    }
+    Ok(())
+}
+
+/// Frees the frames mapped by the table at the level, the tables below it and the table itself.
+fn free_table(frame: PhysFrame, level: u8) {
+    let table = unsafe { table(frame) };
+    for entry in table.iter() {
+        let frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(_) => continue,
+        };
+        if level == 1 {
+            super::deallocate_frame(frame)
+        } else {
+            free_table(frame, level - 1)
+        }
+    }
+    super::deallocate_frame(frame)
+}
+
+/// # Safety
+///
+/// The frame must hold a page table that is not accessed in any other way at the same time.
+unsafe fn table(frame: PhysFrame<Size4KiB>) -> &'static mut PageTable {
+    &mut *paging::phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()
+}
+
+#[test_case]
+fn kernel_is_shared() {
+    let address_space = AddressSpace::new().unwrap();
+    let active = Cr3::read().0;
+    let kernel_code = VirtAddr::from_ptr(AddressSpace::new as *const ());
+    let stack = VirtAddr::from_ptr(&address_space);
+    for &address in [kernel_code, stack].iter() {
+        assert_eq!(
+            paging::with_mapper_for(active, |mapper| mapper.translate_addr(address)),
+            paging::with_mapper_for(address_space.level_4_frame(), |mapper| {
+                mapper.translate_addr(address)
+            })
+        );
+    }
+}
+
+#[test_case]
+fn user_memory_is_separate() {
+    let mut address_space = AddressSpace::new().unwrap();
+    let page = Page::containing_address(VirtAddr::new(crate::userspace::USER_START));
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    address_space.map_page(page, flags).unwrap();
+
+    assert!(address_space.page_flags(page.start_address()).is_some());
+    assert_eq!(paging::page_flags(page.start_address()), None);
+    assert_eq!(
+        address_space.map_page(page, flags),
+        Err(PagingError::AlreadyMapped)
+    );
+
+    // Writes fail once they reach an unmapped page.
+    let data = [0xAB; 16];
+    address_space
+        .write(page.start_address() + 4088u64, &data[..8])
+        .unwrap();
+    assert_eq!(
+        address_space.write(page.start_address() + 4088u64, &data),
+        Err(PagingError::NotMapped)
+    );
+
+    // Dropping frees the frame and the three page tables.
+    let free = super::with_frame_allocator(|allocator| allocator.free_frames());
+    drop(address_space);
+    let freed = super::with_frame_allocator(|allocator| allocator.free_frames()) - free;
+    assert_eq!(freed, 5);
+}
+
+#[test_case]
+fn fork_copies_on_write() {
+    let mut parent = AddressSpace::new().unwrap();
+    let page = Page::containing_address(VirtAddr::new(crate::userspace::USER_START));
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let frame = parent.map_page(page, flags).unwrap();
+    parent.write(page.start_address(), b"parent").unwrap();
+
+    let child = parent.fork().unwrap();
+    for address_space in [&parent, &child].iter() {
+        let flags = address_space.page_flags(page.start_address()).unwrap();
+        assert!(flags.contains(COPY_ON_WRITE));
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+    }
+    assert!(super::is_frame_shared(frame));
+
+    // Writing in the child gives it its own frame.
+    let previous = Cr3::read();
+    unsafe {
+        child.activate();
+        assert!(copy_on_write(page.start_address()));
+        page.start_address().as_mut_ptr::<u8>().write_volatile(b'c');
+        // The page is writable now, there is nothing left to copy.
+        assert!(!copy_on_write(page.start_address()));
+        Cr3::write(previous.0, previous.1)
+    }
+    assert!(!super::is_frame_shared(frame));
+    let memory = paging::phys_to_virt(frame.start_address()).as_ptr::<[u8; 6]>();
+    assert_eq!(unsafe { &*memory }, b"parent");
+    drop(child);
+    drop(parent);
+}
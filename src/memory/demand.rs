@@ -0,0 +1,246 @@
+//! Regions of virtual memory that are only backed by frames once they are touched.
+//!
+//! The regions live in a fixed-size table instead of on the heap, since the heap itself is backed
+//! on demand and a page fault can happen while the heap allocator is locked.
+
+use lazy_static::lazy_static;
+use spinning::Mutex;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use super::paging::{self, PagingError};
+
+// Every thread stack takes up two regions.
+const MAX_REGIONS: usize = 256;
+
+lazy_static! {
+    static ref REGIONS: Mutex<[Option<Region>; MAX_REGIONS]> = Mutex::new([None; MAX_REGIONS]);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Every page gets a zeroed frame mapped with these flags when it is first accessed.
+    Lazy(PageTableFlags),
+    /// Accessing the region is always an error, like running into a stack guard page.
+    Guard,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    pub fn lazy(name: &'static str, start: VirtAddr, size: u64, flags: PageTableFlags) -> Self {
+        Region {
+            name,
+            start,
+            end: start + size,
+            kind: RegionKind::Lazy(flags | PageTableFlags::PRESENT),
+        }
+    }
+
+    pub fn guard(name: &'static str, start: VirtAddr, size: u64) -> Self {
+        Region {
+            name,
+            start,
+            end: start + size,
+            kind: RegionKind::Guard,
+        }
+    }
+
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        self.start <= address && address < self.end
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let start = Page::containing_address(self.start);
+        let end = Page::containing_address(self.end - 1u64);
+        Page::range_inclusive(start, end)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    TableFull,
+    Overlap,
+}
+
+/// Outcome of a page fault inside the registered regions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// A frame was mapped and the faulting instruction can be retried.
+    Resolved,
+    /// The access hit the guard region with this name.
+    Guard(&'static str),
+    /// The access is outside of every region or not allowed by the region flags.
+    Unhandled,
+}
+
+pub fn register(region: Region) -> Result<(), RegionError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        if regions.iter().flatten().any(|r| r.overlaps(&region)) {
+            return Err(RegionError::Overlap);
+        }
+        let slot = regions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegionError::TableFull)?;
+        *slot = Some(region);
+        Ok(())
+    })
+}
+
+/// Removes the region starting at `start`. Frames backing a lazy region are unmapped and freed.
+#[allow(dead_code)]
+pub fn unregister(start: VirtAddr) -> Option<Region> {
+    let region = x86_64::instructions::interrupts::without_interrupts(|| {
+        REGIONS
+            .lock()
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(region) if region.start == start))?
+            .take()
+    })?;
+
+    if let RegionKind::Lazy(_) = region.kind {
+        for page in region.pages() {
+            if let Ok(frame) = paging::unmap_page(page) {
+                super::deallocate_frame(frame)
+            }
+        }
+    }
+    Some(region)
+}
+
+fn find(address: VirtAddr) -> Option<Region> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        REGIONS
+            .lock()
+            .iter()
+            .flatten()
+            .find(|region| region.contains(address))
+            .copied()
+    })
+}
+
+/// Tries to resolve a page fault by backing the faulting page of a lazy region.
+pub fn handle_page_fault(address: VirtAddr, error_code: PageFaultErrorCode) -> Fault {
+    let region = match find(address) {
+        Some(region) => region,
+        None => return Fault::Unhandled,
+    };
+
+    let flags = match region.kind {
+        RegionKind::Guard => return Fault::Guard(region.name),
+        RegionKind::Lazy(flags) => flags,
+    };
+
+    // Faults on present pages and accesses the region flags do not allow are real errors.
+    let allowed = !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && (!error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            || flags.contains(PageTableFlags::WRITABLE))
+        && (!error_code.contains(PageFaultErrorCode::USER_MODE)
+            || flags.contains(PageTableFlags::USER_ACCESSIBLE))
+        && (!error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+            || !flags.contains(PageTableFlags::NO_EXECUTE));
+    if !allowed {
+        return Fault::Unhandled;
+    }
+
+    let frame = match super::allocate_zeroed_frame() {
+        Some(frame) => frame,
+        None => return Fault::Unhandled,
+    };
+
+    match paging::map_page_to(Page::containing_address(address), frame, flags) {
+        Ok(()) => Fault::Resolved,
+        // Someone else backed the page in the meantime.
+        Err(PagingError::AlreadyMapped) => {
+            super::deallocate_frame(frame);
+            Fault::Resolved
+        }
+        Err(_) => {
+            super::deallocate_frame(frame);
+            Fault::Unhandled
+        }
+    }
+}
+
+#[cfg(test)]
+const TEST_REGION_START: u64 = 0xFFFF_FE00_0000_0000;
+
+#[test_case]
+fn lazy_region_is_backed_on_access() {
+    let start = VirtAddr::new(TEST_REGION_START);
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    register(Region::lazy("test", start, 4 * 4096, flags)).unwrap();
+
+    let address = start + 4096u64 + 8u64;
+    assert_eq!(paging::translate_addr(address), None);
+    unsafe {
+        // Fresh pages are zeroed.
+        assert_eq!(address.as_ptr::<u64>().read_volatile(), 0);
+        address.as_mut_ptr::<u64>().write_volatile(42);
+        assert_eq!(address.as_ptr::<u64>().read_volatile(), 42);
+    }
+    assert!(paging::translate_addr(address).is_some());
+    // Only the touched page is backed.
+    assert_eq!(paging::translate_addr(start), None);
+
+    unregister(start).unwrap();
+    assert_eq!(paging::translate_addr(address), None);
+}
+
+#[test_case]
+fn overlapping_regions() {
+    let start = VirtAddr::new(TEST_REGION_START);
+    register(Region::guard("test", start, 2 * 4096)).unwrap();
+    assert_eq!(
+        register(Region::guard("overlap", start + 4096u64, 4096)),
+        Err(RegionError::Overlap)
+    );
+    unregister(start).unwrap();
+}
+
+#[test_case]
+fn guard_region_fault() {
+    let start = VirtAddr::new(TEST_REGION_START);
+    register(Region::guard("test guard", start, 4096)).unwrap();
+    assert_eq!(
+        handle_page_fault(start + 16u64, PageFaultErrorCode::CAUSED_BY_WRITE),
+        Fault::Guard("test guard")
+    );
+    unregister(start).unwrap();
+}
+
+#[test_case]
+fn write_to_read_only_lazy_region() {
+    let start = VirtAddr::new(TEST_REGION_START);
+    register(Region::lazy("test", start, 4096, PageTableFlags::empty())).unwrap();
+    assert_eq!(
+        handle_page_fault(start, PageFaultErrorCode::CAUSED_BY_WRITE),
+        Fault::Unhandled
+    );
+    assert_eq!(paging::translate_addr(start), None);
+    unregister(start).unwrap();
+}
+
+#[test_case]
+fn fault_outside_of_regions() {
+    assert_eq!(
+        handle_page_fault(
+            VirtAddr::new(TEST_REGION_START),
+            PageFaultErrorCode::empty()
+        ),
+        Fault::Unhandled
+    );
+}
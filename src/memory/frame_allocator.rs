@@ -0,0 +1,186 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ptr::addr_of_mut;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Physical memory above this address is ignored by the allocator, even if it is usable.
+const MAX_PHYSICAL_ADDRESS: u64 = 4 * 1024 * 1024 * 1024;
+
+const MAX_FRAMES: usize = (MAX_PHYSICAL_ADDRESS / FRAME_SIZE) as usize;
+const BITMAP_WORDS: usize = MAX_FRAMES / 64;
+
+/// Memory below 1 MiB is reachable from real mode. It is only handed out once everything above is
+/// used up, or when explicitly asked for.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+const LOW_MEMORY_WORDS: usize = (LOW_MEMORY_END / FRAME_SIZE / 64) as usize;
+
+/// Frame allocator that keeps track of the usable frames of the bootloader memory map in a bitmap.
+///
+/// A set bit means the frame is free. Frames of any other region type are never handed out.
+///
+/// Frames can be shared, for example by copy-on-write pages. Every owner deallocates the frame, it
+/// is only freed by the last one.
+pub struct BitmapFrameAllocator {
+    memory_map: &'static MemoryMap,
+    bitmap: &'static mut [u64],
+    // Amount of owners besides the first one, for every frame.
+    shares: &'static mut [u16],
+    // Index of the bitmap word where the next search starts.
+    next_word: usize,
+    free_frames: usize,
+    usable_frames: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Creates the allocator from the memory map passed by the bootloader.
+    ///
+    /// # Safety
+    ///
+    /// The memory map must be valid: every frame marked as `Usable` must really be unused. This
+    /// function must only be called once, since all instances share the same bitmap storage.
+    pub unsafe fn new(memory_map: &'static MemoryMap) -> Self {
+        static mut BITMAP: [u64; BITMAP_WORDS] = [0; BITMAP_WORDS];
+        static mut SHARES: [u16; MAX_FRAMES] = [0; MAX_FRAMES];
+
+        let mut allocator = BitmapFrameAllocator {
+            memory_map,
+            bitmap: &mut *addr_of_mut!(BITMAP),
+            shares: &mut *addr_of_mut!(SHARES),
+            next_word: LOW_MEMORY_WORDS,
+            free_frames: 0,
+            usable_frames: 0,
+        };
+
+        let usable_regions = memory_map
+            .iter()
+            .filter(|region| region.region_type == MemoryRegionType::Usable);
+        for region in usable_regions {
+            let end = region.range.end_frame_number.min(MAX_FRAMES as u64);
+            for number in region.range.start_frame_number..end {
+                allocator.mark_free(number as usize)
+            }
+        }
+        allocator.usable_frames = allocator.free_frames;
+        allocator
+    }
+
+    /// Returns the amount of frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Returns the amount of frames the allocator manages, free or not.
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// Allocates a frame below 1 MiB.
+    pub fn allocate_low_frame(&mut self) -> Option<PhysFrame> {
+        let word = (0..LOW_MEMORY_WORDS).find(|&index| self.bitmap[index] != 0)?;
+        let number = word * 64 + self.bitmap[word].trailing_zeros() as usize;
+        self.mark_used(number);
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            number as u64 * FRAME_SIZE,
+        )))
+    }
+
+    /// Returns whether the frame belongs to a usable region of the memory map.
+    pub fn is_usable(&self, frame: PhysFrame) -> bool {
+        let address = frame.start_address().as_u64();
+        address < MAX_PHYSICAL_ADDRESS
+            && self.memory_map.iter().any(|region| {
+                region.region_type == MemoryRegionType::Usable
+                    && region.range.start_addr() <= address
+                    && address < region.range.end_addr()
+            })
+    }
+
+    /// Adds an owner to the allocated frame.
+    pub fn share(&mut self, frame: PhysFrame) {
+        let number = frame_number(frame);
+        assert!(!self.is_free(number), "Sharing free frame: {:?}", frame);
+        self.shares[number] = self.shares[number]
+            .checked_add(1)
+            .expect("Frame is shared too often")
+    }
+
+    /// Returns whether the frame has more than one owner.
+    pub fn is_shared(&self, frame: PhysFrame) -> bool {
+        self.shares[frame_number(frame)] > 0
+    }
+
+    fn is_free(&self, number: usize) -> bool {
+        self.bitmap[number / 64] & (1 << (number % 64)) != 0
+    }
+
+    fn mark_free(&mut self, number: usize) {
+        self.bitmap[number / 64] |= 1 << (number % 64);
+        self.free_frames += 1
+    }
+
+    fn mark_used(&mut self, number: usize) {
+        self.bitmap[number / 64] &= !(1 << (number % 64));
+        self.free_frames -= 1
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free_frames == 0 {
+            return None;
+        }
+
+        // Start at the last word we allocated from and wrap around once, memory below 1 MiB only
+        // comes after everything above it.
+        let word = (self.next_word..BITMAP_WORDS)
+            .chain(LOW_MEMORY_WORDS..self.next_word)
+            .chain(0..LOW_MEMORY_WORDS)
+            .find(|&index| self.bitmap[index] != 0)?;
+        let number = word * 64 + self.bitmap[word].trailing_zeros() as usize;
+        self.mark_used(number);
+        self.next_word = word.max(LOW_MEMORY_WORDS);
+
+        let address = PhysAddr::new(number as u64 * FRAME_SIZE);
+        Some(PhysFrame::containing_address(address))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        assert!(
+            self.is_usable(frame),
+            "Deallocating frame outside of usable memory: {:?}",
+            frame
+        );
+
+        let number = frame_number(frame);
+        assert!(!self.is_free(number), "Double free of frame: {:?}", frame);
+        if self.shares[number] > 0 {
+            self.shares[number] -= 1
+        } else {
+            self.mark_free(number)
+        }
+    }
+}
+
+fn frame_number(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+#[test_case]
+fn low_memory_comes_last() {
+    crate::memory::with_frame_allocator(|allocator| {
+        let next_word = allocator.next_word;
+        let frame = allocator.allocate_frame().unwrap();
+        unsafe { allocator.deallocate_frame(frame) }
+
+        // Continue the search after the freed frame, from the last word, so it wraps around.
+        allocator.next_word = BITMAP_WORDS - 1;
+        let frame = allocator.allocate_frame().unwrap();
+        assert!(frame.start_address().as_u64() >= LOW_MEMORY_END);
+        unsafe { allocator.deallocate_frame(frame) }
+        allocator.next_word = next_word;
+    })
+}
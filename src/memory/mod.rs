@@ -0,0 +1,200 @@
+//! Physical and virtual memory management.
+//!
+//! Virtual memory layout:
+//!
+//! | Start                   | Contents                                          |
+//! |-------------------------|---------------------------------------------------|
+//! | `0x0000_0000_0000_0000` | Kernel image, mapped by the bootloader            |
+//! | `0x0000_0080_0000_0000` | User programs, see `userspace`                    |
+//! | `0x0000_4000_0000_0000` | Memory mapped by user programs with `mmap`        |
+//! | `0x0000_7FFF_FFFE_F000` | User stack, see `elf`                             |
+//! | `0xFFFF_8000_0000_0000` | Complete physical memory                          |
+//! | `0xFFFF_C000_0000_0000` | Kernel heap, backed on demand, see `allocator`    |
+//! | `0xFFFF_D000_0000_0000` | Thread stacks with guard pages, see `task`        |
+//! | `0xFFFF_E000_0000_0000` | Device registers, see `paging::map_mmio`          |
+//! | `0xFFFF_FF80_0000_0000` | Guard page, followed by the kernel stack          |
+//! | `0xFFFF_FFFF_8000_0000` | Boot info                                         |
+//!
+//! The bootloader addresses are configured in `Cargo.toml`.
+
+pub mod address_space;
+pub mod demand;
+mod frame_allocator;
+pub mod paging;
+
+use bootloader::BootInfo;
+use lazy_static::lazy_static;
+use spinning::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+pub use frame_allocator::BitmapFrameAllocator;
+
+/// The bootloader leaves the lowest page of the kernel stack unmapped as a guard page.
+const KERNEL_STACK_GUARD_PAGE: u64 = 0xFFFF_FF80_0000_0000;
+
+lazy_static! {
+    static ref FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+}
+
+pub fn init(boot_info: &'static BootInfo) {
+    let allocator = unsafe { BitmapFrameAllocator::new(&boot_info.memory_map) };
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+
+    unsafe { paging::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    address_space::init();
+
+    let guard = demand::Region::guard("kernel stack", VirtAddr::new(KERNEL_STACK_GUARD_PAGE), 4096);
+    demand::register(guard).expect("Registering kernel stack guard page failed")
+}
+
+/// Runs `f` with exclusive access to the global frame allocator.
+pub fn with_frame_allocator<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut BitmapFrameAllocator) -> R,
+{
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        f(allocator
+            .as_mut()
+            .expect("Frame allocator is not initialized"))
+    })
+}
+
+pub fn allocate_frame() -> Option<PhysFrame> {
+    GlobalFrameAllocator.allocate_frame()
+}
+
+/// Allocates a frame filled with zeros.
+pub fn allocate_zeroed_frame() -> Option<PhysFrame> {
+    let frame = allocate_frame()?;
+    unsafe {
+        let frame_ptr = paging::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        core::ptr::write_bytes(frame_ptr, 0, 4096);
+    }
+    Some(frame)
+}
+
+/// Allocates a frame below 1 MiB, for code and data that is used in real mode.
+pub fn allocate_low_frame() -> Option<PhysFrame> {
+    with_frame_allocator(|allocator| allocator.allocate_low_frame())
+}
+
+/// Gives up ownership of the frame, it is freed once it has no owner left.
+pub fn deallocate_frame(frame: PhysFrame) {
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) }
+}
+
+/// Adds an owner to the frame, who has to deallocate it as well.
+pub fn share_frame(frame: PhysFrame) {
+    with_frame_allocator(|allocator| allocator.share(frame))
+}
+
+pub fn is_frame_shared(frame: PhysFrame) -> bool {
+    with_frame_allocator(|allocator| allocator.is_shared(frame))
+}
+
+/// Handle to the global frame allocator that can be passed wherever a `FrameAllocator` is expected.
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        with_frame_allocator(|allocator| allocator.allocate_frame())
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        with_frame_allocator(|allocator| allocator.deallocate_frame(frame))
+    }
+}
+
+#[cfg(test)]
+const TEST_FRAMES: usize = 64;
+
+#[cfg(test)]
+fn allocate_test_frames() -> [PhysFrame; TEST_FRAMES] {
+    let mut frames = [PhysFrame::containing_address(x86_64::PhysAddr::zero()); TEST_FRAMES];
+    for frame in frames.iter_mut() {
+        *frame = allocate_frame().expect("Out of physical memory");
+    }
+    frames
+}
+
+#[cfg(test)]
+fn deallocate_test_frames(frames: &[PhysFrame]) {
+    for &frame in frames {
+        deallocate_frame(frame)
+    }
+}
+
+#[test_case]
+fn frames_are_unique() {
+    let frames = allocate_test_frames();
+    for (i, a) in frames.iter().enumerate() {
+        for b in &frames[i + 1..] {
+            assert_ne!(a, b);
+        }
+    }
+    deallocate_test_frames(&frames);
+}
+
+#[test_case]
+fn frames_are_aligned() {
+    let frames = allocate_test_frames();
+    for frame in frames.iter() {
+        assert!(frame.start_address().is_aligned(4096u64));
+    }
+    deallocate_test_frames(&frames);
+}
+
+#[test_case]
+fn frames_are_usable() {
+    let frames = allocate_test_frames();
+    for &frame in frames.iter() {
+        assert!(frame.start_address().as_u64() != 0);
+        assert!(with_frame_allocator(|allocator| allocator.is_usable(frame)));
+    }
+    deallocate_test_frames(&frames);
+}
+
+#[test_case]
+fn low_frames() {
+    let frame = allocate_low_frame().expect("No free frame below 1 MiB");
+    assert!(frame.start_address().as_u64() < 0x10_0000);
+    assert!(with_frame_allocator(|allocator| allocator.is_usable(frame)));
+    deallocate_frame(frame);
+}
+
+#[test_case]
+fn deallocated_frames_are_reused() {
+    let free = with_frame_allocator(|allocator| allocator.free_frames());
+
+    let frames = allocate_test_frames();
+    assert_eq!(
+        with_frame_allocator(|allocator| allocator.free_frames()),
+        free - TEST_FRAMES
+    );
+
+    deallocate_test_frames(&frames);
+    assert_eq!(
+        with_frame_allocator(|allocator| allocator.free_frames()),
+        free
+    );
+}
+
+#[test_case]
+fn shared_frames() {
+    let free = || with_frame_allocator(|allocator| allocator.free_frames());
+    let frame = allocate_frame().unwrap();
+    let free_before = free();
+    share_frame(frame);
+    assert!(is_frame_shared(frame));
+
+    // Only the last owner frees the frame.
+    deallocate_frame(frame);
+    assert!(!is_frame_shared(frame));
+    assert_eq!(free(), free_before);
+    deallocate_frame(frame);
+    assert_eq!(free(), free_before + 1);
+}
@@ -0,0 +1,296 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spinning::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{
+    FlagUpdateError, MapToError, TranslateResult, UnmapError,
+};
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::GlobalFrameAllocator;
+
+lazy_static! {
+    // The kernel half of every address space shares its page tables, so all changes are serialized.
+    static ref PAGE_TABLE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Device registers are mapped from here on upwards. Mappings are never removed.
+const MMIO_START: u64 = 0xFFFF_E000_0000_0000;
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    OutOfMemory,
+    AlreadyMapped,
+    NotMapped,
+    HugePage,
+}
+
+impl From<MapToError<Size4KiB>> for PagingError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => PagingError::OutOfMemory,
+            MapToError::ParentEntryHugePage => PagingError::HugePage,
+            MapToError::PageAlreadyMapped(_) => PagingError::AlreadyMapped,
+        }
+    }
+}
+
+impl From<UnmapError> for PagingError {
+    fn from(error: UnmapError) -> Self {
+        match error {
+            UnmapError::ParentEntryHugePage => PagingError::HugePage,
+            UnmapError::PageNotMapped | UnmapError::InvalidFrameAddress(_) => {
+                PagingError::NotMapped
+            }
+        }
+    }
+}
+
+impl From<FlagUpdateError> for PagingError {
+    fn from(error: FlagUpdateError) -> Self {
+        match error {
+            FlagUpdateError::ParentEntryHugePage => PagingError::HugePage,
+            FlagUpdateError::PageNotMapped => PagingError::NotMapped,
+        }
+    }
+}
+
+/// Sets up access to the page tables.
+///
+/// # Safety
+///
+/// The complete physical memory must be mapped at `physical_memory_offset`, and this function must
+/// only be called once.
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::SeqCst)
+}
+
+/// Returns the virtual address through which the kernel can access a physical address.
+pub fn phys_to_virt(address: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst) + address.as_u64())
+}
+
+/// Runs `f` with exclusive access to the mapper of the active page table.
+pub fn with_mapper<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut OffsetPageTable<'static>) -> R,
+{
+    with_mapper_for(Cr3::read().0, f)
+}
+
+/// Runs `f` with exclusive access to the mapper of the page table with the level 4 table in the
+/// frame, which does not have to be active.
+pub fn with_mapper_for<F, R>(level_4_frame: PhysFrame, f: F) -> R
+where
+    F: FnOnce(&mut OffsetPageTable<'static>) -> R,
+{
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _lock = PAGE_TABLE_LOCK.lock();
+        f(&mut unsafe { mapper_for(level_4_frame) })
+    })
+}
+
+/// Like `with_mapper`, but returns `None` instead of waiting if the page tables are locked. For
+/// exception handlers, which may run while the interrupted code holds the lock.
+pub fn try_with_mapper<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut OffsetPageTable<'static>) -> R,
+{
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _lock = PAGE_TABLE_LOCK.try_lock()?;
+        Some(f(&mut unsafe { mapper_for(Cr3::read().0) }))
+    })
+}
+
+/// Returns a mapper of the page table with the level 4 table in the frame.
+///
+/// # Safety
+///
+/// The page table lock must be held while the mapper is used.
+unsafe fn mapper_for(level_4_frame: PhysFrame) -> OffsetPageTable<'static> {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst);
+    assert!(offset != 0, "Paging is not initialized");
+    let level_4_table = phys_to_virt(level_4_frame.start_address()).as_mut_ptr::<PageTable>();
+    OffsetPageTable::new(&mut *level_4_table, VirtAddr::new(offset))
+}
+
+/// Maps the page to a newly allocated frame and returns the frame.
+#[allow(dead_code)]
+pub fn map_page(page: Page, flags: PageTableFlags) -> Result<PhysFrame, PagingError> {
+    let frame = super::allocate_frame().ok_or(PagingError::OutOfMemory)?;
+    map_page_to(page, frame, flags).inspect_err(|_| super::deallocate_frame(frame))?;
+    Ok(frame)
+}
+
+/// Maps the page to the given frame.
+pub fn map_page_to(page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), PagingError> {
+    with_mapper(|mapper| {
+        let flush = unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator)? };
+        flush.flush();
+        Ok(())
+    })
+}
+
+/// Unmaps the page and returns the frame it was mapped to. The frame is not deallocated.
+#[allow(dead_code)]
+pub fn unmap_page(page: Page) -> Result<PhysFrame, PagingError> {
+    with_mapper(|mapper| {
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    })
+}
+
+/// Replaces the flags of a mapped page.
+#[allow(dead_code)]
+pub fn protect(page: Page, flags: PageTableFlags) -> Result<(), PagingError> {
+    with_mapper(|mapper| {
+        let flush = unsafe { mapper.update_flags(page, flags)? };
+        flush.flush();
+        Ok(())
+    })
+}
+
+/// Walks the page table and returns the physical address the virtual address is mapped to.
+#[allow(dead_code)]
+pub fn translate_addr(address: VirtAddr) -> Option<PhysAddr> {
+    with_mapper(|mapper| mapper.translate_addr(address))
+}
+
+/// Walks the page table and returns the flags of the page containing the virtual address.
+#[allow(dead_code)]
+pub fn page_flags(address: VirtAddr) -> Option<PageTableFlags> {
+    with_mapper(|mapper| match mapper.translate(address) {
+        TranslateResult::Mapped { flags, .. } => Some(flags),
+        TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => None,
+    })
+}
+
+/// Maps `size` bytes of device memory at the physical address with caching disabled, and returns
+/// the virtual address of `address`.
+pub fn map_mmio(address: PhysAddr, size: u64) -> Result<VirtAddr, PagingError> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(address);
+    let last_frame = PhysFrame::<Size4KiB>::containing_address(address + (size.max(1) - 1));
+    let frames = PhysFrame::range_inclusive(first_frame, last_frame);
+
+    let start = NEXT_MMIO.fetch_add(frames.count() as u64 * 4096, Ordering::SeqCst);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::NO_EXECUTE;
+    for (i, frame) in frames.enumerate() {
+        let page = Page::containing_address(VirtAddr::new(start + i as u64 * 4096));
+        map_page_to(page, frame, flags)?;
+    }
+    Ok(VirtAddr::new(start) + (address - first_frame.start_address()))
+}
+
+#[cfg(test)]
+fn test_page() -> Page {
+    Page::containing_address(VirtAddr::new(0xFFFF_FE00_0000_0000))
+}
+
+#[test_case]
+fn map_and_translate() {
+    let page = test_page();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let frame = map_page(page, flags).unwrap();
+
+    let offset = 0x123u64;
+    let address = page.start_address() + offset;
+    assert_eq!(
+        translate_addr(address),
+        Some(frame.start_address() + offset)
+    );
+
+    // The page is writable and shares its frame with the physical memory mapping.
+    unsafe {
+        address.as_mut_ptr::<u64>().write_volatile(0xf00d);
+        let alias = phys_to_virt(frame.start_address() + offset);
+        assert_eq!(alias.as_ptr::<u64>().read_volatile(), 0xf00d);
+    }
+
+    assert_eq!(unmap_page(page), Ok(frame));
+    super::deallocate_frame(frame);
+}
+
+#[test_case]
+fn map_twice() {
+    let page = test_page();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let frame = map_page(page, flags).unwrap();
+    assert_eq!(
+        map_page_to(page, frame, flags),
+        Err(PagingError::AlreadyMapped)
+    );
+
+    unmap_page(page).unwrap();
+    super::deallocate_frame(frame);
+}
+
+#[test_case]
+fn unmap() {
+    let page = test_page();
+    let frame = map_page(page, PageTableFlags::PRESENT).unwrap();
+    assert_eq!(unmap_page(page), Ok(frame));
+    assert_eq!(translate_addr(page.start_address()), None);
+    assert_eq!(unmap_page(page), Err(PagingError::NotMapped));
+    super::deallocate_frame(frame);
+}
+
+#[test_case]
+fn protect_page() {
+    let page = test_page();
+    let frame = map_page(page, PageTableFlags::PRESENT | PageTableFlags::WRITABLE).unwrap();
+    assert!(page_flags(page.start_address())
+        .unwrap()
+        .contains(PageTableFlags::WRITABLE));
+
+    let read_only = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+    protect(page, read_only).unwrap();
+    let flags = page_flags(page.start_address()).unwrap();
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+
+    unmap_page(page).unwrap();
+    super::deallocate_frame(frame);
+}
+
+#[test_case]
+fn mmio_mapping() {
+    // Map a usable frame as if it were device memory, the mapping aliases the frame.
+    let frame = super::allocate_frame().unwrap();
+    let address = frame.start_address() + 0x10u64;
+    let mmio = map_mmio(address, 4096).unwrap();
+    assert_eq!(mmio.as_u64() % 4096, 0x10);
+    assert_eq!(translate_addr(mmio), Some(address));
+    assert_eq!(translate_addr(mmio + 4096u64), Some(address + 4096u64));
+
+    let flags = page_flags(mmio).unwrap();
+    assert!(flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITABLE));
+
+    // The next mapping does not overlap.
+    let next = map_mmio(address, 1).unwrap();
+    assert!(next >= mmio + 4096u64 * 2);
+
+    // The aliases are never accessed, so the frame can be reused.
+    super::deallocate_frame(frame);
+}
+
+#[test_case]
+fn try_mapper_while_locked() {
+    let address = phys_to_virt(PhysAddr::new(0x1000));
+    assert_eq!(
+        try_with_mapper(|mapper| mapper.translate_addr(address)),
+        Some(Some(PhysAddr::new(0x1000)))
+    );
+    let nested = with_mapper(|_| try_with_mapper(|_| ()));
+    assert_eq!(nested, None);
+}
@@ -0,0 +1,165 @@
+//! The PCI bus, enumerated through configuration mechanism #1.
+//!
+//! The configuration space of every function is selected by writing its bus, device, function and
+//! register offset to the address port, and then read through the data port. Functions that do
+//! not exist read as vendor 0xFFFF.
+
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const CONFIG_DATA_PORT: u16 = 0xCFC;
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+// Offsets of the registers in the configuration space header.
+const VENDOR_DEVICE: u8 = 0x00;
+const CLASS_REVISION: u8 = 0x08;
+/// Cache line size, latency timer, header type and BIST.
+const HEADER: u8 = 0x0C;
+
+/// Set in the header type of function 0 if the device has more functions.
+const MULTI_FUNCTION: u8 = 0x80;
+const NO_VENDOR: u16 = 0xFFFF;
+
+const BUSES: u16 = 256;
+const DEVICES: u8 = 32;
+const FUNCTIONS: u8 = 8;
+
+/// A function of a device on the PCI bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+}
+
+impl Device {
+    fn read(bus: u8, device: u8, function: u8) -> Option<Self> {
+        let ids = read_config(bus, device, function, VENDOR_DEVICE);
+        let vendor_id = ids as u16;
+        if vendor_id == NO_VENDOR {
+            return None;
+        }
+        let [revision, prog_if, subclass, class] =
+            read_config(bus, device, function, CLASS_REVISION).to_le_bytes();
+        Some(Device {
+            bus,
+            device,
+            function,
+            vendor_id,
+            device_id: (ids >> 16) as u16,
+            class,
+            subclass,
+            prog_if,
+            revision,
+        })
+    }
+
+    /// Returns what kind of device this is, from its class and subclass.
+    pub fn kind(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x01, 0x01) => "IDE controller",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "NVMe controller",
+            (0x01, _) => "Mass storage controller",
+            (0x02, 0x00) => "Ethernet controller",
+            (0x02, _) => "Network controller",
+            (0x03, 0x00) => "VGA controller",
+            (0x03, _) => "Display controller",
+            (0x04, _) => "Multimedia controller",
+            (0x05, _) => "Memory controller",
+            (0x06, 0x00) => "Host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI bridge",
+            (0x06, _) => "Bridge",
+            (0x07, _) => "Communication controller",
+            (0x08, _) => "System peripheral",
+            (0x0C, 0x03) => "USB controller",
+            (0x0C, 0x05) => "SMBus controller",
+            (0x0C, _) => "Serial bus controller",
+            _ => "Unknown device",
+        }
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{} {:04x}:{:04x} {} (class {:02x}{:02x}, rev {:02x})",
+            self.bus,
+            self.device,
+            self.function,
+            self.vendor_id,
+            self.device_id,
+            self.kind(),
+            self.class,
+            self.subclass,
+            self.revision
+        )
+    }
+}
+
+/// Returns every function on every bus, by trying all addresses.
+pub fn devices() -> Vec<Device> {
+    let mut devices = Vec::new();
+    for bus in 0..BUSES {
+        let bus = bus as u8;
+        for device in 0..DEVICES {
+            let first = match Device::read(bus, device, 0) {
+                Some(first) => first,
+                None => continue,
+            };
+            devices.push(first);
+            let header_type = read_config(bus, device, 0, HEADER).to_le_bytes()[2];
+            if header_type & MULTI_FUNCTION != 0 {
+                devices.extend(
+                    (1..FUNCTIONS).filter_map(|function| Device::read(bus, device, function)),
+                );
+            }
+        }
+    }
+    devices
+}
+
+/// Reads the 32-bit register at the offset, which must be a multiple of 4.
+fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let address = CONFIG_ENABLE
+        | u32::from(bus) << 16
+        | u32::from(device) << 11
+        | u32::from(function) << 8
+        | u32::from(offset);
+    let mut address_port = Port::<u32>::new(CONFIG_ADDRESS_PORT);
+    let mut data_port = Port::<u32>::new(CONFIG_DATA_PORT);
+    // The address must not change before the data is read.
+    interrupts::without_interrupts(|| unsafe {
+        address_port.write(address);
+        data_port.read()
+    })
+}
+
+#[test_case]
+fn enumerate_devices() {
+    // QEMU's PC machine has an i440FX host bridge and a PIIX3 with the IDE controller.
+    let devices = devices();
+    let host_bridge = devices[0];
+    assert_eq!(
+        (host_bridge.bus, host_bridge.device, host_bridge.function),
+        (0, 0, 0)
+    );
+    assert_eq!(host_bridge.kind(), "Host bridge");
+    let ide = devices
+        .iter()
+        .find(|device| device.kind() == "IDE controller")
+        .expect("No IDE controller");
+    assert_eq!(ide.vendor_id, 0x8086);
+    assert!(ide.function > 0);
+}
@@ -0,0 +1,122 @@
+//! Turning the machine off and restarting it.
+//!
+//! Both work on any PC with ACPI, and `reboot` also without it. Neither relies on QEMU's
+//! `isa-debug-exit` device.
+
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::instructions::tables::lidt;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+use crate::acpi::{self, Fadt, SleepType};
+
+// PM1 control register bits.
+const SCI_ENABLE: u16 = 1 << 0;
+const SLEEP_TYPE_SHIFT: u16 = 10;
+const SLEEP_ENABLE: u16 = 1 << 13;
+
+const KEYBOARD_CONTROLLER_STATUS: u16 = 0x64;
+const KEYBOARD_CONTROLLER_INPUT_FULL: u8 = 1 << 1;
+const KEYBOARD_CONTROLLER_RESET: u8 = 0xFE;
+
+/// Amount of writes to the POST port to wait for an attempt to take effect. Every write takes
+/// about a microsecond.
+const ATTEMPT_DELAY: u32 = 100_000;
+
+/// Turns the machine off by entering ACPI sleep state S5. Halts forever if that fails.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+
+    if let Some(tables) = acpi::tables() {
+        if let (Some(fadt), Some(s5)) = (&tables.fadt, tables.s5) {
+            unsafe { enter_s5(fadt, s5) };
+            io_delay(ATTEMPT_DELAY);
+        }
+    }
+
+    eprintln!("Shutdown failed, the machine can be turned off now");
+    crate::hlt_loop()
+}
+
+/// Restarts the machine, using the first method that works of: the ACPI reset register, the
+/// keyboard controller, and a triple fault.
+pub fn reboot() -> ! {
+    interrupts::disable();
+
+    let fadt = acpi::tables().and_then(|tables| tables.fadt.as_ref());
+    if let Some(fadt) = fadt {
+        if let Some(reset_register) = fadt.reset_register {
+            unsafe { reset_register.write_u8(fadt.reset_value) };
+            io_delay(ATTEMPT_DELAY);
+        }
+    }
+
+    unsafe { reset_with_keyboard_controller() };
+    io_delay(ATTEMPT_DELAY);
+
+    unsafe { triple_fault() }
+}
+
+/// # Safety
+///
+/// Turns off the machine.
+unsafe fn enter_s5(fadt: &Fadt, s5: SleepType) {
+    if fadt.pm1a_control_block == 0 {
+        return;
+    }
+    let mut pm1a_control = Port::<u16>::new(fadt.pm1a_control_block as u16);
+
+    // Take over power management from the firmware, unless ACPI is always enabled.
+    if pm1a_control.read() & SCI_ENABLE == 0 && fadt.smi_command != 0 && fadt.acpi_enable != 0 {
+        Port::<u8>::new(fadt.smi_command as u16).write(fadt.acpi_enable);
+        for _ in 0..ATTEMPT_DELAY {
+            if pm1a_control.read() & SCI_ENABLE != 0 {
+                break;
+            }
+            io_delay(1);
+        }
+    }
+
+    pm1a_control.write(u16::from(s5.pm1a) << SLEEP_TYPE_SHIFT | SLEEP_ENABLE);
+    if fadt.pm1b_control_block != 0 {
+        Port::<u16>::new(fadt.pm1b_control_block as u16)
+            .write(u16::from(s5.pm1b) << SLEEP_TYPE_SHIFT | SLEEP_ENABLE);
+    }
+}
+
+/// Pulses the CPU reset line through the 8042 keyboard controller.
+///
+/// # Safety
+///
+/// Resets the machine.
+unsafe fn reset_with_keyboard_controller() {
+    let mut status = Port::<u8>::new(KEYBOARD_CONTROLLER_STATUS);
+    for _ in 0..ATTEMPT_DELAY {
+        if status.read() & KEYBOARD_CONTROLLER_INPUT_FULL == 0 {
+            break;
+        }
+        io_delay(1);
+    }
+    status.write(KEYBOARD_CONTROLLER_RESET)
+}
+
+/// Loads an empty IDT and raises an exception. The CPU cannot deliver it, nor the double fault
+/// that follows, and resets itself.
+unsafe fn triple_fault() -> ! {
+    let idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    lidt(&idt);
+    interrupts::int3();
+    unreachable!("Triple fault did not reset the machine")
+}
+
+/// Waits by writing to the unused POST code port.
+fn io_delay(writes: u32) {
+    let mut port = Port::<u8>::new(0x80);
+    for _ in 0..writes {
+        unsafe { port.write(0) }
+    }
+}
@@ -0,0 +1,496 @@
+//! Processes: user programs running in their own address space.
+//!
+//! Every process has one thread, which runs the program in user mode until it exits. The exit
+//! frees the address space, and the process stays in the table with its exit code until its parent
+//! collects it with `wait`. Processes started by kernel code have no parent process, the kernel
+//! waits for them. Children of an exiting process are orphaned and removed once they exit. A
+//! program that causes an exception, like a page fault, exits with `abi::FAULT_EXIT_CODE`.
+//!
+//! `fork` starts a child with a copy-on-write copy of the address space. `exec` replaces the address
+//! space of a process and restarts its thread in the new program.
+//!
+//! Processes have a table of open files, which starts with the console as standard input, output
+//! and error. Forked children share the open files of the parent, `exec` keeps them.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spinning::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::PhysFrame;
+
+use crate::elf::{self, ElfError};
+use crate::memory::address_space::{self, AddressSpace};
+use crate::memory::demand::RegionError;
+use crate::task::{self, ThreadId};
+use crate::userspace::{self, Registers};
+use crate::vfs::FileTable;
+
+lazy_static! {
+    static ref PROCESSES: Mutex<Processes> = Mutex::new(Processes::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProcessId(u64);
+
+impl ProcessId {
+    // IDs start at 1, like on Unix systems.
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ProcessId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn from_u64(id: u64) -> Self {
+        ProcessId(id)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ProcessId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// Exited with the exit code, waiting to be collected by the parent.
+    Exited(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    Elf(ElfError),
+    /// No stack for the thread of the process.
+    Thread(RegionError),
+    OutOfMemory,
+    /// Called from a kernel thread.
+    NotAProcess,
+}
+
+impl From<ElfError> for ProcessError {
+    fn from(error: ElfError) -> Self {
+        ProcessError::Elf(error)
+    }
+}
+
+struct Process {
+    name: String,
+    /// `None` for processes started by the kernel and for orphans.
+    parent: Option<ProcessId>,
+    /// The parent exited, nobody waits for this process.
+    orphan: bool,
+    thread: ThreadId,
+    state: State,
+    // Taken when the process exits.
+    address_space: Option<AddressSpace>,
+    /// Where to continue in user mode after `exec`.
+    exec_start: Option<Registers>,
+    files: FileTable,
+}
+
+struct Processes {
+    processes: BTreeMap<ProcessId, Process>,
+    /// Threads blocked in `wait` with the parent whose children they wait for.
+    waiting: Vec<(Option<ProcessId>, ThreadId)>,
+}
+
+impl Processes {
+    fn new() -> Self {
+        Processes {
+            processes: BTreeMap::new(),
+            waiting: Vec::new(),
+        }
+    }
+
+    fn id_of_thread(&self, thread: ThreadId) -> Option<ProcessId> {
+        self.processes
+            .iter()
+            .find(|(_, process)| process.thread == thread && process.state == State::Running)
+            .map(|(&id, _)| id)
+    }
+
+    fn of_thread(&mut self, thread: ThreadId) -> Option<(ProcessId, &mut Process)> {
+        self.processes
+            .iter_mut()
+            .find(|(_, process)| process.thread == thread && process.state == State::Running)
+            .map(|(&id, process)| (id, process))
+    }
+
+    fn children(&self, parent: Option<ProcessId>) -> impl Iterator<Item = (&ProcessId, &Process)> {
+        self.processes
+            .iter()
+            .filter(move |(_, process)| process.parent == parent && !process.orphan)
+    }
+
+    /// Marks the process as exited and returns its address space and files to be freed.
+    fn exit(&mut self, id: ProcessId, code: u64) -> (Option<AddressSpace>, FileTable) {
+        let orphans: Vec<(ProcessId, State)> = self
+            .children(Some(id))
+            .map(|(&child, process)| (child, process.state))
+            .collect();
+        for (child, state) in orphans {
+            match state {
+                State::Exited(_) => drop(self.processes.remove(&child)),
+                State::Running => {
+                    let child = self.processes.get_mut(&child).expect("Child is missing");
+                    child.parent = None;
+                    child.orphan = true
+                }
+            }
+        }
+
+        let process = self
+            .processes
+            .get_mut(&id)
+            .expect("Exiting process is missing");
+        process.state = State::Exited(code);
+        let address_space = process.address_space.take();
+        let files = mem::replace(&mut process.files, FileTable::new());
+        let parent = process.parent;
+        if process.orphan {
+            self.processes.remove(&id);
+        } else {
+            let (woken, waiting) = self.waiting.drain(..).partition(|&(p, _)| p == parent);
+            self.waiting = waiting;
+            for (_, thread) in woken {
+                task::wake(thread)
+            }
+        }
+        (address_space, files)
+    }
+
+    /// Removes an exited child of the parent, `id` selects the child or any one if `None`. Returns
+    /// `Ok(None)` if there are matching children but none of them exited yet.
+    fn reap(
+        &mut self,
+        parent: Option<ProcessId>,
+        id: Option<ProcessId>,
+    ) -> Result<Option<(ProcessId, u64)>, ()> {
+        let mut found = false;
+        let mut exited = None;
+        for (&child, process) in self.children(parent) {
+            if id.is_some_and(|id| id != child) {
+                continue;
+            }
+            found = true;
+            if let State::Exited(code) = process.state {
+                exited = Some((child, code));
+                break;
+            }
+        }
+        if !found {
+            return Err(());
+        }
+        if let Some((child, _)) = exited {
+            self.processes.remove(&child);
+        }
+        Ok(exited)
+    }
+}
+
+fn with_processes<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Processes) -> R,
+{
+    interrupts::without_interrupts(|| f(&mut PROCESSES.lock()))
+}
+
+/// Starts the executable as a child of the current process, with the arguments and environment.
+/// The first argument names the process.
+#[allow(dead_code)]
+pub fn spawn(image: &[u8], args: &[&str], env: &[&str]) -> Result<ProcessId, ProcessError> {
+    let program = elf::load(image, args, env)?;
+    let registers = Registers::start(program.entry, program.stack_pointer);
+    let name = String::from(args.first().copied().unwrap_or(""));
+    let files = FileTable::with_console();
+    start(name, current_id(), program.address_space, files, registers)
+}
+
+/// Starts a child of the current process with a copy of its memory and its open files, continuing
+/// with the registers.
+pub fn fork(registers: Registers) -> Result<ProcessId, ProcessError> {
+    let thread = task::current_id();
+    let forked: Result<_, ProcessError> = with_processes(|processes| {
+        let (id, process) = processes
+            .of_thread(thread)
+            .ok_or(ProcessError::NotAProcess)?;
+        let address_space = process
+            .address_space
+            .as_mut()
+            .expect("Running process without address space")
+            .fork()
+            .map_err(|_| ProcessError::OutOfMemory)?;
+        Ok((
+            id,
+            process.name.clone(),
+            address_space,
+            process.files.clone(),
+        ))
+    });
+    let (id, name, address_space, files) = forked?;
+    start(name, Some(id), address_space, files, registers)
+}
+
+/// Replaces the program of the current process. The new program starts once the thread leaves user
+/// mode with `userspace::exit`.
+pub fn exec(image: &[u8], args: &[&str], env: &[&str]) -> Result<(), ProcessError> {
+    let thread = task::current_id();
+    if current_id().is_none() {
+        return Err(ProcessError::NotAProcess);
+    }
+    let program = elf::load(image, args, env)?;
+
+    let previous = interrupts::without_interrupts(|| {
+        unsafe { program.address_space.activate() };
+        with_processes(|processes| {
+            let (_, process) = processes.of_thread(thread).expect("Process is missing");
+            process.name = String::from(args.first().copied().unwrap_or(""));
+            process.exec_start = Some(Registers::start(program.entry, program.stack_pointer));
+            process.address_space.replace(program.address_space)
+        })
+    });
+    drop(previous);
+    Ok(())
+}
+
+/// Adds a process to the table, with a thread that continues in user mode with the registers.
+fn start(
+    name: String,
+    parent: Option<ProcessId>,
+    address_space: AddressSpace,
+    files: FileTable,
+    registers: Registers,
+) -> Result<ProcessId, ProcessError> {
+    let id = ProcessId::new();
+    let level_4_frame = address_space.level_4_frame();
+
+    // The thread only runs once the process is in the table.
+    interrupts::without_interrupts(|| {
+        let thread = task::spawn("process", move || unsafe { run(level_4_frame, registers) })
+            .map_err(ProcessError::Thread)?;
+
+        let process = Process {
+            name,
+            parent,
+            orphan: false,
+            thread,
+            state: State::Running,
+            address_space: Some(address_space),
+            exec_start: None,
+            files,
+        };
+        with_processes(|processes| processes.processes.insert(id, process));
+        Ok(id)
+    })
+}
+
+/// Runs the process on its thread until it exits.
+///
+/// # Safety
+///
+/// The level 4 table must be the one of the process.
+unsafe fn run(level_4_frame: PhysFrame, registers: Registers) -> ! {
+    Cr3::write(level_4_frame, Cr3Flags::empty());
+    let mut registers = registers;
+    loop {
+        let code = userspace::resume(&registers);
+        let thread = task::current_id();
+        let exec_start = with_processes(|processes| {
+            let (_, process) = processes.of_thread(thread).expect("Process is missing");
+            process.exec_start.take()
+        });
+        match exec_start {
+            Some(start) => registers = start,
+            None => exit(code),
+        }
+    }
+}
+
+/// Returns the ID of the process the current thread belongs to, `None` for kernel threads.
+pub fn current_id() -> Option<ProcessId> {
+    let thread = task::current_id();
+    with_processes(|processes| processes.id_of_thread(thread))
+}
+
+/// Calls the function with the file table of the current process. Returns `None` for kernel
+/// threads.
+pub fn with_files<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut FileTable) -> R,
+{
+    let thread = task::current_id();
+    with_processes(|processes| {
+        processes
+            .of_thread(thread)
+            .map(|(_, process)| f(&mut process.files))
+    })
+}
+
+/// What `list` tells about a process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pub id: ProcessId,
+    pub parent: Option<ProcessId>,
+    pub name: String,
+    pub thread: ThreadId,
+    pub state: State,
+}
+
+/// Returns every process that has not been collected yet.
+pub fn list() -> Vec<ProcessInfo> {
+    with_processes(|processes| {
+        processes
+            .processes
+            .iter()
+            .map(|(&id, process)| ProcessInfo {
+                id,
+                parent: process.parent,
+                name: process.name.clone(),
+                thread: process.thread,
+                state: process.state,
+            })
+            .collect()
+    })
+}
+
+/// Ends the current process with the exit code, frees its memory and closes its files.
+pub fn exit(code: u64) -> ! {
+    let id = current_id().expect("Exiting from a kernel thread");
+    interrupts::disable();
+    // The address space cannot be freed while it is active.
+    unsafe { Cr3::write(address_space::kernel_level_4_frame(), Cr3Flags::empty()) }
+    let (address_space, files) = with_processes(|processes| processes.exit(id, code));
+    drop(address_space);
+    interrupts::enable();
+    drop(files);
+    task::exit()
+}
+
+/// Waits until a child of the current process exits, and returns its ID and exit code. `id`
+/// selects the child, or any child if it is `None`. Returns `None` if there is no such child.
+///
+/// Kernel threads wait for the processes started by the kernel.
+pub fn wait(id: Option<ProcessId>) -> Option<(ProcessId, u64)> {
+    let parent = current_id();
+    let thread = task::current_id();
+    loop {
+        // An exit between checking the children and blocking would be missed with interrupts.
+        let reaped = interrupts::without_interrupts(|| {
+            let reaped = with_processes(|processes| {
+                let reaped = processes.reap(parent, id);
+                if let Ok(None) = reaped {
+                    processes.waiting.push((parent, thread))
+                }
+                reaped
+            });
+            if let Ok(None) = reaped {
+                task::block()
+            }
+            reaped
+        });
+        match reaped {
+            Ok(Some(exited)) => return Some(exited),
+            Ok(None) => (),
+            Err(()) => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+const GETPID: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/getpid"));
+
+#[test_case]
+fn spawn_and_wait() {
+    let first = spawn(GETPID, &["first"], &[]).unwrap();
+    let second = spawn(GETPID, &["second"], &[]).unwrap();
+    assert_ne!(first, second);
+    let name = |id| {
+        list()
+            .into_iter()
+            .find(|process| process.id == id)
+            .map(|process| process.name)
+    };
+    assert_eq!(name(first).as_deref(), Some("first"));
+    let listed = list();
+    assert!(listed.iter().any(|process| process.id == second
+        && process.name == "second"
+        && process.parent.is_none()));
+
+    // The programs exit with their process ID.
+    assert_eq!(wait(Some(second)), Some((second, second.as_u64())));
+    assert_eq!(wait(None), Some((first, first.as_u64())));
+    assert_eq!(name(first), None);
+    assert_eq!(wait(None), None);
+    assert_eq!(wait(Some(first)), None);
+}
+
+#[test_case]
+fn exit_frees_memory() {
+    use crate::memory;
+
+    let free_frames = || memory::with_frame_allocator(|allocator| allocator.free_frames());
+    let run = || {
+        let id = spawn(GETPID, &["getpid"], &[]).unwrap();
+        assert!(wait(Some(id)).is_some());
+    };
+    // The first run may grow the heap.
+    run();
+    let free = free_frames();
+    run();
+    assert_eq!(free_frames(), free);
+}
+
+#[test_case]
+fn orphans_are_removed() {
+    let mut processes = Processes::new();
+    let process = |parent| Process {
+        name: String::new(),
+        parent,
+        orphan: false,
+        thread: task::current_id(),
+        state: State::Running,
+        address_space: None,
+        exec_start: None,
+        files: FileTable::new(),
+    };
+    let (parent, exited, running) = (ProcessId::new(), ProcessId::new(), ProcessId::new());
+    processes.processes.insert(parent, process(None));
+    processes.processes.insert(exited, process(Some(parent)));
+    processes.processes.insert(running, process(Some(parent)));
+
+    processes.exit(exited, 1);
+    processes.exit(parent, 0);
+    assert!(!processes.processes.contains_key(&exited));
+    assert_eq!(processes.reap(None, None), Ok(Some((parent, 0))));
+    // The orphan does not count as a child of the kernel.
+    assert_eq!(processes.reap(None, None), Err(()));
+    processes.exit(running, 2);
+    assert!(processes.processes.is_empty());
+}
+
+#[test_case]
+fn fork_copies_on_write() {
+    let fork = crate::vfs::read_file("/bin/fork").unwrap();
+    let id = spawn(&fork, &["fork"], &[]).unwrap();
+    // The child changed its copy of the memory only.
+    assert_eq!(wait(Some(id)), Some((id, 42 + 10)));
+}
+
+#[test_case]
+fn exec_replaces_program() {
+    let exec = crate::vfs::read_file("/bin/exec").unwrap();
+    let id = spawn(&exec, &["exec"], &[]).unwrap();
+    // See `user/args.s`: two arguments, one variable and the first argument is four bytes long.
+    let code = 2 | 1 << 8 | 4 << 16 | 8 << 24 | 4 << 32;
+    assert_eq!(wait(Some(id)), Some((id, code)));
+}
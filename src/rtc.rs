@@ -0,0 +1,159 @@
+//! The CMOS real-time clock, which keeps the date and time while the computer is off.
+//!
+//! Its registers are selected through one I/O port and read through another. They hold the date
+//! and time in BCD or binary and the hour in 12 or 24 hour format, as status register B says. The
+//! RTC is assumed to run in UTC, like QEMU's does by default.
+
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::acpi;
+
+const ADDRESS_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+
+/// Set in status register A while the RTC updates its registers.
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+// Flags of status register B.
+const HOURS_24: u8 = 0x02;
+const BINARY: u8 = 0x04;
+/// Set in the hour in 12 hour format for PM.
+const PM: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns the seconds since 1970-01-01 00:00:00 UTC. The date must not be earlier.
+    pub fn unix_time(&self) -> u64 {
+        // Days since 0000-03-01, which puts leap days at the end of years.
+        let month = self.month as i64;
+        let year = self.year as i64 - if month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        // 719468 days from 0000-03-01 to 1970-01-01.
+        let days = era * 146_097 + day_of_era - 719_468;
+        let seconds = self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        (days * 86400 + seconds) as u64
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    let mut address = Port::<u8>::new(ADDRESS_PORT);
+    let mut data = Port::<u8>::new(DATA_PORT);
+    unsafe {
+        address.write(register);
+        data.read()
+    }
+}
+
+/// Reads the registers of the date and time, and the century if there is a register for it.
+fn read_raw(century: Option<u8>) -> [u8; 7] {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop()
+    }
+    let mut raw = [0; 7];
+    for (value, &register) in raw
+        .iter_mut()
+        .zip([SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].iter())
+    {
+        *value = read_register(register)
+    }
+    raw[6] = century.map_or(0, read_register);
+    raw
+}
+
+/// Reads the date and time. The century is taken from the register the FADT names, or assumed to
+/// be 2000 without one.
+pub fn read() -> DateTime {
+    let century = acpi::tables()
+        .and_then(|tables| tables.fadt.as_ref())
+        .map(|fadt| fadt.century)
+        .filter(|&century| century != 0);
+    let (raw, status) = interrupts::without_interrupts(|| {
+        // An update may start between the reads, until two reads agree.
+        let mut raw = read_raw(century);
+        loop {
+            let again = read_raw(century);
+            if again == raw {
+                break;
+            }
+            raw = again
+        }
+        (raw, read_register(STATUS_B))
+    });
+
+    let decode = |value: u8| {
+        if status & BINARY != 0 {
+            value
+        } else {
+            (value >> 4) * 10 + (value & 0x0F)
+        }
+    };
+    let mut hour = decode(raw[2] & !PM);
+    if status & HOURS_24 == 0 {
+        // 12 AM is midnight, 12 PM noon.
+        hour %= 12;
+        if raw[2] & PM != 0 {
+            hour += 12
+        }
+    }
+    let century = match century {
+        Some(_) => decode(raw[6]) as u16,
+        None => 20,
+    };
+    DateTime {
+        year: century * 100 + decode(raw[5]) as u16,
+        month: decode(raw[4]),
+        day: decode(raw[3]),
+        hour,
+        minute: decode(raw[1]),
+        second: decode(raw[0]),
+    }
+}
+
+#[test_case]
+fn unix_times() {
+    let date_time = |year, month, day, hour, minute, second| DateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    };
+    assert_eq!(date_time(1970, 1, 1, 0, 0, 0).unix_time(), 0);
+    assert_eq!(date_time(1999, 12, 31, 23, 59, 59).unix_time(), 946_684_799);
+    assert_eq!(date_time(2000, 3, 1, 0, 0, 0).unix_time(), 951_868_800);
+    assert_eq!(
+        date_time(2024, 2, 29, 12, 34, 56).unix_time(),
+        1_709_210_096
+    );
+}
+
+#[test_case]
+fn read_clock() {
+    let now = read();
+    // QEMU starts the RTC at the time of the host.
+    assert!(now.year >= 2024);
+    assert!((1..=12).contains(&now.month) && (1..=31).contains(&now.day));
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+}
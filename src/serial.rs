@@ -1,15 +1,86 @@
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
 use lazy_static::lazy_static;
 use spinning::Mutex;
 use uart_16550::SerialPort;
+use x86_64::instructions::port::{Port, PortReadOnly};
+
+const COM1: u16 = 0x3F8;
+const LINE_STATUS_PORT: u16 = COM1 + 5;
+const DATA_READY: u8 = 1 << 0;
+const INPUT_QUEUE_CAPACITY: usize = 256;
 
 lazy_static! {
+    // Initializing the port also enables its interrupt for received data.
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+// Filled by the interrupt handler, like the scancode queue of the keyboard.
+static INPUT_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Reads the received bytes from COM1. Called from the serial interrupt handler.
+///
+/// The ports are read directly instead of through `SERIAL1`, whose lock might be held by the code
+/// that was interrupted. Reading the data register does not disturb sending.
+pub fn process_input() {
+    let mut line_status = PortReadOnly::<u8>::new(LINE_STATUS_PORT);
+    let mut data = Port::<u8>::new(COM1);
+    while unsafe { line_status.read() } & DATA_READY != 0 {
+        let byte = unsafe { data.read() };
+        add_byte(byte)
+    }
+}
+
+fn add_byte(byte: u8) {
+    if let Ok(queue) = INPUT_QUEUE.try_get() {
+        if queue.push(byte).is_ok() {
+            WAKER.wake()
+        }
+    }
+    // Without a stream nobody is interested in the input, so it is dropped.
+}
+
+/// Stream of the bytes received on COM1.
+pub struct ByteStream {
+    queue: &'static ArrayQueue<u8>,
+}
+
+impl ByteStream {
+    pub fn new() -> Self {
+        let queue = INPUT_QUEUE.get_or_init(|| ArrayQueue::new(INPUT_QUEUE_CAPACITY));
+        ByteStream { queue }
+    }
+}
+
+impl Stream for ByteStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u8>> {
+        if let Some(byte) = self.queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        // Register before checking again, so a byte pushed in between still wakes us.
+        WAKER.register(context.waker());
+        match self.queue.pop() {
+            Some(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
@@ -44,3 +115,21 @@ macro_rules! serial_print_fn {
         $crate::serial::_print(format_args!("{}{}", function_name!(), $($arg)*));
     };
 }
+
+#[test_case]
+fn byte_stream() {
+    use crate::task::executor::{Executor, Task};
+    use futures_util::stream::StreamExt;
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let mut bytes = ByteStream::new();
+        for &byte in b"ls\r".iter() {
+            add_byte(byte);
+        }
+        assert_eq!(bytes.next().await, Some(b'l'));
+        assert_eq!(bytes.next().await, Some(b's'));
+        assert_eq!(bytes.next().await, Some(b'\r'));
+    }));
+    executor.run_until_done();
+}
@@ -0,0 +1,244 @@
+//! The built-in commands of the shell.
+
+use alloc::format;
+use alloc::string::String;
+use core::fmt::{self, Write};
+
+use super::Command;
+use crate::process::{self, ProcessInfo};
+use crate::task::{self, State};
+use crate::vfs::{self, FileType};
+use crate::{acpi, allocator, interrupts, memory, pci, power, time};
+
+pub const BUILTIN: [Command; 12] = [
+    Command {
+        name: "acpi",
+        help: "Show the ACPI tables",
+        run: acpi,
+    },
+    Command {
+        name: "cat",
+        help: "Print the contents of files",
+        run: cat,
+    },
+    Command {
+        name: "clear",
+        help: "Clear the screen",
+        run: clear,
+    },
+    Command {
+        name: "help",
+        help: "List the commands",
+        run: help,
+    },
+    Command {
+        name: "ls",
+        help: "List a directory, the root by default",
+        run: ls,
+    },
+    Command {
+        name: "lsirq",
+        help: "Show how often every device interrupt was handled",
+        run: lsirq,
+    },
+    Command {
+        name: "lspci",
+        help: "List the devices on the PCI bus",
+        run: lspci,
+    },
+    Command {
+        name: "mem",
+        help: "Show the physical memory and heap usage",
+        run: mem,
+    },
+    Command {
+        name: "ps",
+        help: "List the threads and processes",
+        run: ps,
+    },
+    Command {
+        name: "reboot",
+        help: "Restart the machine",
+        run: reboot,
+    },
+    Command {
+        name: "shutdown",
+        help: "Turn the machine off",
+        run: shutdown,
+    },
+    Command {
+        name: "uptime",
+        help: "Show the time since boot",
+        run: uptime,
+    },
+];
+
+const FRAME_SIZE_KIB: usize = 4;
+
+fn acpi(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    acpi::dump(out)
+}
+
+fn cat(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    if args.is_empty() {
+        return writeln!(out, "usage: cat <path>...");
+    }
+    for path in args {
+        match vfs::read_file(path) {
+            Ok(contents) => out.write_str(&String::from_utf8_lossy(&contents))?,
+            Err(error) => writeln!(out, "cat: {}: {:?}", path, error)?,
+        }
+    }
+    Ok(())
+}
+
+fn clear(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    // Erase the screen and move the cursor to the top left corner, on the screen and terminals.
+    out.write_str("\x1b[2J\x1b[H")
+}
+
+fn help(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    for command in super::commands() {
+        writeln!(out, "{:<10}{}", command.name, command.help)?;
+    }
+    Ok(())
+}
+
+fn ls(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let path = args.first().copied().unwrap_or("/");
+    let inode = match vfs::lookup(path) {
+        Ok(inode) => inode,
+        Err(error) => return writeln!(out, "ls: {}: {:?}", path, error),
+    };
+    if inode.metadata().kind != FileType::Directory {
+        return writeln!(out, "{}", path);
+    }
+    for index in 0.. {
+        let entry = match inode.read_dir(index) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(error) => return writeln!(out, "ls: {}: {:?}", path, error),
+        };
+        let suffix = match entry.kind {
+            FileType::Directory => "/",
+            FileType::Symlink => "@",
+            FileType::File | FileType::CharDevice => "",
+        };
+        writeln!(out, "{}{}", entry.name, suffix)?;
+    }
+    Ok(())
+}
+
+fn lsirq(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "IRQ  {:>10}  DEVICE", "COUNT")?;
+    for (irq, name, count) in interrupts::irq_counts() {
+        writeln!(out, "{:>3}  {:>10}  {}", irq, count, name)?;
+    }
+    Ok(())
+}
+
+fn lspci(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    for device in pci::devices() {
+        writeln!(out, "{}", device)?;
+    }
+    Ok(())
+}
+
+fn mem(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let (free, usable) = memory::with_frame_allocator(|allocator| {
+        (allocator.free_frames(), allocator.usable_frames())
+    });
+    writeln!(
+        out,
+        "Physical memory: {} KiB used, {} KiB free, {} KiB total",
+        (usable - free) * FRAME_SIZE_KIB,
+        free * FRAME_SIZE_KIB,
+        usable * FRAME_SIZE_KIB
+    )?;
+    writeln!(
+        out,
+        "Kernel heap: {} KiB, backed by frames on first use",
+        allocator::HEAP_SIZE / 1024
+    )
+}
+
+fn ps(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "{:>5}  {:<9} NAME", "TID", "STATE")?;
+    for (id, name, state) in task::threads() {
+        let state = match state {
+            State::Running => "running",
+            State::Ready => "ready",
+            State::Sleeping(_) => "sleeping",
+            State::Blocked | State::BlockedUntil(_) => "blocked",
+            State::Exited => "exited",
+        };
+        writeln!(out, "{:>5}  {:<9} {}", id, state, name)?;
+    }
+
+    let processes = process::list();
+    if processes.is_empty() {
+        return Ok(());
+    }
+    writeln!(
+        out,
+        "\n{:>5} {:>5} {:>5}  {:<9} NAME",
+        "PID", "PPID", "TID", "STATE"
+    )?;
+    for ProcessInfo {
+        id,
+        parent,
+        name,
+        thread,
+        state,
+    } in processes
+    {
+        let parent = parent.map_or(0, |parent| parent.as_u64());
+        let state = match state {
+            process::State::Running => String::from("running"),
+            process::State::Exited(code) => format!("exit {}", code),
+        };
+        writeln!(
+            out,
+            "{:>5} {:>5} {:>5}  {:<9} {}",
+            id, parent, thread, state, name
+        )?;
+    }
+    Ok(())
+}
+
+fn reboot(_args: &[&str], _out: &mut dyn Write) -> fmt::Result {
+    power::reboot()
+}
+
+fn shutdown(_args: &[&str], _out: &mut dyn Write) -> fmt::Result {
+    power::shutdown()
+}
+
+fn uptime(_args: &[&str], out: &mut dyn Write) -> fmt::Result {
+    let seconds = time::uptime().as_secs();
+    writeln!(
+        out,
+        "up {}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[test_case]
+fn builtin_commands() {
+    let run = |line: &str| {
+        let mut out = String::new();
+        super::execute(line, &mut out).unwrap();
+        out
+    };
+    assert!(run("acpi").starts_with("ACPI: revision "));
+    assert_eq!(run("cat"), "usage: cat <path>...\n");
+    assert_eq!(run("cat /nothing"), "cat: /nothing: NotFound\n");
+    assert!(run("ls").lines().any(|line| line == "bin/"));
+    assert!(run("ps").contains(" running   "));
+    assert!(run("lsirq").contains("keyboard"));
+    assert!(run("mem").starts_with("Physical memory: "));
+    assert!(run("uptime").starts_with("up 0:"));
+    assert_eq!(run("clear"), "\x1b[2J\x1b[H");
+}
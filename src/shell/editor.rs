@@ -0,0 +1,286 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+/// Longest line that can be typed. The line is redrawn by returning to the start of the row, so
+/// the prompt and the line have to fit on one row of the screen.
+pub const MAX_LINE_LENGTH: usize = 64;
+/// Amount of lines kept in the history.
+const HISTORY_LENGTH: usize = 100;
+
+/// Keys the editor handles, from the keyboard or decoded from a serial terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    /// Previous line in the history.
+    Up,
+    /// Next line in the history.
+    Down,
+    Tab,
+}
+
+/// Editor for the line typed after the prompt, with a history of the entered lines.
+pub struct LineEditor {
+    prompt: &'static str,
+    line: Vec<char>,
+    cursor: usize,
+    history: VecDeque<String>,
+    // Position in the history while going through it, with the line typed before.
+    browsing: Option<(usize, Vec<char>)>,
+}
+
+impl LineEditor {
+    pub fn new(prompt: &'static str) -> Self {
+        LineEditor {
+            prompt,
+            line: Vec::new(),
+            cursor: 0,
+            history: VecDeque::new(),
+            browsing: None,
+        }
+    }
+
+    /// Writes the prompt for a new line.
+    pub fn start(&self, out: &mut dyn Write) -> fmt::Result {
+        out.write_str(self.prompt)
+    }
+
+    /// Handles the key and shows its effect. Returns the line once it is entered.
+    ///
+    /// `complete` is called for the tab key with the line up to the cursor. It returns the
+    /// candidates for the word before the cursor, which replace the whole word.
+    pub fn handle(
+        &mut self,
+        key: Key,
+        out: &mut dyn Write,
+        complete: &dyn Fn(&str) -> Vec<String>,
+    ) -> Result<Option<String>, fmt::Error> {
+        let length = self.line.len();
+        match key {
+            Key::Char(character) => {
+                if length >= MAX_LINE_LENGTH || character.is_control() {
+                    return Ok(None);
+                }
+                self.line.insert(self.cursor, character);
+                self.cursor += 1;
+                if self.cursor == self.line.len() {
+                    out.write_char(character)?;
+                    return Ok(None);
+                }
+            }
+            Key::Enter => {
+                out.write_char('\n')?;
+                let line: String = self.line.drain(..).collect();
+                self.cursor = 0;
+                self.browsing = None;
+                if !line.trim().is_empty() && self.history.back() != Some(&line) {
+                    if self.history.len() == HISTORY_LENGTH {
+                        self.history.pop_front();
+                    }
+                    self.history.push_back(line.clone())
+                }
+                return Ok(Some(line));
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            Key::Delete if self.cursor < length => {
+                self.line.remove(self.cursor);
+            }
+            Key::Left if self.cursor > 0 => self.cursor -= 1,
+            Key::Right if self.cursor < length => self.cursor += 1,
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = length,
+            Key::Up => self.browse_history(true),
+            Key::Down => self.browse_history(false),
+            Key::Tab => self.complete(out, complete)?,
+            Key::Backspace | Key::Delete | Key::Left | Key::Right => return Ok(None),
+        }
+        self.redraw(out, length)?;
+        Ok(None)
+    }
+
+    fn browse_history(&mut self, back: bool) {
+        let index = match (&self.browsing, back) {
+            (None, true) if !self.history.is_empty() => self.history.len() - 1,
+            (Some((index, _)), true) if *index > 0 => index - 1,
+            (Some((index, _)), false) if index + 1 < self.history.len() => index + 1,
+            (Some(_), false) => {
+                // Past the newest entry, back to what was typed before.
+                let (_, typed) = self.browsing.take().unwrap();
+                self.set_line(typed);
+                return;
+            }
+            _ => return,
+        };
+        let typed = match self.browsing.take() {
+            Some((_, typed)) => typed,
+            None => self.line.clone(),
+        };
+        self.browsing = Some((index, typed));
+        self.set_line(self.history[index].chars().collect())
+    }
+
+    fn set_line(&mut self, line: Vec<char>) {
+        self.line = line;
+        self.cursor = self.line.len()
+    }
+
+    fn complete(
+        &mut self,
+        out: &mut dyn Write,
+        complete: &dyn Fn(&str) -> Vec<String>,
+    ) -> fmt::Result {
+        let before: String = self.line[..self.cursor].iter().collect();
+        let word_start = self.line[..self.cursor]
+            .iter()
+            .rposition(|&character| character == ' ')
+            .map_or(0, |space| space + 1);
+        let candidates = complete(&before);
+
+        let mut completion: Vec<char> = match candidates.as_slice() {
+            [] => return Ok(()),
+            [candidate] => {
+                let mut completion: Vec<char> = candidate.chars().collect();
+                if !candidate.ends_with('/') {
+                    completion.push(' ')
+                }
+                completion
+            }
+            [first, rest @ ..] => {
+                let common = rest.iter().fold(usize::MAX, |common, candidate| {
+                    first
+                        .chars()
+                        .zip(candidate.chars())
+                        .take(common)
+                        .take_while(|(a, b)| a == b)
+                        .count()
+                });
+                first.chars().take(common).collect()
+            }
+        };
+        if completion.len() <= self.cursor - word_start {
+            // Nothing to add, show the candidates above the line.
+            out.write_char('\n')?;
+            for candidate in &candidates {
+                write!(out, "{}  ", candidate)?;
+            }
+            return out.write_char('\n');
+        }
+        let room = MAX_LINE_LENGTH - (self.line.len() - (self.cursor - word_start));
+        completion.truncate(room);
+        let added = completion.len();
+        self.line.splice(word_start..self.cursor, completion);
+        self.cursor = word_start + added;
+        Ok(())
+    }
+
+    /// Redraws the line, which was `previous_length` characters long, and puts the cursor in
+    /// place. Only carriage returns are used, which the screen and every terminal understand.
+    fn redraw(&self, out: &mut dyn Write, previous_length: usize) -> fmt::Result {
+        write!(out, "\r{}", self.prompt)?;
+        for character in &self.line {
+            out.write_char(*character)?;
+        }
+        for _ in self.line.len()..previous_length {
+            out.write_char(' ')?;
+        }
+        write!(out, "\r{}", self.prompt)?;
+        for character in &self.line[..self.cursor] {
+            out.write_char(*character)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn type_keys(editor: &mut LineEditor, keys: &[Key]) -> (Option<String>, String) {
+    let complete = |before: &str| -> Vec<String> {
+        ["help", "hello", "ls"]
+            .iter()
+            .filter(|name| name.starts_with(before))
+            .map(|name| String::from(*name))
+            .collect()
+    };
+    let mut out = String::new();
+    let mut entered = None;
+    for &key in keys {
+        if let Some(line) = editor.handle(key, &mut out, &complete).unwrap() {
+            entered = Some(line)
+        }
+    }
+    (entered, out)
+}
+
+#[cfg(test)]
+fn chars(text: &str) -> Vec<Key> {
+    text.chars().map(Key::Char).collect()
+}
+
+#[test_case]
+fn edit_line() {
+    let mut editor = LineEditor::new("> ");
+    let mut keys = chars("cat /fo");
+    keys.extend([
+        Key::Backspace,
+        Key::Backspace,
+        Key::Left,
+        Key::Left,
+        Key::Delete,
+        Key::End,
+    ]);
+    keys.extend(chars("etc"));
+    keys.extend([Key::Home, Key::Right, Key::Right, Key::Right]);
+    keys.extend(chars("s"));
+    keys.push(Key::Enter);
+
+    let (line, out) = type_keys(&mut editor, &keys);
+    assert_eq!(line.as_deref(), Some("cats/etc"));
+    // Typing at the end only echoes, the other changes redraw the line.
+    assert!(out.starts_with("cat /fo\r> cat /f \r> cat /f"));
+    assert!(out.ends_with("\r> cats/etc\r> cats\n"));
+}
+
+#[test_case]
+fn history() {
+    let mut editor = LineEditor::new("> ");
+    for line in ["first", "second", "second"] {
+        let mut keys = chars(line);
+        keys.push(Key::Enter);
+        type_keys(&mut editor, &keys);
+    }
+    assert_eq!(editor.history.len(), 2);
+
+    let mut keys = chars("new");
+    keys.extend([Key::Up, Key::Up, Key::Up]);
+    type_keys(&mut editor, &keys);
+    assert_eq!(editor.line.iter().collect::<String>(), "first");
+    type_keys(&mut editor, &[Key::Down]);
+    assert_eq!(editor.line.iter().collect::<String>(), "second");
+    type_keys(&mut editor, &[Key::Down]);
+    assert_eq!(editor.line.iter().collect::<String>(), "new");
+}
+
+#[test_case]
+fn tab_completion() {
+    let mut editor = LineEditor::new("> ");
+    // `l` has one candidate, which gets a space after it.
+    let (_, out) = type_keys(&mut editor, &[Key::Char('l'), Key::Tab]);
+    assert_eq!(editor.line.iter().collect::<String>(), "ls ");
+    assert!(out.ends_with("\r> ls "));
+
+    // `h` completes to the common prefix, then lists the candidates.
+    type_keys(&mut editor, &[Key::Enter, Key::Char('h'), Key::Tab]);
+    assert_eq!(editor.line.iter().collect::<String>(), "hel");
+    let (_, out) = type_keys(&mut editor, &[Key::Tab]);
+    assert_eq!(out, "\nhelp  hello  \n\r> hel\r> hel");
+}
@@ -0,0 +1,158 @@
+use core::convert::TryFrom;
+use core::mem;
+use futures_util::future;
+use futures_util::stream::{self, Stream, StreamExt};
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use super::editor::Key;
+use crate::keyboard::KeyStream;
+use crate::serial::ByteStream;
+
+const ESCAPE: u8 = 0x1b;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+/// Returns the keys typed on the keyboard and on the terminal connected to COM1.
+pub fn keys() -> impl Stream<Item = Key> + Unpin {
+    let keyboard = KeyStream::new().filter_map(|key| future::ready(from_keyboard(key)));
+    let mut decoder = SerialDecoder::new();
+    let serial = ByteStream::new().filter_map(move |byte| future::ready(decoder.decode(byte)));
+    stream::select(keyboard, serial)
+}
+
+/// Returns the editor key for the key from the keyboard, `None` for keys the editor ignores.
+fn from_keyboard(key: DecodedKey) -> Option<Key> {
+    match key {
+        DecodedKey::Unicode('\n') => Some(Key::Enter),
+        DecodedKey::Unicode('\t') => Some(Key::Tab),
+        DecodedKey::Unicode(character) => match u8::try_from(character) {
+            Ok(BACKSPACE) => Some(Key::Backspace),
+            Ok(DELETE) => Some(Key::Delete),
+            _ => Some(Key::Char(character)),
+        },
+        DecodedKey::RawKey(code) => match code {
+            KeyCode::ArrowLeft => Some(Key::Left),
+            KeyCode::ArrowRight => Some(Key::Right),
+            KeyCode::ArrowUp => Some(Key::Up),
+            KeyCode::ArrowDown => Some(Key::Down),
+            KeyCode::Home => Some(Key::Home),
+            KeyCode::End => Some(Key::End),
+            KeyCode::Delete => Some(Key::Delete),
+            _ => None,
+        },
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Normal,
+    /// After a carriage return, which some terminals follow with a line feed.
+    Return,
+    /// After the escape byte.
+    Escape,
+    /// In a control sequence, with the number read so far.
+    Sequence(u8),
+}
+
+/// Decodes the bytes a VT100 compatible terminal sends. Only ASCII characters are supported.
+struct SerialDecoder {
+    state: State,
+}
+
+impl SerialDecoder {
+    fn new() -> Self {
+        SerialDecoder {
+            state: State::Normal,
+        }
+    }
+
+    /// Returns the key once the byte completes one.
+    fn decode(&mut self, byte: u8) -> Option<Key> {
+        let state = mem::replace(&mut self.state, State::Normal);
+        match state {
+            State::Normal | State::Return => match byte {
+                ESCAPE => {
+                    self.state = State::Escape;
+                    None
+                }
+                b'\n' if state == State::Return => None,
+                b'\r' | b'\n' => {
+                    self.state = State::Return;
+                    Some(Key::Enter)
+                }
+                b'\t' => Some(Key::Tab),
+                BACKSPACE | DELETE => Some(Key::Backspace),
+                0x20..=0x7e => Some(Key::Char(char::from(byte))),
+                _ => None,
+            },
+            // Cursor keys are sent as `ESC [ A` or as `ESC O A`, depending on the terminal mode.
+            State::Escape => {
+                if byte == b'[' || byte == b'O' {
+                    self.state = State::Sequence(0)
+                }
+                None
+            }
+            State::Sequence(number) => match byte {
+                b'0'..=b'9' => {
+                    let number = number.saturating_mul(10).saturating_add(byte - b'0');
+                    self.state = State::Sequence(number);
+                    None
+                }
+                b'A' => Some(Key::Up),
+                b'B' => Some(Key::Down),
+                b'C' => Some(Key::Right),
+                b'D' => Some(Key::Left),
+                b'H' => Some(Key::Home),
+                b'F' => Some(Key::End),
+                b'~' => match number {
+                    1 | 7 => Some(Key::Home),
+                    3 => Some(Key::Delete),
+                    4 | 8 => Some(Key::End),
+                    _ => None,
+                },
+                _ => None,
+            },
+        }
+    }
+}
+
+#[test_case]
+fn decode_keyboard() {
+    assert_eq!(
+        from_keyboard(DecodedKey::Unicode('a')),
+        Some(Key::Char('a'))
+    );
+    assert_eq!(from_keyboard(DecodedKey::Unicode('\n')), Some(Key::Enter));
+    assert_eq!(
+        from_keyboard(DecodedKey::Unicode('\u{8}')),
+        Some(Key::Backspace)
+    );
+    assert_eq!(
+        from_keyboard(DecodedKey::RawKey(KeyCode::ArrowUp)),
+        Some(Key::Up)
+    );
+    assert_eq!(from_keyboard(DecodedKey::RawKey(KeyCode::F1)), None);
+}
+
+#[test_case]
+fn decode_serial() {
+    use alloc::vec::Vec;
+
+    let mut decoder = SerialDecoder::new();
+    let keys: Vec<Key> = b"a\x7f\r\n\r\x1b[D\x1bOA\x1b[3~\x1b[1~\x1b[5~\t"
+        .iter()
+        .filter_map(|&byte| decoder.decode(byte))
+        .collect();
+    let expected = [
+        Key::Char('a'),
+        Key::Backspace,
+        Key::Enter,
+        Key::Enter,
+        Key::Left,
+        Key::Up,
+        Key::Delete,
+        Key::Home,
+        Key::Tab,
+    ];
+    assert_eq!(keys, expected);
+}
@@ -0,0 +1,173 @@
+//! The kernel shell, which runs commands typed on the keyboard or on a terminal on COM1.
+//!
+//! Lines are typed into a `LineEditor`, with history and tab completion of command names and
+//! paths. The words of an entered line are split at whitespace, and the first one names the
+//! command. Output goes to both the screen and COM1.
+//!
+//! Commands are kept in a registry, which starts with the built-in commands. Other modules can add
+//! their own with `register`.
+
+mod commands;
+mod editor;
+mod input;
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use futures_util::stream::StreamExt;
+use lazy_static::lazy_static;
+use spinning::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::vfs::{self, FileType};
+use editor::LineEditor;
+
+const PROMPT: &str = "ferocios> ";
+
+/// Runs a command with the words after its name, writing the output to `out`.
+pub type Run = fn(args: &[&str], out: &mut dyn Write) -> fmt::Result;
+
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    /// One line about what the command does, shown by `help`.
+    pub help: &'static str,
+    pub run: Run,
+}
+
+lazy_static! {
+    static ref COMMANDS: Mutex<BTreeMap<&'static str, Command>> = Mutex::new(
+        commands::BUILTIN
+            .iter()
+            .map(|&command| (command.name, command))
+            .collect()
+    );
+}
+
+/// Adds the command to the shell, replacing a command with the same name.
+#[allow(dead_code)]
+pub fn register(command: Command) {
+    interrupts::without_interrupts(|| COMMANDS.lock().insert(command.name, command));
+}
+
+/// Returns every command, ordered by name.
+fn commands() -> Vec<Command> {
+    interrupts::without_interrupts(|| COMMANDS.lock().values().copied().collect())
+}
+
+fn find(name: &str) -> Option<Command> {
+    interrupts::without_interrupts(|| COMMANDS.lock().get(name).copied())
+}
+
+/// Writes to the screen and to COM1.
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{}", s);
+        serial_print!("{}", s);
+        Ok(())
+    }
+}
+
+/// Reads and runs commands forever.
+pub async fn run() {
+    let mut keys = input::keys();
+    let mut editor = LineEditor::new(PROMPT);
+    let mut console = Console;
+
+    // Writing to the console cannot fail, so the results are ignored.
+    let _ = writeln!(console, "Type `help` for a list of commands.");
+    let _ = editor.start(&mut console);
+    while let Some(key) = keys.next().await {
+        if let Ok(Some(line)) = editor.handle(key, &mut console, &complete) {
+            let _ = execute(&line, &mut console);
+            let _ = editor.start(&mut console);
+        }
+    }
+}
+
+/// Runs the command line. Empty lines do nothing.
+pub fn execute(line: &str, out: &mut dyn Write) -> fmt::Result {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (name, args) = match words.split_first() {
+        Some(split) => split,
+        None => return Ok(()),
+    };
+    match find(name) {
+        Some(command) => (command.run)(args, out),
+        None => writeln!(out, "{}: command not found", name),
+    }
+}
+
+/// Returns the completions for the last word of the line: command names for the first word, and
+/// absolute paths for the others.
+fn complete(line: &str) -> Vec<String> {
+    match line.rsplit_once(' ') {
+        None => commands()
+            .iter()
+            .filter(|command| command.name.starts_with(line))
+            .map(|command| command.name.to_string())
+            .collect(),
+        Some((_, word)) => complete_path(word),
+    }
+}
+
+/// Returns the paths in the directory of the path that start like it. Directories end with `/`.
+fn complete_path(path: &str) -> Vec<String> {
+    let (directory, prefix) = match path.rsplit_once('/') {
+        Some(split) => split,
+        None => return Vec::new(),
+    };
+    let inode = match vfs::lookup(if directory.is_empty() { "/" } else { directory }) {
+        Ok(inode) => inode,
+        Err(_) => return Vec::new(),
+    };
+    (0..)
+        .map_while(|index| inode.read_dir(index).ok().flatten())
+        .filter(|entry| entry.name != "." && entry.name != ".." && entry.name.starts_with(prefix))
+        .map(|entry| {
+            let slash = if entry.kind == FileType::Directory {
+                "/"
+            } else {
+                ""
+            };
+            format!("{}/{}{}", directory, entry.name, slash)
+        })
+        .collect()
+}
+
+#[test_case]
+fn register_and_execute() {
+    fn greet(args: &[&str], out: &mut dyn Write) -> fmt::Result {
+        writeln!(out, "Hello, {}!", args.join(" "))
+    }
+
+    register(Command {
+        name: "greet",
+        help: "Greet someone",
+        run: greet,
+    });
+    let mut out = String::new();
+    execute("  greet  the   world ", &mut out).unwrap();
+    execute("", &mut out).unwrap();
+    execute("frobnicate", &mut out).unwrap();
+    assert_eq!(out, "Hello, the world!\nfrobnicate: command not found\n");
+
+    out.clear();
+    execute("help", &mut out).unwrap();
+    assert!(out
+        .lines()
+        .any(|line| line.starts_with("greet") && line.ends_with("Greet someone")));
+}
+
+#[test_case]
+fn complete_names_and_paths() {
+    assert_eq!(complete("lsp"), ["lspci"]);
+    assert_eq!(complete("ls"), ["ls", "lsirq", "lspci"]);
+    assert_eq!(complete("cat /bi"), ["/bin/"]);
+    assert!(complete("ls /bin/").contains(&String::from("/bin/fork")));
+    assert!(complete("cat /nowhere/").is_empty());
+}
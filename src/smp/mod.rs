@@ -0,0 +1,249 @@
+//! Symmetric multiprocessing: starting the application processors (APs) and running code on them.
+//!
+//! The bootstrap processor (BSP) starts every enabled CPU listed in the MADT with the
+//! INIT-SIPI-SIPI sequence. Every AP gets its own GDT, TSS and interrupt stacks, loads the shared
+//! IDT and then waits for calls sent to it with `run_on`. Threads only run on the BSP.
+
+pub mod percpu;
+mod trampoline;
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+use crate::acpi;
+use crate::gdt;
+use crate::interrupts::apic;
+use crate::syscall;
+use crate::time::{self, Instant};
+use percpu::Cpu;
+use trampoline::Trampoline;
+
+/// Vector of the IPI that makes a CPU run the calls sent to it.
+pub const CALL_VECTOR: u8 = 0xF0;
+
+const AP_STACK_SIZE: usize = 64 * 1024;
+/// How long an AP may take to check in after its startup IPIs.
+const AP_STARTUP_TIMEOUT: Duration = Duration::from_millis(100);
+
+static CPUS: OnceCell<Vec<&'static Cpu>> = OnceCell::uninit();
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallError {
+    NoSuchCpu,
+}
+
+/// Sets up the per-CPU data of the BSP and starts the APs. Requires the heap, the ACPI tables and
+/// the interrupt controller, and interrupts to be enabled.
+pub fn init() {
+    let bsp_apic_id = if apic::is_enabled() {
+        apic::local_apic_id()
+    } else {
+        0
+    };
+    let madt = acpi::tables().and_then(|tables| tables.madt.as_ref());
+    let ap_apic_ids: Vec<u8> = madt
+        .filter(|_| apic::is_enabled())
+        .map(|madt| {
+            madt.local_apics
+                .iter()
+                .filter(|local_apic| local_apic.enabled && local_apic.apic_id != bsp_apic_id)
+                .map(|local_apic| local_apic.apic_id)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut all = vec![Cpu::new(0, bsp_apic_id)];
+    for (i, &apic_id) in ap_apic_ids.iter().enumerate() {
+        all.push(Cpu::new(i + 1, apic_id))
+    }
+    CPUS.init_once(|| all);
+
+    let bsp = cpus()[0];
+    unsafe { bsp.load(gdt::bsp_tss()) };
+    check_in(bsp);
+
+    if ap_apic_ids.is_empty() {
+        return;
+    }
+    let mut trampoline = match Trampoline::new() {
+        Some(trampoline) => trampoline,
+        None => {
+            eprintln!("No memory below 1 MiB for the AP trampoline");
+            return;
+        }
+    };
+    for &cpu in &cpus()[1..] {
+        start_ap(&mut trampoline, cpu)
+    }
+}
+
+fn cpus() -> &'static [&'static Cpu] {
+    CPUS.try_get().expect("SMP is not initialized")
+}
+
+fn check_in(cpu: &Cpu) {
+    cpu.online.store(true, Ordering::SeqCst);
+    ONLINE_CPUS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Returns the amount of CPUs that are running.
+#[allow(dead_code)]
+pub fn cpu_count() -> usize {
+    ONLINE_CPUS.load(Ordering::SeqCst)
+}
+
+fn start_ap(trampoline: &mut Trampoline, cpu: &'static Cpu) {
+    // Zeroing the stack backs it with frames, the AP cannot handle page faults before its IDT is
+    // loaded.
+    let stack = Box::leak(vec![0u8; AP_STACK_SIZE].into_boxed_slice());
+    let stack_top = VirtAddr::from_ptr(stack.as_ptr()) + AP_STACK_SIZE;
+    trampoline.set_entry(ap_entry, cpu as *const Cpu as u64, stack_top);
+
+    unsafe { apic::send_init(cpu.apic_id) };
+    time::sleep(Duration::from_millis(10));
+    // The second startup IPI is only needed if the first one got lost.
+    for _ in 0..2 {
+        unsafe { apic::send_startup(cpu.apic_id, trampoline.page_number()) };
+        if wait_for_check_in(cpu, Duration::from_millis(1)) {
+            return;
+        }
+    }
+    if !wait_for_check_in(cpu, AP_STARTUP_TIMEOUT) {
+        eprintln!("CPU with APIC ID {} did not start", cpu.apic_id)
+    }
+}
+
+fn wait_for_check_in(cpu: &Cpu, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !deadline.has_passed() {
+        if cpu.online.load(Ordering::SeqCst) {
+            return true;
+        }
+        core::hint::spin_loop()
+    }
+    cpu.online.load(Ordering::SeqCst)
+}
+
+/// Called by the trampoline on the AP's own stack, with the address of its per-CPU data.
+extern "C" fn ap_entry(cpu: u64) -> ! {
+    let cpu = unsafe { &*(cpu as *const Cpu) };
+    let tss = gdt::init_ap();
+    crate::interrupts::init();
+    syscall::init();
+    unsafe {
+        cpu.load(tss);
+        apic::enable_local_apic()
+    }
+    check_in(cpu);
+
+    loop {
+        interrupts::enable_and_hlt()
+    }
+}
+
+/// Makes the CPU with the index run `f`, without waiting for it.
+///
+/// `f` runs in interrupt context with interrupts disabled, so it must not block. If the CPU is the
+/// current one, `f` runs once interrupts are enabled again.
+#[allow(dead_code)]
+pub fn run_on<F>(id: usize, f: F) -> Result<(), CallError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let cpu = *cpus().get(id).ok_or(CallError::NoSuchCpu)?;
+    if !cpu.online.load(Ordering::SeqCst) {
+        return Err(CallError::NoSuchCpu);
+    }
+    if !apic::is_enabled() {
+        // Without APICs there are no IPIs, but also no other CPUs.
+        interrupts::without_interrupts(f);
+        return Ok(());
+    }
+
+    interrupts::without_interrupts(|| cpu.calls.lock().push_back(Box::new(f)));
+    apic::send_interrupt(cpu.apic_id, CALL_VECTOR);
+    Ok(())
+}
+
+/// Runs the calls sent to the current CPU. Called from the interrupt handler of `CALL_VECTOR`.
+pub fn run_calls() {
+    let cpu = percpu::current();
+    loop {
+        // The lock is released before the call runs, which may send calls itself.
+        let call = cpu.calls.lock().pop_front();
+        match call {
+            Some(call) => call(),
+            None => break,
+        }
+    }
+}
+
+#[test_case]
+fn every_cpu_checks_in() {
+    // The tests run with `-smp 4`.
+    assert_eq!(cpu_count(), 4);
+    assert!(cpus().iter().all(|cpu| cpu.online.load(Ordering::SeqCst)));
+}
+
+#[test_case]
+fn bsp_is_cpu_0() {
+    assert_eq!(percpu::current().id, 0);
+    assert_eq!(percpu::current().apic_id, apic::local_apic_id());
+}
+
+#[test_case]
+fn run_on_every_cpu() {
+    use alloc::sync::Arc;
+
+    let visited = Arc::new(AtomicUsize::new(0));
+    for id in 0..cpu_count() {
+        let visited = visited.clone();
+        run_on(id, move || {
+            let cpu = percpu::current();
+            assert_eq!(cpu.apic_id, apic::local_apic_id());
+            visited.fetch_or(1 << cpu.id, Ordering::SeqCst);
+        })
+        .unwrap();
+    }
+
+    let all = (1 << cpu_count()) - 1;
+    let deadline = Instant::now() + Duration::from_millis(100);
+    while visited.load(Ordering::SeqCst) != all {
+        assert!(!deadline.has_passed(), "Not every CPU ran the call");
+        core::hint::spin_loop()
+    }
+}
+
+#[test_case]
+fn call_sends_call() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicBool;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let inner = done.clone();
+    run_on(1, move || {
+        run_on(1, move || inner.store(true, Ordering::SeqCst)).unwrap();
+    })
+    .unwrap();
+
+    let deadline = Instant::now() + Duration::from_millis(100);
+    while !done.load(Ordering::SeqCst) {
+        assert!(
+            !deadline.has_passed(),
+            "The call sent by a call did not run"
+        );
+        core::hint::spin_loop()
+    }
+}
+
+#[test_case]
+fn run_on_missing_cpu() {
+    assert_eq!(run_on(cpu_count(), || ()), Err(CallError::NoSuchCpu));
+}
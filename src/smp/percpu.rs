@@ -0,0 +1,117 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use conquer_once::spin::OnceCell;
+use core::arch::asm;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spinning::Mutex;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+use crate::gdt::Tss;
+
+/// Function sent to another CPU with `smp::run_on`.
+pub type Call = Box<dyn FnOnce() + Send>;
+
+/// Offset of the kernel stack in `Cpu`, for the system call entry.
+pub const KERNEL_STACK_OFFSET: usize = offset_of!(Cpu, kernel_stack);
+/// Offset of the scratch space for the user stack pointer in `Cpu`, for the system call entry.
+pub const USER_STACK_OFFSET: usize = offset_of!(Cpu, user_stack);
+
+/// Data every CPU has its own copy of. In the kernel, the GS segment base of a CPU points to its
+/// copy. While user code runs, the pointer is kept in `KERNEL_GS_BASE` instead, and every entry
+/// from and return to user mode switches the two with `swapgs`, see `KernelGs`.
+#[repr(C)]
+pub struct Cpu {
+    // Address of this structure, must come first so `current` can read it from `gs:0`.
+    address: u64,
+    // Stack for system calls and interrupts in user mode, see `set_kernel_stack`.
+    kernel_stack: AtomicU64,
+    user_stack: AtomicU64,
+    /// Index of the CPU, the bootstrap processor is CPU 0.
+    pub id: usize,
+    pub apic_id: u8,
+    pub online: AtomicBool,
+    pub(super) calls: Mutex<VecDeque<Call>>,
+    tss: OnceCell<&'static Tss>,
+}
+
+impl Cpu {
+    /// Allocates the per-CPU data, which lives forever.
+    pub(super) fn new(id: usize, apic_id: u8) -> &'static Cpu {
+        let cpu = Box::leak(Box::new(Cpu {
+            address: 0,
+            kernel_stack: AtomicU64::new(0),
+            user_stack: AtomicU64::new(0),
+            id,
+            apic_id,
+            online: AtomicBool::new(false),
+            calls: Mutex::new(VecDeque::new()),
+            tss: OnceCell::uninit(),
+        }));
+        cpu.address = cpu as *const Cpu as u64;
+        cpu
+    }
+
+    /// Makes this the data of the current CPU, which uses the TSS.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called once on every CPU, for its own data.
+    pub(super) unsafe fn load(&'static self, tss: &'static Tss) {
+        self.tss.init_once(|| tss);
+        GsBase::write(VirtAddr::new(self.address));
+        // The GS base user code starts with.
+        KernelGsBase::write(VirtAddr::zero())
+    }
+
+    /// Returns the stack the CPU switches to when entering the kernel from user mode.
+    pub fn kernel_stack(&self) -> VirtAddr {
+        VirtAddr::new(self.kernel_stack.load(Ordering::SeqCst))
+    }
+
+    /// Sets the stack the CPU switches to on system calls and interrupts in user mode. Must only
+    /// be called on this CPU.
+    pub fn set_kernel_stack(&self, stack_top: VirtAddr) {
+        self.kernel_stack
+            .store(stack_top.as_u64(), Ordering::SeqCst);
+        let tss = self.tss.try_get().expect("Per-CPU data is not loaded");
+        tss.set_ring_0_stack(stack_top)
+    }
+}
+
+/// Switches to the GS base of the kernel while an interrupt or exception from user mode is
+/// handled, and back to the one of the user code when dropped. User code can change its GS base,
+/// so every handler has to create this first, before anything uses the per-CPU data.
+pub struct KernelGs {
+    from_user: bool,
+}
+
+impl KernelGs {
+    pub fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        // The requested privilege level of the interrupted code segment.
+        let from_user = stack_frame.code_segment & 3 == 3;
+        if from_user {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) }
+        }
+        KernelGs { from_user }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.from_user {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) }
+        }
+    }
+}
+
+/// Returns the data of the current CPU. Must only be called after `smp::init`.
+pub fn current() -> &'static Cpu {
+    let address: u64;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) address, options(nostack, readonly, preserves_flags));
+        &*(address as *const Cpu)
+    }
+}
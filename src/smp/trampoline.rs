@@ -0,0 +1,170 @@
+//! Real-mode code that brings an application processor into long mode.
+//!
+//! A CPU started by a startup IPI runs in real mode at the start of a page below 1 MiB. The
+//! trampoline is copied to such a page, which is identity-mapped while APs start. It loads the
+//! control registers of the bootstrap processor, switching from real mode directly to long mode,
+//! then calls the entry point on the given stack.
+
+use core::arch::global_asm;
+use core::ptr::{addr_of, copy_nonoverlapping};
+use x86_64::registers::control::{Cr0, Cr3, Cr4};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::{self, paging};
+
+global_asm!(
+    ".pushsection .text.ap_trampoline, \"ax\"",
+    ".code16",
+    ".global ferocios_ap_trampoline_start",
+    "ferocios_ap_trampoline_start:",
+    // Offset of the parameters within the trampoline, for real-mode accesses relative to CS.
+    ".set PARAMS, ferocios_ap_trampoline_params - ferocios_ap_trampoline_start",
+    "cli",
+    "cld",
+    // CS points to the trampoline page, make data accesses relative to it as well.
+    "mov ax, cs",
+    "mov ds, ax",
+    "mov eax, dword ptr [PARAMS + 16]",
+    "mov cr4, eax",
+    "mov eax, dword ptr [PARAMS]",
+    "mov cr3, eax",
+    "mov eax, dword ptr [PARAMS + 24]",
+    "xor edx, edx",
+    "mov ecx, 0xC0000080",
+    "wrmsr",
+    "lgdt [PARAMS + 56]",
+    // Enabling protection and paging at once with EFER.LME set switches to long mode.
+    "mov eax, dword ptr [PARAMS + 8]",
+    "mov cr0, eax",
+    // Far jump through the 32-bit pointer at `params.long_mode_offset` to load the 64-bit CS.
+    ".byte 0x66, 0xFF, 0x2E",
+    ".word PARAMS + 62",
+    ".code64",
+    ".global ferocios_ap_trampoline_long_mode",
+    "ferocios_ap_trampoline_long_mode:",
+    "xor eax, eax",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov rsp, [rip + ferocios_ap_trampoline_params + 32]",
+    "mov rax, [rip + ferocios_ap_trampoline_params + 40]",
+    "mov rdi, [rip + ferocios_ap_trampoline_params + 48]",
+    "call rax",
+    "ud2",
+    ".balign 16",
+    ".global ferocios_ap_trampoline_gdt",
+    "ferocios_ap_trampoline_gdt:",
+    ".quad 0",
+    // Present, ring 0, 64-bit code segment.
+    ".quad 0x00209A0000000000",
+    ".balign 8",
+    ".global ferocios_ap_trampoline_params",
+    "ferocios_ap_trampoline_params:",
+    ".skip 68",
+    ".global ferocios_ap_trampoline_end",
+    "ferocios_ap_trampoline_end:",
+    ".popsection",
+);
+
+extern "C" {
+    static ferocios_ap_trampoline_start: u8;
+    static ferocios_ap_trampoline_long_mode: u8;
+    static ferocios_ap_trampoline_gdt: u8;
+    static ferocios_ap_trampoline_params: u8;
+    static ferocios_ap_trampoline_end: u8;
+}
+
+/// Selector of the code segment in the trampoline GDT.
+const CODE_SELECTOR: u16 = 8;
+const GDT_ENTRIES: u16 = 2;
+
+/// Filled in by the bootstrap processor before every startup. The offsets are hard-coded in the
+/// trampoline.
+#[repr(C, packed)]
+struct Params {
+    page_table: u64,
+    cr0: u64,
+    cr4: u64,
+    efer: u64,
+    stack_top: u64,
+    entry: u64,
+    argument: u64,
+    // Operand of `lgdt`.
+    gdt_limit: u16,
+    gdt_base: u32,
+    // Operand of the far jump to long mode.
+    long_mode_offset: u32,
+    long_mode_selector: u16,
+}
+
+/// Returns the offset of the trampoline symbol from the start of the trampoline.
+fn offset_of(symbol: *const u8) -> u64 {
+    symbol as u64 - addr_of!(ferocios_ap_trampoline_start) as u64
+}
+
+/// The trampoline, copied to a page below 1 MiB. The page is unmapped and freed on drop.
+pub struct Trampoline {
+    frame: PhysFrame,
+}
+
+impl Trampoline {
+    pub fn new() -> Option<Self> {
+        let frame = memory::allocate_low_frame()?;
+        let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        if paging::map_page_to(page, frame, flags).is_err() {
+            memory::deallocate_frame(frame);
+            return None;
+        }
+
+        let start = addr_of!(ferocios_ap_trampoline_start);
+        let length = offset_of(addr_of!(ferocios_ap_trampoline_end)) as usize;
+        let destination = paging::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        unsafe { copy_nonoverlapping(start, destination, length) }
+        Some(Trampoline { frame })
+    }
+
+    /// Returns the number of the page, which is the vector of the startup IPI.
+    pub fn page_number(&self) -> u8 {
+        (self.frame.start_address().as_u64() >> 12) as u8
+    }
+
+    /// Prepares the trampoline to call `entry(argument)` on the stack.
+    pub fn set_entry(
+        &mut self,
+        entry: extern "C" fn(u64) -> !,
+        argument: u64,
+        stack_top: VirtAddr,
+    ) {
+        let base = self.frame.start_address();
+        let params = Params {
+            page_table: Cr3::read().0.start_address().as_u64(),
+            cr0: Cr0::read_raw(),
+            cr4: Cr4::read_raw(),
+            // Long mode becomes active when paging is enabled, the flag itself is read-only.
+            efer: Efer::read_raw() & !EferFlags::LONG_MODE_ACTIVE.bits(),
+            stack_top: stack_top.align_down(16u64).as_u64(),
+            entry: entry as usize as u64,
+            argument,
+            gdt_limit: GDT_ENTRIES * 8 - 1,
+            gdt_base: (base + offset_of(addr_of!(ferocios_ap_trampoline_gdt))).as_u64() as u32,
+            long_mode_offset: (base + offset_of(addr_of!(ferocios_ap_trampoline_long_mode)))
+                .as_u64() as u32,
+            long_mode_selector: CODE_SELECTOR,
+        };
+
+        let offset = offset_of(addr_of!(ferocios_ap_trampoline_params));
+        let destination = paging::phys_to_virt(PhysAddr::new(base.as_u64() + offset));
+        unsafe { destination.as_mut_ptr::<Params>().write_unaligned(params) }
+    }
+}
+
+impl Drop for Trampoline {
+    fn drop(&mut self) {
+        let page = Page::containing_address(VirtAddr::new(self.frame.start_address().as_u64()));
+        paging::unmap_page(page).expect("Trampoline page was not mapped");
+        memory::deallocate_frame(self.frame)
+    }
+}
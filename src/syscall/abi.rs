@@ -0,0 +1,256 @@
+//! The system call ABI, shared by the kernel and user programs.
+//!
+//! This file only depends on `core`, so user programs written in Rust can include it with
+//! `#[path = ".../syscall/abi.rs"] mod abi;`. The numbers and error codes never change meaning,
+//! new calls and errors are only ever added.
+//!
+//! A system call is made with the `syscall` instruction. The number of the call is passed in `rax`
+//! and up to six arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`. The result is returned in
+//! `rax`, where values from `-4095` to `-1` are a negated `Error` code. `rcx` and `r11` are
+//! overwritten, all other registers are preserved.
+//!
+//! Programs start with `rsp` pointing to the argument count, followed by the argument pointers, a
+//! null pointer, the environment pointers, a null pointer and the auxiliary vector of type and
+//! value pairs ending with `AT_NULL`, like on System V.
+
+/// `exit(code) -> !`: ends the program with the exit code.
+pub const EXIT: u64 = 0;
+/// `write(fd, buffer, length) -> written`: writes to the file at its offset.
+pub const WRITE: u64 = 1;
+/// `read(fd, buffer, length) -> read`: reads from the file at its offset, 0 at the end of the file.
+/// Reading `STDIN` blocks until at least one byte is available.
+pub const READ: u64 = 2;
+/// `getpid() -> pid`: returns the ID of the calling process.
+pub const GETPID: u64 = 3;
+/// `sleep(milliseconds) -> 0`: blocks for at least the given time.
+pub const SLEEP: u64 = 4;
+/// `yield() -> 0`: gives the CPU to other threads.
+pub const YIELD: u64 = 5;
+/// `mmap(address, length, protection) -> address`: maps zeroed memory. With address 0 the kernel
+/// picks the address, otherwise it must be page-aligned and unused.
+pub const MMAP: u64 = 6;
+/// `fork() -> pid`: creates a copy of the calling process and returns the ID of the child, or 0 in
+/// the child. Memory is copied when it is first written.
+pub const FORK: u64 = 7;
+/// `exec(path, argv, envp) -> !`: replaces the program of the calling process with the executable
+/// at the path. `argv` and `envp` are arrays of string pointers ending with a null pointer, strings
+/// end with a zero byte. Only returns on errors.
+pub const EXEC: u64 = 8;
+/// `waitpid(pid, status) -> pid`: waits until the child with the ID exits, or any child with ID 0,
+/// and returns its ID. Stores the exit code at `status` if it is not null.
+pub const WAITPID: u64 = 9;
+/// `open(path, flags) -> fd`: opens the file or directory at the path with the `O_` flags, and
+/// returns the lowest unused file descriptor.
+pub const OPEN: u64 = 10;
+/// `close(fd) -> 0`
+pub const CLOSE: u64 = 11;
+/// `seek(fd, offset, whence) -> offset`: moves the offset of the file relative to `SEEK_SET`,
+/// `SEEK_CUR` or `SEEK_END`, and returns the new offset.
+pub const SEEK: u64 = 12;
+/// `stat(path, stat) -> 0`: stores the `Stat` of the file at the path, following symbolic links.
+pub const STAT: u64 = 13;
+/// `readdir(fd, entry) -> 1 or 0`: stores the next `DirEntry` of the open directory and returns 1,
+/// or returns 0 after the last entry.
+pub const READDIR: u64 = 14;
+
+/// Amount of system call numbers.
+pub const COUNT: usize = 15;
+
+/// Exit code of programs the kernel ends because of an exception, like a page fault. Shells report
+/// the same code for programs killed by `SIGSEGV`.
+pub const FAULT_EXIT_CODE: u64 = 139;
+
+// Every process starts with these file descriptors open on the console.
+/// Keyboard input, ASCII characters only.
+#[allow(dead_code)]
+pub const STDIN: u64 = 0;
+/// The screen.
+#[allow(dead_code)]
+pub const STDOUT: u64 = 1;
+/// The screen, highlighted, and the serial port.
+#[allow(dead_code)]
+pub const STDERR: u64 = 2;
+
+// Flags of `open`.
+pub const O_READ: u64 = 1 << 0;
+pub const O_WRITE: u64 = 1 << 1;
+/// Creates the file if it does not exist.
+pub const O_CREATE: u64 = 1 << 2;
+/// Removes the contents of the file.
+pub const O_TRUNCATE: u64 = 1 << 3;
+/// Every write goes to the end of the file.
+pub const O_APPEND: u64 = 1 << 4;
+
+// Positions `seek` is relative to.
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+// Types of files in `Stat` and `DirEntry`.
+pub const TYPE_FILE: u64 = 1;
+pub const TYPE_DIRECTORY: u64 = 2;
+pub const TYPE_CHAR_DEVICE: u64 = 3;
+pub const TYPE_SYMLINK: u64 = 4;
+
+/// Longest name of a directory entry.
+pub const MAX_NAME_LENGTH: usize = 255;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    /// Number of the inode, unique within its file system.
+    pub inode: u64,
+    pub kind: u64,
+    pub size: u64,
+    /// Permission bits, like 0o755.
+    pub mode: u64,
+    pub uid: u64,
+    pub gid: u64,
+    /// Times of the last access, change of the contents and change of the inode, in seconds since
+    /// 1970-01-01 00:00:00 UTC.
+    pub accessed: u64,
+    pub modified: u64,
+    pub changed: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirEntry {
+    pub inode: u64,
+    pub kind: u64,
+    pub name_length: u64,
+    /// The name, followed by a zero byte.
+    pub name: [u8; MAX_NAME_LENGTH + 1],
+}
+
+// Protection of `mmap` memory. Mapped memory is always readable.
+pub const PROT_READ: u64 = 1 << 0;
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+// Types of auxiliary vector entries.
+pub const AT_NULL: u64 = 0;
+/// Address of the program headers in memory.
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+
+/// Largest error code, results from `-MAX_ERROR` on are errors.
+pub const MAX_ERROR: u64 = 4095;
+
+/// Errors of system calls, with the codes POSIX systems use for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Error {
+    NotFound = 2,
+    /// The caller or the process it names is no process.
+    NoSuchProcess = 3,
+    /// The device failed.
+    Io = 5,
+    ArgumentsTooLong = 7,
+    /// The file is no executable the kernel can run.
+    NotExecutable = 8,
+    BadFileDescriptor = 9,
+    /// The process has no child to wait for.
+    NoChild = 10,
+    OutOfMemory = 12,
+    /// A pointer argument is not accessible from user mode.
+    BadAddress = 14,
+    /// The mount point is in use.
+    Busy = 16,
+    AlreadyExists = 17,
+    NotDirectory = 20,
+    IsDirectory = 21,
+    InvalidArgument = 22,
+    /// The process has too many open files.
+    TooManyFiles = 24,
+    NoSpace = 28,
+    /// The file has no offset, like the console.
+    InvalidSeek = 29,
+    ReadOnly = 30,
+    /// The system call number is unknown.
+    NotImplemented = 38,
+    /// The directory to remove has entries.
+    NotEmpty = 39,
+    /// Resolving the path followed too many symbolic links.
+    TooManyLinks = 40,
+}
+
+impl Error {
+    pub fn code(self) -> u64 {
+        self as u64
+    }
+
+    pub fn from_code(code: u64) -> Option<Self> {
+        Some(match code {
+            2 => Error::NotFound,
+            3 => Error::NoSuchProcess,
+            5 => Error::Io,
+            7 => Error::ArgumentsTooLong,
+            8 => Error::NotExecutable,
+            9 => Error::BadFileDescriptor,
+            10 => Error::NoChild,
+            12 => Error::OutOfMemory,
+            14 => Error::BadAddress,
+            16 => Error::Busy,
+            17 => Error::AlreadyExists,
+            20 => Error::NotDirectory,
+            21 => Error::IsDirectory,
+            22 => Error::InvalidArgument,
+            24 => Error::TooManyFiles,
+            28 => Error::NoSpace,
+            29 => Error::InvalidSeek,
+            30 => Error::ReadOnly,
+            38 => Error::NotImplemented,
+            39 => Error::NotEmpty,
+            40 => Error::TooManyLinks,
+            _ => return None,
+        })
+    }
+}
+
+/// Turns the result of a system call into the value returned in `rax`.
+pub fn encode(result: Result<u64, Error>) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(error) => error.code().wrapping_neg(),
+    }
+}
+
+/// Turns the value returned in `rax` into the result of the system call. Unknown error codes are
+/// reported as `Error::InvalidArgument`.
+#[allow(dead_code)]
+pub fn decode(value: u64) -> Result<u64, Error> {
+    if value >= MAX_ERROR.wrapping_neg() {
+        Err(Error::from_code(value.wrapping_neg()).unwrap_or(Error::InvalidArgument))
+    } else {
+        Ok(value)
+    }
+}
+
+/// Makes the system call with the number and arguments, and returns the raw result.
+///
+/// # Safety
+///
+/// Must be called from user mode, with arguments that are valid for the call.
+#[allow(dead_code)]
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn syscall(number: u64, arguments: [u64; 6]) -> u64 {
+    let result;
+    core::arch::asm!(
+        "syscall",
+        inlateout("rax") number => result,
+        in("rdi") arguments[0],
+        in("rsi") arguments[1],
+        in("rdx") arguments[2],
+        in("r10") arguments[3],
+        in("r8") arguments[4],
+        in("r9") arguments[5],
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    result
+}
@@ -0,0 +1,275 @@
+//! The system calls, every function handles the call with the same name in `abi`.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use super::abi::{self, Error, PROT_EXEC, PROT_READ, PROT_WRITE};
+use super::user;
+use super::Arguments;
+use crate::elf::ElfError;
+use crate::memory::paging::{self, PagingError};
+use crate::process::{self, ProcessError, ProcessId};
+use crate::userspace::{self, Registers};
+use crate::vfs::{self, FileType, OpenFile, OpenFlags, Seek};
+use crate::{memory, task};
+
+/// `mmap` picks addresses from here on.
+const MMAP_START: u64 = 0x0000_4000_0000_0000;
+static NEXT_MMAP: AtomicU64 = AtomicU64::new(MMAP_START);
+/// `mmap` never maps the last user page: a `syscall` at its end would return to `USER_END`, which
+/// is not canonical.
+pub const MMAP_END: u64 = userspace::USER_END - 4096;
+
+pub fn exit(arguments: Arguments) -> Result<u64, Error> {
+    userspace::exit(arguments[0])
+}
+
+pub fn write(arguments: Arguments) -> Result<u64, Error> {
+    let [fd, buffer, length, ..] = arguments;
+    let buffer = user::slice(buffer, length)?;
+    Ok(file(fd)?.write(buffer)? as u64)
+}
+
+pub fn read(arguments: Arguments) -> Result<u64, Error> {
+    let [fd, buffer, length, ..] = arguments;
+    let buffer = user::slice_mut(buffer, length)?;
+    Ok(file(fd)?.read(buffer)? as u64)
+}
+
+pub fn getpid(_: Arguments) -> Result<u64, Error> {
+    let id = process::current_id().ok_or(Error::NoSuchProcess)?;
+    Ok(id.as_u64())
+}
+
+pub fn sleep(arguments: Arguments) -> Result<u64, Error> {
+    task::sleep(Duration::from_millis(arguments[0]));
+    Ok(0)
+}
+
+pub fn yield_now(_: Arguments) -> Result<u64, Error> {
+    task::yield_now();
+    Ok(0)
+}
+
+pub fn mmap(arguments: Arguments) -> Result<u64, Error> {
+    let [address, length, protection, ..] = arguments;
+    if length == 0 || protection & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Error::InvalidArgument);
+    }
+    let length = length.checked_add(4095).ok_or(Error::InvalidArgument)? & !4095;
+    let address = match address {
+        0 => NEXT_MMAP.fetch_add(length, Ordering::SeqCst),
+        address if address % 4096 == 0 => address,
+        _ => return Err(Error::InvalidArgument),
+    };
+    match address.checked_add(length) {
+        Some(end) if address >= userspace::USER_START && end <= MMAP_END => (),
+        _ => return Err(Error::OutOfMemory),
+    }
+
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if protection & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE
+    }
+    if protection & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE
+    }
+
+    let start = Page::containing_address(VirtAddr::new(address));
+    let pages = Page::range(start, start + length / 4096);
+    for (i, page) in pages.enumerate() {
+        if let Err(error) = map_zeroed(page, flags) {
+            // Undo the pages mapped so far.
+            for page in pages.take(i) {
+                memory::deallocate_frame(paging::unmap_page(page).expect("Page was just mapped"))
+            }
+            return Err(match error {
+                PagingError::AlreadyMapped => Error::AlreadyExists,
+                _ => Error::OutOfMemory,
+            });
+        }
+    }
+    Ok(address)
+}
+
+fn map_zeroed(page: Page, flags: PageTableFlags) -> Result<(), PagingError> {
+    let frame = memory::allocate_zeroed_frame().ok_or(PagingError::OutOfMemory)?;
+    paging::map_page_to(page, frame, flags).inspect_err(|_| memory::deallocate_frame(frame))
+}
+
+/// Called by `handle` with the registers of the caller, which the child continues with.
+pub fn fork(mut registers: Registers) -> Result<u64, Error> {
+    registers.rax = 0;
+    let child = process::fork(registers).map_err(process_error)?;
+    Ok(child.as_u64())
+}
+
+pub fn exec(arguments: Arguments) -> Result<u64, Error> {
+    let [path, argv, envp, ..] = arguments;
+    // `userspace::exit` does not return, everything on the heap is freed before.
+    {
+        let image = vfs::read_file(user::string(path)?)?;
+        // The strings are copied, the memory they are in goes away with the old program.
+        let args: Vec<String> = user::strings(argv)?.into_iter().map(String::from).collect();
+        let env: Vec<String> = user::strings(envp)?.into_iter().map(String::from).collect();
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let env: Vec<&str> = env.iter().map(String::as_str).collect();
+
+        process::exec(&image, &args, &env).map_err(process_error)?;
+    }
+    userspace::exit(0)
+}
+
+pub fn waitpid(arguments: Arguments) -> Result<u64, Error> {
+    let [id, status, ..] = arguments;
+    // Check the status pointer before a child is collected and its exit code could get lost.
+    if status != 0 {
+        user::slice_mut(status, 8)?;
+    }
+    let id = match id {
+        0 => None,
+        id => Some(ProcessId::from_u64(id)),
+    };
+    let (id, code) = process::wait(id).ok_or(Error::NoChild)?;
+    if status != 0 {
+        user::slice_mut(status, 8)?.copy_from_slice(&code.to_le_bytes())
+    }
+    Ok(id.as_u64())
+}
+
+pub fn open(arguments: Arguments) -> Result<u64, Error> {
+    let [path, flags, ..] = arguments;
+    let all = abi::O_READ | abi::O_WRITE | abi::O_CREATE | abi::O_TRUNCATE | abi::O_APPEND;
+    if flags & !all != 0 {
+        return Err(Error::InvalidArgument);
+    }
+    let flags = [
+        (abi::O_READ, OpenFlags::READ),
+        (abi::O_WRITE, OpenFlags::WRITE),
+        (abi::O_CREATE, OpenFlags::CREATE),
+        (abi::O_TRUNCATE, OpenFlags::TRUNCATE),
+        (abi::O_APPEND, OpenFlags::APPEND),
+    ]
+    .iter()
+    .filter(|&&(flag, _)| flags & flag != 0)
+    .fold(OpenFlags::empty(), |flags, &(_, flag)| flags | flag);
+
+    let file = vfs::open(user::string(path)?, flags)?;
+    let descriptor = process::with_files(|files| files.add(file)).ok_or(Error::NoSuchProcess)??;
+    Ok(descriptor as u64)
+}
+
+pub fn close(arguments: Arguments) -> Result<u64, Error> {
+    let descriptor = usize::try_from(arguments[0]).map_err(|_| Error::BadFileDescriptor)?;
+    process::with_files(|files| files.close(descriptor)).ok_or(Error::BadFileDescriptor)??;
+    Ok(0)
+}
+
+pub fn seek(arguments: Arguments) -> Result<u64, Error> {
+    let [fd, offset, whence, ..] = arguments;
+    let seek = match whence {
+        abi::SEEK_SET => Seek::Start(offset),
+        abi::SEEK_CUR => Seek::Current(offset as i64),
+        abi::SEEK_END => Seek::End(offset as i64),
+        _ => return Err(Error::InvalidArgument),
+    };
+    file(fd)?.seek(seek).map_err(|error| match error {
+        vfs::Error::NotSupported => Error::InvalidSeek,
+        error => error.into(),
+    })
+}
+
+pub fn stat(arguments: Arguments) -> Result<u64, Error> {
+    let [path, stat, ..] = arguments;
+    let buffer = user::slice_mut(stat, size_of::<abi::Stat>() as u64)?;
+    let metadata = vfs::stat(user::string(path)?)?;
+    let stat = abi::Stat {
+        inode: metadata.inode,
+        kind: file_type(metadata.kind),
+        size: metadata.size,
+        mode: metadata.mode as u64,
+        uid: metadata.uid as u64,
+        gid: metadata.gid as u64,
+        accessed: metadata.accessed,
+        modified: metadata.modified,
+        changed: metadata.changed,
+    };
+    // The buffer may not be aligned.
+    unsafe { (buffer.as_mut_ptr() as *mut abi::Stat).write_unaligned(stat) }
+    Ok(0)
+}
+
+pub fn readdir(arguments: Arguments) -> Result<u64, Error> {
+    let [fd, entry, ..] = arguments;
+    let buffer = user::slice_mut(entry, size_of::<abi::DirEntry>() as u64)?;
+    let entry = match file(fd)?.read_dir()? {
+        Some(entry) => entry,
+        None => return Ok(0),
+    };
+    let mut name = [0; abi::MAX_NAME_LENGTH + 1];
+    name[..entry.name.len()].copy_from_slice(entry.name.as_bytes());
+    let entry = abi::DirEntry {
+        inode: entry.inode,
+        kind: file_type(entry.kind),
+        name_length: entry.name.len() as u64,
+        name,
+    };
+    unsafe { (buffer.as_mut_ptr() as *mut abi::DirEntry).write_unaligned(entry) }
+    Ok(1)
+}
+
+/// Returns the open file of the current process with the descriptor.
+fn file(descriptor: u64) -> Result<Arc<OpenFile>, Error> {
+    let descriptor = usize::try_from(descriptor).map_err(|_| Error::BadFileDescriptor)?;
+    let file = process::with_files(|files| files.get(descriptor));
+    Ok(file.ok_or(Error::BadFileDescriptor)??)
+}
+
+fn file_type(kind: FileType) -> u64 {
+    match kind {
+        FileType::File => abi::TYPE_FILE,
+        FileType::Directory => abi::TYPE_DIRECTORY,
+        FileType::CharDevice => abi::TYPE_CHAR_DEVICE,
+        FileType::Symlink => abi::TYPE_SYMLINK,
+    }
+}
+
+impl From<vfs::Error> for Error {
+    fn from(error: vfs::Error) -> Self {
+        match error {
+            vfs::Error::NotFound => Error::NotFound,
+            vfs::Error::NotDirectory => Error::NotDirectory,
+            vfs::Error::IsDirectory => Error::IsDirectory,
+            vfs::Error::AlreadyExists => Error::AlreadyExists,
+            vfs::Error::NotEmpty => Error::NotEmpty,
+            vfs::Error::InvalidPath | vfs::Error::NotSupported | vfs::Error::InvalidOffset => {
+                Error::InvalidArgument
+            }
+            vfs::Error::ReadOnly => Error::ReadOnly,
+            vfs::Error::NoSpace => Error::NoSpace,
+            vfs::Error::Busy => Error::Busy,
+            vfs::Error::Io => Error::Io,
+            vfs::Error::BadDescriptor => Error::BadFileDescriptor,
+            vfs::Error::TooManyFiles => Error::TooManyFiles,
+            vfs::Error::TooManyLinks => Error::TooManyLinks,
+        }
+    }
+}
+
+fn process_error(error: ProcessError) -> Error {
+    match error {
+        ProcessError::Elf(ElfError::OutOfMemory)
+        | ProcessError::Thread(_)
+        | ProcessError::OutOfMemory => Error::OutOfMemory,
+        ProcessError::Elf(ElfError::ArgumentsTooLong) => Error::ArgumentsTooLong,
+        ProcessError::Elf(_) => Error::NotExecutable,
+        ProcessError::NotAProcess => Error::NoSuchProcess,
+    }
+}
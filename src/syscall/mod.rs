@@ -0,0 +1,291 @@
+//! System calls from user mode through the `syscall` instruction.
+//!
+//! The calling convention, numbers and errors are defined in `abi`. The entry saves the user
+//! registers and looks the call up in a table indexed by its number.
+
+pub mod abi;
+mod calls;
+mod user;
+
+use core::arch::global_asm;
+use core::convert::TryFrom;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use crate::gdt;
+use crate::smp::percpu::{KERNEL_STACK_OFFSET, USER_STACK_OFFSET};
+use crate::userspace::{self, Registers};
+use abi::Error;
+
+/// The arguments of a system call, in the order of the registers that pass them.
+pub type Arguments = [u64; 6];
+
+type Handler = fn(Arguments) -> Result<u64, Error>;
+
+/// Handlers of the system calls, indexed by number.
+static HANDLERS: [Handler; abi::COUNT] = {
+    let mut handlers: [Handler; abi::COUNT] = [not_implemented; abi::COUNT];
+    handlers[abi::EXIT as usize] = calls::exit;
+    handlers[abi::WRITE as usize] = calls::write;
+    handlers[abi::READ as usize] = calls::read;
+    handlers[abi::GETPID as usize] = calls::getpid;
+    handlers[abi::SLEEP as usize] = calls::sleep;
+    handlers[abi::YIELD as usize] = calls::yield_now;
+    handlers[abi::MMAP as usize] = calls::mmap;
+    // `fork` is handled by `handle`, it needs all registers of the caller.
+    handlers[abi::EXEC as usize] = calls::exec;
+    handlers[abi::WAITPID as usize] = calls::waitpid;
+    handlers[abi::OPEN as usize] = calls::open;
+    handlers[abi::CLOSE as usize] = calls::close;
+    handlers[abi::SEEK as usize] = calls::seek;
+    handlers[abi::STAT as usize] = calls::stat;
+    handlers[abi::READDIR as usize] = calls::readdir;
+    handlers
+};
+
+// `syscall` stores the user RIP in RCX and RFLAGS in R11 and masks interrupts, but does not switch
+// stacks or the GS base. The entry switches to the per-CPU data and the kernel stack of the current
+// CPU, and saves the user registers as a `Frame`.
+global_asm!(
+    ".global ferocios_syscall_entry",
+    "ferocios_syscall_entry:",
+    "swapgs",
+    "mov gs:[{user_stack}], rsp",
+    "mov rsp, gs:[{kernel_stack}]",
+    "and rsp, -16",
+    "push qword ptr gs:[{user_stack}]",
+    "push r11",
+    "push rcx",
+    "push r9",
+    "push r8",
+    "push r10",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rax",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // The frame is saved, other CPUs and threads may use the per-CPU scratch space from here on.
+    "mov rdi, rsp",
+    "sti",
+    "call {handler}",
+    "cli",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "pop rax",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop r10",
+    "pop r8",
+    "pop r9",
+    "pop rcx",
+    "pop r11",
+    "pop rsp",
+    "swapgs",
+    "sysretq",
+    kernel_stack = const KERNEL_STACK_OFFSET,
+    user_stack = const USER_STACK_OFFSET,
+    handler = sym handle,
+);
+
+extern "C" {
+    fn ferocios_syscall_entry();
+}
+
+/// User registers saved by the entry, in the order they are pushed. `rcx` and `r11` hold the
+/// instruction pointer and flags.
+#[repr(C)]
+struct Frame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbp: u64,
+    rbx: u64,
+    rax: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    r10: u64,
+    r8: u64,
+    r9: u64,
+    rip: u64,
+    rflags: u64,
+    rsp: u64,
+}
+
+impl Frame {
+    /// Returns the registers of the user code, with the result of the system call in `rax`.
+    fn registers(&self) -> Registers {
+        Registers {
+            rax: self.rax,
+            rbx: self.rbx,
+            rcx: self.rip,
+            rdx: self.rdx,
+            rsi: self.rsi,
+            rdi: self.rdi,
+            rbp: self.rbp,
+            r8: self.r8,
+            r9: self.r9,
+            r10: self.r10,
+            r11: self.rflags,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rip: self.rip,
+            rflags: self.rflags,
+            rsp: self.rsp,
+        }
+    }
+}
+
+/// Enables `syscall` on the current CPU. The GDT must be loaded.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("GDT does not have the segment order `syscall` expects");
+    LStar::write(VirtAddr::from_ptr(ferocios_syscall_entry as *const ()));
+    // Interrupts stay disabled until the entry is on the kernel stack.
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe { Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS) }
+}
+
+extern "C" fn handle(frame: &mut Frame) {
+    // `sysretq` faults in the kernel on a non-canonical return address, which a `syscall` at the
+    // very end of user memory leads to. The program is ended like on any other fault.
+    if VirtAddr::try_new(frame.rip).is_err() {
+        userspace::exit(abi::FAULT_EXIT_CODE)
+    }
+    let arguments = [
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ];
+    frame.rax = if frame.rax == abi::FORK {
+        abi::encode(calls::fork(frame.registers()))
+    } else {
+        dispatch(frame.rax, arguments)
+    }
+}
+
+fn dispatch(number: u64, arguments: Arguments) -> u64 {
+    let handler = usize::try_from(number)
+        .ok()
+        .and_then(|number| HANDLERS.get(number))
+        .copied()
+        .unwrap_or(not_implemented);
+    abi::encode(handler(arguments))
+}
+
+fn not_implemented(_: Arguments) -> Result<u64, Error> {
+    Err(Error::NotImplemented)
+}
+
+#[test_case]
+fn results_are_encoded() {
+    for &error in [Error::BadAddress, Error::NotImplemented].iter() {
+        let value = abi::encode(Err(error));
+        assert!(value as i64 >= -(abi::MAX_ERROR as i64) && (value as i64) < 0);
+        assert_eq!(abi::decode(value), Err(error));
+    }
+    assert_eq!(abi::decode(abi::encode(Ok(42))), Ok(42));
+    // Addresses in the higher half are no errors.
+    assert_eq!(
+        abi::decode(0xFFFF_8000_0000_0000),
+        Ok(0xFFFF_8000_0000_0000)
+    );
+}
+
+#[test_case]
+fn unknown_system_call() {
+    let result = dispatch(abi::COUNT as u64, [0; 6]);
+    assert_eq!(abi::decode(result), Err(Error::NotImplemented));
+    assert_eq!(
+        abi::decode(dispatch(u64::MAX, [0; 6])),
+        Err(Error::NotImplemented)
+    );
+}
+
+#[test_case]
+fn kernel_memory_is_rejected() {
+    let kernel_buffer = [0u8; 16];
+    let address = kernel_buffer.as_ptr() as u64;
+    let result = dispatch(abi::WRITE, [abi::STDOUT, address, 16, 0, 0, 0]);
+    assert_eq!(abi::decode(result), Err(Error::BadAddress));
+
+    // Wrapping around the address space is no way around the check.
+    let result = dispatch(abi::WRITE, [abi::STDOUT, u64::MAX, 2, 0, 0, 0]);
+    assert_eq!(abi::decode(result), Err(Error::BadAddress));
+}
+
+#[test_case]
+fn mmap_maps_user_memory() {
+    use crate::memory::{self, paging};
+    use x86_64::structures::paging::{Page, PageTableFlags};
+
+    let protection = abi::PROT_READ | abi::PROT_WRITE;
+    let address = abi::decode(dispatch(abi::MMAP, [0, 5000, protection, 0, 0, 0])).unwrap();
+    let flags = paging::page_flags(VirtAddr::new(address + 4096)).unwrap();
+    assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE));
+    assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+
+    // The memory is zeroed and can be passed to system calls.
+    let buffer = user::slice_mut(address, 8192).unwrap();
+    assert!(buffer.iter().all(|&byte| byte == 0));
+    assert_eq!(user::slice(address + 8192, 1), Err(Error::BadAddress));
+    let result = dispatch(abi::WRITE, [42, address, 1, 0, 0, 0]);
+    assert_eq!(abi::decode(result), Err(Error::BadFileDescriptor));
+
+    // Mapping over it fails.
+    let result = dispatch(abi::MMAP, [address, 4096, protection, 0, 0, 0]);
+    assert_eq!(abi::decode(result), Err(Error::AlreadyExists));
+
+    // The last user page is never mapped.
+    let result = dispatch(abi::MMAP, [calls::MMAP_END, 4096, protection, 0, 0, 0]);
+    assert_eq!(abi::decode(result), Err(Error::OutOfMemory));
+
+    let start = Page::containing_address(VirtAddr::new(address));
+    for page in Page::range(start, start + 2) {
+        memory::deallocate_frame(paging::unmap_page(page).unwrap())
+    }
+}
+
+#[test_case]
+fn file_system_calls() {
+    use crate::process;
+    use crate::vfs::{self, tmpfs::TmpFs};
+    use alloc::sync::Arc;
+
+    // An empty /tmp, whatever other tests left there.
+    vfs::mount("/tmp", Arc::new(TmpFs::new())).unwrap();
+    let files = vfs::read_file("/bin/files").unwrap();
+    let id = process::spawn(&files, &["files"], &[]).unwrap();
+    // See `user/files.s`.
+    let code = 4 | 5 << 8 | 1 << 16 | 4 << 24 | 3 << 32;
+    assert_eq!(process::wait(Some(id)), Some((id, code)));
+    assert_eq!(vfs::stat("/tmp/file").unwrap().size, 5);
+    vfs::unmount("/tmp").unwrap();
+
+    // Kernel threads have no files.
+    let result = dispatch(abi::CLOSE, [abi::STDOUT, 0, 0, 0, 0, 0]);
+    assert_eq!(abi::decode(result), Err(Error::BadFileDescriptor));
+}
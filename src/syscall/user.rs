@@ -0,0 +1,101 @@
+//! Access to user memory passed to system calls.
+//!
+//! Pointers from user programs are only used after checking that every page they cover is mapped
+//! and accessible from user mode, so a program cannot make the kernel read or write kernel memory.
+
+use alloc::vec::Vec;
+use core::{slice, str};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use super::abi::Error;
+use crate::memory::address_space::{self, COPY_ON_WRITE};
+use crate::memory::paging;
+use crate::userspace::{USER_END, USER_START};
+
+/// Longest string, without the zero byte at its end.
+const MAX_STRING_LENGTH: u64 = 4096;
+/// Most strings in an array.
+const MAX_STRINGS: u64 = 1024;
+
+/// Returns the user memory as a slice, if the program may read it.
+pub fn slice<'a>(address: u64, length: u64) -> Result<&'a [u8], Error> {
+    if length == 0 {
+        return Ok(&[]);
+    }
+    check(address, length, PageTableFlags::USER_ACCESSIBLE)?;
+    Ok(unsafe { slice::from_raw_parts(address as *const u8, length as usize) })
+}
+
+/// Returns the user memory as a mutable slice, if the program may write it.
+pub fn slice_mut<'a>(address: u64, length: u64) -> Result<&'a mut [u8], Error> {
+    if length == 0 {
+        return Ok(&mut []);
+    }
+    check(
+        address,
+        length,
+        PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE,
+    )?;
+    Ok(unsafe { slice::from_raw_parts_mut(address as *mut u8, length as usize) })
+}
+
+/// Returns the UTF-8 string that ends with a zero byte at the address, without the zero byte.
+pub fn string<'a>(address: u64) -> Result<&'a str, Error> {
+    let mut end = address;
+    loop {
+        // Check one page at a time, the page after the end of the string may not be mapped.
+        let page_rest = 4096 - end % 4096;
+        match slice(end, page_rest)?.iter().position(|&byte| byte == 0) {
+            Some(length) => {
+                end += length as u64;
+                break;
+            }
+            None => end += page_rest,
+        }
+        if end - address > MAX_STRING_LENGTH {
+            return Err(Error::ArgumentsTooLong);
+        }
+    }
+    str::from_utf8(slice(address, end - address)?).map_err(|_| Error::InvalidArgument)
+}
+
+/// Returns the strings of an array of string pointers that ends with a null pointer. A null array
+/// is empty.
+pub fn strings<'a>(address: u64) -> Result<Vec<&'a str>, Error> {
+    let mut strings = Vec::new();
+    if address == 0 {
+        return Ok(strings);
+    }
+    for i in 0..=MAX_STRINGS {
+        let entry = address.checked_add(i * 8).ok_or(Error::BadAddress)?;
+        let mut pointer = [0; 8];
+        pointer.copy_from_slice(slice(entry, 8)?);
+        match u64::from_le_bytes(pointer) {
+            0 => return Ok(strings),
+            pointer => strings.push(string(pointer)?),
+        }
+    }
+    Err(Error::ArgumentsTooLong)
+}
+
+fn check(address: u64, length: u64, flags: PageTableFlags) -> Result<(), Error> {
+    let end = address.checked_add(length).ok_or(Error::BadAddress)?;
+    if address < USER_START || end > USER_END {
+        return Err(Error::BadAddress);
+    }
+
+    let first: Page = Page::containing_address(VirtAddr::new(address));
+    let last = Page::containing_address(VirtAddr::new(end - 1));
+    for page in Page::range_inclusive(first, last) {
+        match paging::page_flags(page.start_address()) {
+            Some(page_flags) if page_flags.contains(flags) => (),
+            // Copy the page up front, so kernel writes never depend on the page fault handler.
+            Some(page_flags)
+                if page_flags.contains(COPY_ON_WRITE)
+                    && address_space::copy_on_write(page.start_address()) => {}
+            _ => return Err(Error::BadAddress),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use core::arch::global_asm;
+use x86_64::VirtAddr;
+
+// Saves the callee-saved registers on the current stack, stores the stack pointer in `*old_rsp`,
+// then loads `new_rsp` and restores the registers saved there. Everything else is either
+// caller-saved or, for the interrupt flag, restored by the code that disabled interrupts.
+global_asm!(
+    ".global ferocios_switch_context",
+    "ferocios_switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
+
+extern "C" {
+    fn ferocios_switch_context(old_rsp: *mut u64, new_rsp: u64);
+}
+
+/// Switches to the stack saved in `new_rsp`, storing the current one in `old_rsp`.
+///
+/// # Safety
+///
+/// Interrupts must be disabled and `new_rsp` must come from a previous switch or from
+/// `initial_stack`.
+pub unsafe fn switch(old_rsp: *mut u64, new_rsp: u64) {
+    ferocios_switch_context(old_rsp, new_rsp)
+}
+
+/// Prepares a fresh stack so that switching to it starts executing `entry`.
+///
+/// # Safety
+///
+/// `stack_top` must be the writable top of an unused stack.
+pub unsafe fn initial_stack(stack_top: VirtAddr, entry: extern "C" fn() -> !) -> u64 {
+    const CALLEE_SAVED_REGISTERS: usize = 6;
+
+    let mut rsp = stack_top.align_down(16u64).as_mut_ptr::<u64>();
+    let mut push = |value: u64| {
+        rsp = rsp.sub(1);
+        rsp.write(value)
+    };
+
+    // `entry` is entered through `ret`, so it sees this dummy return address and a correctly
+    // aligned stack, like any other function.
+    push(0);
+    push(entry as usize as u64);
+    for _ in 0..CALLEE_SAVED_REGISTERS {
+        push(0)
+    }
+    rsp as u64
+}
@@ -0,0 +1,235 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use x86_64::instructions::interrupts;
+
+/// Maximum amount of tasks that can be woken up at the same time.
+const TASK_QUEUE_CAPACITY: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Asynchronous task polled by the `Executor`.
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Self {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+/// Cooperative executor that only polls tasks once their waker was called.
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_CAPACITY)),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        let id = task.id;
+        if self.tasks.insert(id, task).is_some() {
+            panic!("Task with same ID already spawned");
+        }
+        self.task_queue.push(id).expect("Task queue is full");
+    }
+
+    /// Runs the tasks forever.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle()
+        }
+    }
+
+    /// Runs the tasks until all of them are done.
+    #[allow(dead_code)]
+    pub fn run_until_done(&mut self) {
+        loop {
+            self.run_ready_tasks();
+            if self.tasks.is_empty() {
+                return;
+            }
+            self.sleep_if_idle()
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        while let Some(id) = self.task_queue.pop() {
+            let task = match self.tasks.get_mut(&id) {
+                Some(task) => task,
+                // The task is already done.
+                None => continue,
+            };
+            let task_queue = &self.task_queue;
+            let task_waker = self
+                .waker_cache
+                .entry(id)
+                .or_insert_with(|| TaskWaker::new(id, task_queue.clone()));
+            // Wakeups from here on queue the task again.
+            task_waker.queued.store(false, Ordering::SeqCst);
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
+            if let Poll::Ready(()) = task.poll(&mut context) {
+                self.tasks.remove(&id);
+                self.waker_cache.remove(&id);
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // Interrupts are disabled for the check, otherwise a wake up from an interrupt handler
+        // between the check and `hlt` would be missed until the next interrupt.
+        interrupts::disable();
+        if self.task_queue.is_empty() {
+            interrupts::enable_and_hlt()
+        } else {
+            interrupts::enable()
+        }
+    }
+}
+
+struct TaskWaker {
+    id: TaskId,
+    // The task is in the queue and not polled yet, so waking it again has no effect.
+    queued: AtomicBool,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+}
+
+impl TaskWaker {
+    fn new(id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Arc<Self> {
+        Arc::new(TaskWaker {
+            id,
+            queued: AtomicBool::new(false),
+            task_queue,
+        })
+    }
+
+    /// Called from interrupt handlers as well, so it must not panic.
+    fn wake_task(&self) {
+        if self.queued.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Every task is queued at most once, so the queue is only full with more tasks woken at
+        // the same time than it holds. The wakeup is lost then, but a later one can queue it.
+        if self.task_queue.push(self.id).is_err() {
+            self.queued.store(false, Ordering::SeqCst)
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task()
+    }
+}
+
+/// Future that is pending once, giving the other ready tasks a chance to run.
+#[cfg(test)]
+async fn yield_task() {
+    let mut yielded = false;
+    core::future::poll_fn(|context| {
+        if yielded {
+            Poll::Ready(())
+        } else {
+            yielded = true;
+            context.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+#[test_case]
+fn tasks_run_to_completion() {
+    use core::sync::atomic::AtomicUsize;
+
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    for _ in 0..3 {
+        executor.spawn(Task::new(async {
+            DONE.fetch_add(1, Ordering::SeqCst);
+        }));
+    }
+    executor.run_until_done();
+    assert_eq!(DONE.load(Ordering::SeqCst), 3);
+}
+
+#[test_case]
+fn tasks_interleave() {
+    use alloc::vec::Vec;
+    use spinning::Mutex;
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let mut executor = Executor::new();
+    for &name in ["a", "b"].iter() {
+        let log = log.clone();
+        executor.spawn(Task::new(async move {
+            for _ in 0..2 {
+                interrupts::without_interrupts(|| log.lock().push(name));
+                yield_task().await
+            }
+        }));
+    }
+    executor.run_until_done();
+
+    let log = interrupts::without_interrupts(|| log.lock().clone());
+    assert_eq!(log, ["a", "b", "a", "b"]);
+}
+
+#[test_case]
+fn wakeups_queue_a_task_once() {
+    use core::sync::atomic::AtomicUsize;
+
+    static POLLS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(core::future::poll_fn(|context| {
+        if POLLS.fetch_add(1, Ordering::SeqCst) > 0 {
+            return Poll::Ready(());
+        }
+        // More wakeups than the queue holds, like from many timer interrupts.
+        for _ in 0..2 * TASK_QUEUE_CAPACITY {
+            context.waker().wake_by_ref()
+        }
+        Poll::Pending
+    })));
+    executor.run_until_done();
+    assert_eq!(POLLS.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,26 @@
+//! Preemptive kernel threads and cooperative asynchronous tasks.
+//!
+//! Threads are scheduled round-robin and switched from the timer interrupt once their time slice
+//! is used up. All scheduler state is only touched with interrupts disabled.
+//!
+//! Asynchronous tasks run on an `Executor`, which itself runs on a thread. They are only polled
+//! when woken up, for example by the keyboard or timer interrupt.
+
+mod context;
+pub mod executor;
+mod scheduler;
+mod stack;
+mod thread;
+pub mod timer;
+
+pub use scheduler::init;
+#[allow(unused_imports)]
+pub use scheduler::{block, block_for, current_id, exit, sleep, spawn, threads, wake, yield_now};
+#[allow(unused_imports)]
+pub use thread::{State, ThreadId};
+
+/// Called from the timer interrupt handler, with interrupts disabled.
+pub fn on_tick() {
+    timer::wake_expired();
+    scheduler::preempt()
+}
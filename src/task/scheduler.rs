@@ -0,0 +1,412 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use spinning::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+
+use super::context;
+use super::stack::{Stack, DEFAULT_STACK_SIZE};
+use super::thread::{State, Thread, ThreadId};
+use crate::memory::demand::RegionError;
+use crate::smp::percpu;
+use crate::time::{self, Instant};
+
+/// Amount of timer ticks a thread may run before it is preempted, 10 ms at the default frequency.
+const TIME_SLICE_TICKS: u64 = 10;
+
+lazy_static! {
+    static ref SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+}
+
+// Set once the scheduler exists, so the timer interrupt never has to touch `SCHEDULER` before.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Round-robin scheduler of kernel threads.
+struct Scheduler {
+    // Threads are boxed so the saved stack pointers keep their address while switching.
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    ready: VecDeque<ThreadId>,
+    current: ThreadId,
+    // Runs when no other thread is ready, it is never put in the ready queue.
+    idle: ThreadId,
+    exited: Vec<ThreadId>,
+    slice_end: u64,
+}
+
+impl Scheduler {
+    fn new() -> Result<Self, RegionError> {
+        let boot = Box::new(Thread::boot());
+        let idle = Box::new(Thread::new(
+            "idle",
+            Stack::new(DEFAULT_STACK_SIZE)?,
+            thread_entry,
+            Box::new(idle),
+        ));
+
+        let mut scheduler = Scheduler {
+            threads: BTreeMap::new(),
+            ready: VecDeque::new(),
+            current: boot.id,
+            idle: idle.id,
+            exited: Vec::new(),
+            slice_end: 0,
+        };
+        scheduler.threads.insert(boot.id, boot);
+        scheduler.threads.insert(idle.id, idle);
+        Ok(scheduler)
+    }
+
+    fn current_mut(&mut self) -> &mut Thread {
+        self.threads
+            .get_mut(&self.current)
+            .expect("Current thread is missing")
+    }
+
+    fn add(&mut self, thread: Thread) -> ThreadId {
+        let id = thread.id;
+        self.threads.insert(id, Box::new(thread));
+        self.ready.push_back(id);
+        id
+    }
+
+    fn wake_sleepers(&mut self, now: Instant) {
+        for thread in self.threads.values_mut() {
+            if let State::Sleeping(until) | State::BlockedUntil(until) = thread.state {
+                if until <= now {
+                    thread.state = State::Ready;
+                    self.ready.push_back(thread.id)
+                }
+            }
+        }
+    }
+
+    /// Picks the thread to run next. Returns where to save the current stack pointer and the stack
+    /// pointer to switch to, or `None` if the current thread keeps running.
+    fn next_switch(&mut self) -> Option<(*mut u64, u64)> {
+        self.wake_sleepers(Instant::now());
+
+        let current = self.current;
+        let next = match self.ready.pop_front() {
+            Some(id) => id,
+            None if self.threads[&current].state == State::Running => return None,
+            None => self.idle,
+        };
+        self.slice_end = time::ticks() + TIME_SLICE_TICKS;
+        if next == current {
+            self.current_mut().state = State::Running;
+            return None;
+        }
+
+        match self.threads[&current].state {
+            State::Running => {
+                self.current_mut().state = State::Ready;
+                if current != self.idle {
+                    self.ready.push_back(current)
+                }
+            }
+            State::Exited => self.exited.push(current),
+            State::Ready | State::Sleeping(_) | State::Blocked | State::BlockedUntil(_) => (),
+        }
+        // The address space and the kernel stack for user mode belong to the thread, like the
+        // stack pointer.
+        let cpu = percpu::current();
+        let (level_4_frame, _) = Cr3::read();
+        let current_thread = self.current_mut();
+        current_thread.kernel_stack = cpu.kernel_stack();
+        current_thread.level_4_frame = level_4_frame;
+        let old_rsp = &mut current_thread.rsp as *mut u64;
+
+        let next_thread = self.threads.get_mut(&next).expect("Next thread is missing");
+        next_thread.state = State::Running;
+        cpu.set_kernel_stack(next_thread.kernel_stack);
+        if next_thread.level_4_frame != level_4_frame {
+            unsafe { Cr3::write(next_thread.level_4_frame, Cr3Flags::empty()) }
+        }
+        self.current = next;
+        Some((old_rsp, next_thread.rsp))
+    }
+
+    /// Removes the exited threads, except the current one whose stack is still in use.
+    fn take_exited(&mut self) -> Vec<Thread> {
+        let current = self.current;
+        let (running, exited) = self.exited.drain(..).partition(|&id| id == current);
+        self.exited = running;
+        exited
+            .into_iter()
+            .filter_map(|id: ThreadId| self.threads.remove(&id))
+            .map(|thread| *thread)
+            .collect()
+    }
+}
+
+pub fn init() {
+    let scheduler = Scheduler::new().expect("Creating the idle thread failed");
+    interrupts::without_interrupts(|| *SCHEDULER.lock() = Some(scheduler));
+    INITIALIZED.store(true, Ordering::SeqCst)
+}
+
+fn with_scheduler<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Scheduler) -> R,
+{
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        f(scheduler.as_mut().expect("Scheduler is not initialized"))
+    })
+}
+
+/// Switches to the next thread, if there is one. Interrupts must be disabled.
+fn schedule() {
+    let switch = with_scheduler(|scheduler| scheduler.next_switch());
+    if let Some((old_rsp, new_rsp)) = switch {
+        unsafe { context::switch(old_rsp, new_rsp) }
+    }
+    drop_exited()
+}
+
+fn drop_exited() {
+    // Dropping the threads frees their stacks, which is done without holding the scheduler lock.
+    let exited = with_scheduler(|scheduler| scheduler.take_exited());
+    drop(exited)
+}
+
+/// Switches threads if the time slice is used up. Interrupts must be disabled.
+pub fn preempt() {
+    if !INITIALIZED.load(Ordering::SeqCst) {
+        return;
+    }
+    if with_scheduler(|scheduler| time::ticks() >= scheduler.slice_end) {
+        schedule()
+    }
+}
+
+/// Starts a new kernel thread running `f`.
+pub fn spawn<F>(name: &'static str, f: F) -> Result<ThreadId, RegionError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let stack = Stack::new(DEFAULT_STACK_SIZE)?;
+    let thread = Thread::new(name, stack, thread_entry, Box::new(f));
+    Ok(with_scheduler(|scheduler| scheduler.add(thread)))
+}
+
+/// Gives up the rest of the time slice to the other ready threads.
+pub fn yield_now() {
+    interrupts::without_interrupts(schedule)
+}
+
+/// Blocks the current thread for at least the given duration.
+pub fn sleep(duration: Duration) {
+    let until = Instant::now() + duration;
+    interrupts::without_interrupts(|| {
+        with_scheduler(|scheduler| scheduler.current_mut().state = State::Sleeping(until));
+        schedule()
+    })
+}
+
+/// Blocks the current thread until `wake` is called for it. Interrupts must be disabled, so that a
+/// `wake` cannot come between checking what to wait for and blocking.
+pub fn block() {
+    with_scheduler(|scheduler| scheduler.current_mut().state = State::Blocked);
+    schedule()
+}
+
+/// Like `block`, but the thread is woken up after the duration at the latest. Interrupts must be
+/// disabled.
+pub fn block_for(duration: Duration) {
+    let until = Instant::now() + duration;
+    with_scheduler(|scheduler| scheduler.current_mut().state = State::BlockedUntil(until));
+    schedule()
+}
+
+/// Makes the thread ready again if it is blocked.
+pub fn wake(id: ThreadId) {
+    with_scheduler(|scheduler| {
+        if let Some(thread) = scheduler.threads.get_mut(&id) {
+            if let State::Blocked | State::BlockedUntil(_) = thread.state {
+                thread.state = State::Ready;
+                scheduler.ready.push_back(id)
+            }
+        }
+    })
+}
+
+/// Ends the current thread.
+pub fn exit() -> ! {
+    interrupts::disable();
+    with_scheduler(|scheduler| scheduler.current_mut().state = State::Exited);
+    schedule();
+    unreachable!("Exited thread was scheduled again")
+}
+
+pub fn current_id() -> ThreadId {
+    with_scheduler(|scheduler| scheduler.current)
+}
+
+/// Returns the ID, name and state of every thread that has not been removed after exiting.
+pub fn threads() -> Vec<(ThreadId, &'static str, State)> {
+    with_scheduler(|scheduler| {
+        scheduler
+            .threads
+            .values()
+            .map(|thread| (thread.id, thread.name, thread.state))
+            .collect()
+    })
+}
+
+// Every new thread starts here, through the stack prepared by `context::initial_stack`.
+extern "C" fn thread_entry() -> ! {
+    // Finish the switch that got us here, like `schedule` does when returning to a thread.
+    drop_exited();
+    let entry = with_scheduler(|scheduler| scheduler.current_mut().take_entry())
+        .expect("Thread started without entry");
+
+    interrupts::enable();
+    entry();
+    exit()
+}
+
+fn idle() {
+    loop {
+        interrupts::enable_and_hlt();
+        yield_now()
+    }
+}
+
+#[cfg(test)]
+fn wait_until(done: impl Fn() -> bool) {
+    while !done() {
+        yield_now()
+    }
+}
+
+#[test_case]
+fn threads_interleave() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicUsize;
+
+    const STEPS: usize = 5;
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let finished = Arc::new(AtomicUsize::new(0));
+
+    for &name in ["a", "b"].iter() {
+        let log = log.clone();
+        let finished = finished.clone();
+        spawn(name, move || {
+            for _ in 0..STEPS {
+                interrupts::without_interrupts(|| log.lock().push(name));
+                yield_now()
+            }
+            finished.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap();
+    }
+    wait_until(|| finished.load(Ordering::SeqCst) == 2);
+
+    let log = interrupts::without_interrupts(|| log.lock().clone());
+    assert_eq!(log.len(), 2 * STEPS);
+    // Both threads ran before either of them was done.
+    let first_b = log.iter().position(|&name| name == "b").unwrap();
+    let last_a = log.iter().rposition(|&name| name == "a").unwrap();
+    assert!(first_b < last_a);
+}
+
+#[test_case]
+fn thread_exit() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicUsize;
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let thread_counter = counter.clone();
+    spawn("exit", move || {
+        thread_counter.fetch_add(1, Ordering::SeqCst);
+        exit();
+    })
+    .unwrap();
+
+    wait_until(|| counter.load(Ordering::SeqCst) == 1);
+    // Give the thread the chance to run past `exit`.
+    yield_now();
+    assert_eq!(counter.load(Ordering::SeqCst), 1);
+}
+
+#[test_case]
+fn thread_sleep() {
+    use alloc::sync::Arc;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let thread_done = done.clone();
+    let duration = Duration::from_millis(100);
+    let start = Instant::now();
+    spawn("sleep", move || {
+        sleep(duration);
+        thread_done.store(true, Ordering::SeqCst)
+    })
+    .unwrap();
+
+    wait_until(|| done.load(Ordering::SeqCst));
+    assert!(start.elapsed() >= duration);
+}
+
+#[test_case]
+fn block_and_wake() {
+    use alloc::sync::Arc;
+
+    let id = current_id();
+    let woken = Arc::new(AtomicBool::new(false));
+    let thread_woken = woken.clone();
+    // The thread cannot run before this one blocks, which would make the wake-up get lost.
+    interrupts::without_interrupts(|| {
+        spawn("wake", move || {
+            thread_woken.store(true, Ordering::SeqCst);
+            wake(id)
+        })
+        .unwrap();
+        block()
+    });
+    assert!(woken.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn block_times_out() {
+    let duration = Duration::from_millis(50);
+    let start = Instant::now();
+    interrupts::without_interrupts(|| block_for(duration));
+    assert!(start.elapsed() >= duration);
+}
+
+#[test_case]
+fn list_threads() {
+    let threads = threads();
+    let current = threads
+        .iter()
+        .find(|&&(id, _, _)| id == current_id())
+        .unwrap();
+    assert_eq!(current.2, State::Running);
+    assert!(threads.iter().any(|&(_, name, _)| name == "idle"));
+}
+
+#[test_case]
+fn preemption() {
+    use alloc::sync::Arc;
+
+    // The thread never yields, so the test only continues if the timer preempts it.
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = Arc::new(AtomicBool::new(false));
+    let (thread_stop, thread_stopped) = (stop.clone(), stopped.clone());
+    spawn("spin", move || {
+        while !thread_stop.load(Ordering::SeqCst) {
+            core::hint::spin_loop()
+        }
+        thread_stopped.store(true, Ordering::SeqCst)
+    })
+    .unwrap();
+
+    yield_now();
+    stop.store(true, Ordering::SeqCst);
+    wait_until(|| stopped.load(Ordering::SeqCst));
+}
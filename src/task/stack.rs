@@ -0,0 +1,71 @@
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spinning::Mutex;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::memory::demand::{self, Region, RegionError};
+
+const STACKS_START: u64 = 0xFFFF_D000_0000_0000;
+/// Every stack gets a slot of this size, holding the stack and the guard page below it.
+const SLOT_SIZE: u64 = 128 * 1024;
+const GUARD_SIZE: u64 = 4096;
+
+pub const DEFAULT_STACK_SIZE: u64 = 64 * 1024;
+
+lazy_static! {
+    static ref SLOTS: Mutex<Slots> = Mutex::new(Slots {
+        next: 0,
+        free: Vec::new(),
+    });
+}
+
+struct Slots {
+    next: u64,
+    free: Vec<u64>,
+}
+
+/// Kernel stack backed on demand, with a guard page below it to catch overflows.
+#[derive(Debug)]
+pub struct Stack {
+    slot: u64,
+    size: u64,
+}
+
+impl Stack {
+    pub fn new(size: u64) -> Result<Self, RegionError> {
+        assert!(size.is_multiple_of(4096) && size + GUARD_SIZE <= SLOT_SIZE);
+
+        let slot = x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut slots = SLOTS.lock();
+            slots.free.pop().unwrap_or_else(|| {
+                slots.next += 1;
+                slots.next - 1
+            })
+        });
+        let stack = Stack { slot, size };
+
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        demand::register(Region::lazy("thread stack", stack.bottom(), size, flags))?;
+        // On failure, dropping the stack unregisters whatever has been registered.
+        let guard = Region::guard("thread stack", stack.bottom() - GUARD_SIZE, GUARD_SIZE);
+        demand::register(guard)?;
+        Ok(stack)
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        VirtAddr::new(STACKS_START + (self.slot + 1) * SLOT_SIZE)
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.top() - self.size
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        demand::unregister(self.bottom() - GUARD_SIZE);
+        demand::unregister(self.bottom());
+        x86_64::instructions::interrupts::without_interrupts(|| SLOTS.lock().free.push(self.slot))
+    }
+}
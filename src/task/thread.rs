@@ -0,0 +1,98 @@
+use alloc::boxed::Box;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+use super::context;
+use super::stack::Stack;
+use crate::memory::address_space;
+use crate::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Ready,
+    Sleeping(Instant),
+    /// Waiting for `scheduler::wake`.
+    Blocked,
+    /// Waiting for `scheduler::wake`, at most until the instant.
+    BlockedUntil(Instant),
+    Exited,
+}
+
+pub type Entry = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct Thread {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: State,
+    /// Stack pointer saved by the last context switch away from this thread.
+    pub rsp: u64,
+    /// Stack the CPU switches to when this thread enters the kernel from user mode.
+    pub kernel_stack: VirtAddr,
+    /// Level 4 page table of the address space the thread runs in.
+    pub level_4_frame: PhysFrame,
+    // Only kept to free the stack with the thread. The boot thread runs on the stack set up by the
+    // bootloader and has no stack of its own.
+    #[allow(dead_code)]
+    stack: Option<Stack>,
+    entry: Option<Entry>,
+}
+
+impl Thread {
+    /// Creates the thread for the code that is already running, which has to be the boot code.
+    pub fn boot() -> Self {
+        Thread {
+            id: ThreadId::new(),
+            name: "boot",
+            state: State::Running,
+            rsp: 0,
+            kernel_stack: VirtAddr::zero(),
+            level_4_frame: Cr3::read().0,
+            stack: None,
+            entry: None,
+        }
+    }
+
+    /// Creates a thread that will start at `trampoline`, which should run the entry closure.
+    pub fn new(
+        name: &'static str,
+        stack: Stack,
+        trampoline: extern "C" fn() -> !,
+        entry: Entry,
+    ) -> Self {
+        let rsp = unsafe { context::initial_stack(stack.top(), trampoline) };
+        Thread {
+            id: ThreadId::new(),
+            name,
+            state: State::Ready,
+            rsp,
+            kernel_stack: VirtAddr::zero(),
+            level_4_frame: address_space::kernel_level_4_frame(),
+            stack: Some(stack),
+            entry: Some(entry),
+        }
+    }
+
+    pub fn take_entry(&mut self) -> Option<Entry> {
+        self.entry.take()
+    }
+}
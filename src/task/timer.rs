@@ -0,0 +1,130 @@
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use spinning::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::time::Instant;
+
+/// Most timers the interrupt handler wakes at once, more are woken in further batches.
+const WAKE_BATCH: usize = 8;
+
+lazy_static! {
+    // Pending timers. Only the timers themselves add and remove their entries, outside of interrupt
+    // context, so the interrupt handler never drops a waker, which could free memory.
+    static ref TIMERS: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Entry {
+    id: u64,
+    deadline: Instant,
+    waker: Waker,
+    woken: bool,
+}
+
+/// Future that completes once the given duration has passed.
+pub struct Timer {
+    id: u64,
+    deadline: Instant,
+}
+
+#[allow(dead_code)]
+impl Timer {
+    pub fn after(duration: Duration) -> Self {
+        Timer::at(Instant::now() + duration)
+    }
+
+    pub fn at(deadline: Instant) -> Self {
+        Timer {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            deadline,
+        }
+    }
+}
+
+impl Future for Timer {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.deadline.has_passed() {
+            return Poll::Ready(());
+        }
+
+        // The timer interrupt cannot fire between the check and registering the waker.
+        interrupts::without_interrupts(|| {
+            if self.deadline.has_passed() {
+                return Poll::Ready(());
+            }
+            let mut timers = TIMERS.lock();
+            match timers.iter_mut().find(|entry| entry.id == self.id) {
+                Some(entry) => entry.waker.clone_from(context.waker()),
+                None => timers.push(Entry {
+                    id: self.id,
+                    deadline: self.deadline,
+                    waker: context.waker().clone(),
+                    woken: false,
+                }),
+            }
+            Poll::Pending
+        })
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let entry = interrupts::without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            let index = timers.iter().position(|entry| entry.id == self.id)?;
+            Some(timers.swap_remove(index))
+        });
+        // The waker is dropped after the lock is released.
+        drop(entry)
+    }
+}
+
+/// Wakes the timers that expired. Called from the timer interrupt handler.
+pub fn wake_expired() {
+    let now = Instant::now();
+    loop {
+        // The wakers are cloned and woken after the lock is released. The entries keep the
+        // wakers, so waking the clones frees no memory.
+        let mut batch: [Option<Waker>; WAKE_BATCH] = Default::default();
+        let mut count = 0;
+        for entry in TIMERS
+            .lock()
+            .iter_mut()
+            .filter(|entry| !entry.woken && entry.deadline <= now)
+            .take(WAKE_BATCH)
+        {
+            entry.woken = true;
+            batch[count] = Some(entry.waker.clone());
+            count += 1
+        }
+        for waker in batch.iter_mut().filter_map(Option::take) {
+            waker.wake()
+        }
+        if count < WAKE_BATCH {
+            break;
+        }
+    }
+}
+
+#[test_case]
+fn timer_after() {
+    use super::executor::{Executor, Task};
+
+    let duration = Duration::from_millis(100);
+    let start = Instant::now();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move { Timer::after(duration).await }));
+    executor.run_until_done();
+    assert!(start.elapsed() >= duration);
+    // The timer removed its entry when it was dropped.
+    assert!(interrupts::without_interrupts(|| TIMERS.lock().is_empty()));
+}
@@ -1,6 +1,19 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use crate::qemu::{exit_qemu, QemuExitCode};
+use crate::time::Instant;
 use crate::util::digit_width;
 
+// Set by `skip` for the running test.
+static SKIPPED: AtomicBool = AtomicBool::new(false);
+
+/// Reports the running test as skipped for the reason instead of passed. The test should return
+/// right after.
+pub fn skip(reason: &str) {
+    serial_print!("{} ", reason);
+    SKIPPED.store(true, Ordering::SeqCst)
+}
+
 pub fn test_runner(tests: &[&dyn Testable]) {
     let amount = tests.len();
     serial_println!("Running {} tests", amount);
@@ -50,7 +63,12 @@ where
             "",
             width = max_name_len - name.len() + 1
         );
+        let start = Instant::now();
         self();
-        serial_println!("[ok]")
+        if SKIPPED.swap(false, Ordering::SeqCst) {
+            serial_println!("[skipped]")
+        } else {
+            serial_println!("[ok] ({}ms)", start.elapsed().as_millis())
+        }
     }
 }
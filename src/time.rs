@@ -0,0 +1,201 @@
+//! Kernel time keeping based on the programmable interval timer (PIT).
+//!
+//! The PIT raises the timer interrupt at the configured frequency. Every tick adds the length of
+//! the PIT period to a monotonic clock, counted in PIT input cycles so no precision is lost to
+//! rounding. The wall clock time is the time of the RTC at boot plus the time since.
+
+use core::fmt;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::rtc;
+
+/// Frequency of the oscillator driving the PIT.
+const PIT_INPUT_HZ: u64 = 1_193_182;
+const PIT_CHANNEL_0_PORT: u16 = 0x40;
+const PIT_COMMAND_PORT: u16 = 0x43;
+// Channel 0, low then high byte of the divisor, mode 2 (rate generator), binary counting.
+const PIT_CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100;
+
+/// Frequency of the timer interrupt set up at boot.
+pub const TIMER_FREQUENCY_HZ: u32 = 1000;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// PIT input cycles since boot.
+static CYCLES: AtomicU64 = AtomicU64::new(0);
+/// PIT input cycles per tick. The PIT starts with a divisor of 65536.
+static DIVISOR: AtomicU64 = AtomicU64::new(65536);
+/// Unix time when the timer was started.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Starts the timer. The ACPI tables must be parsed, to read the RTC.
+pub fn init() {
+    BOOT_TIME.store(rtc::read().unix_time(), Ordering::Relaxed);
+    set_frequency(TIMER_FREQUENCY_HZ)
+}
+
+/// Programs the PIT to raise the timer interrupt at (about) the given frequency.
+///
+/// The frequency is rounded to the closest one the PIT supports, between ~18.2 Hz and ~1.19 MHz.
+pub fn set_frequency(frequency_hz: u32) {
+    let frequency_hz = u64::from(frequency_hz.max(1));
+    let divisor = ((PIT_INPUT_HZ + frequency_hz / 2) / frequency_hz).clamp(1, 65536);
+
+    interrupts::without_interrupts(|| {
+        let mut command = Port::<u8>::new(PIT_COMMAND_PORT);
+        let mut data = Port::<u8>::new(PIT_CHANNEL_0_PORT);
+        unsafe {
+            command.write(PIT_CHANNEL_0_RATE_GENERATOR);
+            // A divisor of 0 means 65536.
+            data.write(divisor as u8);
+            data.write((divisor >> 8) as u8);
+        }
+        DIVISOR.store(divisor, Ordering::SeqCst)
+    })
+}
+
+/// Returns the actual frequency of the timer interrupt, which is rounded down to whole hertz.
+#[allow(dead_code)]
+pub fn frequency() -> u64 {
+    PIT_INPUT_HZ / DIVISOR.load(Ordering::SeqCst)
+}
+
+/// Called from the timer interrupt handler.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    CYCLES.fetch_add(DIVISOR.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// Returns the amount of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the time since the timer was started, with the resolution of a timer tick.
+#[allow(dead_code)]
+pub fn uptime() -> Duration {
+    Instant::now().since_boot
+}
+
+/// Returns the seconds since 1970-01-01 00:00:00 UTC.
+pub fn unix_time() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + uptime().as_secs()
+}
+
+/// Point in time measured by the monotonic kernel clock.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    since_boot: Duration,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        let cycles = u128::from(CYCLES.load(Ordering::Relaxed));
+        let nanos = cycles * NANOS_PER_SECOND / u128::from(PIT_INPUT_HZ);
+        Instant {
+            since_boot: Duration::from_nanos(nanos as u64),
+        }
+    }
+
+    /// Returns the time passed since `earlier`, or zero if `earlier` is later than `self`.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.since_boot
+            .checked_sub(earlier.since_boot)
+            .unwrap_or_default()
+    }
+
+    #[allow(dead_code)]
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn has_passed(&self) -> bool {
+        Instant::now() >= *self
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant {
+            since_boot: self.since_boot + duration,
+        }
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Instant({:?} since boot)", self.since_boot)
+    }
+}
+
+/// Halts the CPU until the duration has passed. Interrupts must be enabled.
+///
+/// Other threads keep running while this one waits, but it does not give them its time slice. Use
+/// `task::sleep` to block only the current thread.
+#[allow(dead_code)]
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while !deadline.has_passed() {
+        x86_64::instructions::hlt()
+    }
+}
+
+/// Spins until the duration has passed. Interrupts must be enabled.
+#[allow(dead_code)]
+pub fn busy_wait(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while !deadline.has_passed() {
+        core::hint::spin_loop()
+    }
+}
+
+#[test_case]
+fn timer_frequency() {
+    assert_eq!(frequency(), u64::from(TIMER_FREQUENCY_HZ));
+}
+
+#[test_case]
+fn clock_is_monotonic() {
+    let mut previous = Instant::now();
+    for _ in 0..1000 {
+        let now = Instant::now();
+        assert!(now >= previous);
+        previous = now;
+    }
+}
+
+#[test_case]
+fn sleep_duration() {
+    let duration = Duration::from_millis(50);
+    let start = Instant::now();
+    let start_ticks = ticks();
+    sleep(duration);
+    assert!(start.elapsed() >= duration);
+    // The clock advances with the ticks.
+    assert!(ticks() - start_ticks >= 50);
+}
+
+#[test_case]
+fn instant_arithmetic() {
+    let start = Instant::now();
+    let later = start + Duration::from_secs(1);
+    assert_eq!(later - start, Duration::from_secs(1));
+    assert_eq!(start - later, Duration::from_secs(0));
+    assert!(!later.has_passed());
+    assert!(start.has_passed());
+}
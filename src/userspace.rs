@@ -0,0 +1,305 @@
+//! Running code in user mode (ring 3).
+//!
+//! A thread enters user mode with `run` or `resume` and comes back when the user code makes the
+//! `exit` system call. Until then, system calls and interrupts in user mode run on the thread's
+//! kernel stack, right below the frame of `run`.
+
+use core::arch::global_asm;
+use core::mem::offset_of;
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use crate::gdt;
+use crate::smp::percpu;
+
+/// User programs are mapped from here on, above the kernel image in the lower half.
+pub const USER_START: u64 = 0x0000_0080_0000_0000;
+/// End of the lower half, user programs are mapped below it.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// The flags user code may set, the interrupt flag is always set in user mode.
+const USER_FLAGS: RFlags = RFlags::from_bits_truncate(
+    RFlags::CARRY_FLAG.bits()
+        | RFlags::PARITY_FLAG.bits()
+        | RFlags::AUXILIARY_CARRY_FLAG.bits()
+        | RFlags::ZERO_FLAG.bits()
+        | RFlags::SIGN_FLAG.bits()
+        | RFlags::DIRECTION_FLAG.bits()
+        | RFlags::OVERFLOW_FLAG.bits(),
+);
+
+/// The registers of user code.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl Registers {
+    /// Registers to start the code at `entry`, with all other registers zeroed.
+    pub fn start(entry: VirtAddr, stack_top: VirtAddr) -> Self {
+        Registers {
+            rip: entry.as_u64(),
+            rsp: stack_top.align_down(16u64).as_u64(),
+            ..Registers::default()
+        }
+    }
+}
+
+// Saves the callee-saved registers like a context switch, and makes the stack below them the
+// kernel stack of the CPU. `ferocios_leave_user` switches back to it and returns from here.
+global_asm!(
+    ".global ferocios_enter_user",
+    "ferocios_enter_user:",
+    "cli",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov r12, rdi",
+    "mov r13, rsi",
+    "mov r14, rdx",
+    "mov rdi, rsp",
+    "sub rsp, 8",
+    "call {set_kernel_stack}",
+    // Interrupt frame returning to the user code.
+    "push r14",
+    "push qword ptr [r12 + {rsp}]",
+    "push qword ptr [r12 + {rflags}]",
+    "push r13",
+    "push qword ptr [r12 + {rip}]",
+    "mov rax, [r12 + {rax}]",
+    "mov rbx, [r12 + {rbx}]",
+    "mov rcx, [r12 + {rcx}]",
+    "mov rdx, [r12 + {rdx}]",
+    "mov rsi, [r12 + {rsi}]",
+    "mov rdi, [r12 + {rdi}]",
+    "mov rbp, [r12 + {rbp}]",
+    "mov r8, [r12 + {r8}]",
+    "mov r9, [r12 + {r9}]",
+    "mov r10, [r12 + {r10}]",
+    "mov r11, [r12 + {r11}]",
+    "mov r13, [r12 + {r13}]",
+    "mov r14, [r12 + {r14}]",
+    "mov r15, [r12 + {r15}]",
+    "mov r12, [r12 + {r12}]",
+    // Interrupts are disabled until the `iretq`, so nothing runs with the GS base of the user code.
+    "swapgs",
+    "iretq",
+    ".global ferocios_leave_user",
+    "ferocios_leave_user:",
+    "mov rsp, rdi",
+    // Exceptions in user mode leave with interrupts disabled.
+    "sti",
+    "mov rax, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    set_kernel_stack = sym set_kernel_stack,
+    rax = const offset_of!(Registers, rax),
+    rbx = const offset_of!(Registers, rbx),
+    rcx = const offset_of!(Registers, rcx),
+    rdx = const offset_of!(Registers, rdx),
+    rsi = const offset_of!(Registers, rsi),
+    rdi = const offset_of!(Registers, rdi),
+    rbp = const offset_of!(Registers, rbp),
+    r8 = const offset_of!(Registers, r8),
+    r9 = const offset_of!(Registers, r9),
+    r10 = const offset_of!(Registers, r10),
+    r11 = const offset_of!(Registers, r11),
+    r12 = const offset_of!(Registers, r12),
+    r13 = const offset_of!(Registers, r13),
+    r14 = const offset_of!(Registers, r14),
+    r15 = const offset_of!(Registers, r15),
+    rip = const offset_of!(Registers, rip),
+    rflags = const offset_of!(Registers, rflags),
+    rsp = const offset_of!(Registers, rsp),
+);
+
+extern "C" {
+    fn ferocios_enter_user(
+        registers: *const Registers,
+        code_selector: u64,
+        data_selector: u64,
+    ) -> u64;
+    fn ferocios_leave_user(kernel_stack: u64, result: u64) -> !;
+}
+
+extern "C" fn set_kernel_stack(stack_top: u64) {
+    percpu::current().set_kernel_stack(VirtAddr::new(stack_top))
+}
+
+/// Runs the user code at `entry` on the user stack until it exits, and returns its exit code.
+/// Interrupts are enabled in user mode.
+///
+/// # Safety
+///
+/// The code and stack must be mapped user-accessible.
+pub unsafe fn run(entry: VirtAddr, stack_top: VirtAddr) -> u64 {
+    resume(&Registers::start(entry, stack_top))
+}
+
+/// Continues user code with the registers, like `run`. Only the flags in `USER_FLAGS` are taken
+/// over.
+///
+/// # Safety
+///
+/// See `run`, the instruction and stack pointers must point to user memory.
+pub unsafe fn resume(registers: &Registers) -> u64 {
+    let mut registers = *registers;
+    let flags = RFlags::from_bits_truncate(registers.rflags) & USER_FLAGS;
+    registers.rflags = (flags | RFlags::INTERRUPT_FLAG).bits();
+    let selectors = gdt::selectors();
+    ferocios_enter_user(
+        &registers,
+        u64::from(selectors.user_code.0),
+        u64::from(selectors.user_data.0),
+    )
+}
+
+/// Leaves user mode, making `run` return `code`. Called by the `exit` and `exec` system calls, and
+/// by the exception handlers for exceptions in user mode. Interrupts are enabled again.
+pub fn exit(code: u64) -> ! {
+    let kernel_stack = percpu::current().kernel_stack();
+    unsafe { ferocios_leave_user(kernel_stack.as_u64(), code) }
+}
+
+#[cfg(test)]
+global_asm!(
+    ".global ferocios_test_user_program",
+    "ferocios_test_user_program:",
+    // Loading GS sets its base to 0, the kernel must not rely on it.
+    "mov ax, ss",
+    "mov gs, ax",
+    "mov eax, {getpid}",
+    "syscall",
+    // Exit with the result.
+    "mov rdi, rax",
+    "mov eax, {exit}",
+    "syscall",
+    "ud2",
+    ".global ferocios_test_user_program_end",
+    "ferocios_test_user_program_end:",
+    // Reads from the null pointer.
+    ".global ferocios_test_faulting_program",
+    "ferocios_test_faulting_program:",
+    "mov rax, [0]",
+    ".global ferocios_test_faulting_program_end",
+    "ferocios_test_faulting_program_end:",
+    getpid = const crate::syscall::abi::GETPID,
+    exit = const crate::syscall::abi::EXIT,
+);
+
+/// Runs the code between `start` and `end` in user mode, with one page of stack, and returns its
+/// exit code.
+#[cfg(test)]
+fn run_test_program(start: *const u8, end: *const u8) -> u64 {
+    use crate::memory::{self, paging};
+    use core::ptr::copy_nonoverlapping;
+    use x86_64::structures::paging::{Page, PageTableFlags};
+
+    // Copy the program to a user page, the kernel image is not accessible from user mode.
+    let code = Page::containing_address(VirtAddr::new(USER_START));
+    let frame = memory::allocate_frame().unwrap();
+    let length = end as usize - start as usize;
+    unsafe {
+        let destination = paging::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        copy_nonoverlapping(start, destination, length)
+    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    paging::map_page_to(code, frame, flags).unwrap();
+
+    let stack = code + 1;
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    paging::map_page(stack, flags).unwrap();
+
+    let result = unsafe { run(code.start_address(), (stack + 1).start_address()) };
+
+    for page in [code, stack] {
+        memory::deallocate_frame(paging::unmap_page(page).unwrap());
+    }
+    result
+}
+
+#[test_case]
+fn syscall_from_user_mode() {
+    use core::ptr::addr_of;
+
+    extern "C" {
+        static ferocios_test_user_program: u8;
+        static ferocios_test_user_program_end: u8;
+    }
+
+    let result = run_test_program(
+        addr_of!(ferocios_test_user_program),
+        addr_of!(ferocios_test_user_program_end),
+    );
+    // The test thread belongs to no process.
+    let result = crate::syscall::abi::decode(result);
+    assert_eq!(result, Err(crate::syscall::abi::Error::NoSuchProcess));
+}
+
+#[test_case]
+fn fault_in_user_mode() {
+    use core::ptr::addr_of;
+
+    extern "C" {
+        static ferocios_test_faulting_program: u8;
+        static ferocios_test_faulting_program_end: u8;
+    }
+
+    // The page fault ends the program, not the kernel.
+    let result = run_test_program(
+        addr_of!(ferocios_test_faulting_program),
+        addr_of!(ferocios_test_faulting_program_end),
+    );
+    assert_eq!(result, crate::syscall::abi::FAULT_EXIT_CODE);
+    assert!(x86_64::instructions::interrupts::are_enabled());
+}
+
+#[test_case]
+fn syscall_at_end_of_user_memory() {
+    use crate::memory::{self, paging};
+    use x86_64::structures::paging::{Page, PageTableFlags};
+
+    // `syscall` in the last two bytes of user memory, it would return to `USER_END`.
+    let code = Page::containing_address(VirtAddr::new(USER_END - 1));
+    let frame = memory::allocate_zeroed_frame().unwrap();
+    unsafe {
+        let page = paging::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        page.add(4094).copy_from([0x0f, 0x05].as_ptr(), 2)
+    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    paging::map_page_to(code, frame, flags).unwrap();
+
+    let result = unsafe { run(VirtAddr::new(USER_END - 2), VirtAddr::new(USER_START)) };
+    assert_eq!(result, crate::syscall::abi::FAULT_EXIT_CODE);
+
+    memory::deallocate_frame(paging::unmap_page(code).unwrap());
+}
@@ -0,0 +1,79 @@
+//! The console as a device: keyboard input and screen output.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::time::Duration;
+
+use super::{Error, FileType, Inode, Metadata, OpenFile, OpenFlags};
+use crate::{keyboard, task};
+
+/// How often reads check for keyboard input while they wait.
+const READ_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Reads ASCII characters from the keyboard and writes to the screen. The error console writes
+/// highlighted, and to the serial port as well.
+pub struct Console {
+    error: bool,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Console { error: false }
+    }
+
+    pub fn error() -> Self {
+        Console { error: true }
+    }
+}
+
+impl Inode for Console {
+    fn metadata(&self) -> Metadata {
+        Metadata::new(self.error as u64, FileType::CharDevice, 0)
+    }
+
+    /// Blocks until at least one character was typed.
+    fn read_at(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let mut read = 0;
+        loop {
+            while read < buffer.len() {
+                match keyboard::try_read_char() {
+                    Some(character) if character.is_ascii() => {
+                        buffer[read] = character as u8;
+                        read += 1
+                    }
+                    Some(_) => (),
+                    None => break,
+                }
+            }
+            if read > 0 {
+                return Ok(read);
+            }
+            task::sleep(READ_POLL_INTERVAL)
+        }
+    }
+
+    fn write_at(&self, _offset: u64, data: &[u8]) -> Result<usize, Error> {
+        let text = String::from_utf8_lossy(data);
+        if self.error {
+            eprint!("{}", text);
+            serial_print!("{}", text)
+        } else {
+            print!("{}", text)
+        }
+        Ok(data.len())
+    }
+}
+
+/// Opens standard input, output and error, in this order.
+pub fn standard_files() -> [Arc<OpenFile>; 3] {
+    let console: Arc<dyn Inode> = Arc::new(Console::new());
+    let open = |inode, flags| OpenFile::open(inode, flags).expect("Opening the console failed");
+    [
+        open(console.clone(), OpenFlags::READ),
+        open(console, OpenFlags::WRITE),
+        open(Arc::new(Console::error()), OpenFlags::WRITE),
+    ]
+}
@@ -0,0 +1,1490 @@
+//! ext2: the second extended file system, the classic Unix file system of Linux, which images for
+//! the host can be built with, like with `mke2fs -t ext2 -d`.
+//!
+//! The blocks of the file system are divided into groups. Each group has a bitmap of its free
+//! blocks, a bitmap of its free inodes and a table of its inodes, the group descriptors say where.
+//! The superblock at byte 1024 describes the layout. An inode holds the type, permissions, owner
+//! and timestamps of a file, and maps the blocks of its data: 12 directly, then through a single,
+//! double and triple indirect block of block numbers. Block number 0 is a hole, which reads as
+//! zeros. Directories are files of entries with the inode number and the name. Symbolic links keep
+//! targets shorter than 60 bytes in the block map of the inode, longer ones in a data block.
+//!
+//! Only file systems without incompatible features besides the file type in directory entries can
+//! be mounted. Ones with read-only compatible features other than sparse superblocks and large
+//! files are mounted read-only. Access times are not updated.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spinning::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{DirEntry, Error, FileSystem, FileType, Inode, Metadata, MAX_NAME_LENGTH};
+use crate::block::{BlockDevice, DeviceLock};
+use crate::time;
+
+const SUPERBLOCK_POSITION: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+/// Size of inodes in revision 0, and of the part of larger ones this driver uses.
+const OLD_INODE_SIZE: usize = 128;
+/// First inode that is not reserved in revision 0.
+const OLD_FIRST_INODE: u32 = 11;
+const ROOT_INODE: u32 = 2;
+
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+
+// Types in the mode of inodes.
+const TYPE_MASK: u16 = 0xF000;
+const TYPE_CHAR_DEVICE: u16 = 0x2000;
+const TYPE_DIRECTORY: u16 = 0x4000;
+const TYPE_FILE: u16 = 0x8000;
+const TYPE_SYMLINK: u16 = 0xA000;
+const PERMISSION_MASK: u16 = 0o7777;
+
+// Types in directory entries.
+const ENTRY_FILE: u8 = 1;
+const ENTRY_DIRECTORY: u8 = 2;
+const ENTRY_CHAR_DEVICE: u8 = 3;
+const ENTRY_SYMLINK: u8 = 7;
+/// Size of a directory entry without the name.
+const ENTRY_HEADER_SIZE: usize = 8;
+
+/// Flag of directories with a hash tree index, which is not kept up to date.
+const INDEX_FLAG: u32 = 0x1000;
+const DIRECT_BLOCKS: u64 = 12;
+/// Entries of the block map, the direct blocks and the single, double and triple indirect block.
+const BLOCK_MAP_ENTRIES: usize = 15;
+/// Size of the block map, and the longest target of symbolic links kept in it plus 1.
+const BLOCK_MAP_SIZE: usize = BLOCK_MAP_ENTRIES * 4;
+
+// Offsets of the fields of inodes.
+const INODE_MODE: usize = 0;
+const INODE_UID: usize = 2;
+const INODE_SIZE: usize = 4;
+const INODE_ACCESSED: usize = 8;
+const INODE_CHANGED: usize = 12;
+const INODE_MODIFIED: usize = 16;
+const INODE_DELETED: usize = 20;
+const INODE_GID: usize = 24;
+const INODE_LINKS: usize = 26;
+/// Blocks of the inode in 512 byte units, including indirect blocks.
+const INODE_SECTORS: usize = 28;
+const INODE_FLAGS: usize = 32;
+const INODE_BLOCK_MAP: usize = 40;
+/// Block with extended attributes.
+const INODE_ATTRIBUTE_BLOCK: usize = 104;
+/// Upper 32 bits of the size of files.
+const INODE_SIZE_HIGH: usize = 108;
+const INODE_UID_HIGH: usize = 120;
+const INODE_GID_HIGH: usize = 122;
+
+pub struct Ext2Fs {
+    volume: Arc<Volume>,
+    root: Arc<Node>,
+}
+
+impl Ext2Fs {
+    /// Opens the ext2 file system on the device. Fails with `NotSupported` if there is none, or
+    /// it has features this driver does not know.
+    #[allow(dead_code)]
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self, Error> {
+        let volume = Arc::new(Volume::new(device)?);
+        let root = volume.lock.with(|| volume.node(ROOT_INODE))?;
+        if root.inode()?.kind() != FileType::Directory {
+            return Err(Error::NotSupported);
+        }
+        Ok(Ext2Fs { volume, root })
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    /// Writes the free counts to the superblock if they changed, and flushes the device.
+    fn sync(&self) -> Result<(), Error> {
+        self.volume.lock.with(|| self.volume.sync())
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn set_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes())
+}
+
+fn set_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes())
+}
+
+/// Returns the seconds since 1970 as ext2 stores them, in 32 bits.
+fn now() -> u32 {
+    time::unix_time() as u32
+}
+
+/// The part of an inode this driver uses.
+#[derive(Clone, Copy)]
+struct RawInode([u8; OLD_INODE_SIZE]);
+
+impl RawInode {
+    fn mode(&self) -> u16 {
+        u16_at(&self.0, INODE_MODE)
+    }
+
+    fn kind(&self) -> FileType {
+        match self.mode() & TYPE_MASK {
+            TYPE_DIRECTORY => FileType::Directory,
+            TYPE_SYMLINK => FileType::Symlink,
+            TYPE_CHAR_DEVICE => FileType::CharDevice,
+            // Block devices, FIFOs and sockets have no data.
+            _ => FileType::File,
+        }
+    }
+
+    fn is_regular_file(&self) -> bool {
+        self.mode() & TYPE_MASK == TYPE_FILE
+    }
+
+    fn size(&self) -> u64 {
+        let high = if self.is_regular_file() {
+            u32_at(&self.0, INODE_SIZE_HIGH)
+        } else {
+            0
+        };
+        (high as u64) << 32 | u32_at(&self.0, INODE_SIZE) as u64
+    }
+
+    fn set_size(&mut self, size: u64) {
+        set_u32(&mut self.0, INODE_SIZE, size as u32);
+        if self.is_regular_file() {
+            set_u32(&mut self.0, INODE_SIZE_HIGH, (size >> 32) as u32)
+        }
+    }
+
+    fn uid(&self) -> u32 {
+        (u16_at(&self.0, INODE_UID_HIGH) as u32) << 16 | u16_at(&self.0, INODE_UID) as u32
+    }
+
+    fn gid(&self) -> u32 {
+        (u16_at(&self.0, INODE_GID_HIGH) as u32) << 16 | u16_at(&self.0, INODE_GID) as u32
+    }
+
+    fn links(&self) -> u16 {
+        u16_at(&self.0, INODE_LINKS)
+    }
+
+    fn set_links(&mut self, links: u16) {
+        set_u16(&mut self.0, INODE_LINKS, links)
+    }
+
+    fn block(&self, index: usize) -> u32 {
+        u32_at(&self.0, INODE_BLOCK_MAP + index * 4)
+    }
+
+    fn set_block(&mut self, index: usize, block: u32) {
+        set_u32(&mut self.0, INODE_BLOCK_MAP + index * 4, block)
+    }
+
+    fn block_map(&mut self) -> &mut [u8] {
+        &mut self.0[INODE_BLOCK_MAP..INODE_BLOCK_MAP + BLOCK_MAP_SIZE]
+    }
+
+    /// Adds blocks to the count of blocks of the inode, or removes them if negative.
+    fn add_blocks(&mut self, blocks: i64, block_size: u64) {
+        let sectors = u32_at(&self.0, INODE_SECTORS) as i64 + blocks * (block_size / 512) as i64;
+        set_u32(&mut self.0, INODE_SECTORS, sectors as u32)
+    }
+
+    /// Symbolic links with the target in the block map have no blocks but the attribute block.
+    fn is_fast_symlink(&self, block_size: u64) -> bool {
+        let attribute_sectors = match u32_at(&self.0, INODE_ATTRIBUTE_BLOCK) {
+            0 => 0,
+            _ => block_size / 512,
+        };
+        self.kind() == FileType::Symlink
+            && u32_at(&self.0, INODE_SECTORS) as u64 == attribute_sectors
+    }
+
+    /// Sets the times of changes to the contents and the inode to now.
+    fn touch(&mut self) {
+        let now = now();
+        set_u32(&mut self.0, INODE_MODIFIED, now);
+        set_u32(&mut self.0, INODE_CHANGED, now)
+    }
+
+    /// Sets the time of changes to the inode to now.
+    fn touch_changed(&mut self) {
+        set_u32(&mut self.0, INODE_CHANGED, now())
+    }
+}
+
+/// The type of a directory entry for an inode of the type.
+fn entry_type(kind: FileType) -> u8 {
+    match kind {
+        FileType::File => ENTRY_FILE,
+        FileType::Directory => ENTRY_DIRECTORY,
+        FileType::CharDevice => ENTRY_CHAR_DEVICE,
+        FileType::Symlink => ENTRY_SYMLINK,
+    }
+}
+
+/// Size of the directory entry with a name of the length, a multiple of 4.
+fn entry_size(name_length: usize) -> usize {
+    (ENTRY_HEADER_SIZE + name_length + 3) & !3
+}
+
+/// An entry of a directory, which may be unused.
+struct Slot {
+    /// Block the entry is in and its offset there.
+    block: u32,
+    offset: usize,
+    /// 0 in unused entries.
+    inode: u32,
+    /// Size of the entry including the free space after it.
+    length: usize,
+    name: Vec<u8>,
+    kind: u8,
+}
+
+impl Slot {
+    fn is_dot(&self) -> bool {
+        self.name == b"." || self.name == b".."
+    }
+
+    /// Size of the used part of the entry.
+    fn used(&self) -> usize {
+        match self.inode {
+            0 => 0,
+            _ => entry_size(self.name.len()),
+        }
+    }
+}
+
+fn check_name(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name != "."
+        && name != ".."
+        && !name.contains(&['/', '\0'][..]);
+    if !valid {
+        return Err(Error::InvalidPath);
+    }
+    Ok(())
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    block_size: u64,
+    first_data_block: u32,
+    blocks_count: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    group_count: u32,
+    inode_size: u64,
+    first_inode: u32,
+    /// Directory entries have the type of the inode.
+    entry_types: bool,
+    read_only: bool,
+    /// Held during every operation, which may block on the device.
+    lock: DeviceLock,
+    /// The superblock, with the free counts kept up to date in memory.
+    superblock: Mutex<Vec<u8>>,
+    /// The group descriptors, which are written on every change.
+    groups: Mutex<Vec<u8>>,
+    /// Blocks or inodes were allocated or freed since the last sync.
+    changed: AtomicBool,
+    /// The inodes in use by number, so that every file has one.
+    nodes: Mutex<BTreeMap<u32, Weak<Node>>>,
+}
+
+impl Volume {
+    fn new(device: Arc<dyn BlockDevice>) -> Result<Self, Error> {
+        let mut superblock = vec![0; SUPERBLOCK_SIZE];
+        device
+            .read_bytes(SUPERBLOCK_POSITION, &mut superblock)
+            .map_err(|_| Error::Io)?;
+        if u16_at(&superblock, 56) != MAGIC || u32_at(&superblock, 24) > 6 {
+            return Err(Error::NotSupported);
+        }
+        let block_size = 1024 << u32_at(&superblock, 24);
+        let blocks_count = u32_at(&superblock, 4);
+        let first_data_block = u32_at(&superblock, 20);
+        let blocks_per_group = u32_at(&superblock, 32);
+        let inodes_per_group = u32_at(&superblock, 40);
+        let (inode_size, first_inode, incompat, ro_compat) = match u32_at(&superblock, 76) {
+            0 => (OLD_INODE_SIZE as u64, OLD_FIRST_INODE, 0, 0),
+            _ => (
+                u16_at(&superblock, 88) as u64,
+                u32_at(&superblock, 84),
+                u32_at(&superblock, 96),
+                u32_at(&superblock, 100),
+            ),
+        };
+        let bits_per_block = block_size as u32 * 8;
+        let valid = (1..=bits_per_block).contains(&blocks_per_group)
+            && (1..=bits_per_block).contains(&inodes_per_group)
+            && first_data_block < blocks_count
+            && blocks_count as u64 * block_size <= device.capacity()
+            && inode_size.is_power_of_two()
+            && (OLD_INODE_SIZE as u64..=block_size).contains(&inode_size)
+            && first_inode > ROOT_INODE;
+        if !valid || incompat & !INCOMPAT_FILETYPE != 0 {
+            return Err(Error::NotSupported);
+        }
+        let group_count = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+        if u32_at(&superblock, 0) as u64 > group_count as u64 * inodes_per_group as u64 {
+            return Err(Error::NotSupported);
+        }
+
+        let mut groups = vec![0; group_count as usize * GROUP_DESCRIPTOR_SIZE];
+        let groups_position = (first_data_block as u64 + 1) * block_size;
+        device
+            .read_bytes(groups_position, &mut groups)
+            .map_err(|_| Error::Io)?;
+        let known = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE;
+        Ok(Volume {
+            device,
+            block_size,
+            first_data_block,
+            blocks_count,
+            blocks_per_group,
+            inodes_per_group,
+            group_count,
+            inode_size,
+            first_inode,
+            entry_types: incompat & INCOMPAT_FILETYPE != 0,
+            read_only: ro_compat & !known != 0,
+            lock: DeviceLock::new(),
+            superblock: Mutex::new(superblock),
+            groups: Mutex::new(groups),
+            changed: AtomicBool::new(false),
+            nodes: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn large_files(&self) -> bool {
+        let ro_compat = interrupts::without_interrupts(|| u32_at(&self.superblock.lock(), 100));
+        ro_compat & RO_COMPAT_LARGE_FILE != 0
+    }
+
+    fn read_bytes(&self, position: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        self.device
+            .read_bytes(position, buffer)
+            .map_err(|_| Error::Io)
+    }
+
+    fn write_bytes(&self, position: u64, data: &[u8]) -> Result<(), Error> {
+        self.device
+            .write_bytes(position, data)
+            .map_err(|_| Error::Io)
+    }
+
+    fn block_position(&self, block: u32) -> u64 {
+        block as u64 * self.block_size
+    }
+
+    fn read_block(&self, block: u32) -> Result<Vec<u8>, Error> {
+        let mut data = vec![0; self.block_size as usize];
+        self.read_bytes(self.block_position(block), &mut data)?;
+        Ok(data)
+    }
+
+    /// Returns the field of the group descriptor at the offset.
+    fn group_field(&self, group: u32, offset: usize) -> u32 {
+        let position = group as usize * GROUP_DESCRIPTOR_SIZE + offset;
+        interrupts::without_interrupts(|| {
+            let groups = self.groups.lock();
+            match offset {
+                0..=8 => u32_at(&groups, position),
+                _ => u16_at(&groups, position) as u32,
+            }
+        })
+    }
+
+    /// Adds to the 16 bit field of the group descriptor at the offset, and writes the descriptor.
+    fn add_to_group_field(&self, group: u32, offset: usize, value: i32) -> Result<(), Error> {
+        let start = group as usize * GROUP_DESCRIPTOR_SIZE;
+        let descriptor = interrupts::without_interrupts(|| {
+            let mut groups = self.groups.lock();
+            let field = u16_at(&groups, start + offset) as i32 + value;
+            set_u16(&mut groups, start + offset, field as u16);
+            groups[start..start + GROUP_DESCRIPTOR_SIZE].to_vec()
+        });
+        let position = (self.first_data_block as u64 + 1) * self.block_size + start as u64;
+        self.write_bytes(position, &descriptor)
+    }
+
+    /// Adds to the 32 bit field of the superblock at the offset.
+    fn add_to_superblock_field(&self, offset: usize, value: i32) {
+        interrupts::without_interrupts(|| {
+            let mut superblock = self.superblock.lock();
+            let field = u32_at(&superblock, offset) as i64 + value as i64;
+            set_u32(&mut superblock, offset, field as u32)
+        });
+        self.changed.store(true, Ordering::Relaxed)
+    }
+
+    /// Changes the free block count of the group and the superblock.
+    fn add_free_blocks(&self, group: u32, blocks: i32) -> Result<(), Error> {
+        self.add_to_superblock_field(12, blocks);
+        self.add_to_group_field(group, 12, blocks)
+    }
+
+    /// Changes the free inode count of the group and the superblock, and the count of directories
+    /// of the group.
+    fn add_free_inodes(&self, group: u32, inodes: i32, directory: bool) -> Result<(), Error> {
+        self.add_to_superblock_field(16, inodes);
+        self.add_to_group_field(group, 14, inodes)?;
+        if directory {
+            self.add_to_group_field(group, 16, -inodes)?
+        }
+        Ok(())
+    }
+
+    /// Sets the bit of the bitmap at the block, and returns its old value.
+    fn set_bit(&self, bitmap: u32, bit: u32, value: bool) -> Result<bool, Error> {
+        let position = self.block_position(bitmap) + bit as u64 / 8;
+        let mut byte = [0];
+        self.read_bytes(position, &mut byte)?;
+        let mask = 1 << (bit % 8);
+        let old = byte[0] & mask != 0;
+        if value {
+            byte[0] |= mask
+        } else {
+            byte[0] &= !mask
+        }
+        self.write_bytes(position, &byte)?;
+        Ok(old)
+    }
+
+    /// Finds a clear bit below `end` in the bitmap at the block, starting at `start`.
+    fn find_clear_bit(&self, bitmap: u32, start: u32, end: u32) -> Result<Option<u32>, Error> {
+        let data = self.read_block(bitmap)?;
+        Ok((start..end).find(|&bit| data[bit as usize / 8] & 1 << (bit % 8) == 0))
+    }
+
+    /// Returns the amount of blocks in the group, which is less for the last one.
+    fn group_blocks(&self, group: u32) -> u32 {
+        let start = self.first_data_block + group * self.blocks_per_group;
+        (self.blocks_count - start).min(self.blocks_per_group)
+    }
+
+    fn block_group(&self, block: u32) -> u32 {
+        (block.saturating_sub(self.first_data_block) / self.blocks_per_group)
+            .min(self.group_count - 1)
+    }
+
+    /// Allocates a block, close after `goal` if possible, and zeroes it if `zero` is set.
+    fn allocate_block(&self, goal: u32, zero: bool) -> Result<u32, Error> {
+        let goal = goal.clamp(self.first_data_block, self.blocks_count - 1);
+        let goal_group = self.block_group(goal);
+        for index in 0..self.group_count {
+            let group = (goal_group + index) % self.group_count;
+            if self.group_field(group, 12) == 0 {
+                continue;
+            }
+            let bitmap = self.group_field(group, 0);
+            let end = self.group_blocks(group);
+            let start = if index == 0 {
+                goal - self.first_data_block - group * self.blocks_per_group
+            } else {
+                0
+            };
+            let bit = match self.find_clear_bit(bitmap, start, end)? {
+                Some(bit) => bit,
+                None => match self.find_clear_bit(bitmap, 0, start)? {
+                    Some(bit) => bit,
+                    None => continue,
+                },
+            };
+            self.set_bit(bitmap, bit, true)?;
+            self.add_free_blocks(group, -1)?;
+            let block = self.first_data_block + group * self.blocks_per_group + bit;
+            if zero {
+                let zeros = vec![0; self.block_size as usize];
+                self.write_bytes(self.block_position(block), &zeros)?
+            }
+            return Ok(block);
+        }
+        Err(Error::NoSpace)
+    }
+
+    fn free_blocks(&self, blocks: &[u32]) -> Result<(), Error> {
+        for &block in blocks {
+            let group = self.block_group(block);
+            let bit = block - self.first_data_block - group * self.blocks_per_group;
+            if self.set_bit(self.group_field(group, 0), bit, false)? {
+                self.add_free_blocks(group, 1)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocates an inode, in the group if possible.
+    fn allocate_inode(&self, group: u32, directory: bool) -> Result<u32, Error> {
+        for index in 0..self.group_count {
+            let group = (group + index) % self.group_count;
+            if self.group_field(group, 14) == 0 {
+                continue;
+            }
+            // Inodes before the first one are reserved.
+            let first = group * self.inodes_per_group + 1;
+            let start = self.first_inode.saturating_sub(first);
+            let bitmap = self.group_field(group, 4);
+            if let Some(bit) = self.find_clear_bit(bitmap, start, self.inodes_per_group)? {
+                self.set_bit(bitmap, bit, true)?;
+                self.add_free_inodes(group, -1, directory)?;
+                return Ok(first + bit);
+            }
+        }
+        Err(Error::NoSpace)
+    }
+
+    fn free_inode(&self, number: u32, directory: bool) -> Result<(), Error> {
+        let group = (number - 1) / self.inodes_per_group;
+        let bit = (number - 1) % self.inodes_per_group;
+        if self.set_bit(self.group_field(group, 4), bit, false)? {
+            self.add_free_inodes(group, 1, directory)?
+        }
+        Ok(())
+    }
+
+    fn inode_position(&self, number: u32) -> u64 {
+        let group = (number - 1) / self.inodes_per_group;
+        let index = (number - 1) % self.inodes_per_group;
+        self.block_position(self.group_field(group, 8)) + index as u64 * self.inode_size
+    }
+
+    fn read_inode(&self, number: u32) -> Result<RawInode, Error> {
+        if number == 0 || number > self.group_count * self.inodes_per_group {
+            return Err(Error::Io);
+        }
+        let mut inode = RawInode([0; OLD_INODE_SIZE]);
+        self.read_bytes(self.inode_position(number), &mut inode.0)?;
+        Ok(inode)
+    }
+
+    fn write_inode(&self, number: u32, inode: &RawInode) -> Result<(), Error> {
+        self.write_bytes(self.inode_position(number), &inode.0)
+    }
+
+    /// Returns the inode with the number, the same one as long as it is in use.
+    fn node(self: &Arc<Self>, number: u32) -> Result<Arc<Node>, Error> {
+        let cached = interrupts::without_interrupts(|| {
+            self.nodes.lock().get(&number).and_then(Weak::upgrade)
+        });
+        if let Some(node) = cached {
+            return Ok(node);
+        }
+        let inode = self.read_inode(number)?;
+        Ok(self.add_node(number, inode))
+    }
+
+    fn add_node(self: &Arc<Self>, number: u32, inode: RawInode) -> Arc<Node> {
+        let node = Arc::new(Node {
+            volume: self.clone(),
+            number,
+            state: Mutex::new(NodeState {
+                inode,
+                removed: false,
+            }),
+        });
+        interrupts::without_interrupts(|| {
+            self.nodes.lock().insert(number, Arc::downgrade(&node));
+        });
+        node
+    }
+
+    /// Allocates an inode of the type with the default mode, in the group of the parent.
+    fn new_node(self: &Arc<Self>, parent: u32, kind: FileType) -> Result<Arc<Node>, Error> {
+        let directory = kind == FileType::Directory;
+        let group = (parent - 1) / self.inodes_per_group;
+        let number = self.allocate_inode(group, directory)?;
+        let kind_bits = match kind {
+            FileType::File => TYPE_FILE,
+            FileType::Directory => TYPE_DIRECTORY,
+            FileType::Symlink => TYPE_SYMLINK,
+            FileType::CharDevice => TYPE_CHAR_DEVICE,
+        };
+        let mut inode = RawInode([0; OLD_INODE_SIZE]);
+        set_u16(&mut inode.0, INODE_MODE, kind_bits | kind.default_mode());
+        let now = now();
+        for &offset in [INODE_ACCESSED, INODE_CHANGED, INODE_MODIFIED].iter() {
+            set_u32(&mut inode.0, offset, now)
+        }
+        inode.set_links(1);
+        // Clears the rest of larger inodes too.
+        let mut data = vec![0; self.inode_size as usize];
+        data[..OLD_INODE_SIZE].copy_from_slice(&inode.0);
+        self.write_bytes(self.inode_position(number), &data)?;
+        Ok(self.add_node(number, inode))
+    }
+
+    fn pointers_per_block(&self) -> u64 {
+        self.block_size / 4
+    }
+
+    /// Returns the entry of the block map and the indexes in the indirect blocks for the block of
+    /// the file at the index.
+    fn block_path(&self, mut index: u64) -> Result<(usize, Vec<u64>), Error> {
+        if index < DIRECT_BLOCKS {
+            return Ok((index as usize, Vec::new()));
+        }
+        index -= DIRECT_BLOCKS;
+        let pointers = self.pointers_per_block();
+        let mut span = pointers;
+        for level in 1..=3 {
+            if index < span {
+                let mut path = vec![0; level];
+                for entry in path.iter_mut().rev() {
+                    *entry = index % pointers;
+                    index /= pointers
+                }
+                return Ok((DIRECT_BLOCKS as usize + level - 1, path));
+            }
+            index -= span;
+            span *= pointers
+        }
+        Err(Error::NoSpace)
+    }
+
+    fn pointer(&self, block: u32, index: u64) -> Result<u32, Error> {
+        let mut pointer = [0; 4];
+        self.read_bytes(self.block_position(block) + index * 4, &mut pointer)?;
+        Ok(u32::from_le_bytes(pointer))
+    }
+
+    fn set_pointer(&self, block: u32, index: u64, pointer: u32) -> Result<(), Error> {
+        let position = self.block_position(block) + index * 4;
+        self.write_bytes(position, &pointer.to_le_bytes())
+    }
+
+    /// Returns the block with the data of the inode at the index, 0 for holes.
+    fn data_block(&self, inode: &RawInode, index: u64) -> Result<u32, Error> {
+        let (entry, path) = self.block_path(index)?;
+        let mut block = inode.block(entry);
+        for &index in path.iter() {
+            if block == 0 {
+                break;
+            }
+            block = self.pointer(block, index)?
+        }
+        Ok(block)
+    }
+
+    /// Returns the block with the data of the inode at the index, which is allocated with the
+    /// indirect blocks leading to it if needed. New data blocks are zeroed if `zero` is set.
+    fn allocate_data_block(
+        &self,
+        inode: &mut RawInode,
+        index: u64,
+        zero: bool,
+    ) -> Result<u32, Error> {
+        let (entry, path) = self.block_path(index)?;
+        let mut block = inode.block(entry);
+        // New blocks follow the previous one of the file.
+        let mut goal = match index {
+            0 => 0,
+            _ => self.data_block(inode, index - 1)?,
+        };
+        if block == 0 {
+            block = self.allocate_block(goal, zero || !path.is_empty())?;
+            inode.add_blocks(1, self.block_size);
+            inode.set_block(entry, block)
+        }
+        for (depth, &index) in path.iter().enumerate() {
+            goal = block;
+            let parent = block;
+            block = self.pointer(parent, index)?;
+            if block == 0 {
+                block = self.allocate_block(goal, zero || depth + 1 < path.len())?;
+                inode.add_blocks(1, self.block_size);
+                self.set_pointer(parent, index, block)?
+            }
+        }
+        Ok(block)
+    }
+
+    /// Collects the blocks of the tree of the level that maps the data from block `start` on, for
+    /// the data from block `keep` on. Returns whether the whole tree goes, including `block`.
+    fn free_tree(
+        &self,
+        block: u32,
+        level: u32,
+        start: u64,
+        keep: u64,
+        freed: &mut Vec<u32>,
+    ) -> Result<bool, Error> {
+        let pointers = self.pointers_per_block();
+        let span = pointers.pow(level);
+        if keep >= start + span {
+            return Ok(false);
+        }
+        if level > 0 {
+            let child_span = span / pointers;
+            let mut data = self.read_block(block)?;
+            let first = keep.saturating_sub(start) / child_span;
+            for index in first..pointers {
+                let offset = index as usize * 4;
+                let child = u32_at(&data, offset);
+                let child_start = start + index * child_span;
+                if child != 0 && self.free_tree(child, level - 1, child_start, keep, freed)? {
+                    set_u32(&mut data, offset, 0)
+                }
+            }
+            if keep > start {
+                self.write_bytes(self.block_position(block), &data)?;
+                return Ok(false);
+            }
+        }
+        freed.push(block);
+        Ok(true)
+    }
+
+    /// Frees the blocks of the inode from the index on.
+    fn free_data(&self, inode: &mut RawInode, keep: u64) -> Result<(), Error> {
+        let pointers = self.pointers_per_block();
+        let mut freed = Vec::new();
+        let mut start = 0;
+        for entry in 0..BLOCK_MAP_ENTRIES {
+            let level = (entry as u64).saturating_sub(DIRECT_BLOCKS - 1) as u32;
+            let block = inode.block(entry);
+            if block != 0 && self.free_tree(block, level, start, keep, &mut freed)? {
+                inode.set_block(entry, 0)
+            }
+            start += pointers.pow(level)
+        }
+        self.free_blocks(&freed)?;
+        inode.add_blocks(-(freed.len() as i64), self.block_size);
+        Ok(())
+    }
+
+    /// Changes the size of the file. The bytes after the end in its last block are zeroed, so
+    /// that they read as zeros when the file grows again.
+    fn resize(&self, inode: &mut RawInode, size: u64) -> Result<(), Error> {
+        if size < inode.size() {
+            self.free_data(inode, size.div_ceil(self.block_size))?;
+            let inside = size % self.block_size;
+            let block = self.data_block(inode, size / self.block_size)?;
+            if inside != 0 && block != 0 {
+                let zeros = vec![0; (self.block_size - inside) as usize];
+                self.write_bytes(self.block_position(block) + inside, &zeros)?
+            }
+        }
+        inode.set_size(size);
+        Ok(())
+    }
+
+    /// Frees the inode and its blocks.
+    fn delete(&self, number: u32, inode: &mut RawInode) -> Result<(), Error> {
+        if !inode.is_fast_symlink(self.block_size) {
+            self.free_data(inode, 0)?
+        }
+        // Attribute blocks may be shared, they count their references.
+        let attributes = u32_at(&inode.0, INODE_ATTRIBUTE_BLOCK);
+        if attributes != 0 {
+            let position = self.block_position(attributes) + 4;
+            let mut references = [0; 4];
+            self.read_bytes(position, &mut references)?;
+            match u32::from_le_bytes(references) {
+                0 | 1 => self.free_blocks(&[attributes])?,
+                count => self.write_bytes(position, &(count - 1).to_le_bytes())?,
+            }
+            inode.add_blocks(-1, self.block_size);
+            set_u32(&mut inode.0, INODE_ATTRIBUTE_BLOCK, 0)
+        }
+        inode.set_links(0);
+        inode.set_size(0);
+        set_u32(&mut inode.0, INODE_DELETED, now());
+        self.write_inode(number, inode)?;
+        self.free_inode(number, inode.kind() == FileType::Directory)
+    }
+
+    /// Returns the entries of the directory.
+    fn slots(&self, directory: &RawInode) -> Result<Vec<Slot>, Error> {
+        let mut slots = Vec::new();
+        for index in 0..directory.size().div_ceil(self.block_size) {
+            let block = self.data_block(directory, index)?;
+            if block == 0 {
+                return Err(Error::Io);
+            }
+            let data = self.read_block(block)?;
+            let mut offset = 0;
+            while offset < data.len() {
+                let length = u16_at(&data, offset + 4) as usize;
+                // Without types in the entries, the length of the name has 16 bits.
+                let name_length = if self.entry_types {
+                    data[offset + 6] as usize
+                } else {
+                    u16_at(&data, offset + 6) as usize
+                };
+                let valid = length >= ENTRY_HEADER_SIZE
+                    && length.is_multiple_of(4)
+                    && offset + length <= data.len()
+                    && ENTRY_HEADER_SIZE + name_length <= length;
+                if !valid {
+                    return Err(Error::Io);
+                }
+                let name = &data[offset + ENTRY_HEADER_SIZE..][..name_length];
+                slots.push(Slot {
+                    block,
+                    offset,
+                    inode: u32_at(&data, offset),
+                    length,
+                    name: Vec::from(name),
+                    kind: if self.entry_types {
+                        data[offset + 7]
+                    } else {
+                        0
+                    },
+                });
+                offset += length
+            }
+        }
+        Ok(slots)
+    }
+
+    /// Writes the entry for the inode at the offset in the block.
+    fn write_slot(
+        &self,
+        block: u32,
+        offset: usize,
+        length: usize,
+        name: &str,
+        inode: u32,
+        kind: FileType,
+    ) -> Result<(), Error> {
+        let mut entry = vec![0; ENTRY_HEADER_SIZE + name.len()];
+        set_u32(&mut entry, 0, inode);
+        set_u16(&mut entry, 4, length as u16);
+        entry[6] = name.len() as u8;
+        if self.entry_types {
+            entry[7] = entry_type(kind)
+        }
+        entry[ENTRY_HEADER_SIZE..].copy_from_slice(name.as_bytes());
+        self.write_bytes(self.block_position(block) + offset as u64, &entry)
+    }
+
+    /// Adds an entry for the inode to the directory, in free space or a new block.
+    fn add_slot(
+        &self,
+        directory: &mut RawInode,
+        name: &str,
+        inode: u32,
+        kind: FileType,
+    ) -> Result<(), Error> {
+        let needed = entry_size(name.len());
+        let slot = self
+            .slots(directory)?
+            .into_iter()
+            .find(|slot| slot.length - slot.used() >= needed);
+        match slot {
+            Some(slot) => {
+                let used = slot.used();
+                if used != 0 {
+                    let position = self.block_position(slot.block) + slot.offset as u64 + 4;
+                    self.write_bytes(position, &(used as u16).to_le_bytes())?
+                }
+                let length = slot.length - used;
+                self.write_slot(slot.block, slot.offset + used, length, name, inode, kind)?
+            }
+            None => {
+                let index = directory.size() / self.block_size;
+                let block = self.allocate_data_block(directory, index, true)?;
+                let length = self.block_size as usize;
+                self.write_slot(block, 0, length, name, inode, kind)?;
+                directory.set_size((index + 1) * self.block_size)
+            }
+        }
+        changed_directory(directory);
+        Ok(())
+    }
+
+    /// Removes the entry from the directory, by giving its space to the entry in front of it.
+    fn remove_slot(
+        &self,
+        directory: &mut RawInode,
+        slots: &[Slot],
+        index: usize,
+    ) -> Result<(), Error> {
+        let slot = &slots[index];
+        let block = self.block_position(slot.block);
+        match index.checked_sub(1).map(|previous| &slots[previous]) {
+            Some(previous) if previous.block == slot.block => {
+                let length = (previous.length + slot.length) as u16;
+                self.write_bytes(block + previous.offset as u64 + 4, &length.to_le_bytes())?
+            }
+            _ => self.write_bytes(block + slot.offset as u64, &0u32.to_le_bytes())?,
+        }
+        changed_directory(directory);
+        Ok(())
+    }
+
+    /// Writes the free counts and the time to the superblock if they changed, and flushes the
+    /// device.
+    fn sync(&self) -> Result<(), Error> {
+        if self.changed.swap(false, Ordering::Relaxed) {
+            let superblock = interrupts::without_interrupts(|| {
+                let mut superblock = self.superblock.lock();
+                set_u32(&mut superblock, 48, now());
+                superblock.clone()
+            });
+            self.write_bytes(SUPERBLOCK_POSITION, &superblock)?
+        }
+        self.device.flush().map_err(|_| Error::Io)
+    }
+}
+
+/// Updates the times of the directory and drops its index, which no longer matches the entries.
+fn changed_directory(directory: &mut RawInode) {
+    let flags = u32_at(&directory.0, INODE_FLAGS);
+    set_u32(&mut directory.0, INODE_FLAGS, flags & !INDEX_FLAG);
+    directory.touch()
+}
+
+struct NodeState {
+    inode: RawInode,
+    /// The inode was deleted, it cannot be used anymore.
+    removed: bool,
+}
+
+struct Node {
+    volume: Arc<Volume>,
+    number: u32,
+    state: Mutex<NodeState>,
+}
+
+impl Node {
+    fn inode(&self) -> Result<RawInode, Error> {
+        interrupts::without_interrupts(|| {
+            let state = self.state.lock();
+            if state.removed {
+                return Err(Error::NotFound);
+            }
+            Ok(state.inode)
+        })
+    }
+
+    /// Changes the inode, in memory and on the device.
+    fn set_inode(&self, inode: RawInode) -> Result<(), Error> {
+        interrupts::without_interrupts(|| self.state.lock().inode = inode);
+        self.volume.write_inode(self.number, &inode)
+    }
+
+    /// Runs `f` with the inode, locking the volume, and writes the inode if it changed.
+    fn with_inode<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut RawInode) -> Result<R, Error>,
+    {
+        self.volume.lock.with(|| {
+            let mut inode = self.inode()?;
+            let old = inode.0;
+            let result = f(&mut inode);
+            if inode.0 != old {
+                self.set_inode(inode)?
+            }
+            result
+        })
+    }
+
+    /// Runs `f` with the inode of the regular file.
+    fn with_file<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut RawInode) -> Result<R, Error>,
+    {
+        self.with_inode(|inode| match inode.kind() {
+            FileType::Directory => Err(Error::IsDirectory),
+            _ if !inode.is_regular_file() => Err(Error::NotSupported),
+            _ => f(inode),
+        })
+    }
+
+    /// Runs `f` with the inode of the directory and its entries.
+    fn with_directory<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut RawInode, Vec<Slot>) -> Result<R, Error>,
+    {
+        self.with_inode(|inode| {
+            if inode.kind() != FileType::Directory {
+                return Err(Error::NotDirectory);
+            }
+            let slots = self.volume.slots(inode)?;
+            f(inode, slots)
+        })
+    }
+
+    /// Adds a new inode of the type to the directory, and runs `init` with it before the entry is
+    /// added.
+    fn add<F>(&self, name: &str, kind: FileType, init: F) -> Result<Arc<dyn Inode>, Error>
+    where
+        F: FnOnce(&Arc<Node>, &mut RawInode) -> Result<(), Error>,
+    {
+        check_name(name)?;
+        self.volume.check_writable()?;
+        self.with_directory(|directory, slots| {
+            if slots
+                .iter()
+                .any(|slot| slot.inode != 0 && slot.name == name.as_bytes())
+            {
+                return Err(Error::AlreadyExists);
+            }
+            let volume = &self.volume;
+            let node = volume.new_node(self.number, kind)?;
+            let mut inode = node.inode()?;
+            let result = init(&node, &mut inode)
+                .and_then(|_| volume.add_slot(directory, name, node.number, kind));
+            if let Err(error) = result {
+                volume.delete(node.number, &mut inode)?;
+                interrupts::without_interrupts(|| node.state.lock().removed = true);
+                return Err(error);
+            }
+            if kind == FileType::Directory {
+                // The `..` entry of the new directory links to this one.
+                directory.set_links(directory.links() + 1)
+            }
+            node.set_inode(inode)?;
+            Ok(node as Arc<dyn Inode>)
+        })
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            let mut nodes = self.volume.nodes.lock();
+            // A new inode may have replaced this one already.
+            if nodes
+                .get(&self.number)
+                .is_some_and(|node| node.strong_count() == 0)
+            {
+                nodes.remove(&self.number);
+            }
+        })
+    }
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> Metadata {
+        let inode = interrupts::without_interrupts(|| self.state.lock().inode);
+        Metadata {
+            inode: self.number as u64,
+            kind: inode.kind(),
+            size: inode.size(),
+            mode: inode.mode() & PERMISSION_MASK,
+            uid: inode.uid(),
+            gid: inode.gid(),
+            accessed: u32_at(&inode.0, INODE_ACCESSED) as u64,
+            modified: u32_at(&inode.0, INODE_MODIFIED) as u64,
+            changed: u32_at(&inode.0, INODE_CHANGED) as u64,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.with_file(|inode| {
+            let volume = &self.volume;
+            let length = inode.size().saturating_sub(offset).min(buffer.len() as u64) as usize;
+            let mut done = 0;
+            while done < length {
+                let position = offset + done as u64;
+                let inside = position % volume.block_size;
+                let part = ((volume.block_size - inside) as usize).min(length - done);
+                let buffer = &mut buffer[done..done + part];
+                match volume.data_block(inode, position / volume.block_size)? {
+                    0 => buffer.iter_mut().for_each(|byte| *byte = 0),
+                    block => volume.read_bytes(volume.block_position(block) + inside, buffer)?,
+                }
+                done += part
+            }
+            Ok(length)
+        })
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        self.volume.check_writable()?;
+        self.with_file(|inode| {
+            let volume = &self.volume;
+            let end = offset
+                .checked_add(data.len() as u64)
+                .ok_or(Error::NoSpace)?;
+            // Without large files, sizes are 31 bit.
+            if end > i32::MAX as u64 && !volume.large_files() {
+                return Err(Error::NoSpace);
+            }
+            let mut done = 0;
+            while done < data.len() {
+                let position = offset + done as u64;
+                let inside = position % volume.block_size;
+                let part = ((volume.block_size - inside) as usize).min(data.len() - done);
+                let index = position / volume.block_size;
+                // Blocks that are written partly are zeroed first.
+                let whole = part as u64 == volume.block_size;
+                let block = match volume.allocate_data_block(inode, index, !whole) {
+                    Ok(block) => block,
+                    Err(error) if done == 0 => return Err(error),
+                    Err(_) => break,
+                };
+                let position = volume.block_position(block) + inside;
+                volume.write_bytes(position, &data[done..done + part])?;
+                done += part
+            }
+            if offset + done as u64 > inode.size() {
+                inode.set_size(offset + done as u64)
+            }
+            inode.touch();
+            Ok(done)
+        })
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), Error> {
+        self.volume.check_writable()?;
+        self.with_file(|inode| {
+            if size > i32::MAX as u64 && !self.volume.large_files() {
+                return Err(Error::NoSpace);
+            }
+            self.volume.resize(inode, size)?;
+            inode.touch();
+            Ok(())
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        self.with_directory(|_, slots| {
+            let slot = slots
+                .iter()
+                .find(|slot| slot.inode != 0 && slot.name == name.as_bytes())
+                .ok_or(Error::NotFound)?;
+            Ok(self.volume.node(slot.inode)? as Arc<dyn Inode>)
+        })
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, Error> {
+        match kind {
+            FileType::File => self.add(name, kind, |_, _| Ok(())),
+            FileType::Directory => {
+                let parent = self.number;
+                self.add(name, kind, |node, inode| {
+                    let volume = &node.volume;
+                    let block = volume.allocate_data_block(inode, 0, true)?;
+                    let size = volume.block_size as usize;
+                    let dot_size = entry_size(1);
+                    let directory = FileType::Directory;
+                    volume.write_slot(block, 0, dot_size, ".", node.number, directory)?;
+                    volume.write_slot(block, dot_size, size - dot_size, "..", parent, directory)?;
+                    inode.set_size(size as u64);
+                    inode.set_links(2);
+                    Ok(())
+                })
+            }
+            FileType::CharDevice | FileType::Symlink => Err(Error::NotSupported),
+        }
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Error> {
+        let slot = self.with_directory(|_, slots| {
+            Ok(slots
+                .into_iter()
+                .filter(|slot| slot.inode != 0 && !slot.is_dot())
+                .nth(index))
+        })?;
+        let slot = match slot {
+            Some(slot) => slot,
+            None => return Ok(None),
+        };
+        let kind = match slot.kind {
+            ENTRY_FILE => FileType::File,
+            ENTRY_DIRECTORY => FileType::Directory,
+            ENTRY_CHAR_DEVICE => FileType::CharDevice,
+            ENTRY_SYMLINK => FileType::Symlink,
+            // Without types in the entries the inode has it.
+            _ => self
+                .volume
+                .lock
+                .with(|| self.volume.node(slot.inode))?
+                .inode()?
+                .kind(),
+        };
+        Ok(Some(DirEntry {
+            name: String::from_utf8_lossy(&slot.name).into_owned(),
+            inode: slot.inode as u64,
+            kind,
+        }))
+    }
+
+    fn remove(&self, name: &str) -> Result<(), Error> {
+        self.volume.check_writable()?;
+        if name == "." || name == ".." {
+            return Err(Error::InvalidPath);
+        }
+        self.with_directory(|directory, slots| {
+            let volume = &self.volume;
+            let index = slots
+                .iter()
+                .position(|slot| slot.inode != 0 && slot.name == name.as_bytes())
+                .ok_or(Error::NotFound)?;
+            let node = volume.node(slots[index].inode)?;
+            let mut inode = node.inode()?;
+            let is_directory = inode.kind() == FileType::Directory;
+            if is_directory {
+                let children = volume.slots(&inode)?;
+                if children
+                    .iter()
+                    .any(|slot| slot.inode != 0 && !slot.is_dot())
+                {
+                    return Err(Error::NotEmpty);
+                }
+                // The `..` entry goes with it.
+                directory.set_links(directory.links().saturating_sub(1));
+                inode.set_links(0)
+            } else {
+                inode.set_links(inode.links().saturating_sub(1))
+            }
+            volume.remove_slot(directory, &slots, index)?;
+            inode.touch_changed();
+            if inode.links() > 0 {
+                return node.set_inode(inode);
+            }
+            volume.delete(node.number, &mut inode)?;
+            interrupts::without_interrupts(|| {
+                let mut state = node.state.lock();
+                state.inode = inode;
+                state.removed = true;
+                volume.nodes.lock().remove(&node.number)
+            });
+            Ok(())
+        })
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, Error> {
+        if target.is_empty() || target.len() >= self.volume.block_size as usize {
+            return Err(Error::InvalidPath);
+        }
+        self.add(name, FileType::Symlink, |node, inode| {
+            // Short targets are kept in the block map.
+            if target.len() < BLOCK_MAP_SIZE {
+                inode.block_map()[..target.len()].copy_from_slice(target.as_bytes())
+            } else {
+                let volume = &node.volume;
+                let block = volume.allocate_data_block(inode, 0, true)?;
+                volume.write_bytes(volume.block_position(block), target.as_bytes())?
+            }
+            inode.set_size(target.len() as u64);
+            Ok(())
+        })
+    }
+
+    fn read_link(&self) -> Result<String, Error> {
+        self.with_inode(|inode| {
+            if inode.kind() != FileType::Symlink {
+                return Err(Error::NotSupported);
+            }
+            let volume = &self.volume;
+            let size = inode.size() as usize;
+            let target = if inode.is_fast_symlink(volume.block_size) {
+                inode.block_map().get(..size).ok_or(Error::Io)?.to_vec()
+            } else {
+                let block = volume.data_block(inode, 0)?;
+                let data = volume.read_block(block)?;
+                data.get(..size).ok_or(Error::Io)?.to_vec()
+            };
+            String::from_utf8(target).map_err(|_| Error::Io)
+        })
+    }
+
+    fn set_mode(&self, mode: u16) -> Result<(), Error> {
+        self.volume.check_writable()?;
+        self.with_inode(|inode| {
+            let mode = inode.mode() & TYPE_MASK | mode & PERMISSION_MASK;
+            set_u16(&mut inode.0, INODE_MODE, mode);
+            inode.touch_changed();
+            Ok(())
+        })
+    }
+
+    fn set_owner(&self, uid: u32, gid: u32) -> Result<(), Error> {
+        self.volume.check_writable()?;
+        self.with_inode(|inode| {
+            set_u16(&mut inode.0, INODE_UID, uid as u16);
+            set_u16(&mut inode.0, INODE_UID_HIGH, (uid >> 16) as u16);
+            set_u16(&mut inode.0, INODE_GID, gid as u16);
+            set_u16(&mut inode.0, INODE_GID_HIGH, (gid >> 16) as u16);
+            inode.touch_changed();
+            Ok(())
+        })
+    }
+}
+
+/// Size of `docs/big.bin` on the test disk, which `build.rs` writes.
+#[cfg(test)]
+const BIG_FILE_SIZE: usize = 300 * 1024 + 100;
+
+/// Mounts the test disk built by `build.rs` at the path. Returns false if the disk is blank, which
+/// it is when `mke2fs` was not installed, and the test is skipped.
+#[cfg(test)]
+fn mount_test_disk(path: &str) -> bool {
+    let device = crate::block::device("hdb").expect("The ext2 test disk is missing");
+    let mut superblock = vec![0; SUPERBLOCK_SIZE];
+    device
+        .read_bytes(SUPERBLOCK_POSITION, &mut superblock)
+        .unwrap();
+    if superblock.iter().all(|&byte| byte == 0) {
+        crate::test::skip("the ext2 test disk is blank");
+        return false;
+    }
+    match super::create(path, FileType::Directory) {
+        Ok(_) | Err(Error::AlreadyExists) => (),
+        Err(error) => panic!("Creating the mount point failed: {:?}", error),
+    }
+    super::mount(path, Arc::new(Ext2Fs::new(device).unwrap())).unwrap();
+    true
+}
+
+#[test_case]
+fn read_host_files() {
+    use super::{lookup_link, read_file, read_link, stat};
+
+    if !mount_test_disk("/tmp/ext2-read") {
+        return;
+    }
+    assert_eq!(
+        read_file("/tmp/ext2-read/hello.txt").unwrap(),
+        b"Hello from the host!\n"
+    );
+    // Its blocks are mapped through single and double indirect blocks.
+    let big = read_file("/tmp/ext2-read/docs/big.bin").unwrap();
+    assert_eq!(big.len(), BIG_FILE_SIZE);
+    assert!(big
+        .iter()
+        .enumerate()
+        .all(|(index, &byte)| byte == (index % 251) as u8));
+    assert_eq!(stat("/tmp/ext2-read/secret.txt").unwrap().mode, 0o600);
+    assert!(stat("/tmp/ext2-read/hello.txt").unwrap().modified > 0);
+
+    // The short target is in the inode, the long one in a block.
+    assert_eq!(read_link("/tmp/ext2-read/hello").unwrap(), "hello.txt");
+    assert_eq!(
+        lookup_link("/tmp/ext2-read/hello").unwrap().metadata().kind,
+        FileType::Symlink
+    );
+    assert_eq!(
+        read_file("/tmp/ext2-read/hello").unwrap(),
+        b"Hello from the host!\n"
+    );
+    assert_eq!(read_link("/tmp/ext2-read/big").unwrap().len(), 72);
+    assert_eq!(
+        stat("/tmp/ext2-read/big").unwrap().size,
+        BIG_FILE_SIZE as u64
+    );
+
+    let root = super::lookup("/tmp/ext2-read").unwrap();
+    let names: Vec<String> = (0..)
+        .map_while(|index| root.read_dir(index).unwrap())
+        .map(|entry| entry.name)
+        .collect();
+    for name in [
+        "lost+found",
+        "hello.txt",
+        "docs",
+        "secret.txt",
+        "hello",
+        "big",
+    ]
+    .iter()
+    {
+        assert!(names.iter().any(|other| other == name), "{}", name);
+    }
+    super::unmount("/tmp/ext2-read").unwrap();
+}
+
+#[test_case]
+fn write_files() {
+    use super::{open, read_file, OpenFlags};
+
+    if !mount_test_disk("/tmp/ext2-write") {
+        return;
+    }
+    // The disk keeps the files of earlier runs, and of runs that failed halfway.
+    for path in [
+        "/tmp/ext2-write/written by the kernel.txt",
+        "/tmp/ext2-write/new directory/link",
+        "/tmp/ext2-write/new directory/data.bin",
+        "/tmp/ext2-write/new directory",
+    ] {
+        let _ = super::remove(path);
+    }
+
+    let directory = super::create("/tmp/ext2-write/new directory", FileType::Directory).unwrap();
+    let data: Vec<u8> = (0..20000).map(|index| index as u8).collect();
+    let file = directory.create("data.bin", FileType::File).unwrap();
+    assert_eq!(file.write_at(0, &data), Ok(data.len()));
+    file.truncate(700).unwrap();
+    assert_eq!(file.write_at(1000, b"end"), Ok(3));
+    let mut buffer = [0xFF; 1100];
+    assert_eq!(file.read_at(0, &mut buffer), Ok(1003));
+    assert_eq!(buffer[..700], data[..700]);
+    assert!(buffer[700..1000].iter().all(|&byte| byte == 0));
+    assert_eq!(&buffer[1000..1003], b"end");
+
+    file.set_mode(0o640).unwrap();
+    file.set_owner(1000, 100).unwrap();
+    let metadata = file.metadata();
+    assert_eq!(
+        (metadata.mode, metadata.uid, metadata.gid),
+        (0o640, 1000, 100)
+    );
+    assert!(metadata.modified + 60 >= crate::time::unix_time());
+
+    super::symlink("/tmp/ext2-write/new directory/link", "data.bin").unwrap();
+    assert_eq!(
+        read_file("/tmp/ext2-write/new directory/link")
+            .unwrap()
+            .len(),
+        1003
+    );
+    assert_eq!(
+        super::remove("/tmp/ext2-write/new directory"),
+        Err(Error::NotEmpty)
+    );
+    super::remove("/tmp/ext2-write/new directory/link").unwrap();
+    super::remove("/tmp/ext2-write/new directory/data.bin").unwrap();
+    assert_eq!(file.read_at(0, &mut buffer), Err(Error::NotFound));
+    super::remove("/tmp/ext2-write/new directory").unwrap();
+
+    // Left on the disk for the host to check.
+    let flags = OpenFlags::WRITE | OpenFlags::CREATE;
+    let file = open("/tmp/ext2-write/written by the kernel.txt", flags).unwrap();
+    assert_eq!(file.write(b"Hello from FerociOS!\n"), Ok(21));
+    super::unmount("/tmp/ext2-write").unwrap();
+
+    assert!(mount_test_disk("/tmp/ext2-write"));
+    assert_eq!(
+        read_file("/tmp/ext2-write/written by the kernel.txt").unwrap(),
+        b"Hello from FerociOS!\n"
+    );
+    assert_eq!(
+        super::lookup("/tmp/ext2-write/new directory").err(),
+        Some(Error::NotFound)
+    );
+    super::unmount("/tmp/ext2-write").unwrap();
+}
@@ -0,0 +1,1151 @@
+//! FAT32: the file system of memory cards and USB sticks, which images for the host can be built
+//! with, like with `mkfs.fat -F 32` and `mcopy`.
+//!
+//! The boot sector describes the layout: reserved sectors, the file allocation tables and the data
+//! area, which is divided into clusters. The FAT has an entry for every cluster, the number of the
+//! next cluster of its file, 0 for free clusters or an end of chain marker. Only the lower 28 bits
+//! of an entry are used. Directories are files of 32 byte entries with 8.3 names in upper case,
+//! other names are stored in UTF-16 in extra entries in front of them.
+//!
+//! Names are compared case-insensitively for ASCII letters. Timestamps are not kept: new entries
+//! get the first day FAT can represent, 1980-01-01.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use spinning::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{DirEntry, Error, FileSystem, FileType, Inode, Metadata, MAX_NAME_LENGTH};
+use crate::block::{BlockDevice, DeviceLock};
+
+const ENTRY_SIZE: usize = 32;
+/// Limit of entries in a directory.
+const MAX_DIRECTORY_ENTRIES: usize = 65536;
+
+// Attributes of directory entries.
+const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+/// The attributes of long name entries, read-only, hidden, system and volume ID.
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+const ATTRIBUTE_LONG_NAME_MASK: u8 = 0x3F;
+
+// Flags in the reserved byte of short entries: the base or extension of the name is lower case.
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXTENSION: u8 = 0x10;
+
+/// First byte of free entries.
+const FREE_ENTRY: u8 = 0xE5;
+/// First byte of the entry after the last one.
+const END_OF_DIRECTORY: u8 = 0;
+/// Stands for 0xE5 as first byte of a name, which marks free entries.
+const ESCAPED_FREE: u8 = 0x05;
+/// Set in the order of the last long name entry, which is stored first.
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_NAME_UNITS: usize = 13;
+/// Offsets of the UTF-16 units in long name entries.
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_UNITS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const DOT: &[u8; 11] = b".          ";
+const DOT_DOT: &[u8; 11] = b"..         ";
+/// 1980-01-01.
+const FIRST_DATE: u16 = 1 << 5 | 1;
+
+const CLUSTER_MASK: u32 = 0x0FFF_FFFF;
+const FIRST_CLUSTER: u32 = 2;
+/// Entries from here on end a chain.
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+/// Clusters from here on are bad or end chains.
+const MAX_CLUSTER: u32 = 0x0FFF_FFF7;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_SIGNATURE: u32 = 0x6141_7272;
+/// The free cluster count of the FSInfo sector when it is not known.
+const UNKNOWN_FREE_COUNT: u32 = 0xFFFF_FFFF;
+
+/// Inode number of the root directory, which has no entry. Other files have the position of their
+/// entry on the device.
+const ROOT_INODE: u64 = 1;
+
+pub struct FatFs {
+    volume: Arc<Volume>,
+    root: Arc<Node>,
+}
+
+impl FatFs {
+    /// Opens the FAT32 file system on the device. Fails with `NotSupported` if there is none.
+    #[allow(dead_code)]
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self, Error> {
+        let volume = Arc::new(Volume::new(device)?);
+        let root = Arc::new(Node {
+            volume: volume.clone(),
+            kind: FileType::Directory,
+            entry: None,
+            state: Mutex::new(NodeState {
+                first_cluster: volume.root_cluster,
+                size: 0,
+                removed: false,
+            }),
+        });
+        Ok(FatFs { volume, root })
+    }
+}
+
+impl FileSystem for FatFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    /// Writes the FSInfo sector if clusters were allocated or freed, and flushes the device.
+    fn sync(&self) -> Result<(), Error> {
+        self.volume.lock.with(|| self.volume.sync())
+    }
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    sector_size: u64,
+    sectors_per_cluster: u64,
+    cluster_size: u64,
+    /// First sector of the first FAT.
+    fat_start: u64,
+    fat_sectors: u64,
+    fat_count: u64,
+    /// The FAT that is read. Changes are written to all of them if they are mirrored.
+    active_fat: u64,
+    mirrored: bool,
+    data_start: u64,
+    /// The cluster after the last one.
+    cluster_end: u32,
+    root_cluster: u32,
+    fsinfo_sector: Option<u64>,
+    /// Held during every operation, which may block on the device.
+    lock: DeviceLock,
+    /// Where the search for a free cluster starts.
+    next_free: AtomicU32,
+    /// Clusters were allocated or freed since the last sync.
+    changed: AtomicBool,
+    /// The inodes in use by the position of their entry, so that every file has one.
+    nodes: Mutex<BTreeMap<u64, Weak<Node>>>,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Volume {
+    fn new(device: Arc<dyn BlockDevice>) -> Result<Self, Error> {
+        let sector_size = device.sector_size() as u64;
+        let mut boot = vec![0; sector_size as usize];
+        device.read_sectors(0, &mut boot).map_err(|_| Error::Io)?;
+
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved_sectors = u16_at(&boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        let total_sectors = match u16_at(&boot, 19) {
+            0 => u32_at(&boot, 32) as u64,
+            sectors => sectors as u64,
+        };
+        let fat_sectors = u32_at(&boot, 36) as u64;
+        let flags = u16_at(&boot, 40);
+        let root_cluster = u32_at(&boot, 44);
+        let fsinfo_sector = match u16_at(&boot, 48) {
+            0 | 0xFFFF => None,
+            sector => Some(sector as u64),
+        };
+        let data_start = reserved_sectors + fat_count * fat_sectors;
+        // FAT12 and FAT16 have a root directory of fixed size and a 16 bit FAT size.
+        let fat32 = boot[510..512] == [0x55, 0xAA]
+            && u16_at(&boot, 11) as u64 == sector_size
+            && sectors_per_cluster.is_power_of_two()
+            && reserved_sectors > 0
+            && fat_count > 0
+            && u16_at(&boot, 17) == 0
+            && u16_at(&boot, 22) == 0
+            && fat_sectors > 0
+            && data_start < total_sectors
+            && total_sectors <= device.sector_count();
+        if !fat32 {
+            return Err(Error::NotSupported);
+        }
+        let clusters = (total_sectors - data_start) / sectors_per_cluster;
+        let fat_entries = fat_sectors * sector_size / 4;
+        let cluster_end = (clusters + FIRST_CLUSTER as u64)
+            .min(fat_entries)
+            .min(MAX_CLUSTER as u64) as u32;
+        if !(FIRST_CLUSTER..cluster_end).contains(&root_cluster) {
+            return Err(Error::NotSupported);
+        }
+        let mirrored = flags & 0x80 == 0;
+        let active_fat = if mirrored { 0 } else { (flags & 0xF) as u64 };
+        if active_fat >= fat_count {
+            return Err(Error::NotSupported);
+        }
+
+        Ok(Volume {
+            device,
+            sector_size,
+            sectors_per_cluster,
+            cluster_size: sectors_per_cluster * sector_size,
+            fat_start: reserved_sectors,
+            fat_sectors,
+            fat_count,
+            active_fat,
+            mirrored,
+            data_start,
+            cluster_end,
+            root_cluster,
+            fsinfo_sector,
+            lock: DeviceLock::new(),
+            next_free: AtomicU32::new(FIRST_CLUSTER),
+            changed: AtomicBool::new(false),
+            nodes: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Reads the bytes at the position on the device.
+    fn read_bytes(&self, position: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        self.device
+            .read_bytes(position, buffer)
+            .map_err(|_| Error::Io)
+    }
+
+    /// Writes the bytes at the position on the device.
+    fn write_bytes(&self, position: u64, data: &[u8]) -> Result<(), Error> {
+        self.device
+            .write_bytes(position, data)
+            .map_err(|_| Error::Io)
+    }
+
+    fn cluster_position(&self, cluster: u32) -> u64 {
+        let sector = self.data_start + (cluster - FIRST_CLUSTER) as u64 * self.sectors_per_cluster;
+        sector * self.sector_size
+    }
+
+    fn fat_position(&self, fat: u64, cluster: u32) -> u64 {
+        (self.fat_start + fat * self.fat_sectors) * self.sector_size + cluster as u64 * 4
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, Error> {
+        let mut entry = [0; 4];
+        self.read_bytes(self.fat_position(self.active_fat, cluster), &mut entry)?;
+        Ok(u32::from_le_bytes(entry) & CLUSTER_MASK)
+    }
+
+    /// Changes the entry of the cluster, keeping the upper 4 bits.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), Error> {
+        for fat in 0..self.fat_count {
+            if !self.mirrored && fat != self.active_fat {
+                continue;
+            }
+            let position = self.fat_position(fat, cluster);
+            let mut entry = [0; 4];
+            self.read_bytes(position, &mut entry)?;
+            let entry = u32::from_le_bytes(entry) & !CLUSTER_MASK | value;
+            self.write_bytes(position, &entry.to_le_bytes())?
+        }
+        self.changed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Returns the clusters of the chain starting at `first`, which is empty for 0.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, Error> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 {
+            // A chain that is longer than the volume has a loop.
+            if !(FIRST_CLUSTER..self.cluster_end).contains(&cluster)
+                || chain.len() >= self.cluster_end as usize
+            {
+                return Err(Error::Io);
+            }
+            chain.push(cluster);
+            cluster = match self.fat_entry(cluster)? {
+                next if next >= END_OF_CHAIN => 0,
+                // Free clusters in a chain are as wrong as invalid numbers.
+                0 => return Err(Error::Io),
+                next => next,
+            }
+        }
+        Ok(chain)
+    }
+
+    /// Finds a free cluster, searching the FAT one sector at a time.
+    fn find_free(&self) -> Result<u32, Error> {
+        let count = self.cluster_end - FIRST_CLUSTER;
+        let start = self.next_free.load(Ordering::Relaxed).max(FIRST_CLUSTER) - FIRST_CLUSTER;
+        let entries_per_sector = (self.sector_size / 4) as u32;
+        let mut sector = vec![0; self.sector_size as usize];
+        let mut loaded = None;
+        for index in 0..count {
+            let cluster = FIRST_CLUSTER + (start + index) % count;
+            let fat_sector = cluster / entries_per_sector;
+            if loaded != Some(fat_sector) {
+                let position = self.fat_position(self.active_fat, fat_sector * entries_per_sector);
+                self.read_bytes(position, &mut sector)?;
+                loaded = Some(fat_sector)
+            }
+            if u32_at(&sector, (cluster % entries_per_sector) as usize * 4) & CLUSTER_MASK == 0 {
+                self.next_free.store(cluster + 1, Ordering::Relaxed);
+                return Ok(cluster);
+            }
+        }
+        Err(Error::NoSpace)
+    }
+
+    /// Allocates a zeroed cluster at the end of the chain that ends with `last`, or as a new chain.
+    fn allocate_cluster(&self, last: Option<u32>) -> Result<u32, Error> {
+        let cluster = self.find_free()?;
+        self.write_bytes(
+            self.cluster_position(cluster),
+            &vec![0; self.cluster_size as usize],
+        )?;
+        self.set_fat_entry(cluster, CLUSTER_MASK)?;
+        if let Some(last) = last {
+            self.set_fat_entry(last, cluster)?
+        }
+        Ok(cluster)
+    }
+
+    fn free_clusters(&self, clusters: &[u32]) -> Result<(), Error> {
+        clusters
+            .iter()
+            .try_for_each(|&cluster| self.set_fat_entry(cluster, 0))
+    }
+
+    /// Changes the size of a file, allocating or freeing clusters. Bytes after the old size are
+    /// zeros.
+    fn resize(&self, state: &mut NodeState, size: u32) -> Result<(), Error> {
+        let clusters = self.chain(state.first_cluster)?;
+        let needed = (size as u64).div_ceil(self.cluster_size) as usize;
+        if needed < clusters.len() {
+            match needed.checked_sub(1) {
+                Some(last) => self.set_fat_entry(clusters[last], CLUSTER_MASK)?,
+                None => state.first_cluster = 0,
+            }
+            self.free_clusters(&clusters[needed..])?
+        }
+        let mut added: Vec<u32> = Vec::new();
+        for _ in clusters.len()..needed {
+            let last = added.last().or_else(|| clusters.last()).copied();
+            match self.allocate_cluster(last) {
+                Ok(cluster) => added.push(cluster),
+                Err(error) => {
+                    // Give the clusters back, the file keeps its size.
+                    if let Some(&last) = clusters.last() {
+                        self.set_fat_entry(last, CLUSTER_MASK)?
+                    }
+                    self.free_clusters(&added)?;
+                    return Err(error);
+                }
+            }
+        }
+        if let (None, Some(&first)) = (clusters.first(), added.first()) {
+            state.first_cluster = first
+        }
+
+        // New clusters are zeroed, the rest of the old last cluster may still hold old data.
+        let used = state.size as u64 % self.cluster_size;
+        if size > state.size && used != 0 {
+            let length = (size - state.size) as u64;
+            let zeros = vec![0; (self.cluster_size - used).min(length) as usize];
+            let cluster = clusters[(state.size as u64 / self.cluster_size) as usize];
+            self.write_bytes(self.cluster_position(cluster) + used, &zeros)?
+        }
+        state.size = size;
+        Ok(())
+    }
+
+    /// Returns the slots of the directory starting at the cluster, with their positions.
+    fn slots(&self, first_cluster: u32) -> Result<Vec<(u64, [u8; ENTRY_SIZE])>, Error> {
+        let mut slots = Vec::new();
+        let mut data = vec![0; self.cluster_size as usize];
+        for cluster in self.chain(first_cluster)? {
+            if slots.len() >= MAX_DIRECTORY_ENTRIES {
+                return Err(Error::Io);
+            }
+            let position = self.cluster_position(cluster);
+            self.read_bytes(position, &mut data)?;
+            for (index, slot) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+                let slot_position = position + (index * ENTRY_SIZE) as u64;
+                slots.push((slot_position, slot.try_into().unwrap()))
+            }
+        }
+        Ok(slots)
+    }
+
+    /// Returns the files and directories of the directory starting at the cluster.
+    fn entries(&self, first_cluster: u32) -> Result<Vec<Entry>, Error> {
+        Ok(parse_entries(&self.slots(first_cluster)?))
+    }
+
+    /// Returns the inode of the entry, the same one as long as it is in use.
+    fn node(self: &Arc<Self>, entry: &Entry) -> Arc<Node> {
+        interrupts::without_interrupts(|| {
+            let mut nodes = self.nodes.lock();
+            if let Some(node) = nodes.get(&entry.position).and_then(Weak::upgrade) {
+                return node;
+            }
+            let node = Arc::new(Node {
+                volume: self.clone(),
+                kind: entry.kind(),
+                entry: Some(entry.position),
+                state: Mutex::new(NodeState {
+                    first_cluster: entry.first_cluster,
+                    size: entry.size,
+                    removed: false,
+                }),
+            });
+            nodes.insert(entry.position, Arc::downgrade(&node));
+            node
+        })
+    }
+
+    /// Adds the entries of a new file or directory to the directory, which grows if needed.
+    fn add_entry(
+        &self,
+        directory: u32,
+        name: &str,
+        attributes: u8,
+        first_cluster: u32,
+    ) -> Result<u64, Error> {
+        let mut slots = self.slots(directory)?;
+        let names: Vec<[u8; 11]> = slots
+            .iter()
+            .filter(|(_, slot)| slot[0] != FREE_ENTRY && slot[0] != END_OF_DIRECTORY)
+            .map(|(_, slot)| slot[..11].try_into().unwrap())
+            .collect();
+        let (short, case, long) = match exact_short_name(name) {
+            Some((short, case)) => (short, case, None),
+            None => (unique_short_name(name, &names)?, 0, Some(name)),
+        };
+        let mut entries = long.map_or_else(Vec::new, |long| long_entries(long, &short));
+        let mut entry = [0; ENTRY_SIZE];
+        entry[..11].copy_from_slice(&short);
+        entry[11] = attributes;
+        entry[12] = case;
+        for &offset in [16, 18, 24].iter() {
+            entry[offset..offset + 2].copy_from_slice(&FIRST_DATE.to_le_bytes())
+        }
+        entry[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+        entries.push(entry);
+
+        let start = loop {
+            if let Some(start) = find_free_slots(&slots, entries.len()) {
+                break start;
+            }
+            let last = self.chain(directory)?.last().copied();
+            if slots.len() + (self.cluster_size as usize / ENTRY_SIZE) > MAX_DIRECTORY_ENTRIES {
+                return Err(Error::NoSpace);
+            }
+            self.allocate_cluster(last)?;
+            slots = self.slots(directory)?
+        };
+        for (index, entry) in entries.iter().enumerate() {
+            self.write_bytes(slots[start + index].0, entry)?
+        }
+        Ok(slots[start + entries.len() - 1].0)
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        if let (Some(sector), true) = (self.fsinfo_sector, self.changed.load(Ordering::Relaxed)) {
+            let mut fsinfo = vec![0; self.sector_size as usize];
+            let position = sector * self.sector_size;
+            self.read_bytes(position, &mut fsinfo)?;
+            if u32_at(&fsinfo, 0) == FSINFO_LEAD_SIGNATURE
+                && u32_at(&fsinfo, 484) == FSINFO_SIGNATURE
+            {
+                // Counting the free clusters would read the whole FAT.
+                fsinfo[488..492].copy_from_slice(&UNKNOWN_FREE_COUNT.to_le_bytes());
+                let next_free = self.next_free.load(Ordering::Relaxed);
+                fsinfo[492..496].copy_from_slice(&next_free.to_le_bytes());
+                self.write_bytes(position, &fsinfo)?
+            }
+            self.changed.store(false, Ordering::Relaxed)
+        }
+        self.device.flush().map_err(|_| Error::Io)
+    }
+}
+
+/// A file or directory of a directory.
+struct Entry {
+    name: String,
+    /// The 8.3 name, which also names the entry if it has a long name.
+    short_name: String,
+    /// Position of the short entry on the device.
+    position: u64,
+    /// Positions of the long name entries in front of it.
+    long_positions: Vec<u64>,
+    attributes: u8,
+    first_cluster: u32,
+    size: u32,
+}
+
+impl Entry {
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.short_name.eq_ignore_ascii_case(name)
+    }
+
+    fn kind(&self) -> FileType {
+        if self.attributes & ATTRIBUTE_DIRECTORY != 0 {
+            FileType::Directory
+        } else {
+            FileType::File
+        }
+    }
+}
+
+/// Returns the entries of the slots of a directory, without `.`, `..` and volume labels.
+fn parse_entries(slots: &[(u64, [u8; ENTRY_SIZE])]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut long: Vec<&(u64, [u8; ENTRY_SIZE])> = Vec::new();
+    for slot in slots {
+        let (position, bytes) = slot;
+        match bytes[0] {
+            END_OF_DIRECTORY => break,
+            FREE_ENTRY => {
+                long.clear();
+                continue;
+            }
+            _ => (),
+        }
+        if bytes[11] & ATTRIBUTE_LONG_NAME_MASK == ATTRIBUTE_LONG_NAME {
+            if bytes[0] & LAST_LONG_ENTRY != 0 {
+                long.clear()
+            }
+            long.push(slot);
+            continue;
+        }
+        let short = &bytes[..11];
+        if bytes[11] & ATTRIBUTE_VOLUME_ID != 0 || short == DOT || short == DOT_DOT {
+            long.clear();
+            continue;
+        }
+        let long_slots: Vec<&[u8; ENTRY_SIZE]> = long.iter().map(|(_, bytes)| bytes).collect();
+        let (name, long_positions) = match long_name(&long_slots, checksum(short)) {
+            Some(name) => (name, long.iter().map(|(position, _)| *position).collect()),
+            None => (short_name(bytes), Vec::new()),
+        };
+        long.clear();
+        entries.push(Entry {
+            name,
+            short_name: short_name(bytes),
+            position: *position,
+            long_positions,
+            attributes: bytes[11],
+            first_cluster: (u16_at(bytes, 20) as u32) << 16 | u16_at(bytes, 26) as u32,
+            size: u32_at(bytes, 28),
+        })
+    }
+    entries
+}
+
+/// The checksum of the short name that long name entries have.
+fn checksum(short: &[u8]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// Assembles the long name from its entries, which are stored last part first. Returns `None` if
+/// they do not belong together or to the short entry.
+fn long_name(slots: &[&[u8; ENTRY_SIZE]], checksum: u8) -> Option<String> {
+    let count = slots.len();
+    let valid = count > 0
+        && slots[0][0] as usize == count | LAST_LONG_ENTRY as usize
+        && slots.iter().enumerate().all(|(index, slot)| {
+            (slot[0] & !LAST_LONG_ENTRY) as usize == count - index && slot[13] == checksum
+        });
+    if !valid {
+        return None;
+    }
+    let units: Vec<u16> = slots
+        .iter()
+        .rev()
+        .flat_map(|slot| {
+            LONG_NAME_OFFSETS
+                .iter()
+                .map(move |&offset| u16_at(&slot[..], offset))
+        })
+        .take_while(|&unit| unit != 0)
+        .collect();
+    core::char::decode_utf16(units)
+        .collect::<Result<String, _>>()
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+/// Returns the name of a short entry, like `README.TXT`.
+fn short_name(entry: &[u8; ENTRY_SIZE]) -> String {
+    let part = |bytes: &[u8], lower_case: bool| {
+        let mut part = String::new();
+        for (index, &byte) in bytes.iter().enumerate() {
+            let byte = if index == 0 && byte == ESCAPED_FREE {
+                FREE_ENTRY
+            } else {
+                byte
+            };
+            // Bytes beyond ASCII are in a code page, Latin-1 is a guess.
+            let byte = if lower_case {
+                byte.to_ascii_lowercase()
+            } else {
+                byte
+            };
+            part.push(byte as char)
+        }
+        String::from(part.trim_end_matches(' '))
+    };
+    let mut name = part(&entry[..8], entry[12] & LOWER_CASE_BASE != 0);
+    let extension = part(&entry[8..11], entry[12] & LOWER_CASE_EXTENSION != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension)
+    }
+    name
+}
+
+/// Characters besides letters and digits that short names may have.
+fn is_short_name_character(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// Returns the short name and case flags for names that need no long name: 8.3 names where the
+/// base and the extension are either upper or lower case.
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = match name.find('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    let mut short = [b' '; 11];
+    let mut case = 0;
+    let parts = [
+        (base, 8, 0, LOWER_CASE_BASE),
+        (extension, 3, 8, LOWER_CASE_EXTENSION),
+    ];
+    for &(part, length, offset, flag) in parts.iter() {
+        if part.len() > length || (part.is_empty() && length == 8) {
+            return None;
+        }
+        let upper = part.to_ascii_uppercase();
+        if !upper.chars().all(is_short_name_character) {
+            return None;
+        }
+        if part != upper {
+            if part != part.to_ascii_lowercase() {
+                return None;
+            }
+            case |= flag
+        }
+        short[offset..offset + part.len()].copy_from_slice(upper.as_bytes());
+    }
+    if name.ends_with('.') {
+        return None;
+    }
+    Some((short, case))
+}
+
+/// Makes a short name for a long name that differs from the ones of the directory, like
+/// `LONGFI~1TXT` for `long file.txt`.
+fn unique_short_name(name: &str, names: &[[u8; 11]]) -> Result<[u8; 11], Error> {
+    let convert = |part: &str, length: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| c.to_ascii_uppercase())
+            .map(|c| {
+                if is_short_name_character(c) {
+                    c as u8
+                } else {
+                    b'_'
+                }
+            })
+            .take(length)
+            .collect()
+    };
+    let (base, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (convert(&name[..dot], 8), convert(&name[dot + 1..], 3)),
+        _ => (convert(name, 8), Vec::new()),
+    };
+    let mut short = [b' '; 11];
+    short[8..8 + extension.len()].copy_from_slice(&extension);
+    for number in 1..1_000_000 {
+        let tail = alloc::format!("~{}", number);
+        let kept = base.len().min(8 - tail.len());
+        short[..8].fill(b' ');
+        short[..kept].copy_from_slice(&base[..kept]);
+        short[kept..kept + tail.len()].copy_from_slice(tail.as_bytes());
+        if !names.contains(&short) {
+            return Ok(short);
+        }
+    }
+    Err(Error::NoSpace)
+}
+
+/// Returns the long name entries for the name, in the order they are stored.
+fn long_entries(name: &str, short: &[u8; 11]) -> Vec<[u8; ENTRY_SIZE]> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LONG_NAME_UNITS);
+    let checksum = checksum(short);
+    (1..=count)
+        .rev()
+        .map(|order| {
+            let mut entry = [0; ENTRY_SIZE];
+            entry[0] = order as u8 | if order == count { LAST_LONG_ENTRY } else { 0 };
+            entry[11] = ATTRIBUTE_LONG_NAME;
+            entry[13] = checksum;
+            for (index, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                // The name ends with a zero unless it fills the entry, the rest is padding.
+                let unit = match (order - 1) * LONG_NAME_UNITS + index {
+                    position if position < units.len() => units[position],
+                    position if position == units.len() => 0,
+                    _ => 0xFFFF,
+                };
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes())
+            }
+            entry
+        })
+        .collect()
+}
+
+/// Returns the index of the first of `count` consecutive free slots.
+fn find_free_slots(slots: &[(u64, [u8; ENTRY_SIZE])], count: usize) -> Option<usize> {
+    let mut run = 0;
+    let mut ended = false;
+    for (index, (_, slot)) in slots.iter().enumerate() {
+        ended |= slot[0] == END_OF_DIRECTORY;
+        if ended || slot[0] == FREE_ENTRY {
+            run += 1;
+            if run == count {
+                return Some(index + 1 - count);
+            }
+        } else {
+            run = 0
+        }
+    }
+    None
+}
+
+/// Checks that FAT can store the name.
+fn check_name(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name.encode_utf16().count() <= MAX_NAME_LENGTH
+        && name != "."
+        && name != ".."
+        && !name.ends_with('.')
+        && !name.ends_with(' ')
+        && !name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c));
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidPath)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct NodeState {
+    first_cluster: u32,
+    /// Size of a file, 0 for directories.
+    size: u32,
+    /// The entry was removed, the inode cannot be used anymore.
+    removed: bool,
+}
+
+struct Node {
+    volume: Arc<Volume>,
+    kind: FileType,
+    /// Position of the entry on the device, `None` for the root directory.
+    entry: Option<u64>,
+    state: Mutex<NodeState>,
+}
+
+impl Node {
+    fn state(&self) -> Result<NodeState, Error> {
+        let state = interrupts::without_interrupts(|| *self.state.lock());
+        if state.removed {
+            return Err(Error::NotFound);
+        }
+        Ok(state)
+    }
+
+    /// Changes the state, and the entry on the device to match.
+    fn set_state(&self, state: NodeState) -> Result<(), Error> {
+        interrupts::without_interrupts(|| *self.state.lock() = state);
+        let position = match self.entry {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+        let mut entry = [0; ENTRY_SIZE];
+        self.volume.read_bytes(position, &mut entry)?;
+        entry[20..22].copy_from_slice(&((state.first_cluster >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(state.first_cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&state.size.to_le_bytes());
+        self.volume.write_bytes(position, &entry)
+    }
+
+    /// Runs `f` with the state of the file, locking the volume.
+    fn with_file<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(NodeState) -> Result<R, Error>,
+    {
+        if self.kind != FileType::File {
+            return Err(Error::IsDirectory);
+        }
+        self.volume.lock.with(|| f(self.state()?))
+    }
+
+    /// Runs `f` with the entries of the directory and its first cluster, locking the volume.
+    fn with_entries<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(Vec<Entry>, u32) -> Result<R, Error>,
+    {
+        if self.kind != FileType::Directory {
+            return Err(Error::NotDirectory);
+        }
+        self.volume.lock.with(|| {
+            let first_cluster = self.state()?.first_cluster;
+            f(self.volume.entries(first_cluster)?, first_cluster)
+        })
+    }
+
+    /// Copies between the file and the buffer, for the bytes from the offset that are inside the
+    /// file. Returns the amount of bytes.
+    fn transfer<F>(
+        &self,
+        state: NodeState,
+        offset: u64,
+        length: usize,
+        mut f: F,
+    ) -> Result<usize, Error>
+    where
+        F: FnMut(u64, core::ops::Range<usize>) -> Result<(), Error>,
+    {
+        let volume = &self.volume;
+        let length = (state.size as u64)
+            .saturating_sub(offset)
+            .min(length as u64) as usize;
+        let clusters = volume.chain(state.first_cluster)?;
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let cluster = *clusters
+                .get((position / volume.cluster_size) as usize)
+                .ok_or(Error::Io)?;
+            let inside = position % volume.cluster_size;
+            let part = ((volume.cluster_size - inside) as usize).min(length - done);
+            f(volume.cluster_position(cluster) + inside, done..done + part)?;
+            done += part
+        }
+        Ok(length)
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        if let Some(position) = self.entry {
+            interrupts::without_interrupts(|| {
+                let mut nodes = self.volume.nodes.lock();
+                // A new inode may have replaced this one already.
+                if nodes
+                    .get(&position)
+                    .is_some_and(|node| node.strong_count() == 0)
+                {
+                    nodes.remove(&position);
+                }
+            })
+        }
+    }
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> Metadata {
+        let state = interrupts::without_interrupts(|| *self.state.lock());
+        Metadata::new(
+            self.entry.unwrap_or(ROOT_INODE),
+            self.kind,
+            state.size as u64,
+        )
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.with_file(|state| {
+            self.transfer(state, offset, buffer.len(), |position, range| {
+                self.volume.read_bytes(position, &mut buffer[range])
+            })
+        })
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        self.with_file(|mut state| {
+            let end = offset
+                .checked_add(data.len() as u64)
+                .ok_or(Error::NoSpace)?;
+            // Sizes are 32 bit.
+            let end: u32 = end.try_into().map_err(|_| Error::NoSpace)?;
+            if end > state.size {
+                self.volume.resize(&mut state, end)?;
+                self.set_state(state)?
+            }
+            self.transfer(state, offset, data.len(), |position, range| {
+                self.volume.write_bytes(position, &data[range])
+            })
+        })
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), Error> {
+        let size: u32 = size.try_into().map_err(|_| Error::NoSpace)?;
+        self.with_file(|mut state| {
+            self.volume.resize(&mut state, size)?;
+            self.set_state(state)
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        self.with_entries(|entries, _| {
+            let entry = entries
+                .iter()
+                .find(|entry| entry.matches(name))
+                .ok_or(Error::NotFound)?;
+            Ok(self.volume.node(entry) as Arc<dyn Inode>)
+        })
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, Error> {
+        check_name(name)?;
+        let attributes = match kind {
+            FileType::File => ATTRIBUTE_ARCHIVE,
+            FileType::Directory => ATTRIBUTE_DIRECTORY,
+            FileType::CharDevice | FileType::Symlink => return Err(Error::NotSupported),
+        };
+        self.with_entries(|entries, directory| {
+            if entries.iter().any(|entry| entry.matches(name)) {
+                return Err(Error::AlreadyExists);
+            }
+            let volume = &self.volume;
+            let mut first_cluster = 0;
+            if kind == FileType::Directory {
+                first_cluster = volume.allocate_cluster(None)?;
+                // `..` of directories in the root points to cluster 0.
+                let parent = if self.entry.is_none() { 0 } else { directory };
+                let position = volume.cluster_position(first_cluster);
+                for (index, &(name, cluster)) in
+                    [(DOT, first_cluster), (DOT_DOT, parent)].iter().enumerate()
+                {
+                    let mut entry = [0; ENTRY_SIZE];
+                    entry[..11].copy_from_slice(name);
+                    entry[11] = ATTRIBUTE_DIRECTORY;
+                    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+                    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+                    volume.write_bytes(position + (index * ENTRY_SIZE) as u64, &entry)?
+                }
+            }
+            let position = match volume.add_entry(directory, name, attributes, first_cluster) {
+                Ok(position) => position,
+                Err(error) => {
+                    if first_cluster != 0 {
+                        volume.free_clusters(&[first_cluster])?
+                    }
+                    return Err(error);
+                }
+            };
+            let entries = volume.entries(directory)?;
+            let entry = entries
+                .iter()
+                .find(|entry| entry.position == position)
+                .ok_or(Error::Io)?;
+            Ok(volume.node(entry) as Arc<dyn Inode>)
+        })
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Error> {
+        self.with_entries(|entries, _| {
+            Ok(entries.into_iter().nth(index).map(|entry| DirEntry {
+                inode: entry.position,
+                kind: entry.kind(),
+                name: entry.name,
+            }))
+        })
+    }
+
+    fn remove(&self, name: &str) -> Result<(), Error> {
+        self.with_entries(|entries, _| {
+            let volume = &self.volume;
+            let entry = entries
+                .iter()
+                .find(|entry| entry.matches(name))
+                .ok_or(Error::NotFound)?;
+            if entry.kind() == FileType::Directory
+                && !volume.entries(entry.first_cluster)?.is_empty()
+            {
+                return Err(Error::NotEmpty);
+            }
+            for &position in entry.long_positions.iter().chain(&[entry.position]) {
+                volume.write_bytes(position, &[FREE_ENTRY])?
+            }
+            volume.free_clusters(&volume.chain(entry.first_cluster)?)?;
+            interrupts::without_interrupts(|| {
+                let node = volume.nodes.lock().remove(&entry.position);
+                if let Some(node) = node.and_then(|node| node.upgrade()) {
+                    node.state.lock().removed = true
+                }
+            });
+            Ok(())
+        })
+    }
+}
+
+#[test_case]
+fn short_names() {
+    assert_eq!(exact_short_name("README.TXT"), Some((*b"README  TXT", 0)));
+    assert_eq!(
+        exact_short_name("notes.TXT"),
+        Some((*b"NOTES   TXT", LOWER_CASE_BASE))
+    );
+    assert_eq!(exact_short_name("Notes.txt"), None);
+    assert_eq!(exact_short_name("archive.tar.gz"), None);
+    assert_eq!(exact_short_name("toolongname"), None);
+
+    let names = [*b"LONGFI~1TXT"];
+    assert_eq!(
+        unique_short_name("long file.txt", &names),
+        Ok(*b"LONGFI~2TXT")
+    );
+    assert_eq!(unique_short_name(".profile", &[]), Ok(*b"PROFIL~1   "));
+    assert_eq!(unique_short_name("ä+b", &[]), Ok(*b"__B~1      "));
+
+    let entries = long_entries("A long file name.txt", b"ALONGF~1TXT");
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0][0], 2 | LAST_LONG_ENTRY);
+    let slots: Vec<&[u8; ENTRY_SIZE]> = entries.iter().collect();
+    assert_eq!(
+        long_name(&slots, checksum(b"ALONGF~1TXT")).as_deref(),
+        Some("A long file name.txt")
+    );
+    assert_eq!(long_name(&slots[1..], checksum(b"ALONGF~1TXT")), None);
+}
+
+/// Number of lines of `docs/notes.txt` on the test disk.
+#[cfg(test)]
+const NOTES_LINES: usize = 200;
+
+/// Mounts the partition of the test disk built by `build.rs` at the path. Returns false if the
+/// disk is blank, which it is when `mkfs.fat` or `mcopy` was not installed, and the test is skipped.
+#[cfg(test)]
+fn mount_test_disk(path: &str) -> bool {
+    let device = match crate::block::device("hdd1") {
+        Some(device) => device,
+        None => {
+            assert!(
+                crate::block::device("hdd").is_some(),
+                "The FAT test disk is missing"
+            );
+            crate::test::skip("the FAT test disk is blank");
+            return false;
+        }
+    };
+    match super::create(path, FileType::Directory) {
+        Ok(_) | Err(Error::AlreadyExists) => (),
+        Err(error) => panic!("Creating the mount point failed: {:?}", error),
+    }
+    super::mount(path, Arc::new(FatFs::new(device).unwrap())).unwrap();
+    true
+}
+
+#[test_case]
+fn read_host_files() {
+    use super::read_file;
+
+    if !mount_test_disk("/tmp/fat-read") {
+        return;
+    }
+    assert_eq!(
+        read_file("/tmp/fat-read/hello.txt").unwrap(),
+        b"Hello from the host!\n"
+    );
+    assert_eq!(
+        read_file("/tmp/fat-read/A long file name.txt").unwrap(),
+        b"Long names are stored in extra entries.\n"
+    );
+    let notes: String = (0..NOTES_LINES)
+        .map(|line| alloc::format!("Line {}\n", line))
+        .collect();
+    assert_eq!(
+        read_file("/tmp/fat-read/docs/notes.txt").unwrap(),
+        notes.as_bytes()
+    );
+
+    let root = super::lookup("/tmp/fat-read").unwrap();
+    let names: Vec<String> = (0..)
+        .map_while(|index| root.read_dir(index).unwrap())
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(names[..3], ["HELLO.TXT", "A long file name.txt", "docs"]);
+    assert_eq!(
+        super::stat("/tmp/fat-read/docs").unwrap().kind,
+        FileType::Directory
+    );
+    super::unmount("/tmp/fat-read").unwrap();
+}
+
+#[test_case]
+fn write_files() {
+    use super::{open, read_file, OpenFlags};
+
+    if !mount_test_disk("/tmp/fat-write") {
+        return;
+    }
+    // The disk keeps the files of earlier runs, and of runs that failed halfway.
+    for path in [
+        "/tmp/fat-write/written by the kernel.txt",
+        "/tmp/fat-write/new directory/data.bin",
+        "/tmp/fat-write/new directory",
+    ] {
+        let _ = super::remove(path);
+    }
+
+    let directory = super::create("/tmp/fat-write/new directory", FileType::Directory).unwrap();
+    let data: Vec<u8> = (0..3000).map(|index| index as u8).collect();
+    let file = directory.create("Data.bin", FileType::File).unwrap();
+    assert_eq!(file.write_at(0, &data), Ok(data.len()));
+    assert_eq!(directory.lookup("DATA.BIN").unwrap().metadata().size, 3000);
+    file.truncate(700).unwrap();
+    assert_eq!(file.write_at(1000, b"end"), Ok(3));
+    let mut buffer = [0xFF; 1100];
+    assert_eq!(file.read_at(0, &mut buffer), Ok(1003));
+    assert_eq!(buffer[..700], data[..700]);
+    assert!(buffer[700..1000].iter().all(|&byte| byte == 0));
+    assert_eq!(&buffer[1000..1003], b"end");
+
+    assert_eq!(
+        super::remove("/tmp/fat-write/new directory"),
+        Err(Error::NotEmpty)
+    );
+    super::remove("/tmp/fat-write/new directory/data.bin").unwrap();
+    assert_eq!(file.read_at(0, &mut buffer), Err(Error::NotFound));
+    super::remove("/tmp/fat-write/new directory").unwrap();
+
+    // Left on the disk for the host to check.
+    let flags = OpenFlags::WRITE | OpenFlags::CREATE;
+    let file = open("/tmp/fat-write/written by the kernel.txt", flags).unwrap();
+    assert_eq!(file.write(b"Hello from FerociOS!\n"), Ok(21));
+    super::unmount("/tmp/fat-write").unwrap();
+
+    assert!(mount_test_disk("/tmp/fat-write"));
+    assert_eq!(
+        read_file("/tmp/fat-write/written by the kernel.txt").unwrap(),
+        b"Hello from FerociOS!\n"
+    );
+    assert_eq!(
+        super::lookup("/tmp/fat-write/new directory").err(),
+        Some(Error::NotFound)
+    );
+    super::unmount("/tmp/fat-write").unwrap();
+}
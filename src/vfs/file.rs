@@ -0,0 +1,207 @@
+//! Open files and the file descriptor tables of processes.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::ops::BitOr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{console, DirEntry, Error, FileType, Inode, Metadata};
+
+/// Most files a process can have open at the same time.
+const MAX_FILES: usize = 64;
+
+/// How a file is opened, combined with `|`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// Create the file if it does not exist.
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// Remove the contents of the file.
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 3);
+    /// Every write goes to the end of the file.
+    pub const APPEND: OpenFlags = OpenFlags(1 << 4);
+
+    pub fn empty() -> Self {
+        OpenFlags(0)
+    }
+
+    pub fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+/// Position to seek to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Seek {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// An open file description: the inode with the offset of the next read or write. Descriptors of
+/// forked processes share it.
+pub struct OpenFile {
+    inode: Arc<dyn Inode>,
+    kind: FileType,
+    flags: OpenFlags,
+    /// Offset in bytes, or the index of the next entry in directories.
+    offset: AtomicU64,
+}
+
+impl OpenFile {
+    pub fn open(inode: Arc<dyn Inode>, flags: OpenFlags) -> Result<Arc<OpenFile>, Error> {
+        let kind = inode.metadata().kind;
+        if kind == FileType::Directory && flags.contains(OpenFlags::WRITE) {
+            return Err(Error::IsDirectory);
+        }
+        if flags.contains(OpenFlags::TRUNCATE) && kind == FileType::File {
+            inode.truncate(0)?
+        }
+        Ok(Arc::new(OpenFile {
+            inode,
+            kind,
+            flags,
+            offset: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.inode.metadata()
+    }
+
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        if self.kind == FileType::Directory {
+            return Err(Error::IsDirectory);
+        }
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(Error::BadDescriptor);
+        }
+        // The offset is not locked while reading, which may block.
+        let offset = self.offset.load(Ordering::SeqCst);
+        let read = self.inode.read_at(offset, buffer)?;
+        self.offset.store(offset + read as u64, Ordering::SeqCst);
+        Ok(read)
+    }
+
+    pub fn write(&self, data: &[u8]) -> Result<usize, Error> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(Error::BadDescriptor);
+        }
+        let offset = if self.flags.contains(OpenFlags::APPEND) {
+            self.inode.metadata().size
+        } else {
+            self.offset.load(Ordering::SeqCst)
+        };
+        let written = self.inode.write_at(offset, data)?;
+        self.offset.store(offset + written as u64, Ordering::SeqCst);
+        Ok(written)
+    }
+
+    /// Moves the offset and returns the new one. Only files can seek.
+    pub fn seek(&self, seek: Seek) -> Result<u64, Error> {
+        if self.kind != FileType::File {
+            return Err(Error::NotSupported);
+        }
+        let metadata = self.inode.metadata();
+        let offset = match seek {
+            Seek::Start(offset) => Some(offset),
+            Seek::Current(delta) => add(self.offset.load(Ordering::SeqCst), delta),
+            Seek::End(delta) => add(metadata.size, delta),
+        }
+        .ok_or(Error::InvalidOffset)?;
+        self.offset.store(offset, Ordering::SeqCst);
+        Ok(offset)
+    }
+
+    /// Returns the next entry of the directory, or `None` after the last one.
+    pub fn read_dir(&self) -> Result<Option<DirEntry>, Error> {
+        let index = self.offset.load(Ordering::SeqCst);
+        let entry = self.inode.read_dir(index as usize)?;
+        if entry.is_some() {
+            self.offset.store(index + 1, Ordering::SeqCst)
+        }
+        Ok(entry)
+    }
+}
+
+fn add(offset: u64, delta: i64) -> Option<u64> {
+    let offset = i64::try_from(offset).ok()?.checked_add(delta)?;
+    u64::try_from(offset).ok()
+}
+
+/// The open files of a process by descriptor. New descriptors are the lowest free ones.
+#[derive(Clone)]
+pub struct FileTable {
+    files: Vec<Option<Arc<OpenFile>>>,
+}
+
+impl FileTable {
+    pub fn new() -> Self {
+        FileTable { files: Vec::new() }
+    }
+
+    /// Creates a table with the console as standard input, output and error.
+    pub fn with_console() -> Self {
+        let mut table = FileTable::new();
+        for file in console::standard_files().iter() {
+            table.add(file.clone()).expect("Empty file table is full");
+        }
+        table
+    }
+
+    /// Adds the file at the lowest free descriptor, which is returned.
+    pub fn add(&mut self, file: Arc<OpenFile>) -> Result<usize, Error> {
+        let descriptor = match self.files.iter().position(Option::is_none) {
+            Some(descriptor) => descriptor,
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err(Error::TooManyFiles),
+        };
+        self.files[descriptor] = Some(file);
+        Ok(descriptor)
+    }
+
+    pub fn get(&self, descriptor: usize) -> Result<Arc<OpenFile>, Error> {
+        self.files
+            .get(descriptor)
+            .cloned()
+            .flatten()
+            .ok_or(Error::BadDescriptor)
+    }
+
+    /// Removes the descriptor. The file is closed once no descriptor refers to it.
+    pub fn close(&mut self, descriptor: usize) -> Result<(), Error> {
+        let file = self.files.get_mut(descriptor).ok_or(Error::BadDescriptor)?;
+        file.take().map(drop).ok_or(Error::BadDescriptor)
+    }
+}
+
+#[test_case]
+fn lowest_descriptors_are_used() {
+    let mut table = FileTable::with_console();
+    let console = table.get(1).unwrap();
+    assert_eq!(table.add(console.clone()), Ok(3));
+    table.close(1).unwrap();
+    assert_eq!(table.close(1), Err(Error::BadDescriptor));
+    assert_eq!(table.add(console.clone()), Ok(1));
+    assert!(table.get(7).is_err());
+
+    for descriptor in 4..MAX_FILES {
+        assert_eq!(table.add(console.clone()), Ok(descriptor));
+    }
+    assert_eq!(table.add(console).err(), Some(Error::TooManyFiles));
+}
@@ -0,0 +1,229 @@
+//! The initramfs: a newc cpio archive built by `build.rs` from `initramfs/` and the user programs,
+//! which is unpacked into the root file system at boot.
+//!
+//! Every entry is a 110 byte header of ASCII hex fields, followed by the name with a zero byte and
+//! the contents, both padded to 4 bytes. The archive ends with the entry `TRAILER!!!`.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::str;
+
+use super::{Error, FileType, Inode};
+
+/// The archive built by `build.rs`.
+pub static INITRAMFS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.cpio"));
+
+const MAGIC: &[u8] = b"070701";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+// File types in the mode field.
+const MODE_TYPE: u32 = 0o170000;
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_FILE: u32 = 0o100000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitramfsError {
+    /// The archive ends in the middle of an entry or has no trailer.
+    Truncated,
+    /// An entry has no newc header or an invalid name.
+    InvalidHeader,
+    File(Error),
+}
+
+impl From<Error> for InitramfsError {
+    fn from(error: Error) -> Self {
+        InitramfsError::File(error)
+    }
+}
+
+/// An entry of the archive.
+struct Entry<'a> {
+    /// Path relative to the root of the archive.
+    name: &'a str,
+    /// `None` for types of files that are skipped, like symbolic links.
+    kind: Option<FileType>,
+    data: &'a [u8],
+}
+
+/// Reads the entry at the offset and moves the offset to the next one. Returns `None` at the
+/// trailer.
+fn read_entry<'a>(
+    archive: &'a [u8],
+    offset: &mut usize,
+) -> Result<Option<Entry<'a>>, InitramfsError> {
+    let header = bytes(archive, *offset, HEADER_SIZE)?;
+    if &header[..6] != MAGIC {
+        return Err(InitramfsError::InvalidHeader);
+    }
+    let field = |index: usize| {
+        let digits = &header[6 + index * 8..6 + (index + 1) * 8];
+        str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or(InitramfsError::InvalidHeader)
+    };
+    let mode = field(1)?;
+    let file_size = field(6)? as usize;
+    let name_size = field(11)? as usize;
+
+    let name_start = *offset + HEADER_SIZE;
+    let name = match bytes(archive, name_start, name_size)?.split_last() {
+        Some((0, name)) => str::from_utf8(name).map_err(|_| InitramfsError::InvalidHeader)?,
+        _ => return Err(InitramfsError::InvalidHeader),
+    };
+    let data_start = align(name_start + name_size);
+    let data = bytes(archive, data_start, file_size)?;
+    *offset = align(data_start + file_size);
+
+    if name == TRAILER {
+        return Ok(None);
+    }
+    let kind = match mode & MODE_TYPE {
+        MODE_DIRECTORY => Some(FileType::Directory),
+        MODE_FILE => Some(FileType::File),
+        _ => None,
+    };
+    Ok(Some(Entry { name, kind, data }))
+}
+
+fn bytes(archive: &[u8], offset: usize, length: usize) -> Result<&[u8], InitramfsError> {
+    let end = offset
+        .checked_add(length)
+        .ok_or(InitramfsError::Truncated)?;
+    archive.get(offset..end).ok_or(InitramfsError::Truncated)
+}
+
+fn align(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Unpacks the archive into the directory. Missing parent directories are created, existing files
+/// are replaced.
+pub fn unpack(archive: &[u8], root: &Arc<dyn Inode>) -> Result<(), InitramfsError> {
+    let mut offset = 0;
+    while let Some(entry) = read_entry(archive, &mut offset)? {
+        let kind = match entry.kind {
+            Some(kind) => kind,
+            None => continue,
+        };
+        let names: Vec<&str> = entry
+            .name
+            .split('/')
+            .filter(|&name| !name.is_empty() && name != ".")
+            .collect();
+        if names
+            .iter()
+            .any(|&name| name == ".." || name.len() > super::MAX_NAME_LENGTH)
+        {
+            return Err(InitramfsError::InvalidHeader);
+        }
+        // The root itself has no name.
+        let (name, parents) = match names.split_last() {
+            Some(split) => split,
+            None => continue,
+        };
+
+        let mut directory = root.clone();
+        for parent in parents {
+            directory = lookup_or_create(&directory, parent, FileType::Directory)?
+        }
+        let inode = lookup_or_create(&directory, name, kind)?;
+        if kind == FileType::File {
+            inode.truncate(0)?;
+            inode.write_at(0, entry.data)?;
+        }
+    }
+    Ok(())
+}
+
+fn lookup_or_create(
+    directory: &Arc<dyn Inode>,
+    name: &str,
+    kind: FileType,
+) -> Result<Arc<dyn Inode>, Error> {
+    match directory.lookup(name) {
+        Ok(inode) if inode.metadata().kind == kind => Ok(inode),
+        Ok(_) => Err(Error::AlreadyExists),
+        Err(Error::NotFound) => directory.create(name, kind),
+        Err(error) => Err(error),
+    }
+}
+
+/// Builds a newc archive like `build.rs` does.
+#[cfg(test)]
+fn archive(entries: &[(&str, u32, &[u8])]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    let trailer = (TRAILER, 0, &[][..]);
+    for (inode, &(name, mode, data)) in entries.iter().chain([trailer].iter()).enumerate() {
+        let fields = [
+            inode as u32,
+            mode,
+            0,
+            0,
+            1,
+            0,
+            data.len() as u32,
+            0,
+            0,
+            0,
+            0,
+        ];
+        let mut header = alloc::string::String::from("070701");
+        for field in fields.iter().chain(&[name.len() as u32 + 1, 0]) {
+            header += &alloc::format!("{:08X}", field);
+        }
+        archive.extend_from_slice(header.as_bytes());
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(align(archive.len()), 0);
+        archive.extend_from_slice(data);
+        archive.resize(align(archive.len()), 0);
+    }
+    archive
+}
+
+#[test_case]
+fn unpack_archive() {
+    use super::tmpfs::TmpFs;
+    use super::FileSystem;
+
+    let archive = archive(&[
+        (".", MODE_DIRECTORY | 0o755, b""),
+        ("etc", MODE_DIRECTORY | 0o755, b""),
+        ("etc/motd", MODE_FILE | 0o644, b"Welcome\n"),
+        ("usr/lib/empty", MODE_FILE | 0o644, b""),
+        ("etc/link", 0o120000 | 0o777, b"motd"),
+    ]);
+    let tmpfs = TmpFs::new();
+    let root = tmpfs.root();
+    unpack(&archive, &root).unwrap();
+
+    let motd = root.lookup("etc").unwrap().lookup("motd").unwrap();
+    let mut buffer = [0; 16];
+    assert_eq!(motd.read_at(0, &mut buffer), Ok(8));
+    assert_eq!(&buffer[..8], b"Welcome\n");
+    let lib = root.lookup("usr").unwrap().lookup("lib").unwrap();
+    assert_eq!(lib.lookup("empty").unwrap().metadata().size, 0);
+    // Symbolic links are skipped.
+    assert_eq!(root.lookup("etc").unwrap().read_dir(1), Ok(None));
+
+    assert_eq!(
+        unpack(&archive[..archive.len() - 8], &root),
+        Err(InitramfsError::Truncated)
+    );
+    assert_eq!(unpack(b"070702", &root), Err(InitramfsError::Truncated));
+    let mut broken = archive.clone();
+    broken[0] = b'1';
+    assert_eq!(unpack(&broken, &root), Err(InitramfsError::InvalidHeader));
+}
+
+#[test_case]
+fn boot_initramfs() {
+    let hello = super::lookup("/bin/hello").unwrap();
+    assert_eq!(hello.metadata().kind, FileType::File);
+    let mut magic = [0; 4];
+    assert_eq!(hello.read_at(0, &mut magic), Ok(4));
+    assert_eq!(&magic, b"\x7FELF");
+    assert!(super::stat("/etc/motd").unwrap().size > 0);
+}
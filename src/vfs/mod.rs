@@ -0,0 +1,627 @@
+//! The virtual file system, which puts every file system into one tree of paths.
+//!
+//! File systems implement `FileSystem` and `Inode`, and are mounted on a directory. Paths are
+//! absolute and resolved lexically: `.` and `..` are removed first, then the path is looked up in
+//! the file system with the longest mount path that is a prefix of it. Symbolic links are followed
+//! by putting their target in place of the link and resolving the new path the same way.
+//!
+//! Opening an inode gives an `OpenFile`, the open file description with the file offset. Processes
+//! refer to them by descriptor through their `FileTable`.
+//!
+//! The root is a tmpfs with the contents of the initramfs. Disks with FAT32 or ext2 can be mounted
+//! anywhere.
+
+pub mod console;
+pub mod ext2;
+pub mod fat;
+mod file;
+pub mod initramfs;
+pub mod tmpfs;
+
+pub use file::{FileTable, OpenFile, OpenFlags, Seek};
+
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spinning::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Longest name of a directory entry, in bytes.
+pub const MAX_NAME_LENGTH: usize = 255;
+/// Most symbolic links followed while resolving one path.
+pub const MAX_SYMLINKS: usize = 8;
+
+lazy_static! {
+    static ref MOUNTS: Mutex<Mounts> = Mutex::new(Mounts::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    NotFound,
+    NotDirectory,
+    IsDirectory,
+    AlreadyExists,
+    /// The directory to remove has entries.
+    NotEmpty,
+    /// The path is not absolute or has a name that is too long.
+    InvalidPath,
+    ReadOnly,
+    NoSpace,
+    /// A mount point is in use.
+    Busy,
+    /// The device failed.
+    Io,
+    /// The inode does not support the operation, like seeking on the console.
+    NotSupported,
+    /// The offset would be negative or too large.
+    InvalidOffset,
+    /// The descriptor is unknown, or the file is not open for reading or writing.
+    BadDescriptor,
+    /// The file table is full.
+    TooManyFiles,
+    /// Resolving the path followed more than `MAX_SYMLINKS` symbolic links.
+    TooManyLinks,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    /// Device that reads and writes streams of bytes, like the console.
+    CharDevice,
+    /// Symbolic link, a path that is resolved in place of the link.
+    Symlink,
+}
+
+impl FileType {
+    /// Returns the usual permission bits of new inodes of the type.
+    pub fn default_mode(self) -> u16 {
+        match self {
+            FileType::File => 0o644,
+            FileType::Directory => 0o755,
+            FileType::CharDevice => 0o666,
+            FileType::Symlink => 0o777,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// Number of the inode, unique within its file system.
+    pub inode: u64,
+    pub kind: FileType,
+    pub size: u64,
+    /// Permission bits, like 0o644 for read and write access of the owner and read access of the
+    /// group and others.
+    pub mode: u16,
+    /// User and group that own the inode.
+    pub uid: u32,
+    pub gid: u32,
+    /// Times of the last access, change of the contents and change of the inode, in seconds since
+    /// 1970-01-01 00:00:00 UTC. 0 if the file system does not keep them.
+    pub accessed: u64,
+    pub modified: u64,
+    pub changed: u64,
+}
+
+impl Metadata {
+    /// Creates the metadata of an inode owned by root with the default mode of its type, for file
+    /// systems without permissions.
+    pub fn new(inode: u64, kind: FileType, size: u64) -> Self {
+        Metadata {
+            inode,
+            kind,
+            size,
+            mode: kind.default_mode(),
+            uid: 0,
+            gid: 0,
+            accessed: 0,
+            modified: 0,
+            changed: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub kind: FileType,
+}
+
+pub trait FileSystem: Send + Sync {
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Writes changes that are only in memory to the device.
+    fn sync(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A file, directory or device of a file system.
+///
+/// Operations that do not apply to the kind of inode fail with the default implementations.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// Reads from the offset into the buffer, returns the amount of bytes read. 0 means the end of
+    /// the file.
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, Error> {
+        Err(Error::IsDirectory)
+    }
+
+    /// Writes the data at the offset, growing the file if needed. Returns the amount of bytes
+    /// written.
+    fn write_at(&self, _offset: u64, _data: &[u8]) -> Result<usize, Error> {
+        Err(Error::IsDirectory)
+    }
+
+    /// Changes the size of the file, new bytes are zeros.
+    fn truncate(&self, _size: u64) -> Result<(), Error> {
+        Err(Error::IsDirectory)
+    }
+
+    /// Finds the entry of the directory with the name.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, Error> {
+        Err(Error::NotDirectory)
+    }
+
+    /// Adds an empty file or directory with the name to the directory.
+    fn create(&self, _name: &str, _kind: FileType) -> Result<Arc<dyn Inode>, Error> {
+        Err(Error::NotDirectory)
+    }
+
+    /// Returns the entry of the directory at the index, or `None` after the last one.
+    fn read_dir(&self, _index: usize) -> Result<Option<DirEntry>, Error> {
+        Err(Error::NotDirectory)
+    }
+
+    /// Removes the file, symbolic link or empty directory with the name from the directory.
+    fn remove(&self, _name: &str) -> Result<(), Error> {
+        Err(Error::NotDirectory)
+    }
+
+    /// Adds a symbolic link with the name and the target path to the directory.
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, Error> {
+        Err(Error::NotSupported)
+    }
+
+    /// Returns the target of the symbolic link.
+    fn read_link(&self) -> Result<String, Error> {
+        Err(Error::NotSupported)
+    }
+
+    /// Changes the permission bits.
+    #[allow(dead_code)]
+    fn set_mode(&self, _mode: u16) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
+
+    /// Changes the user and group that own the inode.
+    #[allow(dead_code)]
+    fn set_owner(&self, _uid: u32, _gid: u32) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
+}
+
+struct Mount {
+    path: Vec<String>,
+    file_system: Arc<dyn FileSystem>,
+}
+
+/// The mounted file systems.
+struct Mounts {
+    mounts: Vec<Mount>,
+}
+
+impl Mounts {
+    fn new() -> Self {
+        Mounts { mounts: Vec::new() }
+    }
+
+    /// Returns the file system the path is in and the rest of the path inside of it.
+    fn find<'a>(&self, path: &[&'a str]) -> Option<(Arc<dyn FileSystem>, Vec<&'a str>)> {
+        self.mounts
+            .iter()
+            .filter(|mount| mount.path.len() <= path.len())
+            .filter(|mount| mount.path.iter().zip(path).all(|(a, b)| a == b))
+            .max_by_key(|mount| mount.path.len())
+            .map(|mount| {
+                let rest = path[mount.path.len()..].to_vec();
+                (mount.file_system.clone(), rest)
+            })
+    }
+
+    fn add(&mut self, path: &[&str], file_system: Arc<dyn FileSystem>) -> Result<(), Error> {
+        if self.is_mount_point(path) {
+            return Err(Error::Busy);
+        }
+        self.mounts.push(Mount {
+            path: path.iter().map(|name| name.to_string()).collect(),
+            file_system,
+        });
+        Ok(())
+    }
+
+    fn is_mount_point(&self, path: &[&str]) -> bool {
+        self.mounts.iter().any(|mount| mount.path == path)
+    }
+
+    fn remove(&mut self, path: &[&str]) -> Result<Arc<dyn FileSystem>, Error> {
+        let index = self
+            .mounts
+            .iter()
+            .position(|mount| mount.path == path)
+            .ok_or(Error::NotFound)?;
+        let nested = |mount: &Mount| {
+            mount.path.len() > path.len() && mount.path.starts_with(&self.mounts[index].path)
+        };
+        if self.mounts.iter().any(nested) {
+            return Err(Error::Busy);
+        }
+        Ok(self.mounts.remove(index).file_system)
+    }
+}
+
+/// Splits the absolute path into its names, without `.` and `..`.
+fn components(path: &str) -> Result<Vec<&str>, Error> {
+    let relative = path.strip_prefix('/').ok_or(Error::InvalidPath)?;
+    let mut components = Vec::new();
+    for name in relative.split('/') {
+        match name {
+            "" | "." => (),
+            ".." => drop(components.pop()),
+            name if name.len() > MAX_NAME_LENGTH => return Err(Error::InvalidPath),
+            name => components.push(name),
+        }
+    }
+    Ok(components)
+}
+
+/// Returns the inode at the path, following symbolic links.
+fn resolve(mounts: &Mutex<Mounts>, path: &[&str]) -> Result<Arc<dyn Inode>, Error> {
+    resolve_links(mounts, path, true)
+}
+
+/// Returns the inode at the path. A symbolic link at the end of the path is only followed with
+/// `follow`, the ones in front of it always are.
+fn resolve_links(
+    mounts: &Mutex<Mounts>,
+    path: &[&str],
+    follow: bool,
+) -> Result<Arc<dyn Inode>, Error> {
+    let mut path: Vec<String> = path.iter().map(|name| name.to_string()).collect();
+    let mut links = 0;
+    'resolve: loop {
+        // The lookups run without the lock, file systems may block.
+        let (file_system, start) = interrupts::without_interrupts(|| {
+            let names: Vec<&str> = path.iter().map(String::as_str).collect();
+            let (file_system, rest) = mounts.lock().find(&names)?;
+            Some((file_system, names.len() - rest.len()))
+        })
+        .ok_or(Error::NotFound)?;
+        let mut inode = file_system.root();
+        for position in start..path.len() {
+            inode = inode.lookup(&path[position])?;
+            let last = position + 1 == path.len();
+            if inode.metadata().kind != FileType::Symlink || (last && !follow) {
+                continue;
+            }
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(Error::TooManyLinks);
+            }
+            // Relative targets start at the directory of the link.
+            let target = inode.read_link()?;
+            let mut names: Vec<&str> = Vec::new();
+            if !target.starts_with('/') {
+                names.extend(path[..position].iter().map(String::as_str))
+            }
+            names.push(&target);
+            names.extend(path[position + 1..].iter().map(String::as_str));
+            let new = alloc::format!("/{}", names.join("/"));
+            path = components(&new)?
+                .iter()
+                .map(|name| name.to_string())
+                .collect();
+            continue 'resolve;
+        }
+        return Ok(inode);
+    }
+}
+
+/// Mounts a tmpfs as the root and unpacks the initramfs into it. The heap must be initialized.
+pub fn init() {
+    let root = tmpfs::TmpFs::new();
+    initramfs::unpack(initramfs::INITRAMFS, &root.root()).expect("Unpacking the initramfs failed");
+    mount("/", Arc::new(root)).expect("Mounting the root failed");
+    match create("/tmp", FileType::Directory) {
+        Ok(_) | Err(Error::AlreadyExists) => (),
+        Err(error) => panic!("Creating /tmp failed: {:?}", error),
+    }
+}
+
+/// Mounts the file system on the directory at the path, or as the root with `/`.
+pub fn mount(path: &str, file_system: Arc<dyn FileSystem>) -> Result<(), Error> {
+    mount_in(&MOUNTS, path, file_system)
+}
+
+fn mount_in(
+    mounts: &Mutex<Mounts>,
+    path: &str,
+    file_system: Arc<dyn FileSystem>,
+) -> Result<(), Error> {
+    let path = components(path)?;
+    if !path.is_empty() && resolve(mounts, &path)?.metadata().kind != FileType::Directory {
+        return Err(Error::NotDirectory);
+    }
+    interrupts::without_interrupts(|| mounts.lock().add(&path, file_system))
+}
+
+/// Syncs and removes the file system mounted at the path. File systems mounted inside of it have
+/// to be unmounted first.
+#[allow(dead_code)]
+pub fn unmount(path: &str) -> Result<Arc<dyn FileSystem>, Error> {
+    let path = components(path)?;
+    let (file_system, rest) =
+        interrupts::without_interrupts(|| MOUNTS.lock().find(&path)).ok_or(Error::NotFound)?;
+    if rest.is_empty() {
+        file_system.sync()?
+    }
+    interrupts::without_interrupts(|| MOUNTS.lock().remove(&path))
+}
+
+/// Syncs all mounted file systems.
+#[allow(dead_code)]
+pub fn sync() -> Result<(), Error> {
+    let file_systems: Vec<Arc<dyn FileSystem>> = interrupts::without_interrupts(|| {
+        let mounts = MOUNTS.lock();
+        mounts
+            .mounts
+            .iter()
+            .map(|mount| mount.file_system.clone())
+            .collect()
+    });
+    file_systems
+        .iter()
+        .try_for_each(|file_system| file_system.sync())
+}
+
+/// Returns the inode at the path.
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>, Error> {
+    resolve(&MOUNTS, &components(path)?)
+}
+
+/// Returns the inode at the path, or the symbolic link itself if the path ends at one.
+#[allow(dead_code)]
+pub fn lookup_link(path: &str) -> Result<Arc<dyn Inode>, Error> {
+    resolve_links(&MOUNTS, &components(path)?, false)
+}
+
+pub fn stat(path: &str) -> Result<Metadata, Error> {
+    Ok(lookup(path)?.metadata())
+}
+
+/// Creates an empty file or directory at the path. The parent directory must exist.
+pub fn create(path: &str, kind: FileType) -> Result<Arc<dyn Inode>, Error> {
+    create_in(&MOUNTS, path, kind)
+}
+
+fn create_in(mounts: &Mutex<Mounts>, path: &str, kind: FileType) -> Result<Arc<dyn Inode>, Error> {
+    let path = components(path)?;
+    let (name, parent) = path.split_last().ok_or(Error::AlreadyExists)?;
+    resolve(mounts, parent)?.create(name, kind)
+}
+
+/// Creates a symbolic link to the target at the path. The target does not have to exist.
+#[allow(dead_code)]
+pub fn symlink(path: &str, target: &str) -> Result<Arc<dyn Inode>, Error> {
+    symlink_in(&MOUNTS, path, target)
+}
+
+fn symlink_in(mounts: &Mutex<Mounts>, path: &str, target: &str) -> Result<Arc<dyn Inode>, Error> {
+    let path = components(path)?;
+    let (name, parent) = path.split_last().ok_or(Error::AlreadyExists)?;
+    if target.is_empty() {
+        return Err(Error::InvalidPath);
+    }
+    resolve(mounts, parent)?.symlink(name, target)
+}
+
+/// Returns the target of the symbolic link at the path.
+#[allow(dead_code)]
+pub fn read_link(path: &str) -> Result<String, Error> {
+    lookup_link(path)?.read_link()
+}
+
+/// Removes the file, symbolic link or empty directory at the path, which must not be a mount
+/// point. Symbolic links are removed, not their targets.
+#[allow(dead_code)]
+pub fn remove(path: &str) -> Result<(), Error> {
+    remove_in(&MOUNTS, path)
+}
+
+fn remove_in(mounts: &Mutex<Mounts>, path: &str) -> Result<(), Error> {
+    let path = components(path)?;
+    let (name, parent) = path.split_last().ok_or(Error::Busy)?;
+    if interrupts::without_interrupts(|| mounts.lock().is_mount_point(&path)) {
+        return Err(Error::Busy);
+    }
+    resolve(mounts, parent)?.remove(name)
+}
+
+/// Returns the contents of the file at the path.
+pub fn read_file(path: &str) -> Result<Vec<u8>, Error> {
+    let file = open(path, OpenFlags::READ)?;
+    let mut contents = alloc::vec![0; file.metadata().size as usize];
+    let mut read = 0;
+    while read < contents.len() {
+        match file.read(&mut contents[read..])? {
+            0 => break,
+            length => read += length,
+        }
+    }
+    contents.truncate(read);
+    Ok(contents)
+}
+
+/// Opens the file or directory at the path.
+pub fn open(path: &str, flags: OpenFlags) -> Result<Arc<OpenFile>, Error> {
+    open_in(&MOUNTS, path, flags)
+}
+
+fn open_in(mounts: &Mutex<Mounts>, path: &str, flags: OpenFlags) -> Result<Arc<OpenFile>, Error> {
+    let inode = match resolve(mounts, &components(path)?) {
+        Err(Error::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            create_in(mounts, path, FileType::File)?
+        }
+        result => result?,
+    };
+    OpenFile::open(inode, flags)
+}
+
+#[cfg(test)]
+fn test_mounts() -> Mutex<Mounts> {
+    let mounts = Mutex::new(Mounts::new());
+    mount_in(&mounts, "/", Arc::new(tmpfs::TmpFs::new())).unwrap();
+    mounts
+}
+
+#[test_case]
+fn paths_are_normalized() {
+    assert_eq!(components("/").unwrap(), Vec::<&str>::new());
+    assert_eq!(components("//a/./b/../c/").unwrap(), ["a", "c"]);
+    assert_eq!(components("/../a").unwrap(), ["a"]);
+    assert_eq!(components("a/b"), Err(Error::InvalidPath));
+    let long = alloc::format!("/{}", "x".repeat(MAX_NAME_LENGTH + 1));
+    assert_eq!(components(&long), Err(Error::InvalidPath));
+}
+
+#[test_case]
+fn create_and_resolve() {
+    let mounts = test_mounts();
+    create_in(&mounts, "/bin", FileType::Directory).unwrap();
+    create_in(&mounts, "/bin/ls", FileType::File).unwrap();
+    assert_eq!(
+        create_in(&mounts, "/bin/ls", FileType::File).err(),
+        Some(Error::AlreadyExists)
+    );
+
+    let ls = resolve(&mounts, &components("/bin/../bin/./ls").unwrap()).unwrap();
+    assert_eq!(ls.metadata().kind, FileType::File);
+    assert_eq!(
+        resolve(&mounts, &["bin", "ls", "x"]).err(),
+        Some(Error::NotDirectory)
+    );
+    assert_eq!(resolve(&mounts, &["missing"]).err(), Some(Error::NotFound));
+    assert_eq!(
+        create_in(&mounts, "/missing/file", FileType::File).err(),
+        Some(Error::NotFound)
+    );
+
+    assert_eq!(remove_in(&mounts, "/bin"), Err(Error::NotEmpty));
+    remove_in(&mounts, "/bin/ls").unwrap();
+    remove_in(&mounts, "/bin").unwrap();
+    assert_eq!(remove_in(&mounts, "/bin"), Err(Error::NotFound));
+    assert_eq!(remove_in(&mounts, "/"), Err(Error::Busy));
+}
+
+#[test_case]
+fn mount_points() {
+    let mounts = test_mounts();
+    create_in(&mounts, "/mnt", FileType::Directory).unwrap();
+    create_in(&mounts, "/file", FileType::File).unwrap();
+    assert_eq!(
+        mount_in(&mounts, "/file", Arc::new(tmpfs::TmpFs::new())),
+        Err(Error::NotDirectory)
+    );
+    mount_in(&mounts, "/mnt", Arc::new(tmpfs::TmpFs::new())).unwrap();
+    assert_eq!(
+        mount_in(&mounts, "/mnt", Arc::new(tmpfs::TmpFs::new())),
+        Err(Error::Busy)
+    );
+
+    // Files below the mount point are in the mounted file system.
+    create_in(&mounts, "/mnt/inner", FileType::File).unwrap();
+    let root = resolve(&mounts, &[]).unwrap();
+    assert_eq!(
+        root.lookup("mnt").unwrap().lookup("inner").err(),
+        Some(Error::NotFound)
+    );
+    assert!(resolve(&mounts, &["mnt", "inner"]).is_ok());
+    // `..` leaves the mounted file system.
+    assert!(resolve(&mounts, &components("/mnt/../file").unwrap()).is_ok());
+
+    interrupts::without_interrupts(|| mounts.lock().remove(&["mnt"])).unwrap();
+    assert_eq!(
+        resolve(&mounts, &["mnt", "inner"]).err(),
+        Some(Error::NotFound)
+    );
+}
+
+#[test_case]
+fn symbolic_links() {
+    let mounts = test_mounts();
+    create_in(&mounts, "/usr/", FileType::Directory).unwrap();
+    create_in(&mounts, "/usr/lib", FileType::Directory).unwrap();
+    create_in(&mounts, "/usr/lib/libc", FileType::File).unwrap();
+    symlink_in(&mounts, "/lib", "usr/lib").unwrap();
+    symlink_in(&mounts, "/usr/lib/current", "../lib/./libc").unwrap();
+    symlink_in(&mounts, "/usr/libc", "/lib/current").unwrap();
+
+    let libc = resolve(&mounts, &["usr", "lib", "libc"]).unwrap();
+    for path in [
+        "/lib/libc",
+        "/lib/current",
+        "/usr/libc",
+        "/lib/../usr/lib/libc",
+    ]
+    .iter()
+    {
+        let inode = resolve(&mounts, &components(path).unwrap()).unwrap();
+        assert_eq!(inode.metadata().inode, libc.metadata().inode, "{}", path);
+    }
+    let link = resolve_links(&mounts, &["lib"], false).unwrap();
+    assert_eq!(link.metadata().kind, FileType::Symlink);
+    assert_eq!(link.read_link().unwrap(), "usr/lib");
+    // New files are created where the link points to.
+    create_in(&mounts, "/lib/libm", FileType::File).unwrap();
+    assert!(resolve(&mounts, &["usr", "lib", "libm"]).is_ok());
+
+    symlink_in(&mounts, "/loop", "/loop/x").unwrap();
+    assert_eq!(resolve(&mounts, &["loop"]).err(), Some(Error::TooManyLinks));
+    symlink_in(&mounts, "/dangling", "/missing").unwrap();
+    assert_eq!(resolve(&mounts, &["dangling"]).err(), Some(Error::NotFound));
+    remove_in(&mounts, "/lib").unwrap();
+    assert!(resolve(&mounts, &["usr", "lib", "libc"]).is_ok());
+}
+
+#[test_case]
+fn open_files() {
+    let mounts = test_mounts();
+    assert_eq!(
+        open_in(&mounts, "/notes", OpenFlags::READ).err(),
+        Some(Error::NotFound)
+    );
+    let file = open_in(&mounts, "/notes", OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+    assert_eq!(file.write(b"hello world"), Ok(11));
+    assert_eq!(file.read(&mut [0; 4]), Err(Error::BadDescriptor));
+
+    let file = open_in(&mounts, "/notes", OpenFlags::READ).unwrap();
+    let mut buffer = [0; 5];
+    assert_eq!(file.seek(Seek::Start(6)), Ok(6));
+    assert_eq!(file.read(&mut buffer), Ok(5));
+    assert_eq!(&buffer, b"world");
+    assert_eq!(file.read(&mut buffer), Ok(0));
+    assert_eq!(file.seek(Seek::End(-5)), Ok(6));
+
+    let file = open_in(&mounts, "/notes", OpenFlags::WRITE | OpenFlags::TRUNCATE).unwrap();
+    assert_eq!(file.metadata().size, 0);
+
+    let root = open_in(&mounts, "/", OpenFlags::READ).unwrap();
+    let entry = root.read_dir().unwrap().unwrap();
+    assert_eq!(entry.name, "notes");
+    assert_eq!(root.read_dir(), Ok(None));
+    assert_eq!(root.read(&mut buffer), Err(Error::IsDirectory));
+}
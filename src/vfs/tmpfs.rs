@@ -0,0 +1,244 @@
+//! tmpfs: a file system that keeps its files on the heap and loses them at shutdown.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spinning::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{DirEntry, Error, FileSystem, FileType, Inode, Metadata};
+
+pub struct TmpFs {
+    root: Arc<Node>,
+}
+
+impl TmpFs {
+    pub fn new() -> Self {
+        TmpFs {
+            root: Node::new(FileType::Directory, Arc::new(AtomicU64::new(1))),
+        }
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+enum Contents {
+    File(Vec<u8>),
+    /// The entries sorted by name.
+    Directory(BTreeMap<String, Arc<Node>>),
+    /// The target path.
+    Symlink(String),
+}
+
+struct Node {
+    inode: u64,
+    contents: Mutex<Contents>,
+    /// Shared by the nodes of one file system, to number new ones.
+    next_inode: Arc<AtomicU64>,
+}
+
+impl Node {
+    fn new(kind: FileType, next_inode: Arc<AtomicU64>) -> Arc<Node> {
+        let contents = match kind {
+            FileType::Directory => Contents::Directory(BTreeMap::new()),
+            _ => Contents::File(Vec::new()),
+        };
+        Node::with(contents, next_inode)
+    }
+
+    fn with(contents: Contents, next_inode: Arc<AtomicU64>) -> Arc<Node> {
+        Arc::new(Node {
+            inode: next_inode.fetch_add(1, Ordering::Relaxed),
+            contents: Mutex::new(contents),
+            next_inode,
+        })
+    }
+
+    fn kind(&self) -> FileType {
+        self.with_contents(|contents| match contents {
+            Contents::File(_) => FileType::File,
+            Contents::Directory(_) => FileType::Directory,
+            Contents::Symlink(_) => FileType::Symlink,
+        })
+    }
+
+    /// Adds the node to the directory with the name.
+    fn add(&self, name: &str, node: Arc<Node>) -> Result<Arc<dyn Inode>, Error> {
+        self.with_entries(|entries| {
+            if entries.contains_key(name) {
+                return Err(Error::AlreadyExists);
+            }
+            entries.insert(name.to_string(), node.clone());
+            Ok(node as Arc<dyn Inode>)
+        })
+    }
+
+    fn with_contents<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Contents) -> R,
+    {
+        interrupts::without_interrupts(|| f(&mut self.contents.lock()))
+    }
+
+    fn with_data<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut Vec<u8>) -> Result<R, Error>,
+    {
+        self.with_contents(|contents| match contents {
+            Contents::File(data) => f(data),
+            Contents::Directory(_) => Err(Error::IsDirectory),
+            Contents::Symlink(_) => Err(Error::NotSupported),
+        })
+    }
+
+    fn with_entries<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut BTreeMap<String, Arc<Node>>) -> Result<R, Error>,
+    {
+        self.with_contents(|contents| match contents {
+            Contents::Directory(entries) => f(entries),
+            Contents::File(_) | Contents::Symlink(_) => Err(Error::NotDirectory),
+        })
+    }
+}
+
+fn to_usize(value: u64) -> Result<usize, Error> {
+    usize::try_from(value).map_err(|_| Error::NoSpace)
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> Metadata {
+        self.with_contents(|contents| {
+            let (kind, size) = match contents {
+                Contents::File(data) => (FileType::File, data.len()),
+                Contents::Directory(entries) => (FileType::Directory, entries.len()),
+                Contents::Symlink(target) => (FileType::Symlink, target.len()),
+            };
+            Metadata::new(self.inode, kind, size as u64)
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.with_data(|data| {
+            let data = data.get(to_usize(offset)?..).unwrap_or(&[]);
+            let length = data.len().min(buffer.len());
+            buffer[..length].copy_from_slice(&data[..length]);
+            Ok(length)
+        })
+    }
+
+    fn write_at(&self, offset: u64, new: &[u8]) -> Result<usize, Error> {
+        self.with_data(|data| {
+            let start = to_usize(offset)?;
+            let end = start.checked_add(new.len()).ok_or(Error::NoSpace)?;
+            if data.len() < end {
+                data.resize(end, 0)
+            }
+            data[start..end].copy_from_slice(new);
+            Ok(new.len())
+        })
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), Error> {
+        let size = to_usize(size)?;
+        self.with_data(|data| {
+            data.resize(size, 0);
+            Ok(())
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Error> {
+        self.with_entries(|entries| {
+            let node = entries.get(name).ok_or(Error::NotFound)?;
+            Ok(node.clone() as Arc<dyn Inode>)
+        })
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, Error> {
+        if let FileType::CharDevice | FileType::Symlink = kind {
+            return Err(Error::NotSupported);
+        }
+        self.add(name, Node::new(kind, self.next_inode.clone()))
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, Error> {
+        let contents = Contents::Symlink(target.to_string());
+        self.add(name, Node::with(contents, self.next_inode.clone()))
+    }
+
+    fn read_link(&self) -> Result<String, Error> {
+        self.with_contents(|contents| match contents {
+            Contents::Symlink(target) => Ok(target.clone()),
+            _ => Err(Error::NotSupported),
+        })
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Error> {
+        let entry = self.with_entries(|entries| {
+            Ok(entries
+                .iter()
+                .nth(index)
+                .map(|(name, node)| (name.clone(), node.clone())))
+        })?;
+        // The entry is locked after the directory is unlocked.
+        Ok(entry.map(|(name, node)| DirEntry {
+            name,
+            inode: node.inode,
+            kind: node.kind(),
+        }))
+    }
+
+    fn remove(&self, name: &str) -> Result<(), Error> {
+        self.with_entries(|entries| {
+            let node = entries.get(name).ok_or(Error::NotFound)?;
+            // Nodes never lock their parent, so locking the child here cannot deadlock.
+            let empty = node.with_contents(|contents| match contents {
+                Contents::Directory(entries) => entries.is_empty(),
+                Contents::File(_) | Contents::Symlink(_) => true,
+            });
+            if !empty {
+                return Err(Error::NotEmpty);
+            }
+            entries.remove(name);
+            Ok(())
+        })
+    }
+}
+
+#[test_case]
+fn files_and_directories() {
+    let tmpfs = TmpFs::new();
+    let root = tmpfs.root();
+    let directory = root.create("dir", FileType::Directory).unwrap();
+    let file = directory.create("file", FileType::File).unwrap();
+    assert_ne!(directory.metadata().inode, file.metadata().inode);
+
+    assert_eq!(file.write_at(3, b"abc"), Ok(3));
+    let mut buffer = [0xFF; 8];
+    assert_eq!(file.read_at(0, &mut buffer), Ok(6));
+    assert_eq!(&buffer[..6], b"\0\0\0abc");
+    assert_eq!(file.read_at(10, &mut buffer), Ok(0));
+    file.truncate(1).unwrap();
+    assert_eq!(file.metadata().size, 1);
+
+    assert_eq!(file.lookup("x").err(), Some(Error::NotDirectory));
+    assert_eq!(directory.read_at(0, &mut buffer), Err(Error::IsDirectory));
+    root.create("a", FileType::File).unwrap();
+    let names: Vec<String> = (0..)
+        .map_while(|index| root.read_dir(index).unwrap())
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(names, ["a", "dir"]);
+
+    assert_eq!(root.remove("dir"), Err(Error::NotEmpty));
+    directory.remove("file").unwrap();
+    root.remove("dir").unwrap();
+    assert_eq!(root.lookup("dir").err(), Some(Error::NotFound));
+}
@@ -0,0 +1,195 @@
+//! Parser for the VT100 and ANSI escape sequences in the text written to the screen.
+//!
+//! The parser splits the bytes into characters to print, control characters like line feeds,
+//! escape sequences like `ESC 7`, and control sequences like `ESC [ 1 ; 31 m`. What they do is up
+//! to the writer.
+
+const ESCAPE: u8 = 0x1b;
+const DELETE: u8 = 0x7f;
+
+/// Most parameters kept of a control sequence, later ones are ignored.
+const MAX_PARAMETERS: usize = 8;
+
+/// The numbers of a control sequence, separated by `;`. Empty ones are 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Parameters {
+    values: [u16; MAX_PARAMETERS],
+    length: usize,
+}
+
+impl Parameters {
+    fn new() -> Self {
+        Parameters {
+            values: [0; MAX_PARAMETERS],
+            length: 0,
+        }
+    }
+
+    /// Returns the parameter at the index, or `default` if it is missing or 0.
+    pub fn get(&self, index: usize, default: u16) -> u16 {
+        match self.iter().nth(index) {
+            Some(0) | None => default,
+            Some(value) => value,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.values[..self.length.min(MAX_PARAMETERS)]
+            .iter()
+            .copied()
+    }
+
+    fn add_digit(&mut self, digit: u8) {
+        if self.length == 0 {
+            self.length = 1
+        }
+        if let Some(value) = self.values.get_mut(self.length - 1) {
+            *value = value.saturating_mul(10).saturating_add(u16::from(digit))
+        }
+    }
+
+    fn next(&mut self) {
+        // An empty first parameter still counts.
+        self.length = self.length.max(1) + 1
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Character to show, which may be outside of ASCII.
+    Print(u8),
+    /// Control character, like a line feed or a backspace.
+    Control(u8),
+    /// `ESC` followed by the byte.
+    Escape(u8),
+    /// `ESC [`, the parameters and the byte that names the command. Private sequences, like
+    /// `ESC [ ? 25 l`, start with a `?` or another byte from `<` to `?`.
+    ControlSequence {
+        parameters: Parameters,
+        private: bool,
+        command: u8,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    ControlSequence,
+}
+
+pub struct Parser {
+    state: State,
+    parameters: Parameters,
+    private: bool,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Parser {
+            state: State::Ground,
+            parameters: Parameters::new(),
+            private: false,
+        }
+    }
+
+    /// Returns what to do once the byte completes a character or sequence.
+    pub fn advance(&mut self, byte: u8) -> Option<Action> {
+        // Escape starts a new sequence anywhere, even in an unfinished one.
+        if byte == ESCAPE {
+            self.state = State::Escape;
+            return None;
+        }
+        match self.state {
+            State::Ground => match byte {
+                0x00..=0x1f | DELETE => Some(Action::Control(byte)),
+                byte => Some(Action::Print(byte)),
+            },
+            State::Escape => {
+                if byte == b'[' {
+                    self.state = State::ControlSequence;
+                    self.parameters = Parameters::new();
+                    self.private = false;
+                    None
+                } else {
+                    self.state = State::Ground;
+                    Some(Action::Escape(byte))
+                }
+            }
+            // Control characters in a sequence still take effect, like on a VT100.
+            State::ControlSequence => match byte {
+                0x00..=0x1f => Some(Action::Control(byte)),
+                b'0'..=b'9' => {
+                    self.parameters.add_digit(byte - b'0');
+                    None
+                }
+                b';' => {
+                    self.parameters.next();
+                    None
+                }
+                b'<'..=b'?' => {
+                    self.private = true;
+                    None
+                }
+                0x40..=0x7e => {
+                    self.state = State::Ground;
+                    Some(Action::ControlSequence {
+                        parameters: self.parameters,
+                        private: self.private,
+                        command: byte,
+                    })
+                }
+                // Intermediate bytes, which no supported sequence has.
+                _ => None,
+            },
+        }
+    }
+}
+
+#[test_case]
+fn parse() {
+    use alloc::vec::Vec;
+
+    let mut parser = Parser::new();
+    let actions: Vec<Action> = b"a\n\x1b7\x1b[1;;31m\x1b[?25l\x1b[K\xfe"
+        .iter()
+        .filter_map(|&byte| parser.advance(byte))
+        .collect();
+    assert_eq!(actions.len(), 7);
+    assert_eq!(
+        actions[..3],
+        [
+            Action::Print(b'a'),
+            Action::Control(b'\n'),
+            Action::Escape(b'7')
+        ]
+    );
+    match actions[3] {
+        Action::ControlSequence {
+            parameters,
+            private: false,
+            command: b'm',
+        } => assert!(parameters.iter().eq([1, 0, 31])),
+        action => panic!("Unexpected {:?}", action),
+    }
+    assert!(matches!(
+        actions[4],
+        Action::ControlSequence {
+            private: true,
+            command: b'l',
+            ..
+        }
+    ));
+    match actions[5] {
+        Action::ControlSequence {
+            parameters,
+            command: b'K',
+            ..
+        } => {
+            assert_eq!(parameters.iter().count(), 0);
+            assert_eq!(parameters.get(0, 1), 1);
+        }
+        action => panic!("Unexpected {:?}", action),
+    }
+    assert_eq!(actions[6], Action::Print(0xfe));
+}
@@ -23,10 +23,48 @@ pub enum Color {
     White,
 }
 
+/// The ANSI colors 0 to 7 in their normal variant. The bright variants come 8 colors later.
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+];
+
 impl Color {
     pub fn number(&self) -> u8 {
         *self as u8
     }
+
+    /// Returns the color with the ANSI number from 0 to 7, like 1 for red.
+    pub fn from_ansi(number: u8, bright: bool) -> Color {
+        let color = ANSI_COLORS[usize::from(number % 8)];
+        if bright {
+            color.bright()
+        } else {
+            color
+        }
+    }
+
+    /// Returns the bright variant of a normal color, like light red for red.
+    pub fn bright(self) -> Color {
+        match self.number() {
+            number @ 0..=7 => Color::try_from(number + 8).unwrap(),
+            _ => self,
+        }
+    }
+
+    /// Returns the normal variant of a bright color.
+    pub fn normal(self) -> Color {
+        match self.number() {
+            number @ 8..=15 => Color::try_from(number - 8).unwrap(),
+            _ => self,
+        }
+    }
 }
 
 impl TryFrom<u8> for Color {
@@ -44,7 +82,7 @@ impl TryFrom<u8> for Color {
 pub struct ColorCode(u8);
 
 impl ColorCode {
-    pub fn new(foreground: Color, background: Color) -> ColorCode {
+    pub const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
 
@@ -81,6 +119,17 @@ fn Color_from() {
     assert!(Color::try_from((Color::VARIANT_COUNT + 1) as u8).is_err());
 }
 
+#[test_case]
+fn Color_from_ansi() {
+    assert_eq!(Color::from_ansi(1, false), Color::Red);
+    assert_eq!(Color::from_ansi(1, true), Color::LightRed);
+    assert_eq!(Color::from_ansi(3, false), Color::Brown);
+    assert_eq!(Color::from_ansi(3, true), Color::Yellow);
+    assert_eq!(Color::from_ansi(7, true), Color::White);
+    assert_eq!(Color::Yellow.normal(), Color::Brown);
+    assert_eq!(Color::Yellow.bright(), Color::Yellow);
+}
+
 #[test_case]
 fn ColorCode_foreground() {
     let fg = Color::Blue;
@@ -1,3 +1,4 @@
+mod ansi;
 mod color;
 mod color_scoped_writer;
 mod writer;
@@ -40,6 +41,18 @@ pub fn _print(args: fmt::Arguments, color_code: Option<ColorCode>) {
     })
 }
 
+/// Pages the screen back through the lines that scrolled off it. Called by the keyboard driver for
+/// Shift+PageUp.
+pub fn page_up() {
+    x86_64::instructions::interrupts::without_interrupts(|| WRITER.lock().page_up())
+}
+
+/// Pages the screen forward again, up to what is currently written. Called by the keyboard driver
+/// for Shift+PageDown.
+pub fn page_down() {
+    x86_64::instructions::interrupts::without_interrupts(|| WRITER.lock().page_down())
+}
+
 #[doc(hidden)]
 pub fn _eprint(args: fmt::Arguments) {
     _print(args, Some(ColorCode::new(Color::Red, Color::Black)))
@@ -1,13 +1,25 @@
 use core::fmt;
+use core::ops::Range;
+use core::ptr::addr_of_mut;
 use lazy_static::lazy_static;
 use spinning::Mutex;
 use volatile::Volatile;
+use x86_64::instructions::port::Port;
 
+use super::ansi::{Action, Parameters, Parser};
 use super::color::{Color, ColorCode};
 use super::color_scoped_writer::ColorScopedWriter;
 
+/// Amount of lines that scrolled off the top of the screen and can be paged back to.
+const SCROLLBACK_LINES: usize = 4096;
+
+// Only used by the global writer. All zeros, so it takes no space in the kernel image.
+static mut SCROLLBACK: [Line; SCROLLBACK_LINES] = [[EMPTY; BUFFER_WIDTH]; SCROLLBACK_LINES];
+
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer::new());
+    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer::with_scrollback(unsafe {
+        &mut *addr_of_mut!(SCROLLBACK)
+    }));
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -17,28 +29,120 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
+const EMPTY: ScreenChar = ScreenChar {
+    ascii_character: 0,
+    color_code: ColorCode::new(Color::Black, Color::Black),
+};
+
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
 
+type Line = [ScreenChar; BUFFER_WIDTH];
+
+const DEFAULT_FOREGROUND: Color = Color::Yellow;
+const DEFAULT_BACKGROUND: Color = Color::Black;
+const TAB_WIDTH: usize = 8;
+const BACKSPACE: u8 = 0x08;
+
+// The CRT controller registers are selected through one port and written through the next.
+const CRTC_ADDRESS_PORT: u16 = 0x3D4;
+const CRTC_DATA_PORT: u16 = 0x3D5;
+const CURSOR_LOCATION_HIGH: u8 = 0x0E;
+const CURSOR_LOCATION_LOW: u8 = 0x0F;
+
 #[repr(transparent)]
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+/// Ring buffer of the lines that scrolled off the top of the screen, oldest first.
+struct Scrollback {
+    lines: &'static mut [Line],
+    start: usize,
+    length: usize,
+}
+
+impl Scrollback {
+    fn push(&mut self, line: Line) {
+        let capacity = self.lines.len();
+        if capacity == 0 {
+            return;
+        }
+        if self.length < capacity {
+            self.lines[(self.start + self.length) % capacity] = line;
+            self.length += 1
+        } else {
+            // Full, the oldest line makes room.
+            self.lines[self.start] = line;
+            self.start = (self.start + 1) % capacity
+        }
+    }
+
+    fn get(&self, index: usize) -> Line {
+        self.lines[(self.start + index) % self.lines.len()]
+    }
+}
+
+/// Writes to the screen at the cursor, which starts on the bottom row. New lines on the bottom row
+/// scroll the rows above up.
+///
+/// The written text can contain VT100 and ANSI escape sequences, which move the cursor, erase
+/// parts of the screen and set the colors. SGR colors are mapped to the 16 VGA colors, and bold
+/// text gets the bright variant of its color.
+///
+/// What the screen shows is also kept in memory, together with the lines that scrolled off the
+/// top. The screen can be paged back through them, until the next output brings back the live
+/// view.
 pub struct Writer {
     column_position: usize,
+    row_position: usize,
+    // Cursor position stored by `ESC 7` or `ESC [ s`.
+    saved_position: (usize, usize),
+    parser: Parser,
+    bold: bool,
     color_code: ColorCode,
     previous_color_code: Option<ColorCode>,
+    // The live view, what the screen shows when it is not paged back.
+    rows: [Line; BUFFER_HEIGHT],
+    scrollback: Scrollback,
+    // Amount of lines the screen is paged back.
+    view_offset: usize,
     buffer: &'static mut Buffer,
 }
 
 impl Writer {
+    /// Creates a writer without scrollback, which leaves the storage to the global writer.
+    #[cfg(test)]
     pub fn new() -> Self {
+        Writer::with_scrollback(&mut [])
+    }
+
+    /// Creates a writer that keeps as many lines that scrolled off the screen as `lines` holds.
+    /// It starts with what is on the screen.
+    fn with_scrollback(lines: &'static mut [Line]) -> Self {
+        let buffer = unsafe { &mut *(0xb8000 as *mut Buffer) };
+        let mut rows = [[EMPTY; BUFFER_WIDTH]; BUFFER_HEIGHT];
+        for (row, screen_row) in rows.iter_mut().zip(buffer.chars.iter()) {
+            for (character, screen_character) in row.iter_mut().zip(screen_row.iter()) {
+                *character = screen_character.read()
+            }
+        }
         Writer {
             column_position: 0,
-            color_code: ColorCode::new(Color::Yellow, Color::Black),
+            row_position: BUFFER_HEIGHT - 1,
+            saved_position: (BUFFER_HEIGHT - 1, 0),
+            parser: Parser::new(),
+            bold: false,
+            color_code: ColorCode::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND),
             previous_color_code: None,
-            buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+            rows,
+            scrollback: Scrollback {
+                lines,
+                start: 0,
+                length: 0,
+            },
+            view_offset: 0,
+            buffer,
         }
     }
 
@@ -46,55 +150,273 @@ impl Writer {
         self.color_code
     }
 
+    /// Writes the text, interpreting the escape sequences in it. Bytes outside of ASCII are
+    /// shown as a square.
     pub fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
-            match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // Invalid ASCII range
-                _ => self.write_byte(0xfe),
+            if let Some(action) = self.parser.advance(byte) {
+                self.perform(action)
             }
         }
+        self.move_cursor()
     }
 
+    /// Writes the byte at the cursor. Line feeds start a new line and carriage returns go back to
+    /// the start of the line, other bytes are shown as they are.
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            b'\r' => self.column_position = 0,
             byte => {
+                if self.view_offset > 0 {
+                    self.show_live_view()
+                }
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line()
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
 
                 let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code,
-                });
+                self.put(
+                    row,
+                    col,
+                    ScreenChar {
+                        ascii_character: byte,
+                        color_code,
+                    },
+                );
                 self.column_position += 1;
             }
         }
     }
 
+    /// Moves the cursor to the start of the next row. On the bottom row, the rows scroll up and the
+    /// top one goes to the scrollback.
     pub fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character)
+        if self.view_offset > 0 {
+            self.show_live_view()
+        }
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1
+        } else {
+            self.scrollback.push(self.rows[0]);
+            self.rows.copy_within(1.., 0);
+            for row in 0..BUFFER_HEIGHT - 1 {
+                self.draw_row(row, self.rows[row])
             }
+            self.clear_row(BUFFER_HEIGHT - 1);
         }
-        self.clear_row(BUFFER_HEIGHT - 1);
         self.column_position = 0;
     }
 
     pub fn clear_row(&mut self, row: usize) {
+        self.erase(row, 0..BUFFER_WIDTH)
+    }
+
+    fn perform(&mut self, action: Action) {
+        match action {
+            Action::Print(byte) if byte.is_ascii() => self.write_byte(byte),
+            // Invalid ASCII range
+            Action::Print(_) => self.write_byte(0xfe),
+            Action::Control(byte @ (b'\n' | b'\r')) => self.write_byte(byte),
+            Action::Control(BACKSPACE) => self.column_position = self.column().saturating_sub(1),
+            Action::Control(b'\t') => {
+                let stop = (self.column() / TAB_WIDTH + 1) * TAB_WIDTH;
+                self.column_position = stop.min(BUFFER_WIDTH - 1)
+            }
+            Action::Control(_) => (),
+            Action::Escape(b'7') => self.save_cursor(),
+            Action::Escape(b'8') => self.restore_cursor(),
+            Action::Escape(_) => (),
+            // Private sequences, like showing and hiding the cursor, are not supported.
+            Action::ControlSequence { private: true, .. } => (),
+            Action::ControlSequence {
+                parameters,
+                command,
+                ..
+            } => self.control_sequence(command, &parameters),
+        }
+    }
+
+    fn control_sequence(&mut self, command: u8, parameters: &Parameters) {
+        let count = usize::from(parameters.get(0, 1));
+        let (last_row, last_column) = (BUFFER_HEIGHT - 1, BUFFER_WIDTH - 1);
+        match command {
+            b'A' => self.row_position = self.row_position.saturating_sub(count),
+            b'B' => self.row_position = (self.row_position + count).min(last_row),
+            b'C' => self.column_position = (self.column() + count).min(last_column),
+            b'D' => self.column_position = self.column().saturating_sub(count),
+            b'G' => self.column_position = (count - 1).min(last_column),
+            b'H' | b'f' => {
+                self.row_position = (count - 1).min(last_row);
+                self.column_position = usize::from(parameters.get(1, 1) - 1).min(last_column)
+            }
+            b'J' => self.erase_screen(parameters.get(0, 0)),
+            b'K' => self.erase_line(parameters.get(0, 0)),
+            b'm' => self.select_graphic_rendition(parameters),
+            b's' => self.save_cursor(),
+            b'u' => self.restore_cursor(),
+            _ => (),
+        }
+    }
+
+    /// Erases from the cursor to the end of the screen for mode 0, from the start of the screen to
+    /// the cursor for mode 1, and the whole screen for mode 2. Mode 3 also clears the scrollback.
+    fn erase_screen(&mut self, mode: u16) {
+        // The view pages back into the scrollback, which may be cleared.
+        self.show_live_view();
+        let (row, column) = (self.row_position, self.column());
+        match mode {
+            0 => {
+                self.erase(row, column..BUFFER_WIDTH);
+                (row + 1..BUFFER_HEIGHT).for_each(|row| self.clear_row(row))
+            }
+            1 => {
+                (0..row).for_each(|row| self.clear_row(row));
+                self.erase(row, 0..column + 1)
+            }
+            2 | 3 => {
+                (0..BUFFER_HEIGHT).for_each(|row| self.clear_row(row));
+                if mode == 3 {
+                    self.scrollback.length = 0
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Erases from the cursor to the end of the line for mode 0, from the start of the line to the
+    /// cursor for mode 1, and the whole line for mode 2.
+    fn erase_line(&mut self, mode: u16) {
+        let (row, column) = (self.row_position, self.column());
+        match mode {
+            0 => self.erase(row, column..BUFFER_WIDTH),
+            1 => self.erase(row, 0..column + 1),
+            2 => self.clear_row(row),
+            _ => (),
+        }
+    }
+
+    fn erase(&mut self, row: usize, columns: Range<usize>) {
         let blank = ScreenChar {
             ascii_character: b' ',
             color_code: self.color_code,
         };
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank)
+        for col in columns {
+            self.put(row, col, blank)
+        }
+    }
+
+    /// Sets the colors and boldness from the SGR parameters.
+    fn select_graphic_rendition(&mut self, parameters: &Parameters) {
+        let mut foreground = self.color_code.foreground().unwrap_or(DEFAULT_FOREGROUND);
+        let mut background = self.color_code.background().unwrap_or(DEFAULT_BACKGROUND);
+        // No parameters mean a reset.
+        let reset = parameters.iter().next().is_none().then_some(0);
+        for parameter in parameters.iter().chain(reset) {
+            match parameter {
+                0 => {
+                    self.bold = false;
+                    foreground = DEFAULT_FOREGROUND;
+                    background = DEFAULT_BACKGROUND
+                }
+                1 => {
+                    self.bold = true;
+                    foreground = foreground.bright()
+                }
+                22 => {
+                    self.bold = false;
+                    foreground = foreground.normal()
+                }
+                30..=37 => foreground = Color::from_ansi((parameter - 30) as u8, self.bold),
+                39 => foreground = DEFAULT_FOREGROUND,
+                40..=47 => background = Color::from_ansi((parameter - 40) as u8, false),
+                49 => background = DEFAULT_BACKGROUND,
+                90..=97 => foreground = Color::from_ansi((parameter - 90) as u8, true),
+                100..=107 => background = Color::from_ansi((parameter - 100) as u8, true),
+                _ => (),
+            }
+        }
+        self.color_code = ColorCode::new(foreground, background)
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_position = (self.row_position, self.column())
+    }
+
+    fn restore_cursor(&mut self) {
+        (self.row_position, self.column_position) = self.saved_position
+    }
+
+    /// Returns the column of the cursor. After the last column of a row the cursor stays in it
+    /// until the next character wraps to a new line.
+    fn column(&self) -> usize {
+        self.column_position.min(BUFFER_WIDTH - 1)
+    }
+
+    /// Pages back half a screen towards older lines.
+    pub fn page_up(&mut self) {
+        let offset = self.view_offset + BUFFER_HEIGHT / 2;
+        self.scroll_view(offset.min(self.scrollback.length))
+    }
+
+    /// Pages forward half a screen towards the live view.
+    pub fn page_down(&mut self) {
+        self.scroll_view(self.view_offset.saturating_sub(BUFFER_HEIGHT / 2))
+    }
+
+    fn show_live_view(&mut self) {
+        self.scroll_view(0)
+    }
+
+    /// Shows the screen as it was `offset` lines ago.
+    fn scroll_view(&mut self, offset: usize) {
+        if offset == self.view_offset {
+            return;
+        }
+        self.view_offset = offset;
+        let first = self.scrollback.length - offset;
+        for row in 0..BUFFER_HEIGHT {
+            let line = match first + row {
+                index if index < self.scrollback.length => self.scrollback.get(index),
+                index => self.rows[index - self.scrollback.length],
+            };
+            self.draw_row(row, line)
+        }
+        self.move_cursor()
+    }
+
+    fn put(&mut self, row: usize, col: usize, character: ScreenChar) {
+        self.rows[row][col] = character;
+        if self.view_offset == 0 {
+            self.buffer.chars[row][col].write(character)
+        }
+    }
+
+    fn draw_row(&mut self, row: usize, line: Line) {
+        for (screen_character, character) in self.buffer.chars[row].iter_mut().zip(line) {
+            screen_character.write(character)
+        }
+    }
+
+    /// Moves the blinking hardware cursor to where the next character will be written. While
+    /// paged back, it is moved off the screen, which hides it.
+    fn move_cursor(&self) {
+        let position = if self.view_offset > 0 {
+            BUFFER_HEIGHT * BUFFER_WIDTH
+        } else {
+            self.row_position * BUFFER_WIDTH + self.column()
+        };
+        let mut address = Port::<u8>::new(CRTC_ADDRESS_PORT);
+        let mut data = Port::<u8>::new(CRTC_DATA_PORT);
+        let [low, high] = (position as u16).to_le_bytes();
+        unsafe {
+            address.write(CURSOR_LOCATION_HIGH);
+            data.write(high);
+            address.write(CURSOR_LOCATION_LOW);
+            data.write(low)
         }
     }
 
@@ -126,6 +448,17 @@ impl fmt::Write for Writer {
     }
 }
 
+#[test_case]
+fn carriage_return() {
+    let mut writer = Writer::new();
+    writer.write_string("\nabc\rx");
+
+    let row = &writer.buffer.chars[BUFFER_HEIGHT - 1];
+    let text: [u8; 3] = core::array::from_fn(|col| row[col].read().ascii_character);
+    assert_eq!(&text, b"xbc");
+    assert_eq!(writer.column_position, 1);
+}
+
 #[test_case]
 fn set_color_code() {
     let mut writer = Writer::new();
@@ -188,3 +521,86 @@ fn color_scope() {
 
     assert_eq!(writer.color_code, previous);
 }
+
+#[cfg(test)]
+fn row_text(writer: &Writer, row: usize) -> alloc::string::String {
+    let characters = writer.buffer.chars[row].iter();
+    let text: alloc::string::String = characters
+        .map(|character| char::from(character.read().ascii_character))
+        .collect();
+    text.trim_end().into()
+}
+
+#[test_case]
+fn escape_sequences() {
+    let mut writer = Writer::new();
+    // Clear the screen and go to the top left corner.
+    writer.write_string("\x1b[2J\x1b[Hab\x1b[1;31mc\x1b[0m");
+    assert_eq!(row_text(&writer, 0), "abc");
+    let color =
+        |writer: &Writer, row: usize, col: usize| writer.buffer.chars[row][col].read().color_code;
+    assert_eq!(
+        color(&writer, 0, 1),
+        ColorCode::new(Color::Yellow, Color::Black)
+    );
+    assert_eq!(
+        color(&writer, 0, 2),
+        ColorCode::new(Color::LightRed, Color::Black)
+    );
+    assert_eq!(
+        writer.color_code,
+        ColorCode::new(Color::Yellow, Color::Black)
+    );
+
+    // Move, save the position, move again and write at the saved position.
+    writer.write_string("\x1b[3;5Hx\x1b[s\x1b[10;10H\x1b[44my\x1b[49m\x1b[uz");
+    assert_eq!(row_text(&writer, 2), "    xz");
+    assert_eq!(row_text(&writer, 9), "         y");
+    assert_eq!(
+        color(&writer, 9, 9),
+        ColorCode::new(Color::Yellow, Color::Blue)
+    );
+
+    // Erase the end of the first line, then go up from the third line and back.
+    writer.write_string("\x1b[1;2H\x1b[K\x1b[3;1H\x1b[2A\x1b[2C-\x08\x08+");
+    assert_eq!(row_text(&writer, 0), "a+-");
+    assert_eq!((writer.row_position, writer.column_position), (0, 2));
+}
+
+#[test_case]
+fn scrollback() {
+    use alloc::vec;
+
+    let lines = vec![[EMPTY; BUFFER_WIDTH]; 30].leak();
+    let mut writer = Writer::with_scrollback(lines);
+    for line in 0..60 {
+        writer.write_string(&alloc::format!("\nline {}", line))
+    }
+    // 25 rows that were on the screen and `line 0` to `line 4` did not fit in the scrollback.
+    assert_eq!(row_text(&writer, 0), "line 35");
+
+    writer.page_up();
+    assert_eq!(row_text(&writer, 0), "line 23");
+    assert_eq!(row_text(&writer, BUFFER_HEIGHT - 1), "line 47");
+    for _ in 0..3 {
+        writer.page_up()
+    }
+    assert_eq!(row_text(&writer, 0), "line 5");
+    writer.page_down();
+    assert_eq!(row_text(&writer, 0), "line 17");
+
+    // New output shows the live view again.
+    writer.write_string("!");
+    assert_eq!(row_text(&writer, 0), "line 35");
+    assert_eq!(row_text(&writer, BUFFER_HEIGHT - 1), "line 59!");
+
+    // Clearing the scrollback while paged back shows the cleared live view.
+    writer.page_up();
+    writer.page_up();
+    writer.write_string("\x1b[3J");
+    assert_eq!(writer.view_offset, 0);
+    assert_eq!(row_text(&writer, 0), "");
+    writer.page_down();
+    writer.page_up();
+    assert_eq!(row_text(&writer, BUFFER_HEIGHT - 1), "");
+}
@@ -0,0 +1,80 @@
+# Exits with a code built from what the loader set up, for the ELF loader tests:
+#
+#   bits 0-7    argument count
+#   bits 8-15   environment count
+#   bits 16-23  AT_PAGESZ from the auxiliary vector, in KiB
+#   bits 24-31  initialized data plus incremented zero-initialized data, 8 if both are loaded
+#   bits 32-47  length of the first argument
+
+.intel_syntax noprefix
+.include "abi.inc"
+
+.text
+.global _start
+_start:
+    mov rbx, [rsp]                  # argc
+    lea rsi, [rsp + 8 + rbx*8 + 8]  # envp
+
+    # Count the environment, rsi ends up after its null pointer at the auxiliary vector.
+    xor ecx, ecx
+count_env:
+    mov rax, [rsi]
+    add rsi, 8
+    test rax, rax
+    jz find_page_size
+    inc rcx
+    jmp count_env
+
+find_page_size:
+    xor edx, edx
+next_aux:
+    mov rax, [rsi]
+    cmp rax, AT_NULL
+    je aux_done
+    cmp rax, AT_PAGESZ
+    jne skip_aux
+    mov rdx, [rsi + 8]
+skip_aux:
+    add rsi, 16
+    jmp next_aux
+
+aux_done:
+    mov rax, rbx
+    shl rcx, 8
+    or rax, rcx
+    shr rdx, 10
+    shl rdx, 16
+    or rax, rdx
+
+    inc qword ptr [rip + counter]
+    mov rdx, [rip + value]
+    add rdx, [rip + counter]
+    shl rdx, 24
+    or rax, rdx
+
+    # Length of argv[0], if there is one.
+    test rbx, rbx
+    jz done
+    mov rsi, [rsp + 8]
+    xor ecx, ecx
+strlen:
+    cmp byte ptr [rsi + rcx], 0
+    je got_length
+    inc rcx
+    jmp strlen
+got_length:
+    shl rcx, 32
+    or rax, rcx
+
+done:
+    mov rdi, rax
+    mov rax, SYS_EXIT
+    syscall
+
+.data
+value:
+    .quad 7
+
+.bss
+counter:
+    .quad 0
@@ -0,0 +1,34 @@
+# Replaces itself with the args program, which exits with what it was started with.
+
+.intel_syntax noprefix
+.include "abi.inc"
+
+.text
+.global _start
+_start:
+    mov rax, SYS_EXEC
+    lea rdi, [rip + path]
+    lea rsi, [rip + argv]
+    lea rdx, [rip + envp]
+    syscall
+
+    # Only reached if exec failed, exit with the error.
+    mov rdi, rax
+    mov rax, SYS_EXIT
+    syscall
+
+.section .rodata
+path:
+    .asciz "/bin/args"
+first:
+    .asciz "args"
+second:
+    .asciz "x"
+variable:
+    .asciz "A=1"
+
+.data
+argv:
+    .quad first, second, 0
+envp:
+    .quad variable, 0
@@ -0,0 +1,86 @@
+# Writes "hello" to /tmp/file, reads it back from offset 1 and looks at it with stat and readdir.
+# Exits with the bytes read, the size, the readdir result, the length of the first name in /tmp and
+# the descriptor of the second open, one byte each.
+
+.intel_syntax noprefix
+.include "abi.inc"
+
+.text
+.global _start
+_start:
+    mov rax, SYS_OPEN
+    lea rdi, [rip + path]
+    mov rsi, O_WRITE | O_CREATE | O_TRUNCATE
+    syscall
+    mov rbx, rax
+    mov rax, SYS_WRITE
+    mov rdi, rbx
+    lea rsi, [rip + hello]
+    mov rdx, 5
+    syscall
+    mov rax, SYS_CLOSE
+    mov rdi, rbx
+    syscall
+
+    # The descriptor is free again, so this one is the same.
+    mov rax, SYS_OPEN
+    lea rdi, [rip + path]
+    mov rsi, O_READ
+    syscall
+    mov r15, rax
+    mov rax, SYS_SEEK
+    mov rdi, r15
+    mov rsi, 1
+    mov rdx, SEEK_SET
+    syscall
+    mov rax, SYS_READ
+    mov rdi, r15
+    lea rsi, [rip + buffer]
+    mov rdx, 16
+    syscall
+    mov r12, rax
+
+    mov rax, SYS_STAT
+    lea rdi, [rip + path]
+    lea rsi, [rip + stat]
+    syscall
+    mov r13, [rip + stat + 16]
+
+    mov rax, SYS_OPEN
+    lea rdi, [rip + root]
+    mov rsi, O_READ
+    syscall
+    mov rdi, rax
+    mov rax, SYS_READDIR
+    lea rsi, [rip + entry]
+    syscall
+    mov r14, rax
+
+    mov rdi, r12
+    shl r13, 8
+    or rdi, r13
+    shl r14, 16
+    or rdi, r14
+    mov rax, [rip + entry + 16]
+    shl rax, 24
+    or rdi, rax
+    shl r15, 32
+    or rdi, r15
+    mov rax, SYS_EXIT
+    syscall
+
+.section .rodata
+path:
+    .asciz "/tmp/file"
+root:
+    .asciz "/tmp"
+hello:
+    .ascii "hello"
+
+.bss
+buffer:
+    .skip 16
+stat:
+    .skip 72
+entry:
+    .skip 280
@@ -0,0 +1,46 @@
+# Forks, and the child changes memory it shares with the parent copy-on-write. The parent exits
+# with the exit code of the child plus its own value, 42 + 10 if the child got its own copy, and
+# 255 if waiting failed.
+
+.intel_syntax noprefix
+.include "abi.inc"
+
+.text
+.global _start
+_start:
+    mov qword ptr [rip + value], 10
+
+    mov rax, SYS_FORK
+    syscall
+    test rax, rax
+    jz child
+    mov rbx, rax
+
+    mov rax, SYS_WAITPID
+    mov rdi, rbx
+    lea rsi, [rip + status]
+    syscall
+    cmp rax, rbx
+    jne failed
+
+    mov rdi, [rip + status]
+    add rdi, [rip + value]
+    mov rax, SYS_EXIT
+    syscall
+
+failed:
+    mov rax, SYS_EXIT
+    mov rdi, 255
+    syscall
+
+child:
+    add qword ptr [rip + value], 32
+    mov rdi, [rip + value]
+    mov rax, SYS_EXIT
+    syscall
+
+.bss
+value:
+    .quad 0
+status:
+    .quad 0
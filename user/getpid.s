@@ -0,0 +1,14 @@
+# Exits with its process ID.
+
+.intel_syntax noprefix
+.include "abi.inc"
+
+.text
+.global _start
+_start:
+    mov rax, SYS_GETPID
+    syscall
+
+    mov rdi, rax
+    mov rax, SYS_EXIT
+    syscall
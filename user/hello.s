@@ -0,0 +1,22 @@
+# Writes a greeting to the screen and exits with code 0.
+
+.intel_syntax noprefix
+.include "abi.inc"
+
+.text
+.global _start
+_start:
+    mov rax, SYS_WRITE
+    mov rdi, STDOUT
+    lea rsi, [rip + message]
+    mov rdx, message_end - message
+    syscall
+
+    mov rax, SYS_EXIT
+    xor edi, edi
+    syscall
+
+.section .rodata
+message:
+    .ascii "Hello from user mode!\n"
+message_end: